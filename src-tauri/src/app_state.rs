@@ -11,7 +11,15 @@ use tokio::sync::mpsc;
 use tokio_tungstenite::tungstenite::protocol::Message;
 
 use r2d2::Pool;
-use std::collections::VecDeque;
+use std::collections::{HashSet, VecDeque};
+
+use crate::commands::messaging::inbox::governor::InboundGovernor;
+use crate::commands::messaging::inbox::handlers::media::MediaIntegrity;
+use crate::commands::network::config::NetworkConfig;
+use crate::commands::network::halts::HaltedTarget;
+use crate::commands::network::pacing::PacedSender;
+use crate::commands::network::relays::RelayEndpoint;
+use crate::commands::network::stats::NetworkStats;
 
 pub struct RusqliteManager {
     pub path: std::path::PathBuf,
@@ -40,6 +48,16 @@ pub struct DbState {
     pub media_key: Mutex<Option<Vec<u8>>>,
     pub profile: Mutex<String>,
     pub media_proxy_port: Mutex<Option<u16>>,
+    /// Set while the vault is unlocked; the media proxy refuses every request without it.
+    pub media_proxy_auth: Mutex<Option<MediaProxyAuth>>,
+}
+
+/// Per-unlock credentials of the local media proxy.
+pub struct MediaProxyAuth {
+    /// HMAC key signing proxy URLs; never leaves the process.
+    pub secret: [u8; 32],
+    /// Canonical paths the user picked for sending, the only files `/local` serves.
+    pub local_paths: HashSet<std::path::PathBuf>,
 }
 
 #[derive(Debug)]
@@ -59,6 +77,17 @@ impl r2d2::CustomizeConnection<rusqlite::Connection, rusqlite::Error> for SqlCip
 }
 
 impl DbState {
+    /// A locked vault for `profile`; `init_vault` opens the pool.
+    pub fn new(profile: String) -> Self {
+        Self {
+            pool: Mutex::new(None),
+            media_key: Mutex::new(None),
+            profile: Mutex::new(profile),
+            media_proxy_port: Mutex::new(None),
+            media_proxy_auth: Mutex::new(None),
+        }
+    }
+
     pub fn get_conn(&self) -> Result<r2d2::PooledConnection<RusqliteManager>, String> {
         let lock = self.pool.lock().map_err(|_| "DB Pool lock poisoned")?;
         let pool = lock
@@ -71,6 +100,9 @@ impl DbState {
 
 pub struct PacedMessage {
     pub msg: Message,
+    /// Message row the packet belongs to, so an undelivered packet can be written back
+    /// to `pending_outbox` against it.
+    pub msg_id: Option<String>,
 }
 
 pub struct MediaTransferState {
@@ -79,38 +111,94 @@ pub struct MediaTransferState {
     pub last_activity: std::time::Instant,
     pub file_handle: Option<std::fs::File>,
     pub received_count: u32,
+    pub nacks_sent: u32,
+    pub last_nack: Option<std::time::Instant>,
 }
 
 #[derive(Clone, Debug)]
 pub struct PendingMediaMetadata {
     pub id: String,
     pub key: String,
+    /// Plaintext size from the announcement; fragments past it are padding.
+    pub size: u64,
+    pub integrity: MediaIntegrity,
+    /// Integrity resends already requested for this download.
+    pub resends: u32,
+    pub registered_at: std::time::Instant,
+}
+
+/// Ciphertext of an outgoing signal transfer, kept so fragments the recipient reports
+/// missing can be resent until its delivery receipt arrives.
+#[derive(Clone, Debug)]
+pub struct OutgoingSignal {
+    pub msg_id: String,
+    pub recipient: String,
+    pub payload: Vec<u8>,
+    pub queued_at: std::time::Instant,
+    /// Set once the relay has accepted the transfer; starts the receipt timeout.
+    pub accepted_at: Option<std::time::Instant>,
+    /// Only 1:1 messages are receipted; group copies are kept for resends alone.
+    pub awaits_receipt: bool,
+    /// Times the whole transfer went out again for want of a receipt.
+    pub retransmits: u32,
+    pub last_retransmit: Option<std::time::Instant>,
+}
+
+/// A user's pause or cancellation of a media transfer, in either direction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransferControl {
+    Paused,
+    /// Late fragments of a cancelled download are dropped until the entry is swept.
+    Cancelled(std::time::Instant),
 }
 
 #[derive(Clone, Debug)]
 pub struct OutgoingTransferInfo {
+    pub msg_id: String,
+    pub recipients: Vec<String>,
     pub file_path: std::path::PathBuf,
     pub transit_key: [u8; 32],
+    /// Fragment count announced on the wire, padding included.
+    pub total_fragments: u32,
+    pub started_at: std::time::Instant,
+    /// Set once every fragment has been sent; the resend retention runs from here.
+    pub finished_at: Option<std::time::Instant>,
 }
 
+#[derive(Default)]
 pub struct NetworkState {
     pub is_enabled: Mutex<bool>,
     pub url: Mutex<Option<String>>,
     pub proxy_url: Mutex<Option<String>>,
+    pub relays: Mutex<Vec<RelayEndpoint>>,
+    pub active_relay: Mutex<usize>,
+    pub config: Mutex<NetworkConfig>,
     pub queue: Mutex<VecDeque<PacedMessage>>,
-    pub sender: Mutex<Option<mpsc::Sender<PacedMessage>>>,
+    pub sender: Mutex<Option<PacedSender>>,
     pub cancel: Mutex<Option<tokio_util::sync::CancellationToken>>,
+    /// Resolves once the current writer task has persisted its unsent packets.
+    pub writer_drained: Mutex<Option<tokio::sync::oneshot::Receiver<()>>>,
     pub response_channels:
         Mutex<std::collections::HashMap<String, tokio::sync::oneshot::Sender<serde_json::Value>>>,
     pub is_authenticated: Mutex<bool>,
     pub identity_hash: Mutex<Option<String>>,
     pub session_token: Mutex<Option<String>>,
-    pub halted_targets: Mutex<std::collections::HashSet<String>>,
+    pub halted_targets: Mutex<std::collections::HashMap<String, HaltedTarget>>,
     pub media_assembler: Mutex<std::collections::HashMap<String, MediaTransferState>>,
+    pub inbound_governor: Mutex<InboundGovernor>,
     pub pending_media_links: Mutex<std::collections::HashMap<String, PendingMediaMetadata>>, // transfer_key -> (msg_id, dec_key)
+    /// Sender of each media transfer announced in a sealed envelope, keyed by the
+    /// routing header and transfer id its fragments arrive under.
+    pub sealed_media_routes: Mutex<std::collections::HashMap<String, String>>,
     pub binary_receiver: Mutex<Option<mpsc::UnboundedSender<Vec<u8>>>>,
     pub is_refilling: Mutex<bool>,
+    pub is_flushing: Mutex<bool>,
     pub jailed_until: Mutex<Option<tokio::time::Instant>>,
     pub pending_transfers: Mutex<std::collections::HashMap<u32, String>>,
     pub active_outgoing_transfers: Mutex<std::collections::HashMap<u32, OutgoingTransferInfo>>,
+    pub outgoing_signals: Mutex<std::collections::HashMap<u32, OutgoingSignal>>,
+    pub transfer_controls: Mutex<std::collections::HashMap<u32, TransferControl>>,
+    /// When each account's device list was last fetched from the relay.
+    pub device_lists: Mutex<std::collections::HashMap<String, std::time::Instant>>,
+    pub stats: NetworkStats,
 }
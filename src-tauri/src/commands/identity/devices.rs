@@ -0,0 +1,845 @@
+//! Linked Devices
+//!
+//! An account is the identity hash of its primary device. Every other device keeps
+//! an identity key of its own, which the primary authorizes with a signed
+//! `DeviceLink`; the linked device then authenticates and publishes its bundle under
+//! the account hash as `account.device_id`, so all of an account's devices share its
+//! routing hash. Senders encrypt once per device and carry the ciphertexts in one
+//! envelope from which each device takes its own, and messages sent from one device
+//! are mirrored to the others with an encrypted `sent_sync`.
+//!
+//! Links expire after `LINK_LIFETIME`. The primary sends each device it still
+//! authorizes a fresh link with an encrypted `link_renewal` before its current one
+//! runs out, so an unlinked device's link lapses even where a relay keeps listing it.
+//!
+//! Only text messages are mirrored. Media is streamed to its recipients alone, so
+//! the other devices would have nothing behind the entry; files, voice notes and
+//! group media stay on the device that sent them.
+
+use base64::Engine;
+use libsignal_protocol::{DeviceId, IdentityKeyPair, IdentityKeyStore, ProtocolAddress, PublicKey};
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use rusqlite::{Connection, params};
+use serde::Serialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Manager, Runtime, State};
+
+use crate::app_state::{DbState, NetworkState};
+use crate::commands::internal_request;
+use crate::commands::messaging::inbox::decrypt::parse_ciphertext;
+use crate::commands::network::relay_protocol::{
+    ClientMessage, DeviceLink, FetchKeyResponse, decode_response,
+};
+use crate::commands::network::transit::internal_send_to_network;
+use crate::commands::signal::{
+    has_session, internal_establish_session_logic, internal_signal_encrypt, parse_device_bundle,
+    signal_sync_keys,
+};
+use crate::signal_store::SqliteSignalStore;
+
+pub(crate) const PRIMARY_DEVICE: u32 = 1;
+/// Devices a primary may link besides itself.
+pub(crate) const MAX_LINKED_DEVICES: usize = 5;
+/// How long a fetched device list is used before the relay is asked again.
+pub(crate) const DEVICE_LIST_TTL: Duration = Duration::from_secs(6 * 60 * 60);
+/// How long a link authorizes its device.
+pub(crate) const LINK_LIFETIME: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// Links with less than this left are renewed.
+pub(crate) const LINK_RENEWAL_WINDOW: Duration = Duration::from_secs(10 * 24 * 60 * 60);
+const LINK_RENEWAL_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+fn strip_key(key: &[u8]) -> &[u8] {
+    if key.len() == 33 && key[0] == 0x05 {
+        &key[1..]
+    } else {
+        key
+    }
+}
+
+fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn link_message(
+    account: &str,
+    device_id: u32,
+    device_key: &str,
+    issued_at: u64,
+    expires_at: u64,
+) -> Vec<u8> {
+    format!(
+        "entropy-device-link\n{}\n{}\n{}\n{}\n{}",
+        account.to_lowercase(),
+        device_id,
+        device_key.to_lowercase(),
+        issued_at,
+        expires_at
+    )
+    .into_bytes()
+}
+
+fn decode_public_key(hex_key: &str) -> Result<PublicKey, String> {
+    let raw = hex::decode(hex_key).map_err(|_| "Invalid device key")?;
+    if raw.len() != 32 {
+        return Err("Invalid device key".into());
+    }
+    let mut prefixed = Vec::with_capacity(33);
+    prefixed.push(0x05);
+    prefixed.extend_from_slice(&raw);
+    PublicKey::deserialize(&prefixed).map_err(|e| e.to_string())
+}
+
+/// Signs a link authorizing `device_key` as `device_id` of the account `identity`
+/// is the primary of, for `LINK_LIFETIME` from `issued_at`.
+pub(crate) fn sign_link(
+    identity: &IdentityKeyPair,
+    device_id: u32,
+    device_key: &[u8],
+    issued_at: u64,
+) -> Result<DeviceLink, String> {
+    let primary_key = identity.identity_key().serialize();
+    let primary_key = strip_key(&primary_key);
+    let account = hex::encode(Sha256::digest(primary_key));
+    let device_key = hex::encode(strip_key(device_key));
+    let expires_at = issued_at + LINK_LIFETIME.as_millis() as u64;
+    let mut rng = StdRng::from_os_rng();
+    let signature = identity
+        .private_key()
+        .calculate_signature(
+            &link_message(&account, device_id, &device_key, issued_at, expires_at),
+            &mut rng,
+        )
+        .map_err(|e| e.to_string())?;
+    Ok(DeviceLink {
+        account,
+        primary_key: hex::encode(primary_key),
+        device_id,
+        device_key,
+        issued_at,
+        expires_at,
+        signature: hex::encode(signature),
+    })
+}
+
+/// Checks that a link was signed by the primary key its account hash names and has
+/// not expired.
+pub(crate) fn verify_link(link: &DeviceLink) -> Result<(), String> {
+    if link.device_id <= PRIMARY_DEVICE {
+        return Err("A device link cannot name the primary device".into());
+    }
+    decode_public_key(&link.device_key)?;
+    let primary_key = decode_public_key(&link.primary_key)?;
+    let primary_raw = hex::decode(&link.primary_key).map_err(|e| e.to_string())?;
+    if hex::encode(Sha256::digest(&primary_raw)) != link.account.to_lowercase() {
+        return Err("Device link is not from the account's primary device".into());
+    }
+    let signature = hex::decode(&link.signature).map_err(|_| "Invalid device link signature")?;
+    let message = link_message(
+        &link.account,
+        link.device_id,
+        &link.device_key,
+        link.issued_at,
+        link.expires_at,
+    );
+    if !primary_key.verify_signature(&message, &signature) {
+        return Err("Invalid device link signature".into());
+    }
+    if link.expires_at <= now_ms() {
+        return Err("Device link has expired".into());
+    }
+    Ok(())
+}
+
+/// Whether `link` authorizes the identity key `identity_key`.
+pub(crate) fn link_binds(link: &DeviceLink, identity_key: &[u8]) -> bool {
+    hex::encode(strip_key(identity_key)) == link.device_key.to_lowercase()
+}
+
+/// Splits `hash.device` into its parts; a bare hash names no particular device.
+pub(crate) fn split_address(address: &str) -> (&str, Option<u32>) {
+    match address.split_once('.') {
+        Some((hash, device)) => (hash, device.parse().ok()),
+        None => (address, None),
+    }
+}
+
+/// The wire form of a device address. The primary keeps the bare hash, which is
+/// what single-device clients have always used.
+pub(crate) fn device_address(hash: &str, device: u32) -> String {
+    if device == PRIMARY_DEVICE {
+        hash.to_string()
+    } else {
+        format!("{}.{}", hash, device)
+    }
+}
+
+pub(crate) fn protocol_address(hash: &str, device: u32) -> Result<ProtocolAddress, String> {
+    let device_id =
+        DeviceId::try_from(device).map_err(|_| format!("Invalid device id {}", device))?;
+    Ok(ProtocolAddress::new(hash.to_string(), device_id))
+}
+
+/// This device's link record, or `None` on a primary.
+pub(crate) fn stored_link(conn: &Connection) -> Option<DeviceLink> {
+    conn.query_row(
+        "SELECT device_link FROM signal_identity WHERE id = 0",
+        [],
+        |r| r.get::<_, Option<String>>(0),
+    )
+    .ok()
+    .flatten()
+    .and_then(|link| serde_json::from_str(&link).ok())
+}
+
+pub(crate) fn local_link<R: Runtime>(app: &AppHandle<R>) -> Option<DeviceLink> {
+    stored_link(&app.state::<DbState>().get_conn().ok()?)
+}
+
+pub(crate) fn local_device_id<R: Runtime>(app: &AppHandle<R>) -> u32 {
+    local_link(app).map_or(PRIMARY_DEVICE, |l| l.device_id)
+}
+
+/// Linked devices of `account` known here, not counting its primary.
+pub(crate) fn linked_devices(conn: &Connection, account: &str) -> Vec<u32> {
+    let Ok(mut stmt) = conn.prepare(
+        "SELECT device_id FROM account_devices
+         WHERE account = ?1 AND device_id > 1 AND revoked_at IS NULL ORDER BY device_id",
+    ) else {
+        return Vec::new();
+    };
+    stmt.query_map(params![account.to_lowercase()], |r| r.get(0))
+        .map(|rows| rows.filter_map(Result::ok).collect())
+        .unwrap_or_default()
+}
+
+pub(crate) fn record_device(
+    conn: &Connection,
+    link: &DeviceLink,
+    name: Option<&str>,
+) -> Result<(), String> {
+    conn.execute(
+        "INSERT INTO account_devices (account, device_id, device_key, link, name, updated_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+         ON CONFLICT(account, device_id) DO UPDATE SET
+            device_key = excluded.device_key,
+            link = excluded.link,
+            name = COALESCE(excluded.name, account_devices.name),
+            updated_at = excluded.updated_at",
+        params![
+            link.account.to_lowercase(),
+            link.device_id,
+            link.device_key.to_lowercase(),
+            serde_json::to_string(link).map_err(|e| e.to_string())?,
+            name,
+            now_ms() as i64
+        ],
+    )
+    .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+fn is_revoked(conn: &Connection, account: &str, device: u32) -> bool {
+    conn.query_row(
+        "SELECT revoked_at IS NOT NULL FROM account_devices WHERE account = ?1 AND device_id = ?2",
+        params![account.to_lowercase(), device],
+        |r| r.get(0),
+    )
+    .unwrap_or(false)
+}
+
+/// Brings the known devices of a peer account in line with what its relay lists.
+fn set_listed_devices(conn: &Connection, account: &str, listed: &[u32]) {
+    let account = account.to_lowercase();
+    let _ = conn.execute(
+        "UPDATE account_devices SET revoked_at = ?1 WHERE account = ?2 AND revoked_at IS NULL",
+        params![now_ms() as i64, account],
+    );
+    for device in listed {
+        let _ = conn.execute(
+            "UPDATE account_devices SET revoked_at = NULL WHERE account = ?1 AND device_id = ?2",
+            params![account, device],
+        );
+    }
+}
+
+/// Wraps the per-device ciphertexts of one message for sending. A message from a
+/// primary to a lone primary keeps the single-ciphertext envelope older clients read.
+pub(crate) fn device_envelope(
+    mut entries: Vec<(u32, serde_json::Value)>,
+    sender_link: Option<&DeviceLink>,
+) -> serde_json::Value {
+    let mut envelope = if entries.len() == 1 && entries[0].0 == PRIMARY_DEVICE {
+        entries.remove(0).1
+    } else {
+        let devices: serde_json::Map<String, serde_json::Value> = entries
+            .into_iter()
+            .map(|(device, e)| {
+                (
+                    device.to_string(),
+                    json!({ "type": e["type"], "body": e["body"] }),
+                )
+            })
+            .collect();
+        json!({ "devices": devices, "is_signal": true })
+    };
+    if let Some(link) = sender_link {
+        envelope["device"] = json!(link.device_id);
+        envelope["link"] = json!(link);
+    }
+    envelope
+}
+
+/// The ciphertext an envelope carries for `device`, if any.
+pub(crate) fn envelope_for_device(
+    envelope: &serde_json::Value,
+    device: u32,
+) -> Option<serde_json::Value> {
+    match envelope.get("devices") {
+        Some(devices) => devices.get(device.to_string()).cloned(),
+        None => (device == PRIMARY_DEVICE).then(|| envelope.clone()),
+    }
+}
+
+/// Checks the link a linked device sends with its messages. Prekey messages carry
+/// the sender's identity key, which has to be the key the link authorizes, or for a
+/// primary the key the account hash names; later messages ride the session that key
+/// opened.
+pub(crate) fn check_sender_device(
+    sender: &str,
+    device: u32,
+    envelope: &serde_json::Value,
+    message_type: u8,
+    body: &[u8],
+) -> Result<Option<DeviceLink>, String> {
+    if device == PRIMARY_DEVICE {
+        if let Ok(libsignal_protocol::CiphertextMessage::PreKeySignalMessage(m)) =
+            parse_ciphertext(message_type, body)
+            && !hex::encode(Sha256::digest(strip_key(&m.identity_key().serialize())))
+                .eq_ignore_ascii_case(sender)
+        {
+            return Err("Message is not from the account's identity key".into());
+        }
+        return Ok(None);
+    }
+    let link: DeviceLink = serde_json::from_value(envelope["link"].clone())
+        .map_err(|_| "Message from an unlinked device")?;
+    verify_link(&link)?;
+    if !link.account.eq_ignore_ascii_case(sender) || link.device_id != device {
+        return Err("Device link does not match the sender".into());
+    }
+    if let Ok(libsignal_protocol::CiphertextMessage::PreKeySignalMessage(m)) =
+        parse_ciphertext(message_type, body)
+        && !link_binds(&link, &m.identity_key().serialize())
+    {
+        return Err("Message is not from the linked device's key".into());
+    }
+    Ok(Some(link))
+}
+
+/// Admits a message from `sender`'s `device` before it is decrypted, remembering
+/// the device for replies. A link stays valid until it expires after its device is
+/// unlinked, so devices known to be revoked are refused meanwhile.
+pub(crate) fn admit_sender_device<R: Runtime>(
+    app: &AppHandle<R>,
+    sender: &str,
+    device: u32,
+    envelope: &serde_json::Value,
+    message_type: u8,
+    body: &[u8],
+) -> Result<(), String> {
+    let Some(link) = check_sender_device(sender, device, envelope, message_type, body)? else {
+        return Ok(());
+    };
+    let conn = app.state::<DbState>().get_conn()?;
+    if is_revoked(&conn, sender, device) {
+        return Err("Message from an unlinked device".into());
+    }
+    record_device(&conn, &link, None)
+}
+
+/// Fetches the devices published for `account`, checks their links and opens a
+/// session with each one that has none. Returns the devices that can be sent to; one
+/// whose bundle fails is left out of this send but stays listed. A primary's own list
+/// is authoritative for its account, so relay listings of devices it revoked are
+/// ignored.
+pub(crate) async fn refresh_devices<R: Runtime>(
+    app: &AppHandle<R>,
+    net_state: &NetworkState,
+    account: &str,
+) -> Result<Vec<u32>, String> {
+    let own_hash = net_state
+        .identity_hash
+        .lock()
+        .map_err(|_| "Network state poisoned")?
+        .clone()
+        .ok_or("Identity not established")?;
+    let response: FetchKeyResponse = decode_response(
+        internal_request(
+            net_state,
+            ClientMessage::FetchKey {
+                target_hash: account.to_string(),
+                initiator_hash: own_hash.clone(),
+            },
+        )
+        .await?,
+    )?;
+    if !response.found {
+        return Err(format!("Peer {} not found on server", account));
+    }
+
+    let authoritative = account.eq_ignore_ascii_case(&own_hash) && local_link(app).is_none();
+    let authorized = {
+        let conn = app.state::<DbState>().get_conn()?;
+        linked_devices(&conn, account)
+    };
+    let mut listed = Vec::new();
+    let mut devices = Vec::new();
+    for (device, bundle) in response.device_bundles(account) {
+        if authoritative && device != PRIMARY_DEVICE && !authorized.contains(&device) {
+            continue;
+        }
+        listed.push(device);
+        let opened = if has_session(app, account, device).await {
+            parse_device_bundle(account, device, &bundle).map(|_| ())
+        } else {
+            internal_establish_session_logic(app.clone(), account, device, bundle.clone()).await
+        };
+        match opened {
+            Ok(()) => {}
+            // Without the primary there is no account to send to
+            Err(e) if device == PRIMARY_DEVICE => return Err(e),
+            Err(_) => continue,
+        }
+        if device != PRIMARY_DEVICE
+            && let Ok(link) = serde_json::from_value::<DeviceLink>(bundle["link"].clone())
+        {
+            let conn = app.state::<DbState>().get_conn()?;
+            record_device(&conn, &link, None)?;
+        }
+        devices.push(device);
+    }
+    if !authoritative {
+        let conn = app.state::<DbState>().get_conn()?;
+        set_listed_devices(&conn, account, &listed);
+    }
+    if let Ok(mut lists) = net_state.device_lists.lock() {
+        lists.insert(account.to_lowercase(), Instant::now());
+    }
+    Ok(devices)
+}
+
+/// The devices of `account` to encrypt for, fetched again once `DEVICE_LIST_TTL`
+/// has passed. Falls back to the known list while the relay cannot be asked.
+pub(crate) async fn account_devices<R: Runtime>(
+    app: &AppHandle<R>,
+    net_state: &NetworkState,
+    account: &str,
+) -> Vec<u32> {
+    let stale = net_state
+        .device_lists
+        .lock()
+        .map(|lists| {
+            lists
+                .get(&account.to_lowercase())
+                .is_none_or(|at| at.elapsed() >= DEVICE_LIST_TTL)
+        })
+        .unwrap_or(true);
+    if stale && let Ok(devices) = refresh_devices(app, net_state, account).await {
+        return devices;
+    }
+    let mut devices = vec![PRIMARY_DEVICE];
+    if let Ok(conn) = app.state::<DbState>().get_conn() {
+        devices.extend(linked_devices(&conn, account));
+    }
+    devices
+}
+
+/// Mirrors a text message this device sent to `destination` on the account's other
+/// devices, so conversations read the same everywhere. Other message types are not
+/// mirrored.
+pub(crate) async fn sync_sent<R: Runtime>(
+    app: &AppHandle<R>,
+    destination: &str,
+    message: &serde_json::Value,
+) {
+    if message["type"].as_str() != Some("text_msg") {
+        return;
+    }
+    let net_state = app.state::<NetworkState>();
+    let Some(own_hash) = net_state
+        .identity_hash
+        .lock()
+        .ok()
+        .and_then(|lock| lock.clone())
+    else {
+        return;
+    };
+    let has_other_devices = local_link(app).is_some()
+        || app
+            .state::<DbState>()
+            .get_conn()
+            .is_ok_and(|conn| !linked_devices(&conn, &own_hash).is_empty());
+    if !has_other_devices {
+        return;
+    }
+    let payload = json!({
+        "type": "sent_sync",
+        "destination": destination,
+        "message": message,
+    });
+    if let Ok(encrypted) =
+        internal_signal_encrypt(app.clone(), &net_state, &own_hash, payload.to_string()).await
+    {
+        let _ = internal_send_to_network(
+            app.clone(),
+            &net_state,
+            Some(own_hash),
+            None,
+            None,
+            Some(encrypted.to_string().into_bytes()),
+            true,
+            false,
+            None,
+            true,
+        )
+        .await;
+    }
+}
+
+pub fn start_link_renewal<R: Runtime>(app: AppHandle<R>) {
+    tauri::async_runtime::spawn(async move {
+        loop {
+            tokio::time::sleep(LINK_RENEWAL_CHECK_INTERVAL).await;
+            renew_links_if_due(&app).await;
+        }
+    });
+}
+
+/// Re-signs the links of this primary's devices that expire within
+/// `LINK_RENEWAL_WINDOW` and sends each device its new one. Unlinked devices get
+/// none. A link that could not be sent is retried on the next check.
+pub(crate) async fn renew_links_if_due<R: Runtime>(app: &AppHandle<R>) {
+    if local_link(app).is_some() {
+        return;
+    }
+    let net_state = app.state::<NetworkState>();
+    let Ok(account) = own_account(&net_state) else {
+        return;
+    };
+    let renew_before = now_ms() + LINK_RENEWAL_WINDOW.as_millis() as u64;
+    let due: Vec<DeviceLink> = {
+        let Ok(conn) = app.state::<DbState>().get_conn() else {
+            return;
+        };
+        let Ok(mut stmt) = conn.prepare(
+            "SELECT link FROM account_devices
+             WHERE account = ?1 AND device_id > 1 AND revoked_at IS NULL",
+        ) else {
+            return;
+        };
+        stmt.query_map(params![account], |r| r.get::<_, Option<String>>(0))
+            .map(|rows| {
+                rows.filter_map(Result::ok)
+                    .flatten()
+                    .filter_map(|link| serde_json::from_str::<DeviceLink>(&link).ok())
+                    .filter(|link| link.expires_at < renew_before)
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    if due.is_empty() {
+        return;
+    }
+    let Ok(identity) = identity_key_pair(app).await else {
+        return;
+    };
+
+    for old in due {
+        let Ok(device_key) = hex::decode(&old.device_key) else {
+            continue;
+        };
+        let Ok(link) = sign_link(&identity, old.device_id, &device_key, now_ms()) else {
+            continue;
+        };
+        let payload = json!({ "type": "link_renewal", "link": link });
+        let Ok(encrypted) = internal_signal_encrypt(
+            app.clone(),
+            &net_state,
+            &device_address(&account, link.device_id),
+            payload.to_string(),
+        )
+        .await
+        else {
+            continue;
+        };
+        let sent = internal_send_to_network(
+            app.clone(),
+            &net_state,
+            Some(account.clone()),
+            None,
+            None,
+            Some(encrypted.to_string().into_bytes()),
+            true,
+            false,
+            None,
+            false,
+        )
+        .await;
+        if sent.is_ok()
+            && let Ok(conn) = app.state::<DbState>().get_conn()
+        {
+            let _ = record_device(&conn, &link, None);
+        }
+    }
+}
+
+/// Takes a renewed link from this device's primary and publishes it with a fresh
+/// bundle. Links for another device or older than the current one are ignored.
+pub(crate) async fn handle_link_renewal<R: Runtime>(
+    app: AppHandle<R>,
+    decrypted_json: serde_json::Value,
+) -> Result<(), String> {
+    let link: DeviceLink = serde_json::from_value(decrypted_json["link"].clone())
+        .map_err(|_| "Invalid link renewal")?;
+    let Some(current) = local_link(&app) else {
+        return Ok(());
+    };
+    verify_link(&link)?;
+    let identity = identity_key_pair(&app).await?;
+    if !link.account.eq_ignore_ascii_case(&current.account)
+        || link.device_id != current.device_id
+        || !link_binds(&link, &identity.identity_key().serialize())
+        || link.issued_at <= current.issued_at
+    {
+        return Ok(());
+    }
+    {
+        let conn = app.state::<DbState>().get_conn()?;
+        conn.execute(
+            "UPDATE signal_identity SET device_link = ?1 WHERE id = 0",
+            params![serde_json::to_string(&link).map_err(|e| e.to_string())?],
+        )
+        .map_err(|e| e.to_string())?;
+        record_device(&conn, &link, None)?;
+    }
+    signal_sync_keys(app, Some(0)).await
+}
+
+async fn identity_key_pair<R: Runtime>(app: &AppHandle<R>) -> Result<IdentityKeyPair, String> {
+    let store = SqliteSignalStore::new(app.clone());
+    tauri::async_runtime::spawn_blocking(move || {
+        tauri::async_runtime::block_on(async { store.get_identity_key_pair().await })
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())
+}
+
+fn own_account(net_state: &NetworkState) -> Result<String, String> {
+    net_state
+        .identity_hash
+        .lock()
+        .map_err(|_| "Network state poisoned")?
+        .clone()
+        .ok_or_else(|| "Identity not established".to_string())
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LinkedDevice {
+    pub device_id: u32,
+    pub name: Option<String>,
+    pub linked_at: Option<u64>,
+    pub is_current: bool,
+}
+
+/// Starts linking this device to an existing account. Returns the device key to
+/// enter on the primary, which authorizes it with `device_link_approve`.
+#[tauri::command]
+pub async fn device_link_begin<R: Runtime>(app: AppHandle<R>) -> Result<String, String> {
+    if local_link(&app).is_some() {
+        return Err("This device is already linked".into());
+    }
+    let identity = identity_key_pair(&app).await?;
+    Ok(hex::encode(strip_key(&identity.identity_key().serialize())))
+}
+
+/// Authorizes a new device of this account and returns the link code to enter on
+/// it. The relay learns of the device with the key upload that follows.
+#[tauri::command]
+pub async fn device_link_approve<R: Runtime>(
+    app: AppHandle<R>,
+    net_state: State<'_, NetworkState>,
+    device_key: String,
+    name: Option<String>,
+) -> Result<String, String> {
+    if local_link(&app).is_some() {
+        return Err("Only the primary device can link devices".into());
+    }
+    let device_key = device_key.trim().to_lowercase();
+    decode_public_key(&device_key)?;
+    let account = own_account(&net_state)?;
+    let identity = identity_key_pair(&app).await?;
+    if hex::encode(strip_key(&identity.identity_key().serialize())) == device_key {
+        return Err("A device cannot link itself".into());
+    }
+
+    let device_id = {
+        let conn = app.state::<DbState>().get_conn()?;
+        if linked_devices(&conn, &account).len() >= MAX_LINKED_DEVICES {
+            return Err(format!(
+                "At most {} devices can be linked",
+                MAX_LINKED_DEVICES
+            ));
+        }
+        // Ids of revoked devices are not reused, so peers never mistake a new
+        // device for one whose session they still hold
+        let highest: u32 = conn
+            .query_row(
+                "SELECT COALESCE(MAX(device_id), 1) FROM account_devices WHERE account = ?1",
+                params![account],
+                |r| r.get(0),
+            )
+            .map_err(|e| e.to_string())?;
+        highest + 1
+    };
+    let link = sign_link(
+        &identity,
+        device_id,
+        &hex::decode(&device_key).map_err(|e| e.to_string())?,
+        now_ms(),
+    )?;
+    {
+        let conn = app.state::<DbState>().get_conn()?;
+        record_device(&conn, &link, name.as_deref().map(str::trim))?;
+    }
+
+    if let Err(e) = signal_sync_keys(app.clone(), None).await {
+        let conn = app.state::<DbState>().get_conn()?;
+        let _ = conn.execute(
+            "DELETE FROM account_devices WHERE account = ?1 AND device_id = ?2",
+            params![account, device_id],
+        );
+        return Err(e);
+    }
+    let code = serde_json::to_vec(&link).map_err(|e| e.to_string())?;
+    Ok(base64::engine::general_purpose::STANDARD.encode(code))
+}
+
+/// Completes linking on the new device with the code from its primary and returns
+/// the account hash. The device acts for that account from now on, so the caller
+/// reconnects and uploads its keys afterwards.
+#[tauri::command]
+pub async fn device_link_complete<R: Runtime>(
+    app: AppHandle<R>,
+    net_state: State<'_, NetworkState>,
+    link_code: String,
+) -> Result<String, String> {
+    let code = base64::engine::general_purpose::STANDARD
+        .decode(link_code.trim())
+        .map_err(|_| "Invalid link code")?;
+    let link: DeviceLink = serde_json::from_slice(&code).map_err(|_| "Invalid link code")?;
+    verify_link(&link)?;
+    let identity = identity_key_pair(&app).await?;
+    if !link_binds(&link, &identity.identity_key().serialize()) {
+        return Err("This link code was issued to another device".into());
+    }
+
+    {
+        let conn = app.state::<DbState>().get_conn()?;
+        conn.execute(
+            "UPDATE signal_identity SET device_link = ?1, session_token = NULL WHERE id = 0",
+            params![serde_json::to_string(&link).map_err(|e| e.to_string())?],
+        )
+        .map_err(|e| e.to_string())?;
+        record_device(&conn, &link, None)?;
+    }
+    *net_state
+        .identity_hash
+        .lock()
+        .map_err(|_| "Network state poisoned")? = Some(link.account.clone());
+    *net_state
+        .session_token
+        .lock()
+        .map_err(|_| "Network state poisoned")? = None;
+    Ok(link.account)
+}
+
+/// The devices of this account known here, the primary first.
+#[tauri::command]
+pub async fn device_list<R: Runtime>(
+    app: AppHandle<R>,
+    net_state: State<'_, NetworkState>,
+) -> Result<Vec<LinkedDevice>, String> {
+    let account = own_account(&net_state)?;
+    let own_device = local_device_id(&app);
+    let conn = app.state::<DbState>().get_conn()?;
+    let mut stmt = conn
+        .prepare(
+            "SELECT device_id, name, link FROM account_devices
+             WHERE account = ?1 AND device_id > 1 AND revoked_at IS NULL ORDER BY device_id",
+        )
+        .map_err(|e| e.to_string())?;
+    let linked = stmt
+        .query_map(params![account], |r| {
+            Ok((
+                r.get::<_, u32>(0)?,
+                r.get::<_, Option<String>>(1)?,
+                r.get::<_, Option<String>>(2)?,
+            ))
+        })
+        .map_err(|e| e.to_string())?
+        .filter_map(Result::ok)
+        .map(|(device_id, name, link)| LinkedDevice {
+            device_id,
+            name,
+            linked_at: link
+                .and_then(|l| serde_json::from_str::<DeviceLink>(&l).ok())
+                .map(|l| l.issued_at),
+            is_current: device_id == own_device,
+        });
+
+    let mut devices = vec![LinkedDevice {
+        device_id: PRIMARY_DEVICE,
+        name: None,
+        linked_at: None,
+        is_current: own_device == PRIMARY_DEVICE,
+    }];
+    devices.extend(linked);
+    Ok(devices)
+}
+
+/// Revokes a linked device. Its bundle is withdrawn with the key upload that
+/// follows, and peers stop encrypting for it once they fetch the account's devices.
+/// Its link is no longer renewed, so peers refuse it by the time it expires even if
+/// a relay keeps listing it.
+#[tauri::command]
+pub async fn device_unlink<R: Runtime>(
+    app: AppHandle<R>,
+    net_state: State<'_, NetworkState>,
+    device_id: u32,
+) -> Result<(), String> {
+    if local_link(&app).is_some() {
+        return Err("Only the primary device can unlink devices".into());
+    }
+    let account = own_account(&net_state)?;
+    let revoked = {
+        let conn = app.state::<DbState>().get_conn()?;
+        conn.execute(
+            "UPDATE account_devices SET revoked_at = ?1
+             WHERE account = ?2 AND device_id = ?3 AND device_id > 1 AND revoked_at IS NULL",
+            params![now_ms() as i64, account, device_id],
+        )
+        .map_err(|e| e.to_string())?
+    };
+    if revoked == 0 {
+        return Err("No such linked device".into());
+    }
+    signal_sync_keys(app, None).await
+}
@@ -1,4 +1,9 @@
+pub mod devices;
+pub use devices::*;
 pub mod nicknames;
 pub use nicknames::*;
+pub mod prekeys;
+pub use prekeys::*;
+pub mod sealed;
 pub mod trust;
 pub use trust::*;
@@ -1,14 +1,14 @@
 use crate::app_state::NetworkState;
 use crate::commands::internal_request;
+use crate::commands::network::relay_protocol::ClientMessage;
 use crate::signal_store::SqliteSignalStore;
 use libsignal_protocol::IdentityKeyStore;
 use rand::SeedableRng;
-use serde_json::json;
-use tauri::{AppHandle, Manager};
+use tauri::{AppHandle, Manager, Runtime};
 
 #[tauri::command]
-pub async fn register_nickname(
-    handle: AppHandle,
+pub async fn register_nickname<R: Runtime>(
+    handle: AppHandle<R>,
     nickname: String,
 ) -> Result<serde_json::Value, String> {
     let handle_clone = handle.clone();
@@ -39,11 +39,23 @@ pub async fn register_nickname(
     if pk_bytes.len() == 33 && pk_bytes[0] == 0x05 {
         pk_bytes.remove(0);
     }
-    internal_request(&state, "nickname_register", json!({ "identity_hash": id_hash, "nickname": nickname, "public_key": hex::encode(&pk_bytes), "signature": hex::encode(&sig) })).await
+    internal_request(
+        &state,
+        ClientMessage::NicknameRegister {
+            identity_hash: id_hash,
+            nickname,
+            public_key: hex::encode(&pk_bytes),
+            signature: hex::encode(&sig),
+        },
+    )
+    .await
 }
 
 #[tauri::command]
-pub async fn nickname_lookup(handle: AppHandle, name: String) -> Result<serde_json::Value, String> {
+pub async fn nickname_lookup<R: Runtime>(
+    handle: AppHandle<R>,
+    name: String,
+) -> Result<serde_json::Value, String> {
     let state = handle.state::<NetworkState>();
     let id_hash = state
         .identity_hash
@@ -74,20 +86,19 @@ pub async fn nickname_lookup(handle: AppHandle, name: String) -> Result<serde_js
 
     internal_request(
         &state,
-        "nickname_lookup",
-        json!({
-            "name": name,
-            "initiator_hash": id_hash,
-            "public_key": hex::encode(&pk_bytes),
-            "signature": hex::encode(&sig)
-        }),
+        ClientMessage::NicknameLookup {
+            name,
+            initiator_hash: id_hash,
+            public_key: hex::encode(&pk_bytes),
+            signature: hex::encode(&sig),
+        },
     )
     .await
 }
 
 #[tauri::command]
-pub async fn identity_resolve(
-    handle: AppHandle,
+pub async fn identity_resolve<R: Runtime>(
+    handle: AppHandle<R>,
     identity_hash: String,
 ) -> Result<serde_json::Value, String> {
     let state = handle.state::<NetworkState>();
@@ -120,19 +131,18 @@ pub async fn identity_resolve(
 
     internal_request(
         &state,
-        "identity_resolve",
-        json!({
-            "identity_hash": identity_hash,
-            "initiator_hash": id_hash,
-            "public_key": hex::encode(&pk_bytes),
-            "signature": hex::encode(&sig)
-        }),
+        ClientMessage::IdentityResolve {
+            identity_hash,
+            initiator_hash: id_hash,
+            public_key: hex::encode(&pk_bytes),
+            signature: hex::encode(&sig),
+        },
     )
     .await
 }
 
 #[tauri::command]
-pub async fn burn_account(handle: AppHandle) -> Result<serde_json::Value, String> {
+pub async fn burn_account<R: Runtime>(handle: AppHandle<R>) -> Result<serde_json::Value, String> {
     let handle_clone = handle.clone();
     let state = handle_clone.state::<NetworkState>();
     let id_hash = state
@@ -158,5 +168,13 @@ pub async fn burn_account(handle: AppHandle) -> Result<serde_json::Value, String
     if pk_bytes.len() == 33 && pk_bytes[0] == 0x05 {
         pk_bytes.remove(0);
     }
-    internal_request(&state, "account_burn", json!({ "identity_hash": id_hash, "public_key": hex::encode(&pk_bytes), "signature": hex::encode(&sig) })).await
+    internal_request(
+        &state,
+        ClientMessage::AccountBurn {
+            identity_hash: id_hash,
+            public_key: hex::encode(&pk_bytes),
+            signature: hex::encode(&sig),
+        },
+    )
+    .await
 }
@@ -0,0 +1,258 @@
+//! Prekey Rotation
+//!
+//! The signed prekey and the last-resort Kyber key are replaced once they are a week
+//! old instead of on every upload. A replacement only supersedes the old key once the
+//! relay has accepted it; superseded keys are kept for a grace period, for peers that
+//! fetched a bundle before the rotation, and then deleted with `secure_delete` so
+//! their pages are zeroed. One-time keys leave the store as sessions consume them.
+
+use serde::Serialize;
+use std::time::Duration;
+use tauri::{AppHandle, Manager, Runtime};
+
+use crate::app_state::{DbState, NetworkState};
+use crate::commands::signal::signal_sync_keys;
+
+/// Age at which the signed prekey and the last-resort Kyber key are replaced.
+pub(crate) const PREKEY_ROTATION_MS: i64 = 7 * 24 * 60 * 60 * 1000;
+/// How long a superseded key is kept before it is deleted.
+pub(crate) const PREKEY_GRACE_MS: i64 = 30 * 24 * 60 * 60 * 1000;
+const ROTATION_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PreKeyInventory {
+    /// Unused one-time keys held locally, including any the relay has handed out
+    /// that no session has consumed yet.
+    pub one_time_pre_keys: u32,
+    pub one_time_kyber_pre_keys: u32,
+    pub signed_pre_key_id: Option<u32>,
+    pub signed_pre_key_created_at: Option<i64>,
+    pub last_resort_kyber_key_id: Option<u32>,
+    pub last_resort_kyber_created_at: Option<i64>,
+    /// When the older of the two published keys is due for rotation.
+    pub next_rotation_at: Option<i64>,
+    /// Superseded keys still held for their grace period.
+    pub superseded_signed_pre_keys: u32,
+    pub superseded_kyber_pre_keys: u32,
+}
+
+pub(crate) fn now_ms() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+/// Whether a key created at `created_at` is due for replacement at `now`. Keys
+/// stored before creation times were recorded have none and are replaced at once.
+pub(crate) fn rotation_due(created_at: Option<i64>, now: i64) -> bool {
+    created_at.is_none_or(|created| now.saturating_sub(created) >= PREKEY_ROTATION_MS)
+}
+
+/// The newest signed prekey that has not been superseded, with its creation time.
+pub(crate) fn current_signed_pre_key(
+    conn: &rusqlite::Connection,
+) -> Result<Option<(u32, Option<i64>)>, String> {
+    latest_key(
+        conn,
+        "SELECT key_id, timestamp FROM signal_signed_pre_keys WHERE superseded_at IS NULL
+         ORDER BY timestamp IS NULL, timestamp DESC LIMIT 1",
+    )
+}
+
+/// The newest last-resort Kyber key that has not been superseded, with its creation time.
+pub(crate) fn current_last_resort_key(
+    conn: &rusqlite::Connection,
+) -> Result<Option<(u32, Option<i64>)>, String> {
+    latest_key(
+        conn,
+        "SELECT key_id, created_at FROM signal_kyber_pre_keys
+         WHERE last_resort = 1 AND superseded_at IS NULL
+         ORDER BY created_at IS NULL, created_at DESC LIMIT 1",
+    )
+}
+
+fn latest_key(
+    conn: &rusqlite::Connection,
+    sql: &str,
+) -> Result<Option<(u32, Option<i64>)>, String> {
+    match conn.query_row(sql, [], |r| Ok((r.get(0)?, r.get(1)?))) {
+        Ok(key) => Ok(Some(key)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Flags a freshly generated Kyber key as the last-resort key, which is never
+/// consumed by the sessions built on it.
+pub(crate) fn mark_last_resort(conn: &rusqlite::Connection, key_id: u32) -> Result<(), String> {
+    conn.execute(
+        "UPDATE signal_kyber_pre_keys SET last_resort = 1 WHERE key_id = ?1",
+        [key_id],
+    )
+    .map(|_| ())
+    .map_err(|e| e.to_string())
+}
+
+/// Supersedes every signed prekey and last-resort Kyber key other than the ones the
+/// relay has just accepted.
+pub(crate) fn retire_published_keys(
+    conn: &rusqlite::Connection,
+    signed_pre_key_id: u32,
+    last_resort_id: u32,
+    now: i64,
+) -> Result<(), String> {
+    conn.execute(
+        "UPDATE signal_signed_pre_keys SET superseded_at = ?2
+         WHERE superseded_at IS NULL AND key_id <> ?1",
+        rusqlite::params![signed_pre_key_id, now],
+    )
+    .map_err(|e| e.to_string())?;
+    conn.execute(
+        "UPDATE signal_kyber_pre_keys SET superseded_at = ?2
+         WHERE last_resort = 1 AND superseded_at IS NULL AND key_id <> ?1",
+        rusqlite::params![last_resort_id, now],
+    )
+    .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Whether an upload is needed to rotate keys: a published key is due, or a
+/// replacement generated earlier never reached the relay.
+pub(crate) fn rotation_pending(conn: &rusqlite::Connection, now: i64) -> Result<bool, String> {
+    let unpublished: i64 = conn
+        .query_row(
+            "SELECT (SELECT COUNT(*) FROM signal_signed_pre_keys WHERE superseded_at IS NULL) > 1
+                 OR (SELECT COUNT(*) FROM signal_kyber_pre_keys
+                     WHERE last_resort = 1 AND superseded_at IS NULL) > 1",
+            [],
+            |r| r.get(0),
+        )
+        .map_err(|e| e.to_string())?;
+    if unpublished != 0 {
+        return Ok(true);
+    }
+    let signed = current_signed_pre_key(conn)?;
+    let kyber = current_last_resort_key(conn)?;
+    Ok(signed.is_none_or(|(_, created)| rotation_due(created, now))
+        || kyber.is_none_or(|(_, created)| rotation_due(created, now)))
+}
+
+/// Securely deletes superseded keys whose grace period has run out, with the
+/// replay records kept for them. Returns how many keys were deleted.
+pub(crate) fn prune_superseded_keys(
+    conn: &rusqlite::Connection,
+    now: i64,
+) -> Result<usize, String> {
+    let cutoff = now.saturating_sub(PREKEY_GRACE_MS);
+    conn.execute_batch("PRAGMA secure_delete = ON;")
+        .map_err(|e| e.to_string())?;
+    conn.execute(
+        "DELETE FROM signal_kyber_base_keys_seen
+         WHERE kyber_prekey_id IN
+               (SELECT key_id FROM signal_kyber_pre_keys WHERE superseded_at <= ?1)
+            OR ec_prekey_id IN
+               (SELECT key_id FROM signal_signed_pre_keys WHERE superseded_at <= ?1)",
+        [cutoff],
+    )
+    .map_err(|e| e.to_string())?;
+    let signed = conn
+        .execute(
+            "DELETE FROM signal_signed_pre_keys WHERE superseded_at <= ?1",
+            [cutoff],
+        )
+        .map_err(|e| e.to_string())?;
+    let kyber = conn
+        .execute(
+            "DELETE FROM signal_kyber_pre_keys WHERE superseded_at <= ?1",
+            [cutoff],
+        )
+        .map_err(|e| e.to_string())?;
+    if signed + kyber > 0 {
+        // Old page images would otherwise survive in the write-ahead log
+        conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(signed + kyber)
+}
+
+pub fn start_prekey_rotation<R: Runtime>(app: AppHandle<R>) {
+    tauri::async_runtime::spawn(async move {
+        loop {
+            tokio::time::sleep(ROTATION_CHECK_INTERVAL).await;
+            rotate_prekeys_if_due(&app).await;
+        }
+    });
+}
+
+/// Prunes expired keys and uploads a rotated bundle when one is due. Nothing
+/// happens while the vault is locked; a failed upload is retried on the next check.
+pub(crate) async fn rotate_prekeys_if_due<R: Runtime>(app: &AppHandle<R>) {
+    let now = now_ms();
+    let due = {
+        let Ok(conn) = app.state::<DbState>().get_conn() else {
+            return;
+        };
+        let _ = prune_superseded_keys(&conn, now);
+        rotation_pending(&conn, now).unwrap_or(false)
+    };
+    let net_state = app.state::<NetworkState>();
+    if !due
+        || net_state
+            .identity_hash
+            .lock()
+            .map(|h| h.is_none())
+            .unwrap_or(true)
+    {
+        return;
+    }
+    // Share the refill guard so a rotation never races a keys-low upload
+    match net_state.is_refilling.lock() {
+        Ok(mut refilling) if !*refilling => *refilling = true,
+        _ => return,
+    }
+    // The relay keeps the one-time keys it holds, so a rotation adds none
+    let _ = signal_sync_keys(app.clone(), Some(0)).await;
+    if let Ok(mut refilling) = net_state.is_refilling.lock() {
+        *refilling = false;
+    }
+}
+
+fn count(conn: &rusqlite::Connection, sql: &str) -> Result<u32, String> {
+    conn.query_row(sql, [], |r| r.get(0))
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn signal_prekey_inventory<R: Runtime>(
+    app: AppHandle<R>,
+) -> Result<PreKeyInventory, String> {
+    let conn = app.state::<DbState>().get_conn()?;
+    let signed = current_signed_pre_key(&conn)?;
+    let kyber = current_last_resort_key(&conn)?;
+    let next_rotation_at = match (signed, kyber) {
+        (Some((_, Some(s))), Some((_, Some(k)))) => Some(s.min(k) + PREKEY_ROTATION_MS),
+        _ => None,
+    };
+    Ok(PreKeyInventory {
+        one_time_pre_keys: count(&conn, "SELECT COUNT(*) FROM signal_pre_keys")?,
+        one_time_kyber_pre_keys: count(
+            &conn,
+            "SELECT COUNT(*) FROM signal_kyber_pre_keys WHERE last_resort = 0",
+        )?,
+        signed_pre_key_id: signed.map(|(id, _)| id),
+        signed_pre_key_created_at: signed.and_then(|(_, created)| created),
+        last_resort_kyber_key_id: kyber.map(|(id, _)| id),
+        last_resort_kyber_created_at: kyber.and_then(|(_, created)| created),
+        next_rotation_at,
+        superseded_signed_pre_keys: count(
+            &conn,
+            "SELECT COUNT(*) FROM signal_signed_pre_keys WHERE superseded_at IS NOT NULL",
+        )?,
+        superseded_kyber_pre_keys: count(
+            &conn,
+            "SELECT COUNT(*) FROM signal_kyber_pre_keys WHERE superseded_at IS NOT NULL",
+        )?,
+    })
+}
@@ -0,0 +1,283 @@
+//! Sealed Sender
+//!
+//! With `sealedSender` on, each device's ciphertext is wrapped in an outer layer
+//! encrypted to that device's identity key. The sender's account hash and a
+//! certificate — its identity key and a signature binding the message to this
+//! recipient — travel only inside, so the routing header is no longer needed to
+//! attribute a message.
+//!
+//! This hides the sender from the recipient's relay and from anyone reading stored
+//! frames, not from the relay the sender submits to: sealed frames still go out
+//! over the sender's authenticated session, so that relay knows who sent them
+//! whether or not it stamps the routing header. Hiding the sender from it needs
+//! relay support this client does not have yet — accepting sealed frames on a
+//! session that never authenticates (admitted by per-frame proof of work instead
+//! of identity) and forwarding them with the routing header left as the recipient.
+//!
+//! ```text
+//! {"sealed": {"<device>": base64(ephemeral key: 33 | nonce: 24 | XChaCha20-Poly1305)}, "is_signal": true}
+//! ```
+
+use base64::Engine;
+use chacha20poly1305::{
+    Key, XChaCha20Poly1305, XNonce,
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+};
+use hmac::{Hmac, Mac};
+use libsignal_protocol::{IdentityKey, IdentityKeyPair, IdentityKeyStore, KeyPair, PublicKey};
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tauri::{AppHandle, Runtime};
+
+use super::devices::{device_envelope, link_binds, protocol_address, verify_link};
+use crate::commands::network::relay_protocol::DeviceLink;
+use crate::signal_store::SqliteSignalStore;
+
+const EPHEMERAL_LEN: usize = 33;
+const NONCE_LEN: usize = 24;
+const KDF_SALT: &[u8] = b"entropy-sealed-sender";
+
+/// A sealed envelope opened by its recipient.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Unsealed {
+    pub sender: String,
+    /// The envelope as it would have been sent unsealed.
+    pub envelope: serde_json::Value,
+}
+
+pub(crate) fn is_sealed(envelope: &serde_json::Value) -> bool {
+    envelope.get("sealed").is_some()
+}
+
+/// The sealed layer an envelope carries for `device`, if any.
+pub(crate) fn sealed_for_device(envelope: &serde_json::Value, device: u32) -> Option<&str> {
+    envelope["sealed"].get(device.to_string())?.as_str()
+}
+
+/// What the sender's certificate signs: the sender, the recipient's identity key,
+/// the ephemeral key of this layer and the envelope inside it.
+fn certificate_message(
+    sender: &str,
+    recipient_key: &[u8],
+    ephemeral_key: &[u8],
+    envelope: &str,
+) -> Vec<u8> {
+    format!(
+        "entropy-sealed-sender\n{}\n{}\n{}\n{}",
+        sender,
+        hex::encode(recipient_key),
+        hex::encode(ephemeral_key),
+        envelope
+    )
+    .into_bytes()
+}
+
+/// HKDF-SHA256 of the agreement, bound to both public keys, for one 32-byte key.
+fn layer_key(shared: &[u8], ephemeral_key: &[u8], recipient_key: &[u8]) -> [u8; 32] {
+    let mut extract = Hmac::<Sha256>::new_from_slice(KDF_SALT).expect("HMAC takes any key length");
+    extract.update(shared);
+    let prk = extract.finalize().into_bytes();
+    let mut expand = Hmac::<Sha256>::new_from_slice(&prk).expect("HMAC takes any key length");
+    expand.update(ephemeral_key);
+    expand.update(recipient_key);
+    expand.update(&[1]);
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&expand.finalize().into_bytes());
+    key
+}
+
+/// Seals `envelope` from `sender` to the device holding `recipient_key`.
+pub(crate) fn seal(
+    sender_identity: &IdentityKeyPair,
+    sender: &str,
+    recipient_key: &IdentityKey,
+    envelope: &serde_json::Value,
+) -> Result<String, String> {
+    let mut rng = StdRng::from_os_rng();
+    let recipient_bytes = recipient_key.serialize();
+    let ephemeral = KeyPair::generate(&mut rng);
+    let ephemeral_bytes = ephemeral.public_key.serialize();
+
+    let envelope = envelope.to_string();
+    let signature = sender_identity
+        .private_key()
+        .calculate_signature(
+            &certificate_message(sender, &recipient_bytes, &ephemeral_bytes, &envelope),
+            &mut rng,
+        )
+        .map_err(|e| e.to_string())?;
+    let inner = json!({
+        "sender": sender,
+        "certificate": {
+            "identityKey": hex::encode(sender_identity.identity_key().serialize()),
+            "signature": base64::engine::general_purpose::STANDARD.encode(signature)
+        },
+        "envelope": envelope
+    });
+
+    let shared = ephemeral
+        .private_key
+        .calculate_agreement(recipient_key.public_key())
+        .map_err(|e| e.to_string())?;
+    let key = layer_key(&shared, &ephemeral_bytes, &recipient_bytes);
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(&key));
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let aad = [&ephemeral_bytes[..], &recipient_bytes[..]].concat();
+    let ciphertext = cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: inner.to_string().as_bytes(),
+                aad: &aad,
+            },
+        )
+        .map_err(|_| "Failed to seal envelope")?;
+
+    let mut sealed = Vec::with_capacity(EPHEMERAL_LEN + NONCE_LEN + ciphertext.len());
+    sealed.extend_from_slice(&ephemeral_bytes);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    Ok(base64::engine::general_purpose::STANDARD.encode(sealed))
+}
+
+/// Opens a sealed layer addressed to `own_identity` and checks the sender's
+/// certificate: the signature has to verify and the key has to be the sender's,
+/// either hashing to the account or authorized by the link the envelope carries.
+pub(crate) fn unseal(own_identity: &IdentityKeyPair, sealed: &str) -> Result<Unsealed, String> {
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(sealed)
+        .map_err(|e| e.to_string())?;
+    if bytes.len() < EPHEMERAL_LEN + NONCE_LEN {
+        return Err("Sealed envelope too short".into());
+    }
+    let (ephemeral_bytes, rest) = bytes.split_at(EPHEMERAL_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let ephemeral = PublicKey::deserialize(ephemeral_bytes).map_err(|e| e.to_string())?;
+    let own_bytes = own_identity.identity_key().serialize();
+
+    let shared = own_identity
+        .private_key()
+        .calculate_agreement(&ephemeral)
+        .map_err(|e| e.to_string())?;
+    let key = layer_key(&shared, ephemeral_bytes, &own_bytes);
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(&key));
+    let aad = [ephemeral_bytes, &own_bytes[..]].concat();
+    let plaintext = cipher
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: &aad,
+            },
+        )
+        .map_err(|_| "Failed to open sealed envelope")?;
+    let inner: serde_json::Value = serde_json::from_slice(&plaintext).map_err(|e| e.to_string())?;
+
+    let sender = inner["sender"]
+        .as_str()
+        .ok_or("Sealed envelope names no sender")?;
+    let envelope_str = inner["envelope"]
+        .as_str()
+        .ok_or("Sealed envelope carries no envelope")?;
+    let certificate = &inner["certificate"];
+    let sender_key = IdentityKey::decode(
+        &hex::decode(certificate["identityKey"].as_str().unwrap_or_default())
+            .map_err(|e| e.to_string())?,
+    )
+    .map_err(|e| e.to_string())?;
+    let signature = base64::engine::general_purpose::STANDARD
+        .decode(certificate["signature"].as_str().unwrap_or_default())
+        .map_err(|e| e.to_string())?;
+    let message = certificate_message(sender, &own_bytes, ephemeral_bytes, envelope_str);
+    if !sender_key
+        .public_key()
+        .verify_signature(&message, &signature)
+    {
+        return Err("Sender certificate signature is invalid".into());
+    }
+
+    let envelope: serde_json::Value =
+        serde_json::from_str(envelope_str).map_err(|e| e.to_string())?;
+    let sender = sender.to_lowercase();
+    let sender_key_bytes = sender_key.serialize();
+    match envelope.get("link") {
+        Some(link) => {
+            let link: DeviceLink =
+                serde_json::from_value(link.clone()).map_err(|e| e.to_string())?;
+            verify_link(&link)?;
+            if link.account != sender || !link_binds(&link, &sender_key_bytes) {
+                return Err("Sender certificate does not match the device link".into());
+            }
+        }
+        None => {
+            if hex::encode(Sha256::digest(&sender_key_bytes[1..])) != sender {
+                return Err("Sender certificate does not match the sender".into());
+            }
+        }
+    }
+    Ok(Unsealed { sender, envelope })
+}
+
+/// Seals each device's ciphertext to that device's identity key, known from the
+/// session the ciphertext was encrypted with.
+pub(crate) async fn seal_entries<R: Runtime>(
+    app: &AppHandle<R>,
+    own_hash: &str,
+    own_link: Option<&DeviceLink>,
+    remote_hash: &str,
+    entries: Vec<(u32, serde_json::Value)>,
+) -> Result<serde_json::Value, String> {
+    let store = SqliteSignalStore::new(app.clone());
+    let addresses = entries
+        .iter()
+        .map(|(device, _)| protocol_address(remote_hash, *device))
+        .collect::<Result<Vec<_>, String>>()?;
+    let (own_identity, remote_keys) = tauri::async_runtime::spawn_blocking(move || {
+        tauri::async_runtime::block_on(async {
+            let own_identity = store
+                .get_identity_key_pair()
+                .await
+                .map_err(|e| e.to_string())?;
+            let mut remote_keys = Vec::with_capacity(addresses.len());
+            for address in &addresses {
+                remote_keys.push(
+                    store
+                        .get_identity(address)
+                        .await
+                        .map_err(|e| e.to_string())?
+                        .ok_or_else(|| format!("No identity key for {}", address))?,
+                );
+            }
+            Ok::<_, String>((own_identity, remote_keys))
+        })
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+
+    let mut sealed = serde_json::Map::new();
+    for ((device, entry), remote_key) in entries.into_iter().zip(remote_keys) {
+        let envelope = device_envelope(vec![(device, entry)], own_link);
+        sealed.insert(
+            device.to_string(),
+            json!(seal(&own_identity, own_hash, &remote_key, &envelope)?),
+        );
+    }
+    Ok(json!({ "sealed": sealed, "is_signal": true }))
+}
+
+/// Opens the layer an envelope carries for this device with its identity key.
+pub(crate) async fn open_sealed<R: Runtime>(
+    app: &AppHandle<R>,
+    sealed: &str,
+) -> Result<Unsealed, String> {
+    let store = SqliteSignalStore::new(app.clone());
+    let own_identity = tauri::async_runtime::spawn_blocking(move || {
+        tauri::async_runtime::block_on(async { store.get_identity_key_pair().await })
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())?;
+    unseal(&own_identity, sealed)
+}
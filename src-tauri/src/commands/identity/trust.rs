@@ -1,7 +1,19 @@
 use crate::app_state::{DbState, NetworkState};
+use crate::commands::identity::devices::stored_link;
+use crate::commands::messaging::inbox::quarantine::release_held;
+use crate::commands::{DbMessage, internal_db_save_message};
+use base64::Engine;
+use libsignal_protocol::{Fingerprint, IdentityKey};
+use qrcode::QrCode;
+use qrcode::render::svg;
+use rusqlite::{Connection, params};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use sha2::{Digest, Sha256};
-use tauri::State;
+use tauri::{AppHandle, Emitter, Manager, Runtime, State};
+
+/// Trust level of a peer whose safety number was verified.
+pub(crate) const VERIFIED_TRUST: i32 = 2;
 
 #[tauri::command]
 pub async fn signal_get_peer_identity(
@@ -25,29 +37,49 @@ pub async fn signal_get_peer_identity(
     }
 }
 
+/// Sets a peer's trust level. Verifying the peer also handles the messages held
+/// while their changed key awaited verification.
 #[tauri::command]
-pub async fn signal_set_peer_trust(
+pub async fn signal_set_peer_trust<R: Runtime>(
+    app: AppHandle<R>,
     state: State<'_, DbState>,
     address: String,
     trust_level: i32,
 ) -> Result<(), String> {
-    let conn = state.get_conn()?;
+    set_peer_trust(&state.get_conn()?, &address, trust_level)?;
+    if trust_level == VERIFIED_TRUST {
+        let hash = address.split(':').next().unwrap_or(&address);
+        release_held(&app, hash).await?;
+    }
+    Ok(())
+}
+
+/// Sets the trust level of `address`, a bare hash for the primary device. Only
+/// marking the peer verified lifts a block on their changed key.
+pub(crate) fn set_peer_trust(
+    conn: &Connection,
+    address: &str,
+    trust_level: i32,
+) -> Result<(), String> {
     let signal_addr = if !address.contains(':') {
         format!("{}:1", address)
     } else {
-        address.clone()
+        address.to_string()
     };
     conn.execute(
         "UPDATE signal_identities_remote SET trust_level = ?1 WHERE address = ?2",
         rusqlite::params![trust_level, signal_addr],
     )
     .map_err(|e| e.to_string())?;
-    let contact_hash = address.split(':').next().unwrap_or(&address);
+    let contact_hash = address.split(':').next().unwrap_or(address);
     conn.execute(
         "UPDATE contacts SET trust_level = ?1 WHERE hash = ?2",
         rusqlite::params![trust_level, contact_hash],
     )
     .map_err(|e| e.to_string())?;
+    if trust_level == VERIFIED_TRUST {
+        mark_reverified(conn, contact_hash)?;
+    }
     Ok(())
 }
 
@@ -76,6 +108,10 @@ pub async fn signal_get_identity_hash(
             return Ok(hash.clone());
         }
     }
+    // A linked device acts for its primary's account, not for its own key
+    if let Some(link) = stored_link(&db_state.get_conn()?) {
+        return Ok(link.account);
+    }
     let mut pub_key = signal_get_own_identity(db_state).await?;
     if pub_key.len() == 33 && pub_key[0] == 0x05 {
         pub_key.remove(0);
@@ -91,50 +127,339 @@ pub async fn signal_get_identity_hash(
     Ok(hash)
 }
 
+/// Version and iteration count of Signal's numeric fingerprints.
+const FINGERPRINT_VERSION: u32 = 2;
+const FINGERPRINT_ITERATIONS: u32 = 5200;
+
+/// Safety number of two accounts, each identified by the raw bytes of its hash.
+pub(crate) fn safety_number(
+    own_hash: &str,
+    own_key: &[u8],
+    remote_hash: &str,
+    remote_key: &[u8],
+) -> Result<Fingerprint, String> {
+    let own_key = IdentityKey::decode(own_key).map_err(|e| e.to_string())?;
+    let remote_key = IdentityKey::decode(remote_key).map_err(|e| e.to_string())?;
+    Fingerprint::new(
+        FINGERPRINT_VERSION,
+        FINGERPRINT_ITERATIONS,
+        &hex::decode(own_hash).map_err(|e| e.to_string())?,
+        &own_key,
+        &hex::decode(remote_hash).map_err(|e| e.to_string())?,
+        &remote_key,
+    )
+    .map_err(|e| e.to_string())
+}
+
+/// Groups the 60 digits of a safety number in fives, six groups to a line.
+pub(crate) fn format_safety_number(digits: &str) -> String {
+    let groups: Vec<&str> = digits
+        .as_bytes()
+        .chunks(5)
+        .filter_map(|g| std::str::from_utf8(g).ok())
+        .collect();
+    groups
+        .chunks(6)
+        .map(|line| line.join(" "))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The scannable payload as an SVG QR code.
+pub(crate) fn fingerprint_qr_svg(payload: &[u8]) -> Result<String, String> {
+    Ok(QrCode::new(payload)
+        .map_err(|e| e.to_string())?
+        .render::<svg::Color>()
+        .min_dimensions(256, 256)
+        .build())
+}
+
+/// The safety number with `remote_hash` and the peer's trust level.
+async fn peer_fingerprint(
+    db_state: &State<'_, DbState>,
+    net_state: State<'_, NetworkState>,
+    remote_hash: &str,
+) -> Result<(Fingerprint, i32), String> {
+    // Every device of an account shows the safety number of its primary key
+    let own_key = match stored_link(&db_state.get_conn()?) {
+        Some(link) => {
+            let mut key = vec![0x05];
+            key.extend(hex::decode(&link.primary_key).map_err(|e| e.to_string())?);
+            key
+        }
+        None => signal_get_own_identity(db_state.clone()).await?,
+    };
+    let peer_data =
+        signal_get_peer_identity(db_state.clone(), format!("{}:1", remote_hash)).await?;
+    let (peer_key, trust_level) = peer_data.ok_or("Peer identity not found")?;
+    let own_hash = signal_get_identity_hash(db_state.clone(), net_state).await?;
+    let fingerprint = safety_number(&own_hash, &own_key, &remote_hash.to_lowercase(), &peer_key)?;
+    Ok((fingerprint, trust_level))
+}
+
 #[tauri::command]
 pub async fn signal_get_fingerprint(
     db_state: State<'_, DbState>,
     net_state: State<'_, NetworkState>,
     remote_hash: String,
 ) -> Result<serde_json::Value, String> {
-    let own_id_bytes = signal_get_own_identity(db_state.clone()).await?;
-    let peer_data =
-        signal_get_peer_identity(db_state.clone(), format!("{}:1", remote_hash)).await?;
-    let (peer_id_bytes, trust_level) = peer_data.ok_or("Peer identity not found")?;
-    let own_hash = signal_get_identity_hash(db_state, net_state).await?;
-    let mut combined = Vec::with_capacity(own_id_bytes.len() + peer_id_bytes.len());
-    if remote_hash < own_hash {
-        combined.extend_from_slice(&peer_id_bytes);
-        combined.extend_from_slice(&own_id_bytes);
-    } else {
-        combined.extend_from_slice(&own_id_bytes);
-        combined.extend_from_slice(&peer_id_bytes);
+    let (fingerprint, trust_level) = peer_fingerprint(&db_state, net_state, &remote_hash).await?;
+    let digits = fingerprint.display_string().map_err(|e| e.to_string())?;
+    let scannable = fingerprint
+        .scannable
+        .serialize()
+        .map_err(|e| e.to_string())?;
+    Ok(json!({
+        "digits": format_safety_number(&digits),
+        "trustLevel": trust_level,
+        "scannable": base64::engine::general_purpose::STANDARD.encode(&scannable),
+        "qrSvg": fingerprint_qr_svg(&scannable)?
+    }))
+}
+
+/// Compares a payload scanned from the peer's screen with the local safety number
+/// and marks the peer verified when they match. Returns whether they matched.
+#[tauri::command]
+pub async fn signal_verify_scanned_fingerprint<R: Runtime>(
+    app: AppHandle<R>,
+    db_state: State<'_, DbState>,
+    net_state: State<'_, NetworkState>,
+    remote_hash: String,
+    scanned: String,
+) -> Result<bool, String> {
+    let scanned = base64::engine::general_purpose::STANDARD
+        .decode(scanned.trim())
+        .map_err(|e| e.to_string())?;
+    let (fingerprint, _) = peer_fingerprint(&db_state, net_state, &remote_hash).await?;
+    let matches = fingerprint
+        .scannable
+        .compare(&scanned)
+        .map_err(|e| e.to_string())?;
+    if matches {
+        signal_set_peer_trust(app, db_state, remote_hash, VERIFIED_TRUST).await?;
     }
-    let mut hasher = Sha256::new();
-    hasher.update(&combined);
-    let hash_result = hasher.finalize();
-    let mut digits = String::with_capacity(72);
-    for i in 0..12 {
-        let val = ((hash_result[i * 2] as u32) << 8) | (hash_result[i * 2 + 1] as u32);
-        let block_val = val % 100000;
-        if block_val < 10000 {
-            digits.push('0');
-        }
-        if block_val < 1000 {
-            digits.push('0');
-        }
-        if block_val < 100 {
-            digits.push('0');
-        }
-        if block_val < 10 {
-            digits.push('0');
-        }
-        digits.push_str(&block_val.to_string());
-        if i == 5 {
-            digits.push('\n');
-        } else if i < 11 {
-            digits.push(' ');
+    Ok(matches)
+}
+
+/// kv_store key of the [`IdentityChangePolicy`].
+const IDENTITY_POLICY_KEY: &str = "_identity_change_policy";
+
+/// What happens when a peer's identity key changes. Either way the change is
+/// recorded, trust is reset and every chat shared with the peer gets a notice.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum IdentityChangePolicy {
+    /// Keep messaging the new key.
+    #[default]
+    Warn,
+    /// Refuse to send to the new key, and hold what it sends, until the peer is
+    /// verified again.
+    BlockUntilReverified,
+}
+
+/// A change of a peer's identity key, as recorded in the key history.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct IdentityKeyChange {
+    pub address: String,
+    pub old_key: String,
+    pub new_key: String,
+    pub changed_at: i64,
+}
+
+pub(crate) fn identity_change_policy(conn: &Connection) -> IdentityChangePolicy {
+    conn.query_row(
+        "SELECT value FROM kv_store WHERE key = ?1",
+        params![IDENTITY_POLICY_KEY],
+        |row| row.get::<_, String>(0),
+    )
+    .ok()
+    .and_then(|value| serde_json::from_str(&value).ok())
+    .unwrap_or_default()
+}
+
+/// Stores `new_key` in place of `old_key` for `address`: the change goes into the
+/// key history, trust is reset and the key awaits verification. Returns the chats
+/// shared with the peer's account, each with whether it is a group.
+pub(crate) fn record_identity_change(
+    conn: &Connection,
+    address: &str,
+    old_key: &[u8],
+    new_key: &[u8],
+    now: i64,
+) -> Result<Vec<(String, bool)>, String> {
+    conn.execute(
+        "INSERT INTO identity_key_changes (address, old_key, new_key, changed_at)
+         VALUES (?1, ?2, ?3, ?4)",
+        params![address, old_key, new_key, now],
+    )
+    .map_err(|e| e.to_string())?;
+    conn.execute(
+        "INSERT INTO signal_identities_remote (address, public_key, trust_level, awaiting_verification)
+         VALUES (?1, ?2, 0, 1)
+         ON CONFLICT(address) DO UPDATE SET public_key = excluded.public_key,
+             trust_level = 0, awaiting_verification = 1",
+        params![address, new_key],
+    )
+    .map_err(|e| e.to_string())?;
+
+    let hash = address.split(':').next().unwrap_or(address);
+    conn.execute(
+        "UPDATE contacts SET trust_level = 0 WHERE hash = ?1",
+        params![hash],
+    )
+    .map_err(|e| e.to_string())?;
+    let mut stmt = conn
+        .prepare(
+            "SELECT address, COALESCE(is_group, 0) FROM chats
+             WHERE (address = ?1 AND COALESCE(is_group, 0) = 0)
+                OR address IN (SELECT chat_address FROM chat_members WHERE member_hash = ?1)",
+        )
+        .map_err(|e| e.to_string())?;
+    stmt.query_map(params![hash], |row| Ok((row.get(0)?, row.get(1)?)))
+        .map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())
+}
+
+/// Whether the key stored for `address` changed and has not been verified since.
+pub(crate) fn awaiting_verification(conn: &Connection, address: &str) -> Result<bool, String> {
+    match conn.query_row(
+        "SELECT awaiting_verification FROM signal_identities_remote WHERE address = ?1",
+        params![address],
+        |row| row.get::<_, bool>(0),
+    ) {
+        Ok(awaiting) => Ok(awaiting),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(false),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Whether messages to and from `address` are refused because its key changed
+/// under [`IdentityChangePolicy::BlockUntilReverified`] and awaits verification.
+pub(crate) fn blocked_until_reverified(conn: &Connection, address: &str) -> Result<bool, String> {
+    Ok(
+        identity_change_policy(conn) == IdentityChangePolicy::BlockUntilReverified
+            && awaiting_verification(conn, address)?,
+    )
+}
+
+/// Accepts the current keys of every device of `hash` after the peer was verified.
+pub(crate) fn mark_reverified(conn: &Connection, hash: &str) -> Result<(), String> {
+    conn.execute(
+        "UPDATE signal_identities_remote SET awaiting_verification = 0
+         WHERE address LIKE ?1 || ':%'",
+        params![hash],
+    )
+    .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Key changes of every device of `hash`, newest first.
+pub(crate) fn key_history(conn: &Connection, hash: &str) -> Result<Vec<IdentityKeyChange>, String> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT address, old_key, new_key, changed_at FROM identity_key_changes
+             WHERE address LIKE ?1 || ':%' ORDER BY changed_at DESC, id DESC",
+        )
+        .map_err(|e| e.to_string())?;
+    stmt.query_map(params![hash], |row| {
+        Ok(IdentityKeyChange {
+            address: row.get(0)?,
+            old_key: hex::encode(row.get::<_, Vec<u8>>(1)?),
+            new_key: hex::encode(row.get::<_, Vec<u8>>(2)?),
+            changed_at: row.get(3)?,
+        })
+    })
+    .map_err(|e| e.to_string())?
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(|e| e.to_string())
+}
+
+/// The system message posted to a chat shared with `hash` when its key changes.
+pub(crate) fn identity_change_notice(
+    hash: &str,
+    is_group: bool,
+    policy: IdentityChangePolicy,
+) -> String {
+    let short = hash.get(..8).unwrap_or(hash);
+    let notice = if is_group {
+        format!("Safety number with {} changed", short)
+    } else {
+        format!(
+            "Safety number with {} changed. Verify it to make sure nobody is in between",
+            short
+        )
+    };
+    match policy {
+        IdentityChangePolicy::Warn => notice,
+        IdentityChangePolicy::BlockUntilReverified => {
+            format!(
+                "{}. Messages with them are blocked until they are verified again",
+                notice
+            )
         }
     }
-    Ok(json!({ "digits": digits, "trustLevel": trust_level }))
+}
+
+/// Posts a notice of `hash`'s key change to each chat in `chats`.
+pub(crate) async fn post_identity_change_notices<R: Runtime>(
+    app: &AppHandle<R>,
+    hash: &str,
+    chats: Vec<(String, bool)>,
+    policy: IdentityChangePolicy,
+) -> Result<(), String> {
+    let db_state = app.state::<DbState>();
+    for (chat_address, is_group) in chats {
+        let sys_msg = DbMessage {
+            id: uuid::Uuid::new_v4().to_string(),
+            chat_address,
+            sender_hash: hash.to_string(),
+            content: identity_change_notice(hash, is_group, policy),
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            r#type: "system".to_string(),
+            status: "delivered".to_string(),
+            attachment_json: None,
+            is_starred: false,
+            is_group,
+            reply_to_json: None,
+            reactions_json: None,
+        };
+        internal_db_save_message(&db_state, sys_msg.clone()).await?;
+        app.emit("msg://added", json!(sys_msg))
+            .map_err(|e: tauri::Error| e.to_string())?;
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn signal_get_identity_policy(
+    state: State<'_, DbState>,
+) -> Result<IdentityChangePolicy, String> {
+    Ok(identity_change_policy(&state.get_conn()?))
+}
+
+#[tauri::command]
+pub async fn signal_set_identity_policy(
+    state: State<'_, DbState>,
+    policy: IdentityChangePolicy,
+) -> Result<(), String> {
+    let value = serde_json::to_string(&policy).map_err(|e| e.to_string())?;
+    state
+        .get_conn()?
+        .execute(
+            "INSERT OR REPLACE INTO kv_store (key, value) VALUES (?1, ?2)",
+            params![IDENTITY_POLICY_KEY, value],
+        )
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// When and how often the identity keys of `remote_hash`'s devices changed.
+#[tauri::command]
+pub async fn signal_get_key_history(
+    state: State<'_, DbState>,
+    remote_hash: String,
+) -> Result<Vec<IdentityKeyChange>, String> {
+    key_history(&state.get_conn()?, &remote_hash)
 }
@@ -5,7 +5,7 @@ use crate::commands::{DbChat, DbContact, DbMessage, vault_delete_media};
 use rusqlite::params;
 use serde_json::{Value, json};
 use std::collections::HashSet;
-use tauri::{AppHandle, State};
+use tauri::{AppHandle, Runtime, State};
 
 #[tauri::command]
 pub async fn db_get_contacts(state: State<'_, DbState>) -> Result<Vec<DbContact>, String> {
@@ -422,8 +422,8 @@ pub async fn db_reset_unread_count(
 }
 
 #[tauri::command]
-pub async fn db_delete_chat(
-    app: AppHandle,
+pub async fn db_delete_chat<R: Runtime>(
+    app: AppHandle<R>,
     state: State<'_, DbState>,
     address: String,
 ) -> Result<(), String> {
@@ -0,0 +1,360 @@
+//! Signal Frame Delivery
+//!
+//! Selective repeat for `FRAME_SIGNAL` transfers. The sender keeps each transfer's
+//! ciphertext in `NetworkState.outgoing_signals`; a receiver whose reassembly stalls
+//! sends an encrypted `signal_resend_request` listing the missing indices, and only
+//! those fragments are sent again. A recipient that lost every fragment has nothing
+//! to ask for, so a transfer still unreceipted `signal_retransmit_secs` after relay
+//! acceptance is sent again in full, up to `signal_max_retransmits` times. Retention ends with the peer's delivery receipt or,
+//! once the relay has accepted the transfer, after `delivery_ack_timeout_secs`, at
+//! which point an unreceipted message is marked `failed`. A receipt arriving later
+//! still marks it `delivered`.
+
+use serde_json::json;
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter, Manager, Runtime};
+
+use crate::app_state::{DbState, MediaTransferState, NetworkState, OutgoingSignal};
+use crate::commands::internal_signal_encrypt;
+use crate::commands::messaging::inbox::reassembler::parse_assembler_key;
+use crate::commands::network::codec::{CHUNK_CAPACITY, FRAME_SIGNAL, FrameCodec};
+use crate::commands::network::config::{NetworkConfig, current_config};
+use crate::commands::network::transit::{internal_dispatch_fragment, internal_send_to_network};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RetentionVerdict {
+    Keep,
+    /// The relay accepted the transfer but no receipt came back in time.
+    TimedOut,
+    /// Never accepted by the relay; the outbox owns its fate.
+    Abandon,
+}
+
+/// Decides whether a retained outgoing transfer is still worth keeping at `now`.
+pub(crate) fn retention_verdict(
+    signal: &OutgoingSignal,
+    now: Instant,
+    config: &NetworkConfig,
+) -> RetentionVerdict {
+    match signal.accepted_at {
+        Some(accepted) => {
+            let waited = now.saturating_duration_since(accepted);
+            if waited >= Duration::from_secs(config.delivery_ack_timeout_secs) {
+                RetentionVerdict::TimedOut
+            } else {
+                RetentionVerdict::Keep
+            }
+        }
+        None => {
+            let waited = now.saturating_duration_since(signal.queued_at);
+            if waited >= Duration::from_secs(config.outbox_max_age_secs) {
+                RetentionVerdict::Abandon
+            } else {
+                RetentionVerdict::Keep
+            }
+        }
+    }
+}
+
+/// Whether a retained transfer has waited long enough for its receipt to go out again.
+pub(crate) fn retransmit_due(
+    signal: &OutgoingSignal,
+    now: Instant,
+    config: &NetworkConfig,
+) -> bool {
+    let Some(accepted) = signal.accepted_at else {
+        return false;
+    };
+    let since = signal.last_retransmit.unwrap_or(accepted);
+    signal.awaits_receipt
+        && signal.retransmits < config.signal_max_retransmits
+        && now.saturating_duration_since(since)
+            >= Duration::from_secs(config.signal_retransmit_secs)
+}
+
+/// Missing fragment indices of a stalled reassembly, if a resend request is due now.
+pub(crate) fn nack_due(
+    state: &MediaTransferState,
+    now: Instant,
+    config: &NetworkConfig,
+) -> Option<Vec<u32>> {
+    let delay = Duration::from_secs(config.signal_nack_delay_secs);
+    if state.nacks_sent >= config.signal_max_nacks
+        || now.saturating_duration_since(state.last_activity) < delay
+        || state
+            .last_nack
+            .is_some_and(|at| now.saturating_duration_since(at) < delay)
+    {
+        return None;
+    }
+    let missing: Vec<u32> = state
+        .received_chunks
+        .iter()
+        .enumerate()
+        .filter(|(_, received)| !**received)
+        .map(|(idx, _)| idx as u32)
+        .collect();
+    (!missing.is_empty()).then_some(missing)
+}
+
+/// Retains `payload` for resends and sends it as one signal transfer.
+pub(crate) async fn send_retained_signal<R: Runtime>(
+    app: &AppHandle<R>,
+    net_state: &NetworkState,
+    recipient: &str,
+    msg_id: &str,
+    payload: Vec<u8>,
+    awaits_receipt: bool,
+) -> Result<(), String> {
+    let transfer_id: u32 = rand::random();
+    net_state
+        .outgoing_signals
+        .lock()
+        .map_err(|_| "Network state poisoned")?
+        .insert(
+            transfer_id,
+            OutgoingSignal {
+                msg_id: msg_id.to_string(),
+                recipient: recipient.to_string(),
+                payload: payload.clone(),
+                queued_at: Instant::now(),
+                accepted_at: None,
+                awaits_receipt,
+                retransmits: 0,
+                last_retransmit: None,
+            },
+        );
+    internal_send_to_network(
+        app.clone(),
+        net_state,
+        Some(recipient.to_string()),
+        Some(msg_id.to_string()),
+        None,
+        Some(payload),
+        true,
+        false,
+        Some(transfer_id),
+        false,
+    )
+    .await
+}
+
+/// Starts the receipt timeout of a retained transfer the relay has accepted.
+pub(crate) fn mark_relay_accepted(net_state: &NetworkState, transfer_id: Option<u32>) {
+    if let Some(tid) = transfer_id
+        && let Ok(mut signals) = net_state.outgoing_signals.lock()
+        && let Some(signal) = signals.get_mut(&tid)
+    {
+        signal.accepted_at.get_or_insert_with(Instant::now);
+    }
+}
+
+/// Releases the retained ciphertext of messages the peer has receipted.
+pub(crate) fn release_receipted(net_state: &NetworkState, sender: &str, msg_ids: &[String]) {
+    if let Ok(mut signals) = net_state.outgoing_signals.lock() {
+        signals.retain(|_, s| {
+            !(s.recipient.eq_ignore_ascii_case(sender) && msg_ids.contains(&s.msg_id))
+        });
+    }
+}
+
+/// Resends the fragments a recipient reported missing. Requests for transfers that were
+/// not sent to `sender` are ignored.
+pub(crate) async fn handle_signal_resend_request<R: Runtime>(
+    app: AppHandle<R>,
+    sender: String,
+    decrypted_json: serde_json::Value,
+) -> Result<(), String> {
+    let transfer_id = decrypted_json["transfer_id"]
+        .as_u64()
+        .ok_or("Missing transfer_id")? as u32;
+    let net_state = app.state::<NetworkState>();
+    let signal = net_state
+        .outgoing_signals
+        .lock()
+        .map_err(|_| "Network state poisoned")?
+        .get(&transfer_id)
+        .filter(|s| s.recipient.eq_ignore_ascii_case(&sender))
+        .cloned();
+    let Some(signal) = signal else {
+        return Ok(());
+    };
+
+    let total = FrameCodec::chunk_count(signal.payload.len());
+    let mut indices: Vec<u32> = decrypted_json["indices"]
+        .as_array()
+        .ok_or("Missing indices")?
+        .iter()
+        .filter_map(|v| v.as_u64())
+        .filter(|i| *i < total as u64)
+        .map(|i| i as u32)
+        .collect();
+    indices.sort_unstable();
+    indices.dedup();
+    resend_fragments(&app, &net_state, transfer_id, &signal, indices).await
+}
+
+async fn resend_fragments<R: Runtime>(
+    app: &AppHandle<R>,
+    net_state: &NetworkState,
+    transfer_id: u32,
+    signal: &OutgoingSignal,
+    indices: impl IntoIterator<Item = u32>,
+) -> Result<(), String> {
+    let total = FrameCodec::chunk_count(signal.payload.len());
+    let routing = FrameCodec::routing_header(&signal.recipient);
+    for idx in indices {
+        let start = idx as usize * CHUNK_CAPACITY;
+        let end = (start + CHUNK_CAPACITY).min(signal.payload.len());
+        internal_dispatch_fragment(
+            app.clone(),
+            net_state,
+            routing,
+            None,
+            transfer_id,
+            idx,
+            total,
+            &signal.payload[start..end],
+            false,
+            false,
+            true,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+pub fn start_delivery_monitor<R: Runtime>(app: AppHandle<R>) {
+    tauri::async_runtime::spawn(async move {
+        loop {
+            let interval = current_config(&app.state::<NetworkState>()).signal_nack_delay_secs;
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+            request_missing_fragments(&app).await;
+            retransmit_unreceipted(&app).await;
+            expire_retained_signals(&app);
+        }
+    });
+}
+
+/// Asks the senders of stalled incoming signal transfers for their missing fragments.
+async fn request_missing_fragments<R: Runtime>(app: &AppHandle<R>) {
+    let net_state = app.state::<NetworkState>();
+    let config = current_config(&net_state);
+    let now = Instant::now();
+    let requests: Vec<(String, u32, Vec<u32>)> = match net_state.media_assembler.lock() {
+        Ok(mut assemblers) => assemblers
+            .iter_mut()
+            .filter_map(|(key, state)| {
+                let (sender, tid, frame_type) = parse_assembler_key(key)?;
+                if frame_type != FRAME_SIGNAL {
+                    return None;
+                }
+                let missing = nack_due(state, now, &config)?;
+                state.nacks_sent += 1;
+                state.last_nack = Some(now);
+                Some((sender.to_string(), tid, missing))
+            })
+            .collect(),
+        Err(_) => return,
+    };
+
+    for (sender, transfer_id, indices) in requests {
+        let request = json!({
+            "type": "signal_resend_request",
+            "transfer_id": transfer_id,
+            "indices": indices,
+        });
+        if let Ok(encrypted) =
+            internal_signal_encrypt(app.clone(), &net_state, &sender, request.to_string()).await
+        {
+            let _ = internal_send_to_network(
+                app.clone(),
+                &net_state,
+                Some(sender.clone()),
+                None,
+                None,
+                Some(encrypted.to_string().into_bytes()),
+                true,
+                false,
+                None,
+                true,
+            )
+            .await;
+        }
+    }
+}
+
+/// Sends again in full the retained transfers whose receipt is overdue.
+async fn retransmit_unreceipted<R: Runtime>(app: &AppHandle<R>) {
+    let net_state = app.state::<NetworkState>();
+    let config = current_config(&net_state);
+    let now = Instant::now();
+    let due: Vec<(u32, OutgoingSignal)> = match net_state.outgoing_signals.lock() {
+        Ok(mut signals) => signals
+            .iter_mut()
+            .filter(|(_, s)| retransmit_due(s, now, &config))
+            .map(|(tid, s)| {
+                s.retransmits += 1;
+                s.last_retransmit = Some(now);
+                (*tid, s.clone())
+            })
+            .collect(),
+        Err(_) => return,
+    };
+
+    for (transfer_id, signal) in due {
+        let total = FrameCodec::chunk_count(signal.payload.len());
+        let _ = resend_fragments(app, &net_state, transfer_id, &signal, 0..total).await;
+    }
+}
+
+/// Drops retained transfers past their verdict and fails 1:1 messages whose receipt
+/// never arrived.
+fn expire_retained_signals<R: Runtime>(app: &AppHandle<R>) {
+    let net_state = app.state::<NetworkState>();
+    let config = current_config(&net_state);
+    let now = Instant::now();
+    let timed_out: Vec<String> = match net_state.outgoing_signals.lock() {
+        Ok(mut signals) => {
+            let mut timed_out = Vec::new();
+            signals.retain(|_, s| match retention_verdict(s, now, &config) {
+                RetentionVerdict::Keep => true,
+                RetentionVerdict::TimedOut => {
+                    if s.awaits_receipt {
+                        timed_out.push(s.msg_id.clone());
+                    }
+                    false
+                }
+                RetentionVerdict::Abandon => false,
+            });
+            timed_out
+        }
+        Err(_) => return,
+    };
+
+    let db_state = app.state::<DbState>();
+    let Ok(conn) = db_state.get_conn() else {
+        return;
+    };
+    for msg_id in timed_out {
+        let chat_address: Option<String> = conn
+            .query_row(
+                "SELECT chat_address FROM messages WHERE id = ?1",
+                [&msg_id],
+                |r| r.get(0),
+            )
+            .ok();
+        let updated = conn
+            .execute(
+                "UPDATE messages SET status = 'failed' WHERE id = ?1 AND status IN ('sending', 'sent')",
+                [&msg_id],
+            )
+            .unwrap_or(0);
+        if updated > 0 {
+            let _ = app.emit(
+                "msg://status",
+                json!({ "id": msg_id, "status": "failed", "chat_address": chat_address }),
+            );
+        }
+    }
+}
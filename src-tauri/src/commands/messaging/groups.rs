@@ -9,12 +9,12 @@ use hex;
 use rand;
 use rusqlite::params;
 use serde_json::json;
-use tauri::{AppHandle, Emitter, State};
+use tauri::{AppHandle, Emitter, Runtime, State};
 use uuid;
 
 #[tauri::command]
-pub async fn create_group(
-    app: AppHandle,
+pub async fn create_group<R: Runtime>(
+    app: AppHandle<R>,
     db_state: State<'_, DbState>,
     state: State<'_, NetworkState>,
     name: String,
@@ -123,8 +123,8 @@ pub async fn create_group(
 }
 
 #[tauri::command]
-pub async fn add_to_group(
-    app: AppHandle,
+pub async fn add_to_group<R: Runtime>(
+    app: AppHandle<R>,
     db_state: State<'_, DbState>,
     state: State<'_, NetworkState>,
     group_id: String,
@@ -260,8 +260,8 @@ pub async fn add_to_group(
 }
 
 #[tauri::command]
-pub async fn update_group_name(
-    app: AppHandle,
+pub async fn update_group_name<R: Runtime>(
+    app: AppHandle<R>,
     db_state: State<'_, DbState>,
     state: State<'_, NetworkState>,
     group_id: String,
@@ -328,8 +328,8 @@ pub async fn update_group_name(
 }
 
 #[tauri::command]
-pub async fn leave_group(
-    app: AppHandle,
+pub async fn leave_group<R: Runtime>(
+    app: AppHandle<R>,
     db_state: State<'_, DbState>,
     state: State<'_, NetworkState>,
     group_id: String,
@@ -1,48 +1,58 @@
 use crate::app_state::NetworkState;
+use crate::commands::identity::devices::{
+    PRIMARY_DEVICE, local_device_id, protocol_address, split_address,
+};
+use crate::noise::TrafficNormalizer;
 use crate::signal_store::SqliteSignalStore;
 use libsignal_protocol::{
-    CiphertextMessage, CiphertextMessageType, DeviceId, ProtocolAddress, SignalProtocolError,
-    message_decrypt,
+    CiphertextMessage, CiphertextMessageType, SignalProtocolError, message_decrypt,
 };
 use rand::SeedableRng;
 use rand::rngs::StdRng;
-use tauri::{AppHandle, Manager};
+use tauri::{AppHandle, Manager, Runtime};
 
-pub async fn internal_signal_decrypt(
-    app: AppHandle,
-    remote_hash: &str,
+/// Rebuilds a Signal ciphertext from the `type`/`body` pair of a received envelope.
+pub(crate) fn parse_ciphertext(
     message_type: u8,
     message_body: &[u8],
-) -> Result<String, String> {
-    let mut store = SqliteSignalStore::new(app.clone());
-    let address = ProtocolAddress::new(
-        remote_hash.to_string(),
-        DeviceId::try_from(1u32).expect("valid ID"),
-    );
-
-    let mut rng = StdRng::from_os_rng();
-
+) -> Result<CiphertextMessage, String> {
     let ciphertext_type =
         CiphertextMessageType::try_from(message_type).map_err(|_| "Invalid message type")?;
 
-    let ciphertext = match ciphertext_type {
-        CiphertextMessageType::Whisper => CiphertextMessage::SignalMessage(
+    match ciphertext_type {
+        CiphertextMessageType::Whisper => Ok(CiphertextMessage::SignalMessage(
             libsignal_protocol::SignalMessage::try_from(message_body)
                 .map_err(|e: SignalProtocolError| e.to_string())?,
-        ),
-        CiphertextMessageType::PreKey => CiphertextMessage::PreKeySignalMessage(
+        )),
+        CiphertextMessageType::PreKey => Ok(CiphertextMessage::PreKeySignalMessage(
             libsignal_protocol::PreKeySignalMessage::try_from(message_body)
                 .map_err(|e: SignalProtocolError| e.to_string())?,
-        ),
-        _ => return Err("Unsupported ciphertext type".into()),
-    };
+        )),
+        _ => Err("Unsupported ciphertext type".into()),
+    }
+}
+
+/// Decrypts a message from `remote`, a bare hash for a primary or `hash.device`.
+pub async fn internal_signal_decrypt<R: Runtime>(
+    app: AppHandle<R>,
+    remote: &str,
+    message_type: u8,
+    message_body: &[u8],
+) -> Result<String, String> {
+    let mut store = SqliteSignalStore::new(app.clone());
+    let (remote_hash, device) = split_address(remote);
+    let address = protocol_address(remote_hash, device.unwrap_or(PRIMARY_DEVICE))?;
+
+    let mut rng = StdRng::from_os_rng();
+
+    let ciphertext = parse_ciphertext(message_type, message_body)?;
 
     let own_hash = {
         let ns = app.state::<NetworkState>();
         let lock = ns.identity_hash.lock().map_err(|_| "Net lock poisoned")?;
         lock.clone().ok_or("Local identity not found")?
     };
-    let own_address = ProtocolAddress::new(own_hash, DeviceId::try_from(1u32).expect("valid ID"));
+    let own_address = protocol_address(&own_hash, local_device_id(&app))?;
 
     let ptext = message_decrypt(
         &ciphertext,
@@ -58,5 +68,8 @@ pub async fn internal_signal_decrypt(
     .await
     .map_err(|e: SignalProtocolError| e.to_string())?;
 
-    String::from_utf8(ptext).map_err(|e: std::string::FromUtf8Error| e.to_string())
+    let mut plaintext =
+        String::from_utf8(ptext).map_err(|e: std::string::FromUtf8Error| e.to_string())?;
+    TrafficNormalizer::unpad_plaintext(&mut plaintext);
+    Ok(plaintext)
 }
@@ -3,11 +3,11 @@ use crate::signal_store::SqliteSignalStore;
 use libsignal_protocol::{
     CiphertextMessage, DeviceId, ProtocolAddress, message_decrypt,
 };
-use tauri::AppHandle;
+use tauri::{AppHandle, Runtime};
 use rand::{SeedableRng, rngs::StdRng};
 
-pub async fn internal_signal_decrypt(
-    app: AppHandle,
+pub async fn internal_signal_decrypt<R: Runtime>(
+    app: AppHandle<R>,
     net_state: &NetworkState,
     sender: &str,
     payload: &[u8],
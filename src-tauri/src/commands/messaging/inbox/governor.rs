@@ -0,0 +1,209 @@
+//! Inbound Flood Protection
+//!
+//! Bounds what remote peers can make this client hold: concurrent reassemblies and
+//! the disk they reserve, per sender and in total, plus a per-second fragment budget.
+//! Senders that exceed a per-sender limit are muted for `inbound_mute_secs`; global
+//! limits only shed the offending fragment, since the sender may be innocent.
+
+use serde_json::json;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter, Manager, Runtime};
+
+use crate::app_state::NetworkState;
+use crate::commands::messaging::inbox::reassembler::parse_assembler_key;
+use crate::commands::network::codec::CHUNK_CAPACITY;
+use crate::commands::network::config::{NetworkConfig, current_config};
+use crate::commands::network::sweeper::discard_sender_transfers;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaLimit {
+    SenderTransfers,
+    SenderBytes,
+    SenderRate,
+    GlobalTransfers,
+    GlobalBytes,
+    GlobalRate,
+}
+
+impl QuotaLimit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QuotaLimit::SenderTransfers => "sender_transfers",
+            QuotaLimit::SenderBytes => "sender_bytes",
+            QuotaLimit::SenderRate => "sender_rate",
+            QuotaLimit::GlobalTransfers => "global_transfers",
+            QuotaLimit::GlobalBytes => "global_bytes",
+            QuotaLimit::GlobalRate => "global_rate",
+        }
+    }
+
+    /// Per-sender violations mute the sender; global ones only drop the fragment.
+    pub fn mutes_sender(&self) -> bool {
+        matches!(
+            self,
+            QuotaLimit::SenderTransfers | QuotaLimit::SenderBytes | QuotaLimit::SenderRate
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Admission {
+    Accept,
+    /// The sender is muted; drop silently.
+    Muted,
+    Reject(QuotaLimit),
+}
+
+struct RateWindow {
+    started: Instant,
+    count: u32,
+}
+
+impl RateWindow {
+    fn new(now: Instant) -> Self {
+        Self {
+            started: now,
+            count: 0,
+        }
+    }
+
+    /// Counts one fragment in the current one-second window; false once over `limit`.
+    fn hit(&mut self, now: Instant, limit: u32) -> bool {
+        if now.saturating_duration_since(self.started) >= Duration::from_secs(1) {
+            self.started = now;
+            self.count = 0;
+        }
+        self.count += 1;
+        self.count <= limit
+    }
+}
+
+/// Disk reserved by one reassembly: every declared fragment at full capacity.
+pub(crate) fn reserved_bytes(total: u32) -> u64 {
+    total as u64 * CHUNK_CAPACITY as u64
+}
+
+pub struct InboundGovernor {
+    rates: HashMap<String, RateWindow>,
+    global_rate: RateWindow,
+    muted: HashMap<String, Instant>,
+}
+
+impl Default for InboundGovernor {
+    fn default() -> Self {
+        Self {
+            rates: HashMap::new(),
+            global_rate: RateWindow::new(Instant::now()),
+            muted: HashMap::new(),
+        }
+    }
+}
+
+impl InboundGovernor {
+    pub fn is_muted(&mut self, sender: &str, now: Instant) -> bool {
+        match self.muted.get(sender) {
+            Some(until) if *until > now => true,
+            Some(_) => {
+                self.muted.remove(sender);
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub fn mute(&mut self, sender: &str, now: Instant, config: &NetworkConfig) {
+        self.muted.insert(
+            sender.to_string(),
+            now + Duration::from_secs(config.inbound_mute_secs),
+        );
+        self.rates.remove(sender);
+    }
+
+    /// Applies the fragment-rate budgets to one incoming fragment.
+    pub fn admit_fragment(
+        &mut self,
+        sender: &str,
+        now: Instant,
+        config: &NetworkConfig,
+    ) -> Admission {
+        if self.is_muted(sender, now) {
+            return Admission::Muted;
+        }
+        let within_sender = self
+            .rates
+            .entry(sender.to_string())
+            .or_insert_with(|| RateWindow::new(now))
+            .hit(now, config.max_fragments_per_sec_per_sender);
+        if !within_sender {
+            return Admission::Reject(QuotaLimit::SenderRate);
+        }
+        if !self
+            .global_rate
+            .hit(now, config.max_fragments_per_sec_total)
+        {
+            return Admission::Reject(QuotaLimit::GlobalRate);
+        }
+        Admission::Accept
+    }
+
+    /// Forgets rate windows that have gone quiet and mutes that have lapsed.
+    pub fn prune(&mut self, now: Instant) {
+        self.rates
+            .retain(|_, w| now.saturating_duration_since(w.started) < Duration::from_secs(60));
+        self.muted.retain(|_, until| *until > now);
+    }
+}
+
+/// Checks whether `sender` may open a new reassembly declaring `total` fragments,
+/// given the keys and declared totals of the reassemblies already in progress.
+pub(crate) fn admit_transfer<'a>(
+    in_progress: impl Iterator<Item = (&'a str, u32)>,
+    sender: &str,
+    total: u32,
+    config: &NetworkConfig,
+) -> Result<(), QuotaLimit> {
+    let (mut sender_transfers, mut sender_bytes) = (0u32, 0u64);
+    let (mut all_transfers, mut all_bytes) = (0u32, 0u64);
+    for (key, existing_total) in in_progress {
+        let bytes = reserved_bytes(existing_total);
+        all_transfers += 1;
+        all_bytes += bytes;
+        if parse_assembler_key(key).is_some_and(|(s, _, _)| s == sender) {
+            sender_transfers += 1;
+            sender_bytes += bytes;
+        }
+    }
+
+    let incoming = reserved_bytes(total);
+    if sender_transfers >= config.max_transfers_per_sender {
+        return Err(QuotaLimit::SenderTransfers);
+    }
+    if sender_bytes + incoming > config.max_bytes_per_sender {
+        return Err(QuotaLimit::SenderBytes);
+    }
+    if all_transfers >= config.max_transfers_total {
+        return Err(QuotaLimit::GlobalTransfers);
+    }
+    if all_bytes + incoming > config.max_bytes_total {
+        return Err(QuotaLimit::GlobalBytes);
+    }
+    Ok(())
+}
+
+/// Mutes the sender when the limit is theirs, drops their in-progress transfers, and
+/// surfaces the hit as a `network-warning`.
+pub(crate) fn report_violation<R: Runtime>(app: &AppHandle<R>, sender: &str, limit: QuotaLimit) {
+    let net_state = app.state::<NetworkState>();
+    if limit.mutes_sender() {
+        let config = current_config(&net_state);
+        if let Ok(mut governor) = net_state.inbound_governor.lock() {
+            governor.mute(sender, Instant::now(), &config);
+        }
+        discard_sender_transfers(app, sender);
+    }
+    let _ = app.emit(
+        "network-warning",
+        json!({ "type": "inbound_quota", "limit": limit.as_str(), "sender": sender }),
+    );
+}
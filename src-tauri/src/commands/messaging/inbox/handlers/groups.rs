@@ -2,10 +2,10 @@ use crate::app_state::DbState;
 use crate::commands::{DbChat, DbMessage, internal_db_save_message, internal_db_upsert_chat};
 use rusqlite::params;
 use serde_json::json;
-use tauri::{AppHandle, Emitter, Manager};
+use tauri::{AppHandle, Emitter, Manager, Runtime};
 
-pub async fn handle_group_invite(
-    app: AppHandle,
+pub async fn handle_group_invite<R: Runtime>(
+    app: AppHandle<R>,
     sender: String,
     decrypted_json: serde_json::Value,
     own_hash: &str,
@@ -140,8 +140,8 @@ pub async fn handle_group_invite(
     Ok(())
 }
 
-pub async fn handle_group_leave(
-    app: AppHandle,
+pub async fn handle_group_leave<R: Runtime>(
+    app: AppHandle<R>,
     sender: String,
     decrypted_json: serde_json::Value,
 ) -> Result<(), String> {
@@ -184,8 +184,8 @@ pub async fn handle_group_leave(
     Ok(())
 }
 
-pub async fn handle_group_update(
-    app: AppHandle,
+pub async fn handle_group_update<R: Runtime>(
+    app: AppHandle<R>,
     sender: String,
     decrypted_json: serde_json::Value,
     own_hash: &str,
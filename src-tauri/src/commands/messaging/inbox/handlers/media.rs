@@ -1,5 +1,9 @@
 use crate::app_state::{DbState, NetworkState, PendingMediaMetadata};
 use crate::commands::messaging::inbox::internal_send_volatile;
+use crate::commands::messaging::inbox::reassembler::{
+    assembler_key, reopen_reassembly, temp_filename,
+};
+use crate::commands::network::codec::{CHUNK_CAPACITY, FRAME_MEDIA, sealed_media_len};
 use crate::commands::{
     DbMessage, get_media_dir, internal_db_save_message, internal_signal_encrypt,
 };
@@ -10,11 +14,80 @@ use chacha20poly1305::{
 };
 use rusqlite::params;
 use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
 use std::io::{Read, Write};
-use tauri::{AppHandle, Emitter, Manager};
+use tauri::{AppHandle, Emitter, Manager, Runtime};
+
+/// Integrity resends requested for one download before it is given up on.
+const MAX_INTEGRITY_RESENDS: u32 = 3;
+
+/// What a file announcement promises about the plaintext. Older senders announce
+/// neither field, in which case only the per-block tags are checked.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MediaIntegrity {
+    pub sha256: Option<[u8; 32]>,
+    pub blocks: Option<u32>,
+}
+
+impl MediaIntegrity {
+    pub fn from_bundle(bundle: &serde_json::Value) -> Self {
+        Self {
+            sha256: bundle["sha256"]
+                .as_str()
+                .and_then(|h| hex::decode(h).ok())
+                .and_then(|b| b.try_into().ok()),
+            blocks: bundle["blocks"].as_u64().map(|b| b as u32),
+        }
+    }
+
+    /// Blocks to ask the sender for again, or none if the file checks out. Blocks
+    /// that failed to open are asked for alone; a short file is topped up; anything
+    /// else means the whole file is sent again.
+    pub fn blocks_to_resend(&self, report: &BridgeReport) -> Vec<u32> {
+        if !report.bad_blocks.is_empty() {
+            return report.bad_blocks.clone();
+        }
+        let expected = self.blocks.unwrap_or(report.blocks);
+        if report.blocks < expected {
+            return (report.blocks..expected).collect();
+        }
+        if report.blocks != expected || self.sha256.is_some_and(|d| d != report.sha256) {
+            return (0..expected).collect();
+        }
+        Vec::new()
+    }
+}
+
+/// Outcome of sealing a download into the vault.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BridgeReport {
+    /// Blocks read up to the announced size.
+    pub blocks: u32,
+    /// Digest of the plaintext of every block that opened.
+    pub sha256: [u8; 32],
+    /// Blocks that failed to open or repeated an earlier block.
+    pub bad_blocks: Vec<u32>,
+}
 
-pub async fn handle_media_msg(
-    app: AppHandle,
+/// The sender, transfer id and metadata of a download of `msg_id` that has been
+/// announced but not yet bridged into the vault.
+pub(crate) fn find_partial_download(
+    net_state: &NetworkState,
+    msg_id: &str,
+) -> Option<(String, u32, PendingMediaMetadata)> {
+    let links = net_state.pending_media_links.lock().ok()?;
+    links.iter().find_map(|(key, meta)| {
+        if meta.id != msg_id {
+            return None;
+        }
+        let (sender, tid) = key.rsplit_once(':')?;
+        Some((sender.to_string(), tid.parse().ok()?, meta.clone()))
+    })
+}
+
+pub async fn handle_media_msg<R: Runtime>(
+    app: AppHandle<R>,
     sender: String,
     decrypted_json: serde_json::Value,
     net_state: &NetworkState,
@@ -53,8 +126,7 @@ pub async fn handle_media_msg(
     let media_dir = get_media_dir(&app, &db_state)?;
     let final_file_path = media_dir.join(&msg_id);
 
-    let temp_filename = format!("transfer_{}_{}_media.bin", sender, inner_transfer_id);
-    let temp_path = media_dir.join(&temp_filename);
+    let temp_path = media_dir.join(temp_filename(&sender, inner_transfer_id, FRAME_MEDIA));
     let key_str = bundle["key"].as_str().unwrap_or_default().to_string();
 
     // 1. Handle thumbnail saving to vault
@@ -88,112 +160,73 @@ pub async fn handle_media_msg(
             PendingMediaMetadata {
                 id: msg_id.clone(),
                 key: key_str.clone(),
+                size,
+                integrity: MediaIntegrity::from_bundle(&bundle),
+                resends: 0,
+                registered_at: std::time::Instant::now(),
             },
         );
     }
 
     if temp_path.exists() {
-        // Decrypt and save media arriving before metadata (Streaming O(1) RAM)
-        if let Ok(key_bytes) = base64::engine::general_purpose::STANDARD.decode(&key_str) {
-            let transit_key = Key::from_slice(&key_bytes);
-            let transit_cipher = XChaCha20Poly1305::new(transit_key);
-
-            let vault_key_bytes = {
-                let lock = db_state
-                    .media_key
-                    .lock()
-                    .map_err(|_| "Media key lock poisoned")?;
-                lock.clone().ok_or("Media key not initialized")?
-            };
-            let vault_key = Key::from_slice(&vault_key_bytes);
-            let vault_cipher = XChaCha20Poly1305::new(vault_key);
-
-            let vault_path = media_dir.join(&msg_id);
-
-            // 2. Check for missing fragments (Selective Repeat)
-            let mut missing = Vec::new();
-            {
-                let assemblers = net_state
-                    .media_assembler
-                    .lock()
-                    .map_err(|_| "Network state poisoned")?;
-                let transfer_key = format!("{}:{}:2", sender, inner_transfer_id);
-                if let Some(assembler) = assemblers.get(&transfer_key) {
-                    for (idx, received) in assembler.received_chunks.iter().enumerate() {
-                        if !received {
-                            missing.push(idx as u32);
-                        }
+        // Media arriving before metadata: check for missing fragments (Selective Repeat)
+        let mut missing = Vec::new();
+        {
+            let assemblers = net_state
+                .media_assembler
+                .lock()
+                .map_err(|_| "Network state poisoned")?;
+            let transfer_key = assembler_key(&sender, inner_transfer_id, FRAME_MEDIA);
+            if let Some(assembler) = assemblers.get(&transfer_key) {
+                for (idx, received) in assembler.received_chunks.iter().enumerate() {
+                    if !received {
+                        missing.push(idx as u32);
                     }
                 }
             }
+        }
 
-            if !missing.is_empty() {
-                // Request resend via background signal
-                let resend_req = serde_json::json!({
-                    "type": "media_resend_request",
-                    "transfer_id": inner_transfer_id,
-                    "indices": missing,
-                    "msg_id": msg_id.clone()
-                });
-
-                if let Ok(encrypted) =
-                    internal_signal_encrypt(app.clone(), net_state, &sender, resend_req.to_string())
-                        .await
-                {
-                    let _ = crate::commands::network::transit::internal_send_to_network(
-                        app.clone(),
-                        net_state,
-                        Some(sender.clone()),
-                        None,
-                        None,
-                        Some(encrypted.to_string().into_bytes()),
-                        true,
-                        false,
-                        None,
-                        true,
-                    )
-                    .await;
-                }
-                return Ok(());
-            }
-
-            // All fragments present - consume the metadata and bridge now
+        if !missing.is_empty() {
+            // Request resend via background signal
+            let resend_req = serde_json::json!({
+                "type": "media_resend_request",
+                "transfer_id": inner_transfer_id,
+                "indices": missing,
+                "msg_id": msg_id.clone()
+            });
+
+            if let Ok(encrypted) =
+                internal_signal_encrypt(app.clone(), net_state, &sender, resend_req.to_string())
+                    .await
             {
-                let mut links = net_state
-                    .pending_media_links
-                    .lock()
-                    .map_err(|_| "Network state poisoned")?;
-                let transfer_key = format!("{}:{}", sender, inner_transfer_id);
-                links.remove(&transfer_key);
+                let _ = crate::commands::network::transit::internal_send_to_network(
+                    app.clone(),
+                    net_state,
+                    Some(sender.clone()),
+                    None,
+                    None,
+                    Some(encrypted.to_string().into_bytes()),
+                    true,
+                    false,
+                    None,
+                    true,
+                )
+                .await;
             }
+            return Ok(());
+        }
 
-            if let Err(e) = internal_vault_bridge(
-                &app,
-                &temp_path,
-                &vault_path,
-                &transit_cipher,
-                &vault_cipher,
-                inner_transfer_id,
-                &sender,
-            ) {
-                let _ = app.emit(
-                    "network-bin-error",
-                    serde_json::json!({
-                        "msg_id": msg_id.clone(),
-                        "error": e
-                    }),
-                );
-            } else {
-                let _ = std::fs::remove_file(&temp_path);
-                let _ = app.emit(
-                    "network-bin-complete",
-                    serde_json::json!({
-                        "sender": sender,
-                        "transfer_id": inner_transfer_id,
-                        "msg_id": Some(msg_id.clone())
-                    }),
-                );
-            }
+        // All fragments present - consume the metadata and bridge now
+        let meta = {
+            let mut links = net_state
+                .pending_media_links
+                .lock()
+                .map_err(|_| "Network state poisoned")?;
+            let transfer_key = format!("{}:{}", sender, inner_transfer_id);
+            links.remove(&transfer_key)
+        };
+        if let Some(meta) = meta {
+            finish_download(app.clone(), sender.clone(), inner_transfer_id, meta).await?;
         }
     }
 
@@ -324,18 +357,13 @@ pub async fn handle_media_msg(
     Ok(())
 }
 
-pub async fn handle_media_completion(
-    app: AppHandle,
+pub async fn handle_media_completion<R: Runtime>(
+    app: AppHandle<R>,
     sender: String,
     transfer_id: u32,
     net_state: &NetworkState,
 ) -> Result<(), String> {
-    let db_state = app.state::<DbState>();
     let link_key = format!("{}:{}", sender, transfer_id);
-    let media_dir = get_media_dir(&app, &db_state)?;
-    let temp_filename = format!("transfer_{}_{}_media.bin", sender, transfer_id);
-    let temp_path = media_dir.join(&temp_filename);
-
     let meta = {
         let mut links = net_state
             .pending_media_links
@@ -345,68 +373,23 @@ pub async fn handle_media_completion(
     };
 
     if let Some(m) = meta {
-        // Vault decryption bridge (Streaming O(1) RAM)
-        // Offload to blocking thread pool to avoid starving the websocket processing loop
-        let app_clone = app.clone();
-        let sender_clone = sender.clone();
-        let m_clone = m.clone();
-        let temp_path_clone = temp_path.clone();
-
-        let vault_key_bytes = {
-            let lock = db_state
-                .media_key
-                .lock()
-                .map_err(|_| "Media key lock poisoned")?;
-            lock.clone().ok_or("Media key not initialized")?
-        };
-
-        let transit_key_bytes = base64::engine::general_purpose::STANDARD
-            .decode(&m_clone.key)
-            .map_err(|_| "Invalid transit key format")?;
-
-        tokio::task::spawn_blocking(move || {
-            let vault_key = Key::from_slice(&vault_key_bytes);
-            let vault_cipher = XChaCha20Poly1305::new(vault_key);
-            let transit_key = Key::from_slice(&transit_key_bytes);
-            let transit_cipher = XChaCha20Poly1305::new(transit_key);
-
-            let media_dir = get_media_dir(&app_clone, &app_clone.state::<DbState>())?;
-            let vault_path = media_dir.join(&m_clone.id);
-
-            if let Err(e) = internal_vault_bridge(
-                &app_clone,
-                &temp_path_clone,
-                &vault_path,
-                &transit_cipher,
-                &vault_cipher,
-                transfer_id,
-                &sender_clone,
-            ) {
-                let _ = app_clone.emit(
+        // Bridging runs off the websocket processing loop
+        tauri::async_runtime::spawn(async move {
+            if let Err(e) = finish_download(app.clone(), sender, transfer_id, m.clone()).await {
+                let _ = app.emit(
                     "network-bin-error",
-                    serde_json::json!({
-                        "msg_id": m_clone.id,
-                        "error": e
-                    }),
-                );
-            } else {
-                let _ = std::fs::remove_file(&temp_path_clone);
-                let _ = app_clone.emit(
-                    "network-bin-complete",
-                    serde_json::json!({
-                        "sender": sender_clone,
-                        "transfer_id": transfer_id,
-                        "msg_id": Some(m_clone.id)
-                    }),
+                    serde_json::json!({ "msg_id": m.id, "error": e }),
                 );
             }
-            Ok::<(), String>(())
         });
     }
     Ok(())
 }
 
-pub fn handle_vault_retry_bridge(app: AppHandle, msg_id: String) -> Result<(), String> {
+pub fn handle_vault_retry_bridge<R: Runtime>(
+    app: AppHandle<R>,
+    msg_id: String,
+) -> Result<(), String> {
     let db_state = app.state::<DbState>();
     let net_state = app.state::<NetworkState>();
 
@@ -430,8 +413,7 @@ pub fn handle_vault_retry_bridge(app: AppHandle, msg_id: String) -> Result<(), S
 
     // 2. Locate temp file
     let media_dir = get_media_dir(&app, &db_state)?;
-    let temp_filename = format!("transfer_{}_{}_media.bin", sender, transfer_id);
-    let temp_path = media_dir.join(&temp_filename);
+    let temp_path = media_dir.join(temp_filename(&sender, transfer_id, FRAME_MEDIA));
 
     if !temp_path.exists() {
         return Err("Temporary media file not found. It may have been cleared.".into());
@@ -446,7 +428,7 @@ pub fn handle_vault_retry_bridge(app: AppHandle, msg_id: String) -> Result<(), S
     let vault_cipher = XChaCha20Poly1305::new(vault_key);
 
     let transfer_key = format!("{}:{}", sender, transfer_id);
-    let transit_key_str = {
+    let link = {
         let lock = net_state
             .pending_media_links
             .lock()
@@ -454,10 +436,9 @@ pub fn handle_vault_retry_bridge(app: AppHandle, msg_id: String) -> Result<(), S
         lock.get(&transfer_key)
             .cloned()
             .ok_or("Transfer metadata expired. You may need to ask the sender to resend.")?
-            .key
     };
     let transit_key_bytes = base64::engine::general_purpose::STANDARD
-        .decode(&transit_key_str)
+        .decode(&link.key)
         .map_err(|_| "Invalid transit key format")?;
     let transit_key = Key::from_slice(&transit_key_bytes);
     let transit_cipher = XChaCha20Poly1305::new(transit_key);
@@ -465,7 +446,7 @@ pub fn handle_vault_retry_bridge(app: AppHandle, msg_id: String) -> Result<(), S
     let vault_path = media_dir.join(&msg_id);
 
     // 4. Run bridge
-    internal_vault_bridge(
+    let report = internal_vault_bridge(
         &app,
         &temp_path,
         &vault_path,
@@ -473,7 +454,14 @@ pub fn handle_vault_retry_bridge(app: AppHandle, msg_id: String) -> Result<(), S
         &vault_cipher,
         transfer_id,
         &sender,
+        link.size,
     )?;
+    if !link.integrity.blocks_to_resend(&report).is_empty() {
+        let _ = std::fs::remove_file(&vault_path);
+        return Err(
+            "Integrity check failed. The file does not match what the sender announced.".into(),
+        );
+    }
 
     // 5. Cleanup and notify
     let _ = std::fs::remove_file(&temp_path);
@@ -500,30 +488,159 @@ pub fn handle_vault_retry_bridge(app: AppHandle, msg_id: String) -> Result<(), S
     Ok(())
 }
 
-pub fn internal_vault_bridge(
-    app: &tauri::AppHandle,
+/// Bridges a fully reassembled download into the vault and checks it against its
+/// announcement. On a mismatch the vault file is dropped and the damaged blocks are
+/// requested again, up to `MAX_INTEGRITY_RESENDS` times.
+async fn finish_download<R: Runtime>(
+    app: AppHandle<R>,
+    sender: String,
+    transfer_id: u32,
+    meta: PendingMediaMetadata,
+) -> Result<(), String> {
+    let db_state = app.state::<DbState>();
+    let media_dir = get_media_dir(&app, &db_state)?;
+    let temp_path = media_dir.join(temp_filename(&sender, transfer_id, FRAME_MEDIA));
+    let vault_path = media_dir.join(&meta.id);
+
+    let vault_key_bytes = {
+        let lock = db_state
+            .media_key
+            .lock()
+            .map_err(|_| "Media key lock poisoned")?;
+        lock.clone().ok_or("Media key not initialized")?
+    };
+    let transit_key_bytes = base64::engine::general_purpose::STANDARD
+        .decode(&meta.key)
+        .map_err(|_| "Invalid transit key format")?;
+
+    // Offload to blocking thread pool to avoid starving the websocket processing loop
+    let report = {
+        let app = app.clone();
+        let sender = sender.clone();
+        let temp_path = temp_path.clone();
+        let vault_path = vault_path.clone();
+        let size = meta.size;
+        tokio::task::spawn_blocking(move || {
+            let vault_cipher = XChaCha20Poly1305::new(Key::from_slice(&vault_key_bytes));
+            let transit_cipher = XChaCha20Poly1305::new(Key::from_slice(&transit_key_bytes));
+            internal_vault_bridge(
+                &app,
+                &temp_path,
+                &vault_path,
+                &transit_cipher,
+                &vault_cipher,
+                transfer_id,
+                &sender,
+                size,
+            )
+        })
+        .await
+        .map_err(|e| e.to_string())??
+    };
+
+    let resend = meta.integrity.blocks_to_resend(&report);
+    if resend.is_empty() {
+        let _ = std::fs::remove_file(&temp_path);
+        let _ = app.emit(
+            "network-bin-complete",
+            serde_json::json!({
+                "sender": sender,
+                "transfer_id": transfer_id,
+                "msg_id": Some(meta.id)
+            }),
+        );
+        return Ok(());
+    }
+
+    let _ = std::fs::remove_file(&vault_path);
+    if meta.resends >= MAX_INTEGRITY_RESENDS {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(
+            "Integrity check failed. The file does not match what the sender announced.".into(),
+        );
+    }
+
+    // Reassemble again around the blocks being replaced
+    let net_state = app.state::<NetworkState>();
+    let total = report.blocks.max(meta.integrity.blocks.unwrap_or(0));
+    reopen_reassembly(&app, &net_state, &sender, transfer_id, total, &resend)?;
+    let msg_id = meta.id.clone();
+    net_state
+        .pending_media_links
+        .lock()
+        .map_err(|_| "Network state poisoned")?
+        .insert(
+            format!("{}:{}", sender, transfer_id),
+            PendingMediaMetadata {
+                resends: meta.resends + 1,
+                registered_at: std::time::Instant::now(),
+                ..meta
+            },
+        );
+
+    let resend_req = serde_json::json!({
+        "type": "media_resend_request",
+        "transfer_id": transfer_id,
+        "indices": resend,
+        "msg_id": msg_id
+    });
+    if let Ok(encrypted) =
+        internal_signal_encrypt(app.clone(), &net_state, &sender, resend_req.to_string()).await
+    {
+        let _ = crate::commands::network::transit::internal_send_to_network(
+            app.clone(),
+            &net_state,
+            Some(sender.clone()),
+            None,
+            None,
+            Some(encrypted.to_string().into_bytes()),
+            true,
+            false,
+            None,
+            true,
+        )
+        .await;
+    }
+    Ok(())
+}
+
+/// Seals a reassembled download into the vault, block by block (Streaming O(1) RAM).
+/// Blocks that fail to open, or that repeat an earlier block, are left out and
+/// reported so the caller can ask for them again.
+pub fn internal_vault_bridge<R: Runtime>(
+    app: &tauri::AppHandle<R>,
     src_path: &std::path::Path,
     vault_path: &std::path::Path,
     transit_cipher: &XChaCha20Poly1305,
     vault_cipher: &XChaCha20Poly1305,
     transfer_id: u32,
     sender: &str,
-) -> Result<(), String> {
-    let mut src =
+    plain_size: u64,
+) -> Result<BridgeReport, String> {
+    let src =
         std::fs::File::open(src_path).map_err(|e| format!("Failed to open temp file: {}", e))?;
     let mut dst = std::fs::File::create(vault_path)
         .map_err(|e| format!("Failed to create vault file: {}", e))?;
 
-    let file_size = src.metadata().map(|m| m.len()).unwrap_or(0);
-    let total_blocks = (file_size as f64 / 1319.0).ceil() as u64;
+    // Sealed blocks end where the announced size does; anything after is padding
+    let file_size = src
+        .metadata()
+        .map(|m| m.len())
+        .unwrap_or(0)
+        .min(sealed_media_len(plain_size));
+    let mut src = src.take(file_size);
+    let total_blocks = file_size.div_ceil(CHUNK_CAPACITY as u64);
     let progress_step = (total_blocks / 10).max(1);
 
-    let mut block_buf = [0u8; 1319];
+    let mut block_buf = [0u8; CHUNK_CAPACITY];
     let mut block_count = 0;
+    let mut hasher = Sha256::new();
+    let mut seen_nonces = HashSet::new();
+    let mut bad_blocks = Vec::new();
 
     loop {
         let mut n = 0;
-        while n < 1319 {
+        while n < CHUNK_CAPACITY {
             match src.read(&mut block_buf[n..]) {
                 Ok(0) => break,
                 Ok(read) => n += read,
@@ -537,11 +654,19 @@ pub fn internal_vault_bridge(
 
         let chunk = &block_buf[..n];
         if chunk.len() > 40 {
+            let index = block_count as u32;
+            block_count += 1;
+
+            // A replayed block opens fine but carries the nonce of the one it copies
             let nonce = XNonce::from_slice(&chunk[..24]);
-            let ciphertext = &chunk[24..];
-            let ptext = transit_cipher
-                .decrypt(nonce, ciphertext)
-                .map_err(|_| "Decryption failed during bridge - potential corruption")?;
+            let ptext = match transit_cipher.decrypt(nonce, &chunk[24..]) {
+                Ok(p) if seen_nonces.insert(*nonce) => p,
+                _ => {
+                    bad_blocks.push(index);
+                    continue;
+                }
+            };
+            hasher.update(&ptext);
 
             let v_nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
             let v_cipher = vault_cipher
@@ -563,7 +688,6 @@ pub fn internal_vault_bridge(
                 }
             }
 
-            block_count += 1;
             if block_count % progress_step == 0 || block_count == total_blocks {
                 let _ = app.emit(
                     "network-bin-progress",
@@ -591,5 +715,9 @@ pub fn internal_vault_bridge(
             Err(e) => return Err(format!("Final sync failed: {}", e)),
         }
     }
-    Ok(())
+    Ok(BridgeReport {
+        blocks: block_count as u32,
+        sha256: hasher.finalize().into(),
+        bad_blocks,
+    })
 }
@@ -1,10 +1,10 @@
 use crate::app_state::DbState;
 use rusqlite::params;
 use serde_json::{Value, json};
-use tauri::{AppHandle, Emitter, Manager};
+use tauri::{AppHandle, Emitter, Manager, Runtime};
 
-pub async fn handle_reaction(
-    app: AppHandle,
+pub async fn handle_reaction<R: Runtime>(
+    app: AppHandle<R>,
     sender: String,
     decrypted_json: Value,
 ) -> Result<(), String> {
@@ -1,10 +1,11 @@
-use crate::app_state::DbState;
+use crate::app_state::{DbState, NetworkState};
+use crate::commands::messaging::delivery::release_receipted;
 use crate::commands::{db_set_contact_global_nickname, db_update_messages};
 use serde_json::json;
-use tauri::{AppHandle, Emitter, Manager};
+use tauri::{AppHandle, Emitter, Manager, Runtime};
 
-pub async fn handle_receipt(
-    app: AppHandle,
+pub async fn handle_receipt<R: Runtime>(
+    app: AppHandle<R>,
     sender: String,
     decrypted_json: serde_json::Value,
 ) -> Result<(), String> {
@@ -14,6 +15,7 @@ pub async fn handle_receipt(
             .filter_map(|v| v.as_str().map(|s| s.to_string()))
             .collect();
         if let Some(status) = decrypted_json["status"].as_str() {
+            release_receipted(&app.state::<NetworkState>(), &sender, &id_strs);
             let _ = db_update_messages(
                 app.state::<DbState>(),
                 id_strs.clone(),
@@ -32,8 +34,8 @@ pub async fn handle_receipt(
     Ok(())
 }
 
-pub async fn handle_typing(
-    app: AppHandle,
+pub async fn handle_typing<R: Runtime>(
+    app: AppHandle<R>,
     sender: String,
     decrypted_json: serde_json::Value,
 ) -> Result<(), String> {
@@ -45,8 +47,8 @@ pub async fn handle_typing(
     Ok(())
 }
 
-pub async fn handle_profile_update(
-    app: AppHandle,
+pub async fn handle_profile_update<R: Runtime>(
+    app: AppHandle<R>,
     sender: String,
     decrypted_json: serde_json::Value,
 ) -> Result<(), String> {
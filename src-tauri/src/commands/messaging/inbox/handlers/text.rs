@@ -3,10 +3,10 @@ use crate::commands::messaging::inbox::internal_send_volatile;
 use crate::commands::{DbMessage, internal_db_save_message, internal_signal_encrypt};
 use rusqlite::params;
 use serde_json::json;
-use tauri::{AppHandle, Emitter, Manager};
+use tauri::{AppHandle, Emitter, Manager, Runtime};
 
-pub async fn handle_text_msg(
-    app: AppHandle,
+pub async fn handle_text_msg<R: Runtime>(
+    app: AppHandle<R>,
     sender: String,
     decrypted_json: serde_json::Value,
 ) -> Result<(), String> {
@@ -104,3 +104,57 @@ pub async fn handle_text_msg(
 
     Ok(())
 }
+
+/// A message another device of this account sent, mirrored here so the
+/// conversation shows it too. Only text messages are mirrored; anything else is
+/// refused, since its media never reaches this device.
+pub async fn handle_sent_sync<R: Runtime>(
+    app: AppHandle<R>,
+    decrypted_json: serde_json::Value,
+    own_hash: &str,
+) -> Result<(), String> {
+    let message = &decrypted_json["message"];
+    match message["type"].as_str() {
+        Some("text_msg") => {}
+        Some(other) => return Err(format!("Sent sync of {} messages is not supported", other)),
+        None => return Err("Missing message type".into()),
+    }
+    let destination = decrypted_json["destination"]
+        .as_str()
+        .ok_or("Missing destination")?;
+    let is_group = message["isGroup"].as_bool().unwrap_or(false);
+
+    let db_msg = DbMessage {
+        id: message["id"].as_str().ok_or("Missing msg id")?.to_string(),
+        chat_address: destination.to_string(),
+        sender_hash: own_hash.to_string(),
+        content: message["content"]
+            .as_str()
+            .ok_or("Missing content")?
+            .to_string(),
+        timestamp: message["timestamp"].as_i64().ok_or("Missing timestamp")?,
+        r#type: "text".to_string(),
+        status: "sent".to_string(),
+        attachment_json: None,
+        is_starred: false,
+        is_group,
+        reply_to_json: message["replyTo"]
+            .as_object()
+            .map(|r| serde_json::to_string(r).unwrap_or_default()),
+        reactions_json: None,
+    };
+
+    let db_state = app.state::<DbState>();
+    internal_db_save_message(&db_state, db_msg.clone()).await?;
+    let mut final_json =
+        serde_json::to_value(&db_msg).map_err(|e: serde_json::Error| e.to_string())?;
+    if is_group && let Some(obj) = final_json.as_object_mut() {
+        let _ = obj.insert("chatAlias".to_string(), json!(message["groupName"]));
+        if let Some(members) = message["groupMembers"].as_array() {
+            let _ = obj.insert("chatMembers".to_string(), json!(members));
+        }
+    }
+    app.emit("msg://added", final_json)
+        .map_err(|e: tauri::Error| e.to_string())?;
+    Ok(())
+}
@@ -1,15 +1,45 @@
 use crate::app_state::{DbState, NetworkState};
 pub mod decrypt;
+pub mod governor;
 pub mod handlers;
+pub mod quarantine;
 pub mod reassembler;
+use crate::commands::identity::trust::blocked_until_reverified;
+use crate::commands::identity::{devices, sealed};
 use crate::commands::internal_send_to_network;
+use crate::commands::messaging::outbox::handlers::media::sealed_filler;
+use crate::commands::network::codec::{
+    FRAME_MEDIA, FRAME_SIGNAL, FRAME_VOLATILE, FrameCodec, MEDIA_BLOCK_SIZE, ROUTING_LEN,
+};
+use crate::commands::network::config::current_config;
 use base64::Engine;
+use governor::Admission;
 use rusqlite::params;
 use serde_json::json;
-use tauri::{AppHandle, Emitter, Manager};
+use tauri::{AppHandle, Emitter, Manager, Runtime};
 
-pub async fn process_incoming_binary(
-    app: AppHandle,
+fn is_blocked(db_state: &DbState, sender: &str) -> bool {
+    if sender.is_empty() {
+        return false;
+    }
+    let Ok(conn) = db_state.get_conn() else {
+        return false;
+    };
+    conn.query_row(
+        "SELECT is_blocked FROM contacts WHERE hash = ?1",
+        params![sender],
+        |row: &rusqlite::Row| row.get::<_, i32>(0),
+    )
+    .unwrap_or(0)
+        != 0
+}
+
+/// Handles one packet from the relay. Transfers are reassembled under the sender
+/// the routing header names, or `override_sender` where the caller knows better;
+/// a sealed envelope is then attributed to the sender its certificate proves.
+/// Fragments of a media transfer it announced take that sender as their override.
+pub async fn process_incoming_binary<R: Runtime>(
+    app: AppHandle<R>,
     payload: Vec<u8>,
     override_sender: Option<String>,
 ) -> Result<(), String> {
@@ -23,69 +53,50 @@ pub async fn process_incoming_binary(
         lock.clone().ok_or("No identity found")?
     };
 
-    let trimmed = &payload;
-    if trimmed.len() < 65 {
-        return Ok(());
-    }
-
-    if payload[0] == 0x03 {
+    if payload.len() <= ROUTING_LEN || FrameCodec::is_dummy(&payload) {
         return Ok(());
     }
 
-    let header_bytes = &trimmed[0..64];
-    let header_str = String::from_utf8_lossy(header_bytes).to_string();
-    let sender = override_sender
-        .unwrap_or_else(|| header_str.trim().to_string())
-        .to_lowercase();
+    let mut routing = [0u8; ROUTING_LEN];
+    routing.copy_from_slice(&payload[..ROUTING_LEN]);
+    let routed_by = FrameCodec::routing_str(&routing);
+    let frame_type = payload[ROUTING_LEN];
 
-    if !sender.is_empty()
-        && let Ok(conn) = db_state.get_conn()
-    {
-        let is_blocked = conn
-            .query_row(
-                "SELECT is_blocked FROM contacts WHERE hash = ?1",
-                params![sender],
-                |row: &rusqlite::Row| row.get::<_, i32>(0),
-            )
-            .unwrap_or(0)
-            != 0;
-
-        if is_blocked {
-            return Ok(());
-        }
-    }
+    // Fragments of a sealed media transfer belong to the sender its announcement proved
+    let override_sender = override_sender.or_else(|| match frame_type {
+        FRAME_MEDIA => reassembler::sealed_media_sender(
+            &net_state,
+            &routed_by,
+            FrameCodec::transfer_id(&payload)?,
+        ),
+        _ => None,
+    });
+    let sender = override_sender.unwrap_or(routed_by).to_lowercase();
 
-    let body_data = &trimmed[64..];
-    if body_data.is_empty() {
+    if is_blocked(&db_state, &sender) {
         return Ok(());
     }
 
-    let frame_type = body_data[0];
-    let payload_data = &body_data[1..];
-
-    if frame_type == 0x01 || frame_type == 0x02 || frame_type == 0x04 {
-        if payload_data.len() < 16 {
-            return Err("Invalid binary fragment header (too short)".into());
-        }
-
-        let (tid_bytes, rest) = payload_data.split_at(4);
-        let (idx_bytes, rest) = rest.split_at(4);
-        let (total_bytes, rest) = rest.split_at(4);
-        let (len_bytes, raw_chunk_data) = rest.split_at(4);
-
-        let transfer_id = u32::from_be_bytes(tid_bytes.try_into().unwrap());
-        let index = u32::from_be_bytes(idx_bytes.try_into().unwrap());
-        let total = u32::from_be_bytes(total_bytes.try_into().unwrap());
-        let chunk_len = u32::from_be_bytes(len_bytes.try_into().unwrap()) as usize;
+    if frame_type == FRAME_SIGNAL || frame_type == FRAME_MEDIA || frame_type == FRAME_VOLATILE {
+        let frame = FrameCodec::decode(&payload)?;
+        let transfer_id = frame.transfer_id;
 
-        if total > 250_000 {
-            return Err("Payload exceeds limit".into());
-        }
-
-        if raw_chunk_data.len() < chunk_len {
-            return Err("Fragment data too short".into());
+        let admission = {
+            let config = current_config(&net_state);
+            let mut governor = net_state
+                .inbound_governor
+                .lock()
+                .map_err(|_| "Network state poisoned")?;
+            governor.admit_fragment(&sender, std::time::Instant::now(), &config)
+        };
+        match admission {
+            Admission::Accept => {}
+            Admission::Muted => return Ok(()),
+            Admission::Reject(limit) => {
+                governor::report_violation(&app, &sender, limit);
+                return Ok(());
+            }
         }
-        let chunk_data = &raw_chunk_data[..chunk_len];
 
         let (is_complete, complete_data) = reassembler::internal_process_fragments(
             app.clone(),
@@ -94,211 +105,80 @@ pub async fn process_incoming_binary(
             reassembler::FragmentHeader {
                 frame_type,
                 transfer_id,
-                index,
-                total,
+                index: frame.index,
+                total: frame.total,
             },
-            chunk_data,
+            &frame.payload,
         )
         .await?;
 
         if is_complete {
-            if frame_type == 0x01 || frame_type == 0x04 {
+            if frame_type == FRAME_SIGNAL || frame_type == FRAME_VOLATILE {
                 let complete_data = complete_data.ok_or("Failed to load reassembled data")?;
                 let envelope: serde_json::Value = serde_json::from_slice(&complete_data)
                     .map_err(|e| format!("Failed to parse message envelope: {}", e))?;
 
-                let msg_type = envelope["type"].as_u64().unwrap_or(1) as u8;
-                let body_b64 = envelope["body"].as_str().ok_or("Missing envelope body")?;
+                // A sealed envelope names its sender inside; the routing header only
+                // keyed the reassembly
+                let routed_by = sender.clone();
+                let (sender, envelope) = if sealed::is_sealed(&envelope) {
+                    let Some(layer) =
+                        sealed::sealed_for_device(&envelope, devices::local_device_id(&app))
+                    else {
+                        return Ok(());
+                    };
+                    let unsealed = sealed::open_sealed(&app, layer).await?;
+                    if is_blocked(&db_state, &unsealed.sender) {
+                        return Ok(());
+                    }
+                    (unsealed.sender, unsealed.envelope)
+                } else {
+                    (sender, envelope)
+                };
+
+                // Every device of an account receives the envelope; each takes its own
+                let Some(ciphertext) =
+                    devices::envelope_for_device(&envelope, devices::local_device_id(&app))
+                else {
+                    return Ok(());
+                };
+                let sender_device = envelope["device"]
+                    .as_u64()
+                    .map_or(devices::PRIMARY_DEVICE, |d| d as u32);
+
+                let msg_type = ciphertext["type"].as_u64().unwrap_or(1) as u8;
+                let body_b64 = ciphertext["body"].as_str().ok_or("Missing envelope body")?;
                 let body_bytes = base64::engine::general_purpose::STANDARD
                     .decode(body_b64)
                     .map_err(|e| e.to_string())?;
+                devices::admit_sender_device(
+                    &app,
+                    &sender,
+                    sender_device,
+                    &envelope,
+                    msg_type,
+                    &body_bytes,
+                )?;
 
-                match decrypt::internal_signal_decrypt(app.clone(), &sender, msg_type, &body_bytes)
-                    .await
-                {
-                    Ok(decrypted_str) => {
-                        let decrypted_json: serde_json::Value =
-                            serde_json::from_str(&decrypted_str).map_err(|e| e.to_string())?;
-
-                        // Check if chat is active for group messages (except invites)
-                        if let Some(p_type) = decrypted_json["type"].as_str()
-                            && p_type != "group_invite"
-                            && let Some(gid) = decrypted_json["groupId"].as_str()
-                            && let Ok(conn) = db_state.get_conn()
-                        {
-                            let is_active: i32 = conn
-                                .query_row(
-                                    "SELECT is_active FROM chats WHERE address = ?1",
-                                    params![gid],
-                                    |r| r.get(0),
-                                )
-                                .unwrap_or(1);
-                            if is_active == 0 {
-                                return Ok(());
-                            }
-                        }
-
-                        let p_type = decrypted_json["type"]
-                            .as_str()
-                            .ok_or("Missing message type")?;
-                        match p_type {
-                            "media_resend_request" => {
-                                let transfer_id = decrypted_json["transfer_id"]
-                                    .as_u64()
-                                    .ok_or("Missing transfer_id")?
-                                    as u32;
-                                let indices: Vec<u32> = decrypted_json["indices"]
-                                    .as_array()
-                                    .ok_or("Missing indices")?
-                                    .iter()
-                                    .filter_map(|v| v.as_u64().map(|i| i as u32))
-                                    .collect();
-                                let info = {
-                                    let active =
-                                        net_state.active_outgoing_transfers.lock().unwrap();
-                                    active.get(&transfer_id).cloned()
-                                };
-                                if let Some(info) = info {
-                                    let app_clone = app.clone();
-                                    let recipient = sender.clone();
-                                    tokio::spawn(async move {
-                                        let net_state = app_clone.state::<NetworkState>();
-                                        if let Ok(mut file) = std::fs::File::open(&info.file_path) {
-                                            let file_size =
-                                                file.metadata().map(|m| m.len()).unwrap_or(0);
-                                            let total_fragments =
-                                                (file_size as f64 / 1279.0).ceil() as u32;
-                                            let mut routing_hash = [0u8; 64];
-                                            let r_bytes = recipient.as_bytes();
-                                            let r_len = std::cmp::min(r_bytes.len(), 64);
-                                            routing_hash[..r_len]
-                                                .copy_from_slice(&r_bytes[..r_len]);
-
-                                            for idx in indices {
-                                                let mut buffer = vec![0u8; 1279];
-                                                let offset = (idx as u64) * 1279;
-                                                use std::io::{Read, Seek, SeekFrom};
-                                                if file.seek(SeekFrom::Start(offset)).is_ok() {
-                                                    let n = file.read(&mut buffer).unwrap_or(0);
-                                                    if n > 0 {
-                                                        let chunk = &buffer[..n];
-
-                                                        use chacha20poly1305::{
-                                                            Key as ChaKey, XChaCha20Poly1305,
-                                                            aead::{
-                                                                Aead, AeadCore, KeyInit, OsRng,
-                                                            },
-                                                        };
-                                                        let transit_cipher = XChaCha20Poly1305::new(
-                                                            ChaKey::from_slice(&info.transit_key),
-                                                        );
-                                                        let t_nonce =
-                                                            XChaCha20Poly1305::generate_nonce(
-                                                                &mut OsRng,
-                                                            );
-                                                        let t_cipher = transit_cipher
-                                                            .encrypt(&t_nonce, chunk)
-                                                            .unwrap();
-
-                                                        let mut packet =
-                                                            Vec::with_capacity(t_cipher.len() + 24);
-                                                        packet.extend_from_slice(&t_nonce);
-                                                        packet.extend_from_slice(&t_cipher);
+                let decrypted_str = decrypt::internal_signal_decrypt(
+                    app.clone(),
+                    &devices::device_address(&sender, sender_device),
+                    msg_type,
+                    &body_bytes,
+                )
+                .await?;
 
-                                                        let _ = crate::commands::network::transit::internal_dispatch_fragment(
-                                                            app_clone.clone(), &net_state, routing_hash, None, transfer_id, idx, total_fragments, &packet, true, true, true
-                                                        ).await;
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    });
-                                }
-                                return Ok(());
-                            }
-                            "group_invite" => {
-                                handlers::groups::handle_group_invite(
-                                    app.clone(),
-                                    sender.clone(),
-                                    decrypted_json,
-                                    &own_hash,
-                                )
-                                .await?
-                            }
-                            "group_leave" => {
-                                handlers::groups::handle_group_leave(
-                                    app.clone(),
-                                    sender.clone(),
-                                    decrypted_json,
-                                )
-                                .await?
-                            }
-                            "group_update" => {
-                                handlers::groups::handle_group_update(
-                                    app.clone(),
-                                    sender.clone(),
-                                    decrypted_json,
-                                    &own_hash,
-                                )
-                                .await?
-                            }
-                            "text_msg" => {
-                                handlers::text::handle_text_msg(
-                                    app.clone(),
-                                    sender.clone(),
-                                    decrypted_json,
-                                )
-                                .await?
-                            }
-                            "receipt" => {
-                                handlers::status::handle_receipt(
-                                    app.clone(),
-                                    sender.clone(),
-                                    decrypted_json,
-                                )
-                                .await?
-                            }
-                            "typing" => {
-                                handlers::status::handle_typing(
-                                    app.clone(),
-                                    sender.clone(),
-                                    decrypted_json,
-                                )
-                                .await?
-                            }
-                            "profile_update" => {
-                                handlers::status::handle_profile_update(
-                                    app.clone(),
-                                    sender.clone(),
-                                    decrypted_json,
-                                )
-                                .await?
-                            }
-                            "reaction" => {
-                                handlers::reaction::handle_reaction(
-                                    app.clone(),
-                                    sender.clone(),
-                                    decrypted_json,
-                                )
-                                .await?
-                            }
-                            "file" | "media" => {
-                                handlers::media::handle_media_msg(
-                                    app.clone(),
-                                    sender.clone(),
-                                    decrypted_json,
-                                    &net_state,
-                                )
-                                .await?
-                            }
-                            _ => {
-                                app.emit("msg://decrypted", json!({ "sender": sender, "type": p_type, "payload": decrypted_json })).map_err(|e: tauri::Error| e.to_string())?;
-                            }
-                        }
+                // A message from a key awaiting verification under the blocking policy
+                // has already used up its ratchet step, so it is held until then
+                let address = format!("{}:{}", sender, sender_device);
+                {
+                    let conn = db_state.get_conn()?;
+                    if blocked_until_reverified(&conn, &address)? {
+                        return quarantine::hold(&conn, &address, &routed_by, &decrypted_str);
                     }
-                    Err(e) => return Err(e),
                 }
-            } else if frame_type == 0x02 {
+                dispatch_decrypted(&app, &sender, &routed_by, &decrypted_str, &own_hash).await?;
+            } else if frame_type == FRAME_MEDIA {
                 handlers::media::handle_media_completion(
                     app.clone(),
                     sender.clone(),
@@ -313,8 +193,224 @@ pub async fn process_incoming_binary(
     Ok(())
 }
 
-pub async fn internal_send_volatile(
-    app: AppHandle,
+/// Handles a decrypted message from `sender`. `routed_by` is the routing header its
+/// envelope arrived under, which the fragments of a media transfer it announces will
+/// carry as well.
+pub(crate) async fn dispatch_decrypted<R: Runtime>(
+    app: &AppHandle<R>,
+    sender: &str,
+    routed_by: &str,
+    decrypted_str: &str,
+    own_hash: &str,
+) -> Result<(), String> {
+    let db_state = app.state::<DbState>();
+    let net_state = app.state::<NetworkState>();
+    let app = app.clone();
+    let sender = sender.to_string();
+    let own_hash = own_hash.to_string();
+    let decrypted_json: serde_json::Value =
+        serde_json::from_str(decrypted_str).map_err(|e| e.to_string())?;
+
+    // Check if chat is active for group messages (except invites)
+    if let Some(p_type) = decrypted_json["type"].as_str()
+        && p_type != "group_invite"
+        && let Some(gid) = decrypted_json["groupId"].as_str()
+        && let Ok(conn) = db_state.get_conn()
+    {
+        let is_active: i32 = conn
+            .query_row(
+                "SELECT is_active FROM chats WHERE address = ?1",
+                params![gid],
+                |r| r.get(0),
+            )
+            .unwrap_or(1);
+        if is_active == 0 {
+            return Ok(());
+        }
+    }
+
+    let p_type = decrypted_json["type"]
+        .as_str()
+        .ok_or("Missing message type")?;
+    match p_type {
+        "media_resend_request" => {
+            let transfer_id = decrypted_json["transfer_id"]
+                .as_u64()
+                .ok_or("Missing transfer_id")? as u32;
+            let indices: Vec<u32> = decrypted_json["indices"]
+                .as_array()
+                .ok_or("Missing indices")?
+                .iter()
+                .filter_map(|v| v.as_u64().map(|i| i as u32))
+                .collect();
+            // Only a recipient the upload was addressed to may have fragments resent
+            let info = net_state
+                .active_outgoing_transfers
+                .lock()
+                .map_err(|_| "Network state poisoned")?
+                .get(&transfer_id)
+                .filter(|t| {
+                    t.recipients
+                        .iter()
+                        .any(|r| devices::split_address(r).0.eq_ignore_ascii_case(&sender))
+                })
+                .cloned();
+            if let Some(info) = info {
+                let app_clone = app.clone();
+                let recipient = sender.clone();
+                tokio::spawn(async move {
+                    let net_state = app_clone.state::<NetworkState>();
+                    if let Ok(mut file) = std::fs::File::open(&info.file_path) {
+                        let total_fragments = info.total_fragments;
+                        let routing_hash = FrameCodec::routing_header(&recipient);
+
+                        for idx in indices {
+                            if idx >= total_fragments {
+                                continue;
+                            }
+                            let mut buffer = vec![0u8; MEDIA_BLOCK_SIZE];
+                            let offset = (idx as u64) * MEDIA_BLOCK_SIZE as u64;
+                            use std::io::{Read, Seek, SeekFrom};
+                            if file.seek(SeekFrom::Start(offset)).is_ok() {
+                                let n = file.read(&mut buffer).unwrap_or(0);
+                                if n > 0 {
+                                    let chunk = &buffer[..n];
+
+                                    use chacha20poly1305::{
+                                        Key as ChaKey, XChaCha20Poly1305,
+                                        aead::{Aead, AeadCore, KeyInit, OsRng},
+                                    };
+                                    let transit_cipher = XChaCha20Poly1305::new(
+                                        ChaKey::from_slice(&info.transit_key),
+                                    );
+                                    let t_nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+                                    let Ok(t_cipher) = transit_cipher.encrypt(&t_nonce, chunk)
+                                    else {
+                                        continue;
+                                    };
+
+                                    let mut packet = Vec::with_capacity(t_cipher.len() + 24);
+                                    packet.extend_from_slice(&t_nonce);
+                                    packet.extend_from_slice(&t_cipher);
+
+                                    let _ = crate::commands::network::transit::internal_dispatch_fragment(
+                                        app_clone.clone(), &net_state, routing_hash, None, transfer_id, idx, total_fragments, &packet, true, true, true
+                                    ).await;
+                                } else {
+                                    // Past the end of the file: a padding block
+                                    let packet = sealed_filler(&info.transit_key);
+                                    let _ = crate::commands::network::transit::internal_dispatch_fragment(
+                                        app_clone.clone(), &net_state, routing_hash, None, transfer_id, idx, total_fragments, &packet, true, true, true
+                                    ).await;
+                                }
+                            }
+                        }
+                    }
+                });
+            }
+            return Ok(());
+        }
+        "signal_resend_request" => {
+            crate::commands::messaging::delivery::handle_signal_resend_request(
+                app.clone(),
+                sender.clone(),
+                decrypted_json,
+            )
+            .await?
+        }
+        "media_cancel" => {
+            crate::commands::messaging::transfers::handle_media_cancel(
+                app.clone(),
+                sender.clone(),
+                decrypted_json,
+            )
+            .await?
+        }
+        "media_pause" | "media_resume" => {
+            let paused = p_type == "media_pause";
+            crate::commands::messaging::transfers::handle_media_pause(
+                app.clone(),
+                sender.clone(),
+                decrypted_json,
+                paused,
+            )
+            .await?
+        }
+        "group_invite" => {
+            handlers::groups::handle_group_invite(
+                app.clone(),
+                sender.clone(),
+                decrypted_json,
+                &own_hash,
+            )
+            .await?
+        }
+        "group_leave" => {
+            handlers::groups::handle_group_leave(app.clone(), sender.clone(), decrypted_json)
+                .await?
+        }
+        "group_update" => {
+            handlers::groups::handle_group_update(
+                app.clone(),
+                sender.clone(),
+                decrypted_json,
+                &own_hash,
+            )
+            .await?
+        }
+        "sent_sync" => {
+            // Only this account's own devices report what they sent
+            if sender == own_hash {
+                handlers::text::handle_sent_sync(app.clone(), decrypted_json, &own_hash).await?
+            }
+        }
+        "link_renewal" => {
+            // Renewals come from this account's primary; the link's signature proves it
+            if sender == own_hash {
+                devices::handle_link_renewal(app.clone(), decrypted_json).await?
+            }
+        }
+        "text_msg" => {
+            handlers::text::handle_text_msg(app.clone(), sender.clone(), decrypted_json).await?
+        }
+        "receipt" => {
+            handlers::status::handle_receipt(app.clone(), sender.clone(), decrypted_json).await?
+        }
+        "typing" => {
+            handlers::status::handle_typing(app.clone(), sender.clone(), decrypted_json).await?
+        }
+        "profile_update" => {
+            handlers::status::handle_profile_update(app.clone(), sender.clone(), decrypted_json)
+                .await?
+        }
+        "reaction" => {
+            handlers::reaction::handle_reaction(app.clone(), sender.clone(), decrypted_json).await?
+        }
+        "file" | "media" => {
+            if let Some(tid) = decrypted_json["transfer_id"].as_u64() {
+                reassembler::route_sealed_media(&app, &net_state, routed_by, &sender, tid as u32)?;
+            }
+            handlers::media::handle_media_msg(
+                app.clone(),
+                sender.clone(),
+                decrypted_json,
+                &net_state,
+            )
+            .await?
+        }
+        _ => {
+            app.emit(
+                "msg://decrypted",
+                json!({ "sender": sender, "type": p_type, "payload": decrypted_json }),
+            )
+            .map_err(|e: tauri::Error| e.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn internal_send_volatile<R: Runtime>(
+    app: AppHandle<R>,
     net_state: &NetworkState,
     to: &str,
     payload: serde_json::Value,
@@ -338,6 +434,9 @@ pub async fn internal_send_volatile(
 }
 
 #[tauri::command]
-pub async fn vault_retry_bridge(app: tauri::AppHandle, msg_id: String) -> Result<(), String> {
+pub async fn vault_retry_bridge<R: Runtime>(
+    app: tauri::AppHandle<R>,
+    msg_id: String,
+) -> Result<(), String> {
     handlers::media::handle_vault_retry_bridge(app, msg_id)
 }
@@ -0,0 +1,87 @@
+//! Identity Quarantine
+//!
+//! Under the block-until-reverified policy, messages from a key that awaits
+//! verification are still decrypted: refusing them would not bring them back, since
+//! the ratchet has moved on and the relay delivers each message once. Their plaintext
+//! is held in the vault instead and handled as if it had just arrived once the peer
+//! is verified again.
+
+use rusqlite::{Connection, params};
+use tauri::{AppHandle, Manager, Runtime};
+
+use super::dispatch_decrypted;
+use crate::app_state::{DbState, NetworkState};
+use crate::commands::identity::prekeys::now_ms;
+
+/// A decrypted message held back from a peer.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct QuarantinedMessage {
+    /// Routing header the message arrived under.
+    pub routed_by: String,
+    pub plaintext: String,
+}
+
+/// Holds a decrypted message from `address`, a `hash:device` identity address, until
+/// its key is verified.
+pub(crate) fn hold(
+    conn: &Connection,
+    address: &str,
+    routed_by: &str,
+    plaintext: &str,
+) -> Result<(), String> {
+    conn.execute(
+        "INSERT INTO quarantined_messages (address, routed_by, plaintext, received_at)
+         VALUES (?1, ?2, ?3, ?4)",
+        params![address, routed_by, plaintext, now_ms()],
+    )
+    .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Removes and returns the messages held from every device of `hash`, oldest first.
+pub(crate) fn take_held(conn: &Connection, hash: &str) -> Result<Vec<QuarantinedMessage>, String> {
+    let held = {
+        let mut stmt = conn
+            .prepare(
+                "SELECT routed_by, plaintext FROM quarantined_messages
+                 WHERE address LIKE ?1 || ':%' ORDER BY id",
+            )
+            .map_err(|e| e.to_string())?;
+        stmt.query_map(params![hash], |row| {
+            Ok(QuarantinedMessage {
+                routed_by: row.get(0)?,
+                plaintext: row.get(1)?,
+            })
+        })
+        .map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?
+    };
+    conn.execute(
+        "DELETE FROM quarantined_messages WHERE address LIKE ?1 || ':%'",
+        params![hash],
+    )
+    .map_err(|e| e.to_string())?;
+    Ok(held)
+}
+
+/// Handles the messages held from `hash` now that the peer has been verified. One
+/// that fails is dropped like any other malformed message.
+pub(crate) async fn release_held<R: Runtime>(app: &AppHandle<R>, hash: &str) -> Result<(), String> {
+    let held = take_held(&app.state::<DbState>().get_conn()?, hash)?;
+    if held.is_empty() {
+        return Ok(());
+    }
+    let own_hash = app
+        .state::<NetworkState>()
+        .identity_hash
+        .lock()
+        .map_err(|_| "Network state poisoned")?
+        .clone()
+        .ok_or("Identity not established")?;
+    for message in held {
+        let _ =
+            dispatch_decrypted(app, hash, &message.routed_by, &message.plaintext, &own_hash).await;
+    }
+    Ok(())
+}
@@ -1,7 +1,205 @@
+use super::governor::{admit_transfer, report_violation};
 use crate::app_state::{DbState, MediaTransferState, NetworkState};
+use crate::commands::messaging::transfers::drops_fragments;
+use crate::commands::network::codec::{CHUNK_CAPACITY, FRAME_MEDIA, FRAME_SIGNAL, FRAME_VOLATILE};
+use crate::commands::network::config::current_config;
 use serde_json::json;
 use std::io::{Seek, SeekFrom, Write};
-use tauri::{AppHandle, Emitter, Manager};
+use tauri::{AppHandle, Emitter, Manager, Runtime};
+
+/// Key of an in-flight reassembly in `NetworkState.media_assembler`.
+pub(crate) fn assembler_key(sender: &str, transfer_id: u32, frame_type: u8) -> String {
+    format!("{}:{}:{}", sender, transfer_id, frame_type)
+}
+
+/// Splits an assembler key back into sender, transfer id and frame type.
+pub(crate) fn parse_assembler_key(key: &str) -> Option<(&str, u32, u8)> {
+    let mut parts = key.rsplitn(3, ':');
+    let frame_type = parts.next()?.parse().ok()?;
+    let transfer_id = parts.next()?.parse().ok()?;
+    let sender = parts.next()?;
+    Some((sender, transfer_id, frame_type))
+}
+
+/// Name of the media-directory file backing an in-flight reassembly.
+pub(crate) fn temp_filename(sender: &str, transfer_id: u32, frame_type: u8) -> String {
+    let type_suffix = if frame_type == FRAME_MEDIA {
+        "media"
+    } else {
+        "sig"
+    };
+    format!("transfer_{}_{}_{}.bin", sender, transfer_id, type_suffix)
+}
+
+pub(crate) fn is_temp_filename(name: &str) -> bool {
+    name.starts_with("transfer_") && name.ends_with(".bin")
+}
+
+/// The sender a sealed announcement proved for fragments of `transfer_id` arriving
+/// under the routing header `routed_by`.
+pub(crate) fn sealed_media_sender(
+    net_state: &NetworkState,
+    routed_by: &str,
+    transfer_id: u32,
+) -> Option<String> {
+    let routes = net_state.sealed_media_routes.lock().ok()?;
+    routes
+        .get(&format!("{}:{}", routed_by, transfer_id))
+        .cloned()
+}
+
+/// Attributes the fragments of a media transfer announced in a sealed envelope to
+/// `sender` instead of the routing header they arrive under. Fragments that arrived
+/// before the announcement are moved over, temp file included.
+pub(crate) fn route_sealed_media<R: Runtime>(
+    app: &AppHandle<R>,
+    net_state: &NetworkState,
+    routed_by: &str,
+    sender: &str,
+    transfer_id: u32,
+) -> Result<(), String> {
+    if routed_by == sender {
+        return Ok(());
+    }
+    net_state
+        .sealed_media_routes
+        .lock()
+        .map_err(|_| "Network state poisoned")?
+        .insert(format!("{}:{}", routed_by, transfer_id), sender.to_string());
+
+    let media_dir = crate::commands::vault::get_media_dir(app, &app.state::<DbState>())?;
+    let from = media_dir.join(temp_filename(routed_by, transfer_id, FRAME_MEDIA));
+    let to = media_dir.join(temp_filename(sender, transfer_id, FRAME_MEDIA));
+    let mut assemblers = net_state
+        .media_assembler
+        .lock()
+        .map_err(|_| "Network state poisoned")?;
+    // The handle is closed before the move and reopened without truncating after it
+    let moved = assemblers
+        .remove(&assembler_key(routed_by, transfer_id, FRAME_MEDIA))
+        .map(|mut state| {
+            let had_file = state.file_handle.take().is_some();
+            (state, had_file)
+        });
+    if from.exists() {
+        std::fs::rename(&from, &to)
+            .map_err(|e| format!("Failed to move reassembly file: {}", e))?;
+    }
+    if let Some((mut state, had_file)) = moved {
+        if had_file {
+            state.file_handle = Some(
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .open(&to)
+                    .map_err(|e| format!("Failed to reopen reassembly file: {}", e))?,
+            );
+        }
+        assemblers.insert(assembler_key(sender, transfer_id, FRAME_MEDIA), state);
+    }
+    Ok(())
+}
+
+/// Where one block of an incoming media transfer stands, for readers of its temp file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum BlockState {
+    /// Written to the temp file.
+    Received,
+    /// Still expected from the sender.
+    Pending,
+    /// The transfer was bridged into the vault or abandoned.
+    Gone,
+}
+
+/// `received` is the block's slot in a live reassembly, if there is one. Without one,
+/// a remaining temp file means every block arrived and the bridge has yet to run;
+/// buffered metadata alone means no fragment has arrived yet.
+pub(crate) fn block_state(received: Option<bool>, linked: bool, temp_exists: bool) -> BlockState {
+    match received {
+        Some(true) => BlockState::Received,
+        Some(false) => BlockState::Pending,
+        None if temp_exists => BlockState::Received,
+        None if linked => BlockState::Pending,
+        None => BlockState::Gone,
+    }
+}
+
+pub(crate) fn media_block_state<R: Runtime>(
+    app: &AppHandle<R>,
+    sender: &str,
+    transfer_id: u32,
+    index: u32,
+) -> BlockState {
+    let net_state = app.state::<NetworkState>();
+    let received = net_state
+        .media_assembler
+        .lock()
+        .ok()
+        .and_then(|assemblers| {
+            assemblers
+                .get(&assembler_key(sender, transfer_id, FRAME_MEDIA))
+                .map(|a| {
+                    a.received_chunks
+                        .get(index as usize)
+                        .copied()
+                        .unwrap_or(false)
+                })
+        });
+    let linked = net_state
+        .pending_media_links
+        .lock()
+        .map(|links| links.contains_key(&format!("{}:{}", sender, transfer_id)))
+        .unwrap_or(false);
+    let temp_exists = crate::commands::vault::get_media_dir(app, &app.state::<DbState>())
+        .map(|dir| {
+            dir.join(temp_filename(sender, transfer_id, FRAME_MEDIA))
+                .exists()
+        })
+        .unwrap_or(false);
+    block_state(received, linked, temp_exists)
+}
+
+/// Starts reassembling a finished download again with only `missing` outstanding, so
+/// resent fragments overwrite their slots in the existing temp file.
+pub(crate) fn reopen_reassembly<R: Runtime>(
+    app: &AppHandle<R>,
+    net_state: &NetworkState,
+    sender: &str,
+    transfer_id: u32,
+    total: u32,
+    missing: &[u32],
+) -> Result<(), String> {
+    let db_state = app.state::<DbState>();
+    let media_dir = crate::commands::vault::get_media_dir(app, &db_state)?;
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(media_dir.join(temp_filename(sender, transfer_id, FRAME_MEDIA)))
+        .map_err(|e| format!("Failed to reopen reassembly file: {}", e))?;
+
+    let mut received_chunks = vec![true; total as usize];
+    for &idx in missing {
+        if let Some(slot) = received_chunks.get_mut(idx as usize) {
+            *slot = false;
+        }
+    }
+    let received_count = received_chunks.iter().filter(|r| **r).count() as u32;
+    net_state
+        .media_assembler
+        .lock()
+        .map_err(|_| "Network state poisoned")?
+        .insert(
+            assembler_key(sender, transfer_id, FRAME_MEDIA),
+            MediaTransferState {
+                total,
+                received_chunks,
+                last_activity: std::time::Instant::now(),
+                file_handle: Some(file),
+                received_count,
+                nacks_sent: 0,
+                last_nack: None,
+            },
+        );
+    Ok(())
+}
 
 pub struct FragmentHeader {
     pub frame_type: u8,
@@ -10,19 +208,31 @@ pub struct FragmentHeader {
     pub total: u32,
 }
 
-pub async fn internal_process_fragments(
-    app: AppHandle,
+pub async fn internal_process_fragments<R: Runtime>(
+    app: AppHandle<R>,
     net_state: &NetworkState,
     sender: &str,
     header: FragmentHeader,
     chunk_data: &[u8],
 ) -> Result<(bool, Option<Vec<u8>>), String> {
+    if header.frame_type == FRAME_MEDIA && drops_fragments(net_state, header.transfer_id) {
+        return Ok((false, None));
+    }
     let (is_complete, complete_data) = {
         let mut assemblers = net_state
             .media_assembler
             .lock()
             .map_err(|_| "Network state poisoned")?;
-        let transfer_key = format!("{}:{}:{}", sender, header.transfer_id, header.frame_type);
+        let transfer_key = assembler_key(sender, header.transfer_id, header.frame_type);
+        if !assemblers.contains_key(&transfer_key) {
+            let config = current_config(net_state);
+            let in_progress = assemblers.iter().map(|(k, a)| (k.as_str(), a.total));
+            if let Err(limit) = admit_transfer(in_progress, sender, header.total, &config) {
+                drop(assemblers);
+                report_violation(&app, sender, limit);
+                return Ok((false, None));
+            }
+        }
         let assembler =
             assemblers
                 .entry(transfer_key.clone())
@@ -32,6 +242,8 @@ pub async fn internal_process_fragments(
                     last_activity: std::time::Instant::now(),
                     file_handle: None,
                     received_count: 0,
+                    nacks_sent: 0,
+                    last_nack: None,
                 });
 
         if (header.index as usize) < assembler.received_chunks.len()
@@ -40,16 +252,8 @@ pub async fn internal_process_fragments(
             if assembler.file_handle.is_none() {
                 let db_state = app.state::<DbState>();
                 let media_dir = crate::commands::vault::get_media_dir(&app, &db_state)?;
-                let type_suffix = if header.frame_type == 0x02 {
-                    "media"
-                } else {
-                    "sig"
-                };
-                let temp_filename = format!(
-                    "transfer_{}_{}_{}.bin",
-                    sender, header.transfer_id, type_suffix
-                );
-                let file_path = media_dir.join(&temp_filename);
+                let file_path =
+                    media_dir.join(temp_filename(sender, header.transfer_id, header.frame_type));
                 let f = std::fs::OpenOptions::new()
                     .create(true)
                     .truncate(true)
@@ -64,7 +268,7 @@ pub async fn internal_process_fragments(
                 let max_retries = 3;
                 loop {
                     let res = f
-                        .seek(SeekFrom::Start(header.index as u64 * 1319))
+                        .seek(SeekFrom::Start(header.index as u64 * CHUNK_CAPACITY as u64))
                         .and_then(|_| f.write_all(chunk_data));
 
                     match res {
@@ -109,15 +313,14 @@ pub async fn internal_process_fragments(
         }
 
         if complete {
-            if header.frame_type == 0x01 || header.frame_type == 0x04 {
+            if header.frame_type == FRAME_SIGNAL || header.frame_type == FRAME_VOLATILE {
                 let db_state = app.state::<DbState>();
-                let type_suffix = "sig";
-                let temp_filename = format!(
-                    "transfer_{}_{}_{}.bin",
-                    sender, header.transfer_id, type_suffix
-                );
                 if let Ok(media_dir) = crate::commands::vault::get_media_dir(&app, &db_state) {
-                    let file_path = media_dir.join(&temp_filename);
+                    let file_path = media_dir.join(temp_filename(
+                        sender,
+                        header.transfer_id,
+                        header.frame_type,
+                    ));
                     if let Ok(data) = std::fs::read(&file_path) {
                         let _ = std::fs::remove_file(file_path);
                         assemblers.remove(&transfer_key);
@@ -1,9 +1,12 @@
 pub mod chat;
+pub mod delivery;
 pub mod groups;
 pub mod inbox;
 pub mod outbox;
+pub mod transfers;
 
 pub use chat::*;
 pub use groups::*;
 pub use inbox::*;
 pub use outbox::*;
+pub use transfers::*;
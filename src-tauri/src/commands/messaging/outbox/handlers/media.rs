@@ -1,17 +1,25 @@
 use super::super::OutgoingMedia;
+use super::super::uploads::{
+    CHECKPOINT_INTERVAL, checkpoint_upload, complete_upload, record_upload,
+};
 use crate::app_state::{DbState, NetworkState, OutgoingTransferInfo};
+use crate::commands::messaging::transfers::{is_paused, wait_while_paused};
+use crate::commands::network::codec::{FrameCodec, MEDIA_BLOCK_SIZE};
+use crate::commands::network::config::current_config;
 use crate::commands::{
     DbMessage, get_media_dir, internal_db_save_message, internal_dispatch_fragment,
     internal_send_to_network, internal_signal_encrypt,
 };
+use crate::noise::TrafficNormalizer;
 use base64::Engine;
 use chacha20poly1305::{
     Key as ChaKey, XChaCha20Poly1305,
     aead::{Aead, AeadCore, KeyInit, OsRng},
 };
 use serde_json::json;
+use sha2::{Digest, Sha256};
 use std::io::{Read, Write};
-use tauri::{AppHandle, Emitter, Manager};
+use tauri::{AppHandle, Emitter, Manager, Runtime};
 
 struct MediaTransfer {
     msg_id: String,
@@ -22,8 +30,8 @@ struct MediaTransfer {
     is_group: bool,
 }
 
-pub fn process_outgoing_media(
-    app: AppHandle,
+pub fn process_outgoing_media<R: Runtime>(
+    app: AppHandle<R>,
     payload: OutgoingMedia,
 ) -> Result<serde_json::Value, String> {
     let msg_id = uuid::Uuid::new_v4().to_string();
@@ -108,8 +116,8 @@ pub fn process_outgoing_media(
     Ok(serde_json::to_value(&db_msg).unwrap())
 }
 
-pub async fn process_outgoing_group_media(
-    app: AppHandle,
+pub async fn process_outgoing_group_media<R: Runtime>(
+    app: AppHandle<R>,
     payload: OutgoingMedia,
 ) -> Result<serde_json::Value, String> {
     let msg_id = uuid::Uuid::new_v4().to_string();
@@ -192,6 +200,36 @@ pub async fn process_outgoing_group_media(
     Ok(serde_json::to_value(&db_msg).unwrap())
 }
 
+/// A sealed block of zeros sent as padding. Receivers drop every block past the
+/// announced file size, so the content is never opened.
+pub(crate) fn sealed_filler(transit_key: &[u8; 32]) -> Vec<u8> {
+    let cipher = XChaCha20Poly1305::new(ChaKey::from_slice(transit_key));
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let sealed = cipher
+        .encrypt(&nonce, [0u8; MEDIA_BLOCK_SIZE].as_slice())
+        .unwrap_or_default();
+    let mut packet = Vec::with_capacity(sealed.len() + 24);
+    packet.extend_from_slice(&nonce);
+    packet.extend_from_slice(&sealed);
+    packet
+}
+
+/// SHA-256 of a file's plaintext, announced so the receiver can check the file it
+/// reassembles.
+pub(crate) fn plaintext_digest(mut reader: impl Read) -> std::io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => hasher.update(&buf[..n]),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(hasher.finalize().into())
+}
+
 fn validate_media_payload(
     payload: &OutgoingMedia,
 ) -> Result<(Option<std::path::PathBuf>, u64), String> {
@@ -215,8 +253,23 @@ fn validate_media_payload(
     }
 }
 
-fn spawn_transfer_task(
-    app: AppHandle,
+/// An upload's fragments and announcement, shared by fresh and resumed uploads.
+pub(crate) struct TransferStream {
+    pub msg_id: String,
+    pub chat_address: String,
+    pub transfer_id: u32,
+    pub recipients: Vec<String>,
+    pub file_size: u64,
+    pub total_fragments: u32,
+    /// The file announcement, including the transit key in `bundle.key`.
+    pub announcement: serde_json::Value,
+    pub transit_key: [u8; 32],
+    /// Whether progress is checkpointed to `outgoing_transfers`.
+    pub resumable: bool,
+}
+
+fn spawn_transfer_task<R: Runtime>(
+    app: AppHandle<R>,
     payload: OutgoingMedia,
     recipients: Vec<String>,
     task: MediaTransfer,
@@ -231,13 +284,24 @@ fn spawn_transfer_task(
             let net_state = app.state::<NetworkState>();
 
             let net_key = XChaCha20Poly1305::generate_key(&mut OsRng);
+            let real_fragments = (task.file_size as usize).div_ceil(MEDIA_BLOCK_SIZE);
+            let total_fragments = if current_config(&net_state).media_padding {
+                TrafficNormalizer::padme_len(real_fragments)
+            } else {
+                real_fragments
+            } as u32;
             {
                 if let Ok(mut active) = net_state.active_outgoing_transfers.lock() {
                     active.insert(
                         task.transfer_id,
                         OutgoingTransferInfo {
+                            msg_id: task.msg_id.clone(),
+                            recipients: recipients.clone(),
                             file_path: task.canonical_path.clone().unwrap_or_default(),
                             transit_key: net_key.into(),
+                            total_fragments,
+                            started_at: std::time::Instant::now(),
+                            finished_at: None,
                         },
                     );
                 }
@@ -249,9 +313,14 @@ fn spawn_transfer_task(
 
             let media_dir = get_media_dir(&app, &db_state).unwrap();
             let vault_path = media_dir.join(&task.msg_id);
-            let mut vault_file = std::fs::File::create(&vault_path).unwrap();
+            let vault_file = std::fs::File::create(&vault_path).unwrap();
 
             let key_b64 = base64::engine::general_purpose::STANDARD.encode(net_key);
+            let digest = if let Some(ref p) = task.canonical_path {
+                std::fs::File::open(p).and_then(|f| plaintext_digest(std::io::BufReader::new(f)))
+            } else {
+                plaintext_digest(payload.file_data.as_deref().unwrap_or_default())
+            };
 
             let mut announcement = serde_json::json!({
                 "type": "file",
@@ -266,43 +335,53 @@ fn spawn_transfer_task(
                 "bundle": {
                     "key": key_b64,
                     "file_name": payload.file_name,
-                    "file_type": payload.file_type
+                    "file_type": payload.file_type,
+                    "sha256": digest.ok().map(hex::encode),
+                    "blocks": real_fragments
                 }
             });
             if task.is_group
-                && let Some(obj) = announcement.as_object_mut() {
-                    obj.insert("isGroup".to_string(), json!(true));
-                    obj.insert("groupId".to_string(), json!(payload.recipient));
-                    obj.insert("groupName".to_string(), json!(payload.group_name));
-                }
+                && let Some(obj) = announcement.as_object_mut()
+            {
+                obj.insert("isGroup".to_string(), json!(true));
+                obj.insert("groupId".to_string(), json!(payload.recipient));
+                obj.insert("groupName".to_string(), json!(payload.group_name));
+            }
+
+            let mut stream = TransferStream {
+                msg_id: task.msg_id.clone(),
+                chat_address: payload.recipient.clone(),
+                transfer_id: task.transfer_id,
+                recipients,
+                file_size: task.file_size,
+                total_fragments,
+                announcement,
+                transit_key: net_key.into(),
+                resumable: false,
+            };
+            // Only uploads read from disk can be picked up again after a restart
+            if let Some(ref p) = task.canonical_path {
+                stream.resumable = record_upload(&app, &stream, p).is_ok();
+            }
 
-            for recipient in &recipients {
-                if let Ok(encrypted) = internal_signal_encrypt(
-                    app.clone(),
-                    &net_state,
-                    recipient,
-                    announcement.to_string(),
-                )
-                .await
+            if let Some(thumb_b64) = &payload.thumbnail
+                && let Ok(thumb_bytes) = base64::engine::general_purpose::STANDARD.decode(thumb_b64)
+            {
+                let thumb_path = media_dir.join(format!("{}_thumb", task.msg_id));
+                let v_nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+                if let Ok(v_cipher) = vault_cipher.encrypt(&v_nonce, thumb_bytes.as_slice())
+                    && let Ok(mut f) = std::fs::File::create(&thumb_path)
                 {
-                    let routing_hash = recipient.split('.').next().unwrap_or(recipient).to_string();
-                    let _ = internal_send_to_network(
-                        app.clone(),
-                        &net_state,
-                        Some(routing_hash),
-                        Some(task.msg_id.clone()),
-                        None,
-                        Some(encrypted.to_string().into_bytes()),
-                        true,
-                        false,
-                        Some(task.transfer_id),
-                        true,
-                    )
-                    .await;
+                    let _ = f
+                        .write_all(&v_nonce)
+                        .and_then(|_| f.write_all(&v_cipher))
+                        .and_then(|_| f.sync_all());
                 }
             }
 
-            let mut reader: Box<dyn std::io::Read + Send> = if let Some(ref p) = task.canonical_path {
+            announce_transfer(&app, &stream).await;
+
+            let reader: Box<dyn std::io::Read + Send> = if let Some(ref p) = task.canonical_path {
                 Box::new(std::io::BufReader::new(std::fs::File::open(p).unwrap()))
             } else if let Some(ref d) = payload.file_data {
                 Box::new(std::io::Cursor::new(d.to_vec()))
@@ -310,143 +389,190 @@ fn spawn_transfer_task(
                 return;
             };
 
-            let mut fragment_index = 0;
-            let mut buffer = vec![0u8; 1279];
-            let total_fragments = (task.file_size as f64 / 1279.0).ceil() as u32;
-
-            loop {
-                let mut n = 0;
-                let mut read_retries = 0;
-                while n < 1279 {
-                    match reader.read(&mut buffer[n..]) {
-                        Ok(0) => break,
-                        Ok(read) => {
-                            n += read;
-                            read_retries = 0;
-                        }
-                        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
-                        Err(_e) if read_retries < 3 => {
-                            read_retries += 1;
-                            std::thread::sleep(std::time::Duration::from_millis(50));
-                        }
-                        Err(e) => {
-                            let _ = app.emit(
-                                "network-bin-error",
-                                json!({ "msg_id": task.msg_id.clone(), "error": format!("Disk read error: {}", e) }),
-                            );
-                            return;
-                        }
-                    }
-                }
-                if n == 0 {
-                    break;
-                }
-                let chunk = &buffer[..n];
+            stream_transfer(&app, &stream, reader, vault_file, &vault_path, 0).await;
+        });
+    });
+}
 
-                let v_nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
-                let v_cipher = vault_cipher.encrypt(&v_nonce, chunk).unwrap();
-                let _ = vault_file
-                    .write_all(&v_nonce)
-                    .and_then(|_| vault_file.write_all(&v_cipher));
-
-                let transit_cipher = XChaCha20Poly1305::new(ChaKey::from_slice(&net_key));
-                let t_nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
-                let t_cipher = transit_cipher.encrypt(&t_nonce, chunk).unwrap();
-                let mut packet = Vec::with_capacity(t_cipher.len() + 24);
-                packet.extend_from_slice(&t_nonce);
-                packet.extend_from_slice(&t_cipher);
-
-                for recipient in &recipients {
-                    let routing_hash_str =
-                        recipient.split('.').next().unwrap_or(recipient).to_string();
-                    let mut routing_hash = [0u8; 64];
-                    let r_bytes = routing_hash_str.as_bytes();
-                    let r_len = std::cmp::min(r_bytes.len(), 64);
-                    routing_hash[..r_len].copy_from_slice(&r_bytes[..r_len]);
-
-                    let _ = internal_dispatch_fragment(
-                        app.clone(),
-                        &net_state,
-                        routing_hash,
-                        Some(task.msg_id.clone()),
-                        task.transfer_id,
-                        fragment_index,
-                        total_fragments,
-                        &packet,
-                        true,
-                        true,
-                        false,
-                    )
-                    .await;
-                }
-                fragment_index += 1;
-            }
-            let _ = vault_file.sync_all();
+/// Sends the file announcement to every recipient.
+pub(crate) async fn announce_transfer<R: Runtime>(app: &AppHandle<R>, stream: &TransferStream) {
+    let net_state = app.state::<NetworkState>();
+    for recipient in &stream.recipients {
+        if let Ok(encrypted) = internal_signal_encrypt(
+            app.clone(),
+            &net_state,
+            recipient,
+            stream.announcement.to_string(),
+        )
+        .await
+        {
+            let routing_hash = recipient.split('.').next().unwrap_or(recipient).to_string();
+            let _ = internal_send_to_network(
+                app.clone(),
+                &net_state,
+                Some(routing_hash),
+                Some(stream.msg_id.clone()),
+                None,
+                Some(encrypted.to_string().into_bytes()),
+                true,
+                false,
+                Some(stream.transfer_id),
+                true,
+            )
+            .await;
+        }
+    }
+}
 
-            if let Some(thumb_b64) = &payload.thumbnail
-                && let Ok(thumb_bytes) = base64::engine::general_purpose::STANDARD.decode(thumb_b64)
-                {
-                    let thumb_path = media_dir.join(format!("{}_thumb", task.msg_id));
-                    let v_nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
-                    if let Ok(v_cipher) = vault_cipher.encrypt(&v_nonce, thumb_bytes.as_slice())
-                        && let Ok(mut f) = std::fs::File::create(&thumb_path) {
-                            let _ = f
-                                .write_all(&v_nonce)
-                                .and_then(|_| f.write_all(&v_cipher))
-                                .and_then(|_| f.sync_all());
-                        }
-                }
+async fn dispatch_to_recipients<R: Runtime>(
+    app: &AppHandle<R>,
+    stream: &TransferStream,
+    index: u32,
+    packet: &[u8],
+) {
+    let net_state = app.state::<NetworkState>();
+    for recipient in &stream.recipients {
+        let routing_hash_str = recipient.split('.').next().unwrap_or(recipient).to_string();
+        let _ = internal_dispatch_fragment(
+            app.clone(),
+            &net_state,
+            FrameCodec::routing_header(&routing_hash_str),
+            Some(stream.msg_id.clone()),
+            stream.transfer_id,
+            index,
+            stream.total_fragments,
+            packet,
+            true,
+            true,
+            false,
+        )
+        .await;
+    }
+}
 
-            for recipient in &recipients {
-                if let Ok(encrypted) = internal_signal_encrypt(
-                    app.clone(),
-                    &net_state,
-                    recipient,
-                    announcement.to_string(),
-                )
-                .await
-                {
-                    let routing_hash = recipient.split('.').next().unwrap_or(recipient).to_string();
-                    let _ = internal_send_to_network(
-                        app.clone(),
-                        &net_state,
-                        Some(routing_hash),
-                        Some(task.msg_id.clone()),
-                        None,
-                        Some(encrypted.to_string().into_bytes()),
-                        true,
-                        false,
-                        Some(task.transfer_id),
-                        true,
-                    )
-                    .await;
+/// Sends fragments from `start` onwards, sealing each block into the vault copy as
+/// it goes, then re-announces the transfer and marks the message `sent`. `reader`
+/// and `vault_file` must already be positioned at fragment `start`. Waits while the
+/// upload is paused and stops quietly if it is cancelled.
+pub(crate) async fn stream_transfer<R: Runtime>(
+    app: &AppHandle<R>,
+    stream: &TransferStream,
+    mut reader: Box<dyn std::io::Read + Send>,
+    mut vault_file: std::fs::File,
+    vault_path: &std::path::Path,
+    start: u32,
+) {
+    let db_state = app.state::<DbState>();
+    let net_state = app.state::<NetworkState>();
+    let Some(vault_key_bytes) = db_state.media_key.lock().ok().and_then(|k| k.clone()) else {
+        return;
+    };
+    let vault_cipher = XChaCha20Poly1305::new(ChaKey::from_slice(&vault_key_bytes));
+    let transit_cipher = XChaCha20Poly1305::new(ChaKey::from_slice(&stream.transit_key));
+    let real_fragments = (stream.file_size as usize).div_ceil(MEDIA_BLOCK_SIZE) as u32;
+
+    let mut fragment_index = start;
+    let mut buffer = vec![0u8; MEDIA_BLOCK_SIZE];
+
+    loop {
+        // A restart while paused resumes from where the pause began
+        if stream.resumable && is_paused(&net_state, stream.transfer_id) {
+            let _ = vault_file.sync_data();
+            checkpoint_upload(app, stream.transfer_id, fragment_index);
+        }
+        if !wait_while_paused(&net_state, stream.transfer_id).await {
+            return;
+        }
+        let mut n = 0;
+        let mut read_retries = 0;
+        while n < MEDIA_BLOCK_SIZE {
+            match reader.read(&mut buffer[n..]) {
+                Ok(0) => break,
+                Ok(read) => {
+                    n += read;
+                    read_retries = 0;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(_e) if read_retries < 3 => {
+                    read_retries += 1;
+                    std::thread::sleep(std::time::Duration::from_millis(50));
+                }
+                Err(e) => {
+                    let _ = app.emit(
+                        "network-bin-error",
+                        json!({ "msg_id": stream.msg_id.clone(), "error": format!("Disk read error: {}", e) }),
+                    );
+                    return;
                 }
             }
+        }
+        if n == 0 {
+            break;
+        }
+        let chunk = &buffer[..n];
+
+        let v_nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let v_cipher = vault_cipher.encrypt(&v_nonce, chunk).unwrap();
+        let _ = vault_file
+            .write_all(&v_nonce)
+            .and_then(|_| vault_file.write_all(&v_cipher));
+
+        let t_nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let t_cipher = transit_cipher.encrypt(&t_nonce, chunk).unwrap();
+        let mut packet = Vec::with_capacity(t_cipher.len() + 24);
+        packet.extend_from_slice(&t_nonce);
+        packet.extend_from_slice(&t_cipher);
+
+        dispatch_to_recipients(app, stream, fragment_index, &packet).await;
+        fragment_index += 1;
+
+        // The vault copy must be on disk before a checkpoint claims its blocks
+        if stream.resumable && fragment_index % CHECKPOINT_INTERVAL == 0 {
+            let _ = vault_file.sync_data();
+            checkpoint_upload(app, stream.transfer_id, fragment_index);
+        }
+    }
+    let _ = vault_file.sync_all();
 
-            let final_attachment_obj = json!({
-                "fileName": payload.file_name,
-                "fileType": payload.file_type,
-                "size": task.file_size,
-                "duration": payload.duration,
-                "thumbnail": payload.thumbnail,
-                "originalPath": task.canonical_path.as_ref().map(|p| p.to_string_lossy().to_string()),
-                "transferId": task.transfer_id,
-                "vaultPath": vault_path.to_string_lossy().to_string()
-            });
-
-            if let Ok(conn) = db_state.get_conn() {
-                let _ = conn.execute(
-                    "UPDATE messages SET status = 'sent', attachment_json = ?2 WHERE id = ?1",
-                    rusqlite::params![task.msg_id, final_attachment_obj.to_string()],
-                );
-            }
-            let _ = app.emit("msg://status", json!({
-                "id": task.msg_id, "status": "sent", "chatAddress": payload.recipient, "attachment": final_attachment_obj
-            }));
+    // Round the transfer up to its padded fragment count
+    for idx in fragment_index.max(real_fragments)..stream.total_fragments {
+        if !wait_while_paused(&net_state, stream.transfer_id).await {
+            return;
+        }
+        let packet = sealed_filler(&stream.transit_key);
+        dispatch_to_recipients(app, stream, idx, &packet).await;
+    }
 
-            if let Ok(mut active) = net_state.active_outgoing_transfers.lock() {
-                active.remove(&task.transfer_id);
-            }
-        });
-    });
+    announce_transfer(app, stream).await;
+
+    let final_attachment_obj = {
+        let Ok(conn) = db_state.get_conn() else {
+            return;
+        };
+        let mut attachment: serde_json::Value = conn
+            .query_row(
+                "SELECT attachment_json FROM messages WHERE id = ?1",
+                [&stream.msg_id],
+                |r| r.get::<_, Option<String>>(0),
+            )
+            .ok()
+            .flatten()
+            .and_then(|a| serde_json::from_str(&a).ok())
+            .unwrap_or_else(|| json!({}));
+        if let Some(obj) = attachment.as_object_mut() {
+            obj.insert(
+                "vaultPath".to_string(),
+                json!(vault_path.to_string_lossy().to_string()),
+            );
+        }
+        let _ = conn.execute(
+            "UPDATE messages SET status = 'sent', attachment_json = ?2 WHERE id = ?1",
+            rusqlite::params![stream.msg_id, attachment.to_string()],
+        );
+        attachment
+    };
+    complete_upload(app, stream.transfer_id);
+    let _ = app.emit("msg://status", json!({
+        "id": stream.msg_id, "status": "sent", "chatAddress": stream.chat_address, "attachment": final_attachment_obj
+    }));
 }
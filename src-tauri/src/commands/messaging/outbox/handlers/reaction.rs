@@ -3,10 +3,10 @@ use crate::app_state::{DbState, NetworkState};
 use crate::commands::{internal_send_to_network, internal_signal_encrypt};
 use rusqlite::params;
 use serde_json::json;
-use tauri::{AppHandle, Emitter, State};
+use tauri::{AppHandle, Emitter, Runtime, State};
 
-pub async fn process_outgoing_reaction(
-    app: AppHandle,
+pub async fn process_outgoing_reaction<R: Runtime>(
+    app: AppHandle<R>,
     db_state: State<'_, DbState>,
     net_state: State<'_, NetworkState>,
     payload: OutgoingReaction,
@@ -1,10 +1,10 @@
 use crate::app_state::{DbState, NetworkState};
 use crate::commands::{internal_send_to_network, internal_signal_encrypt};
 use serde_json::json;
-use tauri::{AppHandle, State};
+use tauri::{AppHandle, Runtime, State};
 
-pub async fn send_typing_status(
-    app: AppHandle,
+pub async fn send_typing_status<R: Runtime>(
+    app: AppHandle<R>,
     db_state: State<'_, DbState>,
     net_state: State<'_, NetworkState>,
     peer_hash: String,
@@ -52,8 +52,8 @@ pub async fn send_typing_status(
     Ok(())
 }
 
-pub async fn send_receipt(
-    app: AppHandle,
+pub async fn send_receipt<R: Runtime>(
+    app: AppHandle<R>,
     db_state: State<'_, DbState>,
     net_state: State<'_, NetworkState>,
     peer_hash: String,
@@ -95,8 +95,8 @@ pub async fn send_receipt(
     Ok(())
 }
 
-pub async fn send_profile_update(
-    app: AppHandle,
+pub async fn send_profile_update<R: Runtime>(
+    app: AppHandle<R>,
     _db_state: State<'_, DbState>,
     net_state: State<'_, NetworkState>,
     peer_hash: String,
@@ -1,14 +1,14 @@
 use super::super::OutgoingText;
 use crate::app_state::{DbState, NetworkState};
-use crate::commands::{
-    DbMessage, internal_db_save_message, internal_send_to_network, internal_signal_encrypt,
-};
+use crate::commands::identity::devices::sync_sent;
+use crate::commands::messaging::delivery::send_retained_signal;
+use crate::commands::{DbMessage, internal_db_save_message, internal_signal_encrypt};
 use rusqlite::params;
 use serde_json::json;
-use tauri::{AppHandle, Emitter, Manager, State};
+use tauri::{AppHandle, Emitter, Manager, Runtime, State};
 
-pub async fn process_outgoing_text(
-    app: AppHandle,
+pub async fn process_outgoing_text<R: Runtime>(
+    app: AppHandle<R>,
     db_state: State<'_, DbState>,
     net_state: State<'_, NetworkState>,
     payload: OutgoingText,
@@ -75,13 +75,30 @@ pub async fn process_outgoing_text(
         "isGroup": false,
     });
 
-    let ciphertext_obj = internal_signal_encrypt(
+    let ciphertext_obj = match internal_signal_encrypt(
         app.clone(),
         &net_state,
         &payload.recipient,
         signal_payload.to_string(),
     )
-    .await?;
+    .await
+    {
+        Ok(ciphertext_obj) => ciphertext_obj,
+        Err(e) => {
+            // Nothing was sent, so the message must not sit in `sending`
+            if let Ok(conn) = db_state.get_conn() {
+                let _ = conn.execute(
+                    "UPDATE messages SET status = 'failed' WHERE id = ?1",
+                    params![msg_id],
+                );
+            }
+            let _ = app.emit(
+                "msg://status",
+                json!({ "id": msg_id, "status": "failed", "chatAddress": payload.recipient }),
+            );
+            return Err(e);
+        }
+    };
 
     let routing_hash = payload
         .recipient
@@ -90,45 +107,28 @@ pub async fn process_outgoing_text(
         .unwrap_or(&payload.recipient);
     let payload_bytes = ciphertext_obj.to_string().into_bytes();
 
-    let _ = internal_send_to_network(
-        app.clone(),
-        &net_state,
-        Some(routing_hash.to_string()),
-        Some(msg_id.clone()),
-        None,
-        Some(payload_bytes),
-        true,
-        false,
-        None,
-        false,
-    )
-    .await;
+    // Status now follows the transport: `pending` if queued in the outbox, `sent` on
+    // relay acceptance, then `delivered` on the peer's receipt or `failed` on timeout
+    let _ =
+        send_retained_signal(&app, &net_state, routing_hash, &msg_id, payload_bytes, true).await;
+    sync_sent(&app, &payload.recipient, &signal_payload).await;
 
-    {
-        let conn = db_state.get_conn()?;
-        let _ = conn.execute(
-            "UPDATE messages SET status = 'sent' WHERE id = ?1",
+    let status: String = db_state
+        .get_conn()?
+        .query_row(
+            "SELECT status FROM messages WHERE id = ?1",
             params![msg_id],
-        );
-        let _ = conn.execute(
-            "UPDATE chats SET last_status = 'sent' WHERE LOWER(address) = LOWER(?1)",
-            params![payload.recipient],
-        );
-    }
-    app.emit(
-        "msg://status",
-        json!({ "id": msg_id, "status": "sent", "chatAddress": payload.recipient }),
-    )
-    .map_err(|e: tauri::Error| e.to_string())?;
-
+            |r| r.get(0),
+        )
+        .unwrap_or_else(|_| "sending".to_string());
     if let Some(obj) = final_json.as_object_mut() {
-        let _ = obj.insert("status".to_string(), json!("sent"));
+        let _ = obj.insert("status".to_string(), json!(status));
     }
     Ok(final_json)
 }
 
-pub async fn process_outgoing_group_text(
-    app: AppHandle,
+pub async fn process_outgoing_group_text<R: Runtime>(
+    app: AppHandle<R>,
     payload: OutgoingText,
 ) -> Result<serde_json::Value, String> {
     let db_state = app.state::<DbState>();
@@ -185,6 +185,8 @@ pub async fn process_outgoing_group_text(
     });
     let payload_str = signal_inner_payload.to_string();
 
+    let mut dispatched = 0;
+    let mut skipped = 0;
     for member_id in members {
         if member_id == &own_id {
             continue;
@@ -198,36 +200,47 @@ pub async fn process_outgoing_group_text(
         {
             Ok(ciphertext_obj) => {
                 let payload_bytes = ciphertext_obj.to_string().into_bytes();
-                let _ = internal_send_to_network(
-                    app.clone(),
+                let _ = send_retained_signal(
+                    &app,
                     &net_state,
-                    Some(routing_hash),
-                    Some(msg_id.clone()),
-                    None,
-                    Some(payload_bytes),
-                    true,
-                    false,
-                    None,
+                    &routing_hash,
+                    &msg_id,
+                    payload_bytes,
                     false,
                 )
                 .await;
+                dispatched += 1;
             }
             Err(_e) => {
                 // Skipping member
+                skipped += 1;
             }
         }
     }
+    sync_sent(&app, &payload.recipient, &signal_inner_payload).await;
 
-    if let Ok(conn) = db_state.get_conn() {
-        let _ = conn.execute(
-            "UPDATE messages SET status = 'sent' WHERE id = ?1",
+    // Like 1:1 text, status follows the transport once any member's copy went out;
+    // otherwise it is settled here
+    let conn = db_state.get_conn()?;
+    let status = if dispatched > 0 {
+        conn.query_row(
+            "SELECT status FROM messages WHERE id = ?1",
             params![msg_id],
+            |r| r.get(0),
+        )
+        .unwrap_or_else(|_| "sending".to_string())
+    } else {
+        let status = if skipped > 0 { "failed" } else { "sent" };
+        let _ = conn.execute(
+            "UPDATE messages SET status = ?1 WHERE id = ?2",
+            params![status, msg_id],
         );
         let _ = conn.execute(
-            "UPDATE chats SET last_status = 'sent' WHERE LOWER(address) = LOWER(?1)",
-            params![payload.recipient],
+            "UPDATE chats SET last_status = ?1 WHERE LOWER(address) = LOWER(?2)",
+            params![status, payload.recipient],
         );
-    }
+        status.to_string()
+    };
 
     let mut final_json =
         serde_json::to_value(&db_msg).map_err(|e: serde_json::Error| e.to_string())?;
@@ -240,13 +253,13 @@ pub async fn process_outgoing_group_text(
             "chatMembers".to_string(),
             serde_json::json!(payload.group_members.clone()),
         );
-        obj.insert("status".to_string(), json!("sent"));
+        obj.insert("status".to_string(), json!(status));
     }
     app.emit("msg://added", final_json.clone())
         .map_err(|e| e.to_string())?;
     app.emit(
         "msg://status",
-        json!({ "id": msg_id, "status": "sent", "chatAddress": payload.recipient }),
+        json!({ "id": msg_id, "status": status, "chatAddress": payload.recipient }),
     )
     .map_err(|e| e.to_string())?;
 
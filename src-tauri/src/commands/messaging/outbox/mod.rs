@@ -1,8 +1,10 @@
 use crate::app_state::{DbState, NetworkState};
 use serde::{Deserialize, Serialize};
-use tauri::{AppHandle, State};
+use tauri::{AppHandle, Runtime, State};
 
 pub mod handlers;
+pub mod uploads;
+pub use uploads::*;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -45,8 +47,8 @@ pub struct OutgoingMedia {
 }
 
 #[tauri::command]
-pub async fn process_outgoing_text(
-    app: AppHandle,
+pub async fn process_outgoing_text<R: Runtime>(
+    app: AppHandle<R>,
     db_state: State<'_, DbState>,
     net_state: State<'_, NetworkState>,
     payload: OutgoingText,
@@ -55,32 +57,32 @@ pub async fn process_outgoing_text(
 }
 
 #[tauri::command]
-pub async fn process_outgoing_group_text(
-    app: AppHandle,
+pub async fn process_outgoing_group_text<R: Runtime>(
+    app: AppHandle<R>,
     payload: OutgoingText,
 ) -> Result<serde_json::Value, String> {
     handlers::text::process_outgoing_group_text(app, payload).await
 }
 
 #[tauri::command]
-pub fn process_outgoing_media(
-    app: AppHandle,
+pub fn process_outgoing_media<R: Runtime>(
+    app: AppHandle<R>,
     payload: OutgoingMedia,
 ) -> Result<serde_json::Value, String> {
     handlers::media::process_outgoing_media(app, payload)
 }
 
 #[tauri::command]
-pub async fn process_outgoing_group_media(
-    app: AppHandle,
+pub async fn process_outgoing_group_media<R: Runtime>(
+    app: AppHandle<R>,
     payload: OutgoingMedia,
 ) -> Result<serde_json::Value, String> {
     handlers::media::process_outgoing_group_media(app, payload).await
 }
 
 #[tauri::command]
-pub async fn send_typing_status(
-    app: AppHandle,
+pub async fn send_typing_status<R: Runtime>(
+    app: AppHandle<R>,
     db_state: State<'_, DbState>,
     net_state: State<'_, NetworkState>,
     peer_hash: String,
@@ -90,8 +92,8 @@ pub async fn send_typing_status(
 }
 
 #[tauri::command]
-pub async fn send_receipt(
-    app: AppHandle,
+pub async fn send_receipt<R: Runtime>(
+    app: AppHandle<R>,
     db_state: State<'_, DbState>,
     net_state: State<'_, NetworkState>,
     peer_hash: String,
@@ -102,8 +104,8 @@ pub async fn send_receipt(
 }
 
 #[tauri::command]
-pub async fn send_profile_update(
-    app: AppHandle,
+pub async fn send_profile_update<R: Runtime>(
+    app: AppHandle<R>,
     db_state: State<'_, DbState>,
     net_state: State<'_, NetworkState>,
     peer_hash: String,
@@ -124,8 +126,8 @@ pub struct OutgoingReaction {
 }
 
 #[tauri::command]
-pub async fn process_outgoing_reaction(
-    app: AppHandle,
+pub async fn process_outgoing_reaction<R: Runtime>(
+    app: AppHandle<R>,
     db_state: State<'_, DbState>,
     net_state: State<'_, NetworkState>,
     payload: OutgoingReaction,
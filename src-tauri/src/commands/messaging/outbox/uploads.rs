@@ -0,0 +1,493 @@
+//! Resumable Uploads
+//!
+//! Uploads read from disk are recorded in the vault's `outgoing_transfers` table with
+//! their recipients, progress, announcement and the transit key sealed under the vault
+//! media key. Progress is checkpointed every `CHECKPOINT_INTERVAL` fragments, so an
+//! upload cut short by a restart continues from its last checkpoint once the vault is
+//! unlocked, and a finished one keeps answering `media_resend_request` until its resend
+//! retention expires. Uploads of in-memory data cannot be re-read and are not recorded.
+
+use base64::Engine;
+use chacha20poly1305::{
+    XChaCha20Poly1305, XNonce,
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+};
+use serde::Serialize;
+use serde_json::json;
+use std::io::{Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter, Manager, Runtime};
+
+use super::handlers::media::{TransferStream, announce_transfer, stream_transfer};
+use crate::app_state::{DbState, NetworkState, OutgoingTransferInfo, TransferControl};
+use crate::commands::messaging::transfers::cancel_upload;
+use crate::commands::network::codec::{MEDIA_BLOCK_SIZE, sealed_media_len};
+use crate::commands::network::config::current_config;
+use crate::commands::network::retry::unix_now;
+use crate::commands::vault::get_media_dir;
+
+/// Fragments sent between progress checkpoints.
+pub(crate) const CHECKPOINT_INTERVAL: u32 = 32;
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OutgoingUpload {
+    pub transfer_id: u32,
+    pub msg_id: String,
+    pub chat_address: String,
+    pub recipients: Vec<String>,
+    pub file_size: u64,
+    pub total_fragments: u32,
+    /// Fragments sent as of the last checkpoint.
+    pub fragments_sent: u32,
+    /// Relay acknowledgements received for the transfer.
+    pub fragments_confirmed: u32,
+    pub created_at: i64,
+    pub completed_at: Option<i64>,
+    pub paused: bool,
+}
+
+struct StoredUpload {
+    upload: OutgoingUpload,
+    file_path: PathBuf,
+    sealed_key: Vec<u8>,
+    announcement: serde_json::Value,
+}
+
+/// Seals a transit key under the vault media key as `nonce || ciphertext`.
+pub(crate) fn seal_transit_key(
+    media_key: &[u8],
+    transit_key: &[u8; 32],
+) -> Result<Vec<u8>, String> {
+    let cipher = XChaCha20Poly1305::new_from_slice(media_key).map_err(|e| e.to_string())?;
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let sealed = cipher
+        .encrypt(&nonce, transit_key.as_slice())
+        .map_err(|e| e.to_string())?;
+    let mut out = Vec::with_capacity(nonce.len() + sealed.len());
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&sealed);
+    Ok(out)
+}
+
+pub(crate) fn open_transit_key(media_key: &[u8], sealed: &[u8]) -> Result<[u8; 32], String> {
+    if sealed.len() < 24 {
+        return Err("Sealed transit key too short".into());
+    }
+    let cipher = XChaCha20Poly1305::new_from_slice(media_key).map_err(|e| e.to_string())?;
+    let (nonce, ciphertext) = sealed.split_at(24);
+    let key = cipher
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| "Transit key does not open under this vault")?;
+    key.try_into().map_err(|_| "Malformed transit key".into())
+}
+
+/// Fragment an interrupted upload continues from. The checkpoint is trusted only if
+/// the vault copy holds every block it covers; otherwise the upload falls back to the
+/// last whole block on disk.
+pub(crate) fn resume_index(file_size: u64, fragments_sent: u32, vault_len: u64) -> u32 {
+    if vault_len >= vault_len_at(file_size, fragments_sent) {
+        return fragments_sent;
+    }
+    let sealed_block = sealed_media_len(MEDIA_BLOCK_SIZE as u64);
+    (vault_len / sealed_block) as u32
+}
+
+/// Size of the vault copy once the first `fragments` blocks have been sealed into it.
+pub(crate) fn vault_len_at(file_size: u64, fragments: u32) -> u64 {
+    sealed_media_len((fragments as u64 * MEDIA_BLOCK_SIZE as u64).min(file_size))
+}
+
+pub(crate) fn upload_in_progress(state: &NetworkState, transfer_id: u32) -> bool {
+    state
+        .active_outgoing_transfers
+        .lock()
+        .map(|active| {
+            active
+                .get(&transfer_id)
+                .is_some_and(|t| t.finished_at.is_none())
+        })
+        .unwrap_or(false)
+}
+
+/// Records a new upload so it can be resumed.
+pub(crate) fn record_upload<R: Runtime>(
+    app: &AppHandle<R>,
+    stream: &TransferStream,
+    file_path: &Path,
+) -> Result<(), String> {
+    let db_state = app.state::<DbState>();
+    let media_key = db_state
+        .media_key
+        .lock()
+        .map_err(|_| "Media key lock poisoned")?
+        .clone()
+        .ok_or("Vault locked")?;
+    let sealed_key = seal_transit_key(&media_key, &stream.transit_key)?;
+
+    // The transit key is stored sealed, never inside the announcement
+    let mut announcement = stream.announcement.clone();
+    if let Some(bundle) = announcement
+        .get_mut("bundle")
+        .and_then(|b| b.as_object_mut())
+    {
+        bundle.remove("key");
+    }
+    let recipients = serde_json::to_string(&stream.recipients).map_err(|e| e.to_string())?;
+
+    db_state
+        .get_conn()?
+        .execute(
+            "INSERT OR REPLACE INTO outgoing_transfers (transfer_id, msg_id, chat_address, recipients, file_path, file_size, total_fragments, transit_key, announcement, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            rusqlite::params![
+                stream.transfer_id,
+                stream.msg_id,
+                stream.chat_address,
+                recipients,
+                file_path.to_string_lossy().to_string(),
+                stream.file_size as i64,
+                stream.total_fragments,
+                sealed_key,
+                announcement.to_string(),
+                unix_now()
+            ],
+        )
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+pub(crate) fn set_upload_paused<R: Runtime>(app: &AppHandle<R>, transfer_id: u32, paused: bool) {
+    if let Ok(conn) = app.state::<DbState>().get_conn() {
+        let _ = conn.execute(
+            "UPDATE outgoing_transfers SET paused = ?2 WHERE transfer_id = ?1",
+            rusqlite::params![transfer_id, paused],
+        );
+    }
+}
+
+pub(crate) fn checkpoint_upload<R: Runtime>(
+    app: &AppHandle<R>,
+    transfer_id: u32,
+    fragments_sent: u32,
+) {
+    if let Ok(conn) = app.state::<DbState>().get_conn() {
+        let _ = conn.execute(
+            "UPDATE outgoing_transfers SET fragments_sent = ?2 WHERE transfer_id = ?1",
+            rusqlite::params![transfer_id, fragments_sent],
+        );
+    }
+}
+
+/// Marks an upload fully sent; its resend retention starts now.
+pub(crate) fn complete_upload<R: Runtime>(app: &AppHandle<R>, transfer_id: u32) {
+    if let Ok(mut active) = app.state::<NetworkState>().active_outgoing_transfers.lock()
+        && let Some(info) = active.get_mut(&transfer_id)
+    {
+        info.finished_at = Some(Instant::now());
+    }
+    if let Ok(conn) = app.state::<DbState>().get_conn() {
+        let _ = conn.execute(
+            "UPDATE outgoing_transfers SET fragments_sent = total_fragments, completed_at = ?2 WHERE transfer_id = ?1",
+            rusqlite::params![transfer_id, unix_now()],
+        );
+    }
+}
+
+/// Counts a relay acknowledgement against the upload it belongs to, if any.
+pub(crate) fn confirm_upload_fragment<R: Runtime>(app: &AppHandle<R>, transfer_id: Option<u32>) {
+    let Some(tid) = transfer_id else {
+        return;
+    };
+    let tracked = app
+        .state::<NetworkState>()
+        .active_outgoing_transfers
+        .lock()
+        .map(|active| active.contains_key(&tid))
+        .unwrap_or(false);
+    if tracked && let Ok(conn) = app.state::<DbState>().get_conn() {
+        let _ = conn.execute(
+            "UPDATE outgoing_transfers SET fragments_confirmed = fragments_confirmed + 1 WHERE transfer_id = ?1",
+            [tid],
+        );
+    }
+}
+
+/// Drops the record of an upload whose resend retention has expired.
+pub(crate) fn forget_upload<R: Runtime>(app: &AppHandle<R>, transfer_id: u32) {
+    if let Ok(conn) = app.state::<DbState>().get_conn() {
+        let _ = conn.execute(
+            "DELETE FROM outgoing_transfers WHERE transfer_id = ?1",
+            [transfer_id],
+        );
+    }
+}
+
+/// Stops an upload for good: its record, queued fragments and vault copy are removed
+/// and the message is marked `failed`.
+pub(crate) fn abort_upload<R: Runtime>(app: &AppHandle<R>, transfer_id: u32, msg_id: &str) {
+    let net_state = app.state::<NetworkState>();
+    if let Ok(mut active) = net_state.active_outgoing_transfers.lock() {
+        active.remove(&transfer_id);
+    }
+    if let Ok(mut controls) = net_state.transfer_controls.lock() {
+        controls.remove(&transfer_id);
+    }
+    let db_state = app.state::<DbState>();
+    if let Ok(media_dir) = get_media_dir(app, &db_state) {
+        let _ = std::fs::remove_file(media_dir.join(msg_id));
+    }
+    let Ok(conn) = db_state.get_conn() else {
+        return;
+    };
+    let _ = conn.execute(
+        "DELETE FROM outgoing_transfers WHERE transfer_id = ?1",
+        [transfer_id],
+    );
+    let _ = conn.execute(
+        "DELETE FROM pending_outbox WHERE transfer_id = ?1",
+        [transfer_id],
+    );
+    let chat_address: Option<String> = conn
+        .query_row(
+            "SELECT chat_address FROM messages WHERE id = ?1",
+            [msg_id],
+            |r| r.get(0),
+        )
+        .ok();
+    let updated = conn
+        .execute(
+            "UPDATE messages SET status = 'failed' WHERE id = ?1 AND status = 'sending'",
+            [msg_id],
+        )
+        .unwrap_or(0);
+    if updated > 0 {
+        let _ = app.emit(
+            "msg://status",
+            json!({ "id": msg_id, "status": "failed", "chatAddress": chat_address }),
+        );
+    }
+}
+
+fn load_uploads<R: Runtime>(app: &AppHandle<R>) -> Result<Vec<StoredUpload>, String> {
+    let conn = app.state::<DbState>().get_conn()?;
+    let mut stmt = conn
+        .prepare(
+            "SELECT transfer_id, msg_id, chat_address, recipients, file_size, total_fragments, fragments_sent, fragments_confirmed, created_at, completed_at, file_path, transit_key, announcement, paused
+             FROM outgoing_transfers ORDER BY created_at ASC",
+        )
+        .map_err(|e| e.to_string())?;
+    let rows = stmt
+        .query_map([], |row| {
+            let recipients: String = row.get(3)?;
+            let file_size: i64 = row.get(4)?;
+            let file_path: String = row.get(10)?;
+            let announcement: String = row.get(12)?;
+            Ok(StoredUpload {
+                upload: OutgoingUpload {
+                    transfer_id: row.get(0)?,
+                    msg_id: row.get(1)?,
+                    chat_address: row.get(2)?,
+                    recipients: serde_json::from_str(&recipients).unwrap_or_default(),
+                    file_size: file_size as u64,
+                    total_fragments: row.get(5)?,
+                    fragments_sent: row.get(6)?,
+                    fragments_confirmed: row.get(7)?,
+                    created_at: row.get(8)?,
+                    completed_at: row.get(9)?,
+                    paused: row.get(13)?,
+                },
+                file_path: PathBuf::from(file_path),
+                sealed_key: row.get(11)?,
+                announcement: serde_json::from_str(&announcement).unwrap_or_default(),
+            })
+        })
+        .map_err(|e| e.to_string())?;
+    Ok(rows.flatten().collect())
+}
+
+/// Restores recorded uploads once the vault is unlocked: finished ones answer resend
+/// requests again, unfinished ones resume sending once the relay session is up.
+pub(crate) fn resume_uploads<R: Runtime>(app: &AppHandle<R>) {
+    let net_state = app.state::<NetworkState>();
+    let retention = current_config(&net_state).outgoing_retention_secs as i64;
+    let now = unix_now();
+    if let Ok(conn) = app.state::<DbState>().get_conn() {
+        let _ = conn.execute(
+            "DELETE FROM outgoing_transfers WHERE completed_at IS NOT NULL AND completed_at <= ?1",
+            [now - retention],
+        );
+    }
+    let Some(media_key) = app
+        .state::<DbState>()
+        .media_key
+        .lock()
+        .ok()
+        .and_then(|k| k.clone())
+    else {
+        return;
+    };
+    let Ok(uploads) = load_uploads(app) else {
+        return;
+    };
+
+    for stored in uploads {
+        let tid = stored.upload.transfer_id;
+        let Ok(transit_key) = open_transit_key(&media_key, &stored.sealed_key) else {
+            abort_upload(app, tid, &stored.upload.msg_id);
+            continue;
+        };
+        let finished_at = stored.upload.completed_at.map(|done| {
+            let age = Duration::from_secs(now.saturating_sub(done).max(0) as u64);
+            Instant::now().checked_sub(age).unwrap_or_else(Instant::now)
+        });
+        {
+            let Ok(mut active) = net_state.active_outgoing_transfers.lock() else {
+                return;
+            };
+            // Already running from an earlier unlock in this session
+            if active.contains_key(&tid) {
+                continue;
+            }
+            active.insert(
+                tid,
+                OutgoingTransferInfo {
+                    msg_id: stored.upload.msg_id.clone(),
+                    recipients: stored.upload.recipients.clone(),
+                    file_path: stored.file_path.clone(),
+                    transit_key,
+                    total_fragments: stored.upload.total_fragments,
+                    started_at: Instant::now(),
+                    finished_at,
+                },
+            );
+        }
+        if finished_at.is_none() {
+            if stored.upload.paused
+                && let Ok(mut controls) = net_state.transfer_controls.lock()
+            {
+                controls.insert(tid, TransferControl::Paused);
+            }
+            spawn_resume(app.clone(), stored, transit_key);
+        }
+    }
+}
+
+fn spawn_resume<R: Runtime>(app: AppHandle<R>, stored: StoredUpload, transit_key: [u8; 32]) {
+    std::thread::spawn(move || {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async move {
+            let tid = stored.upload.transfer_id;
+            let msg_id = stored.upload.msg_id.clone();
+            // Sending before the session is authenticated would only fill the outbox
+            loop {
+                let net_state = app.state::<NetworkState>();
+                if !upload_in_progress(&net_state, tid) {
+                    return;
+                }
+                if net_state
+                    .is_authenticated
+                    .lock()
+                    .map(|l| *l)
+                    .unwrap_or(false)
+                {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_secs(2)).await;
+            }
+            if resume_upload(&app, stored, transit_key).await.is_err() {
+                abort_upload(&app, tid, &msg_id);
+            }
+        });
+    });
+}
+
+async fn resume_upload<R: Runtime>(
+    app: &AppHandle<R>,
+    stored: StoredUpload,
+    transit_key: [u8; 32],
+) -> Result<(), String> {
+    let upload = stored.upload;
+    let metadata = std::fs::metadata(&stored.file_path).map_err(|e| e.to_string())?;
+    if metadata.len() != upload.file_size {
+        return Err("Source file changed since the upload started".into());
+    }
+
+    let vault_path = get_media_dir(app, &app.state::<DbState>())?.join(&upload.msg_id);
+    let vault_len = std::fs::metadata(&vault_path).map(|m| m.len()).unwrap_or(0);
+    let start = resume_index(upload.file_size, upload.fragments_sent, vault_len);
+
+    // Drop any blocks sealed after the checkpoint; they are sent again
+    let mut vault_file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&vault_path)
+        .map_err(|e| e.to_string())?;
+    vault_file
+        .set_len(vault_len_at(upload.file_size, start))
+        .and_then(|_| vault_file.seek(SeekFrom::End(0)))
+        .map_err(|e| e.to_string())?;
+
+    let mut file = std::fs::File::open(&stored.file_path).map_err(|e| e.to_string())?;
+    file.seek(SeekFrom::Start(start as u64 * MEDIA_BLOCK_SIZE as u64))
+        .map_err(|e| e.to_string())?;
+
+    let mut announcement = stored.announcement;
+    if let Some(bundle) = announcement
+        .get_mut("bundle")
+        .and_then(|b| b.as_object_mut())
+    {
+        bundle.insert(
+            "key".to_string(),
+            json!(base64::engine::general_purpose::STANDARD.encode(transit_key)),
+        );
+    }
+    let stream = TransferStream {
+        msg_id: upload.msg_id,
+        chat_address: upload.chat_address,
+        transfer_id: upload.transfer_id,
+        recipients: upload.recipients,
+        file_size: upload.file_size,
+        total_fragments: upload.total_fragments,
+        announcement,
+        transit_key,
+        resumable: true,
+    };
+
+    // Recipients may have dropped the transfer's metadata while we were away
+    announce_transfer(app, &stream).await;
+    stream_transfer(
+        app,
+        &stream,
+        Box::new(std::io::BufReader::new(file)),
+        vault_file,
+        &vault_path,
+        start,
+    )
+    .await;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn outbox_list_uploads<R: Runtime>(app: AppHandle<R>) -> Result<Vec<OutgoingUpload>, String> {
+    Ok(load_uploads(&app)?.into_iter().map(|s| s.upload).collect())
+}
+
+/// Cancels an upload that is still sending; the message is marked `failed` and its
+/// recipients are told to stop waiting for it.
+#[tauri::command]
+pub async fn outbox_cancel_upload<R: Runtime>(
+    app: AppHandle<R>,
+    transfer_id: u32,
+) -> Result<(), String> {
+    if cancel_upload(&app, transfer_id).await? {
+        Ok(())
+    } else {
+        Err("Upload is not in progress".into())
+    }
+}
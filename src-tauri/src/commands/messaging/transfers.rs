@@ -0,0 +1,440 @@
+//! Transfer Controls
+//!
+//! Pause, resume and cancel for media transfers in either direction, keyed by transfer
+//! id. A paused upload stops sending between fragments. A paused download tells its
+//! sender with an encrypted `media_pause`, drops the fragments still in flight and
+//! asks for them again after `media_resume`. Cancelling removes the transfer's state
+//! and temp files and tells the peer with an encrypted `media_cancel`, so a receiver
+//! stops waiting for fragments and a 1:1 sender stops sending them. Changes are
+//! reported on `transfer://state`.
+//!
+//! A group upload keeps going when one member pauses or cancels, since the others
+//! still want the file; that member's fragments cost bandwidth and are dropped, and
+//! are requested again on resume.
+
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter, Manager, Runtime, State};
+
+use crate::app_state::{DbState, NetworkState, TransferControl};
+use crate::commands::internal_signal_encrypt;
+use crate::commands::messaging::inbox::reassembler::{
+    assembler_key, parse_assembler_key, temp_filename,
+};
+use crate::commands::messaging::outbox::uploads::{
+    abort_upload, set_upload_paused, upload_in_progress,
+};
+use crate::commands::network::codec::FRAME_MEDIA;
+use crate::commands::network::transit::internal_send_to_network;
+use crate::commands::vault::get_media_dir;
+
+pub(crate) fn is_paused(state: &NetworkState, transfer_id: u32) -> bool {
+    state
+        .transfer_controls
+        .lock()
+        .map(|c| c.get(&transfer_id) == Some(&TransferControl::Paused))
+        .unwrap_or(false)
+}
+
+/// Whether arriving fragments of `transfer_id` are to be dropped.
+pub(crate) fn drops_fragments(state: &NetworkState, transfer_id: u32) -> bool {
+    state
+        .transfer_controls
+        .lock()
+        .map(|c| c.contains_key(&transfer_id))
+        .unwrap_or(false)
+}
+
+/// Blocks an upload while it is paused. Returns `false` once the upload is no longer
+/// in progress, so the caller stops sending.
+pub(crate) async fn wait_while_paused(state: &NetworkState, transfer_id: u32) -> bool {
+    loop {
+        if !upload_in_progress(state, transfer_id) {
+            return false;
+        }
+        if !is_paused(state, transfer_id) {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+}
+
+/// Forgets cancellations older than `ttl` and pauses of transfers that are no longer
+/// `live`, i.e. have neither an upload in progress nor a reassembly.
+pub(crate) fn prune_transfer_controls(
+    controls: &mut HashMap<u32, TransferControl>,
+    live: &HashSet<u32>,
+    now: Instant,
+    ttl: Duration,
+) {
+    controls.retain(|tid, c| match c {
+        TransferControl::Paused => live.contains(tid),
+        TransferControl::Cancelled(at) => now.saturating_duration_since(*at) < ttl,
+    });
+}
+
+/// The unfinished upload `transfer_id` as seen by `peer`: whether it is addressed to
+/// them, whether they are its only recipient, and its message id.
+fn upload_for_peer(
+    state: &NetworkState,
+    transfer_id: u32,
+    peer: &str,
+) -> Result<Option<(bool, bool, String)>, String> {
+    Ok(state
+        .active_outgoing_transfers
+        .lock()
+        .map_err(|_| "Network state poisoned")?
+        .get(&transfer_id)
+        .filter(|t| t.finished_at.is_none())
+        .map(|t| {
+            let addressed = t
+                .recipients
+                .iter()
+                .any(|r| r.split('.').next().unwrap_or(r).eq_ignore_ascii_case(peer));
+            (addressed, t.recipients.len() == 1, t.msg_id.clone())
+        }))
+}
+
+/// Senders of incoming media transfers with `transfer_id`, whether fragments or only
+/// the announcement have arrived so far.
+fn incoming_senders(state: &NetworkState, transfer_id: u32) -> Vec<String> {
+    let mut senders: Vec<String> = state
+        .media_assembler
+        .lock()
+        .map(|assemblers| {
+            assemblers
+                .keys()
+                .filter_map(|k| parse_assembler_key(k))
+                .filter(|(_, tid, frame_type)| *tid == transfer_id && *frame_type == FRAME_MEDIA)
+                .map(|(sender, _, _)| sender.to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+    if let Ok(links) = state.pending_media_links.lock() {
+        for key in links.keys() {
+            if let Some((sender, tid)) = key.split_once(':')
+                && tid.parse::<u32>() == Ok(transfer_id)
+                && !senders.iter().any(|s| s == sender)
+            {
+                senders.push(sender.to_string());
+            }
+        }
+    }
+    senders
+}
+
+/// Drops the reassembly and metadata of a download and deletes its temp file.
+/// Returns the message id if the announcement had arrived.
+fn discard_download<R: Runtime>(
+    app: &AppHandle<R>,
+    sender: &str,
+    transfer_id: u32,
+) -> Option<String> {
+    let net_state = app.state::<NetworkState>();
+    // Dropping the state closes its file handle before the file is deleted
+    if let Ok(mut assemblers) = net_state.media_assembler.lock() {
+        assemblers.remove(&assembler_key(sender, transfer_id, FRAME_MEDIA));
+    }
+    if let Ok(media_dir) = get_media_dir(app, &app.state::<DbState>()) {
+        let _ =
+            std::fs::remove_file(media_dir.join(temp_filename(sender, transfer_id, FRAME_MEDIA)));
+    }
+    net_state
+        .pending_media_links
+        .lock()
+        .ok()
+        .and_then(|mut links| links.remove(&format!("{}:{}", sender, transfer_id)))
+        .map(|m| m.id)
+}
+
+fn emit_state<R: Runtime>(
+    app: &AppHandle<R>,
+    transfer_id: u32,
+    state: &str,
+    direction: &str,
+    msg_id: Option<&str>,
+) {
+    let _ = app.emit(
+        "transfer://state",
+        json!({
+            "transfer_id": transfer_id,
+            "state": state,
+            "direction": direction,
+            "msgId": msg_id
+        }),
+    );
+}
+
+async fn send_control<R: Runtime>(app: &AppHandle<R>, peer: &str, payload: serde_json::Value) {
+    let net_state = app.state::<NetworkState>();
+    if let Ok(encrypted) =
+        internal_signal_encrypt(app.clone(), &net_state, peer, payload.to_string()).await
+    {
+        let routing_hash = peer.split('.').next().unwrap_or(peer).to_string();
+        let _ = internal_send_to_network(
+            app.clone(),
+            &net_state,
+            Some(routing_hash),
+            None,
+            None,
+            Some(encrypted.to_string().into_bytes()),
+            true,
+            false,
+            None,
+            true,
+        )
+        .await;
+    }
+}
+
+/// Asks the senders of a download for every fragment it is still missing.
+async fn request_missing_media<R: Runtime>(app: &AppHandle<R>, transfer_id: u32) {
+    let net_state = app.state::<NetworkState>();
+    let requests: Vec<(String, Vec<u32>)> = match net_state.media_assembler.lock() {
+        Ok(assemblers) => assemblers
+            .iter()
+            .filter_map(|(key, state)| {
+                let (sender, tid, frame_type) = parse_assembler_key(key)?;
+                if tid != transfer_id || frame_type != FRAME_MEDIA {
+                    return None;
+                }
+                let missing: Vec<u32> = state
+                    .received_chunks
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, received)| !**received)
+                    .map(|(idx, _)| idx as u32)
+                    .collect();
+                (!missing.is_empty()).then(|| (sender.to_string(), missing))
+            })
+            .collect(),
+        Err(_) => return,
+    };
+    for (sender, indices) in requests {
+        let request = json!({
+            "type": "media_resend_request",
+            "transfer_id": transfer_id,
+            "indices": indices,
+        });
+        send_control(app, &sender, request).await;
+    }
+}
+
+/// Stops an upload still in progress and tells its recipients. Returns `false` if
+/// there was none.
+pub(crate) async fn cancel_upload<R: Runtime>(
+    app: &AppHandle<R>,
+    transfer_id: u32,
+) -> Result<bool, String> {
+    let info = app
+        .state::<NetworkState>()
+        .active_outgoing_transfers
+        .lock()
+        .map_err(|_| "Network state poisoned")?
+        .get(&transfer_id)
+        .filter(|t| t.finished_at.is_none())
+        .cloned();
+    let Some(info) = info else {
+        return Ok(false);
+    };
+    abort_upload(app, transfer_id, &info.msg_id);
+    for recipient in &info.recipients {
+        send_control(
+            app,
+            recipient,
+            json!({ "type": "media_cancel", "transfer_id": transfer_id }),
+        )
+        .await;
+    }
+    emit_state(app, transfer_id, "cancelled", "upload", Some(&info.msg_id));
+    Ok(true)
+}
+
+#[tauri::command]
+pub async fn transfer_pause<R: Runtime>(
+    app: AppHandle<R>,
+    net_state: State<'_, NetworkState>,
+    transfer_id: u32,
+) -> Result<(), String> {
+    let upload = upload_in_progress(&net_state, transfer_id);
+    let downloading = net_state
+        .media_assembler
+        .lock()
+        .map_err(|_| "Network state poisoned")?
+        .keys()
+        .filter_map(|k| parse_assembler_key(k))
+        .any(|(_, tid, frame_type)| tid == transfer_id && frame_type == FRAME_MEDIA);
+    if !upload && !downloading {
+        return Err("No such transfer in progress".into());
+    }
+    net_state
+        .transfer_controls
+        .lock()
+        .map_err(|_| "Network state poisoned")?
+        .insert(transfer_id, TransferControl::Paused);
+    if upload {
+        set_upload_paused(&app, transfer_id, true);
+    } else {
+        // Spare the sender's bandwidth instead of dropping everything it sends
+        for sender in incoming_senders(&net_state, transfer_id) {
+            send_control(
+                &app,
+                &sender,
+                json!({ "type": "media_pause", "transfer_id": transfer_id }),
+            )
+            .await;
+        }
+    }
+    let direction = if upload { "upload" } else { "download" };
+    emit_state(&app, transfer_id, "paused", direction, None);
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn transfer_resume<R: Runtime>(
+    app: AppHandle<R>,
+    net_state: State<'_, NetworkState>,
+    transfer_id: u32,
+) -> Result<(), String> {
+    {
+        let mut controls = net_state
+            .transfer_controls
+            .lock()
+            .map_err(|_| "Network state poisoned")?;
+        if controls.get(&transfer_id) != Some(&TransferControl::Paused) {
+            return Err("Transfer is not paused".into());
+        }
+        controls.remove(&transfer_id);
+    }
+    let upload = upload_in_progress(&net_state, transfer_id);
+    if upload {
+        set_upload_paused(&app, transfer_id, false);
+    } else {
+        for sender in incoming_senders(&net_state, transfer_id) {
+            send_control(
+                &app,
+                &sender,
+                json!({ "type": "media_resume", "transfer_id": transfer_id }),
+            )
+            .await;
+        }
+        // Fragments dropped while paused are sent again on request
+        request_missing_media(&app, transfer_id).await;
+    }
+    let direction = if upload { "upload" } else { "download" };
+    emit_state(&app, transfer_id, "resumed", direction, None);
+    Ok(())
+}
+
+/// Cancels an upload or download and notifies the peer.
+#[tauri::command]
+pub async fn transfer_cancel<R: Runtime>(
+    app: AppHandle<R>,
+    net_state: State<'_, NetworkState>,
+    transfer_id: u32,
+) -> Result<(), String> {
+    if cancel_upload(&app, transfer_id).await? {
+        return Ok(());
+    }
+    let senders = incoming_senders(&net_state, transfer_id);
+    if senders.is_empty() {
+        return Err("No such transfer in progress".into());
+    }
+    net_state
+        .transfer_controls
+        .lock()
+        .map_err(|_| "Network state poisoned")?
+        .insert(transfer_id, TransferControl::Cancelled(Instant::now()));
+    for sender in senders {
+        let msg_id = discard_download(&app, &sender, transfer_id);
+        send_control(
+            &app,
+            &sender,
+            json!({ "type": "media_cancel", "transfer_id": transfer_id }),
+        )
+        .await;
+        emit_state(
+            &app,
+            transfer_id,
+            "cancelled",
+            "download",
+            msg_id.as_deref(),
+        );
+    }
+    Ok(())
+}
+
+/// A peer cancelled a transfer: either the sender of a download abandoned it, or the
+/// only recipient of an upload no longer wants it.
+pub(crate) async fn handle_media_cancel<R: Runtime>(
+    app: AppHandle<R>,
+    sender: String,
+    decrypted_json: serde_json::Value,
+) -> Result<(), String> {
+    let transfer_id = decrypted_json["transfer_id"]
+        .as_u64()
+        .ok_or("Missing transfer_id")? as u32;
+    let net_state = app.state::<NetworkState>();
+
+    if let Some((addressed, sole_recipient, msg_id)) =
+        upload_for_peer(&net_state, transfer_id, &sender)?
+    {
+        // Other group members may still want the file
+        if addressed && sole_recipient {
+            abort_upload(&app, transfer_id, &msg_id);
+            emit_state(&app, transfer_id, "cancelled", "upload", Some(&msg_id));
+        }
+        return Ok(());
+    }
+
+    if !incoming_senders(&net_state, transfer_id).contains(&sender) {
+        return Ok(());
+    }
+    net_state
+        .transfer_controls
+        .lock()
+        .map_err(|_| "Network state poisoned")?
+        .insert(transfer_id, TransferControl::Cancelled(Instant::now()));
+    let msg_id = discard_download(&app, &sender, transfer_id);
+    emit_state(
+        &app,
+        transfer_id,
+        "cancelled",
+        "download",
+        msg_id.as_deref(),
+    );
+    Ok(())
+}
+
+/// The only recipient of an upload paused or resumed its download; the upload
+/// follows. Group uploads carry on for the other members.
+pub(crate) async fn handle_media_pause<R: Runtime>(
+    app: AppHandle<R>,
+    sender: String,
+    decrypted_json: serde_json::Value,
+    paused: bool,
+) -> Result<(), String> {
+    let transfer_id = decrypted_json["transfer_id"]
+        .as_u64()
+        .ok_or("Missing transfer_id")? as u32;
+    let net_state = app.state::<NetworkState>();
+    let Some((true, true, msg_id)) = upload_for_peer(&net_state, transfer_id, &sender)? else {
+        return Ok(());
+    };
+    {
+        let mut controls = net_state
+            .transfer_controls
+            .lock()
+            .map_err(|_| "Network state poisoned")?;
+        if paused {
+            controls.insert(transfer_id, TransferControl::Paused);
+        } else if controls.remove(&transfer_id).is_none() {
+            return Ok(());
+        }
+    }
+    set_upload_paused(&app, transfer_id, paused);
+    let state = if paused { "paused" } else { "resumed" };
+    emit_state(&app, transfer_id, state, "upload", Some(&msg_id));
+    Ok(())
+}
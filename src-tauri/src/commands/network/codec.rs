@@ -0,0 +1,209 @@
+//! Binary Frame Codec
+//!
+//! Single definition of the packet geometry shared by every sender and receiver.
+//! Each binary packet on the wire is exactly `PACKET_SIZE` bytes:
+//!
+//! ```text
+//! [routing: 64][type: u8][transfer_id: u32][index: u32][total: u32][version: u8][len: u24][data][zero padding]
+//! ```
+//!
+//! All integers are big-endian. The version byte occupies what used to be the high
+//! byte of a u32 length; version 0 is therefore byte-identical to the legacy framing.
+//! Dummy cover packets are marked by `FRAME_DUMMY` in the first byte and carry no header.
+
+pub const PACKET_SIZE: usize = 1400;
+pub const ROUTING_LEN: usize = 64;
+/// Frame type, transfer id, index, total, version and length.
+pub const FRAGMENT_HEADER_LEN: usize = 17;
+pub const HEADER_LEN: usize = ROUTING_LEN + FRAGMENT_HEADER_LEN;
+pub const CHUNK_CAPACITY: usize = PACKET_SIZE - HEADER_LEN;
+
+/// XChaCha20-Poly1305 nonce plus tag added to every media block.
+pub const AEAD_OVERHEAD: usize = 24 + 16;
+/// Plaintext bytes per media fragment, sized so a sealed block fills one chunk.
+pub const MEDIA_BLOCK_SIZE: usize = CHUNK_CAPACITY - AEAD_OVERHEAD;
+
+/// Sealed bytes a media file of `plain_size` bytes occupies in a reassembled transfer.
+pub fn sealed_media_len(plain_size: u64) -> u64 {
+    plain_size + plain_size.div_ceil(MEDIA_BLOCK_SIZE as u64) * AEAD_OVERHEAD as u64
+}
+
+/// Plaintext bytes in media block `index` of a file of `plain_size` bytes; zero past
+/// the end of the file.
+pub fn media_block_len(plain_size: u64, index: u64) -> usize {
+    plain_size
+        .saturating_sub(index * MEDIA_BLOCK_SIZE as u64)
+        .min(MEDIA_BLOCK_SIZE as u64) as usize
+}
+
+/// JSON above this size is sent as `FRAME_CONTROL` fragments instead of a text frame.
+pub const INLINE_JSON_LIMIT: usize = 1200;
+pub const MAX_FRAGMENTS: u32 = 250_000;
+
+pub const FRAME_VERSION: u8 = 0;
+
+pub const FRAME_CONTROL: u8 = 0x00;
+pub const FRAME_SIGNAL: u8 = 0x01;
+pub const FRAME_MEDIA: u8 = 0x02;
+pub const FRAME_DUMMY: u8 = 0x03;
+pub const FRAME_VOLATILE: u8 = 0x04;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub routing: [u8; ROUTING_LEN],
+    pub frame_type: u8,
+    pub transfer_id: u32,
+    pub index: u32,
+    pub total: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// The routing header as an address, without NUL padding.
+    pub fn routing_str(&self) -> String {
+        FrameCodec::routing_str(&self.routing)
+    }
+}
+
+pub struct FrameCodec;
+
+impl FrameCodec {
+    pub fn routing_header(hash: &str) -> [u8; ROUTING_LEN] {
+        let mut header = [0u8; ROUTING_LEN];
+        let bytes = hash.as_bytes();
+        let len = bytes.len().min(ROUTING_LEN);
+        header[..len].copy_from_slice(&bytes[..len]);
+        header
+    }
+
+    pub fn routing_str(routing: &[u8; ROUTING_LEN]) -> String {
+        String::from_utf8_lossy(routing)
+            .trim_matches(|c: char| c == '\0' || c.is_whitespace())
+            .to_lowercase()
+    }
+
+    /// Number of fragments needed to carry `len` bytes.
+    pub fn chunk_count(len: usize) -> u32 {
+        len.div_ceil(CHUNK_CAPACITY) as u32
+    }
+
+    /// Selects the frame type for a Signal-layer payload.
+    pub fn data_frame_type(is_media: bool, is_volatile: bool) -> u8 {
+        if is_media {
+            FRAME_MEDIA
+        } else if is_volatile {
+            FRAME_VOLATILE
+        } else {
+            FRAME_SIGNAL
+        }
+    }
+
+    pub fn encode_fragment(
+        routing: &[u8; ROUTING_LEN],
+        frame_type: u8,
+        transfer_id: u32,
+        index: u32,
+        total: u32,
+        chunk: &[u8],
+    ) -> Result<Vec<u8>, String> {
+        if chunk.len() > CHUNK_CAPACITY {
+            return Err(format!(
+                "Fragment of {} bytes exceeds capacity {}",
+                chunk.len(),
+                CHUNK_CAPACITY
+            ));
+        }
+        let len = (chunk.len() as u32).to_be_bytes();
+        let mut packet = Vec::with_capacity(PACKET_SIZE);
+        packet.extend_from_slice(routing);
+        packet.push(frame_type);
+        packet.extend_from_slice(&transfer_id.to_be_bytes());
+        packet.extend_from_slice(&index.to_be_bytes());
+        packet.extend_from_slice(&total.to_be_bytes());
+        packet.push(FRAME_VERSION);
+        packet.extend_from_slice(&len[1..]);
+        packet.extend_from_slice(chunk);
+        packet.resize(PACKET_SIZE, 0);
+        Ok(packet)
+    }
+
+    /// Splits `data` into padded packets; empty input yields no packets.
+    pub fn encode(
+        routing: &[u8; ROUTING_LEN],
+        frame_type: u8,
+        transfer_id: u32,
+        data: &[u8],
+    ) -> Vec<Vec<u8>> {
+        let total = Self::chunk_count(data.len());
+        data.chunks(CHUNK_CAPACITY)
+            .enumerate()
+            .filter_map(|(i, chunk)| {
+                Self::encode_fragment(routing, frame_type, transfer_id, i as u32, total, chunk).ok()
+            })
+            .collect()
+    }
+
+    pub fn dummy() -> Vec<u8> {
+        let mut packet = vec![0u8; PACKET_SIZE];
+        packet[0] = FRAME_DUMMY;
+        packet
+    }
+
+    pub fn is_dummy(packet: &[u8]) -> bool {
+        packet.first() == Some(&FRAME_DUMMY)
+    }
+
+    /// Frame type of a packet without decoding it; `None` for dummies and short packets.
+    pub fn frame_type(packet: &[u8]) -> Option<u8> {
+        if Self::is_dummy(packet) {
+            return None;
+        }
+        packet.get(ROUTING_LEN).copied()
+    }
+
+    /// Transfer id of a packet without decoding it; `None` for short packets.
+    pub fn transfer_id(packet: &[u8]) -> Option<u32> {
+        let bytes = packet.get(ROUTING_LEN + 1..ROUTING_LEN + 5)?;
+        Some(u32::from_be_bytes(bytes.try_into().ok()?))
+    }
+
+    pub fn decode(packet: &[u8]) -> Result<Frame, String> {
+        if packet.len() < HEADER_LEN {
+            return Err("Invalid binary fragment header (too short)".into());
+        }
+        let (routing_bytes, body) = packet.split_at(ROUTING_LEN);
+        let read_u32 = |at: usize| {
+            let mut b = [0u8; 4];
+            b.copy_from_slice(&body[at..at + 4]);
+            u32::from_be_bytes(b)
+        };
+
+        let version = body[13];
+        if version != FRAME_VERSION {
+            return Err(format!("Unsupported frame version {}", version));
+        }
+        let len = u32::from_be_bytes([0, body[14], body[15], body[16]]) as usize;
+        let total = read_u32(9);
+        let index = read_u32(5);
+        if total > MAX_FRAGMENTS {
+            return Err("Payload exceeds limit".into());
+        }
+        if index >= total {
+            return Err("Fragment index out of range".into());
+        }
+        if len > CHUNK_CAPACITY || body.len() < FRAGMENT_HEADER_LEN + len {
+            return Err("Fragment data too short".into());
+        }
+
+        let mut routing = [0u8; ROUTING_LEN];
+        routing.copy_from_slice(routing_bytes);
+        Ok(Frame {
+            routing,
+            frame_type: body[0],
+            transfer_id: read_u32(1),
+            index,
+            total,
+            payload: body[FRAGMENT_HEADER_LEN..FRAGMENT_HEADER_LEN + len].to_vec(),
+        })
+    }
+}
@@ -0,0 +1,259 @@
+//! Network Tuning Configuration
+//!
+//! Transport-layer settings persisted in the vault as JSON under the `_network_config`
+//! kv_store key. Fields missing from a stored config take their defaults, so vaults
+//! written by older builds keep loading as new settings are introduced.
+
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Manager, Runtime, State};
+
+use super::codec::PACKET_SIZE;
+use crate::app_state::{DbState, NetworkState};
+
+const CONFIG_KEY: &str = "_network_config";
+
+/// How the paced writer hides traffic timing.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum CoverTrafficMode {
+    /// Real packets go out as they arrive, with dummies after random 1-10 s pauses.
+    #[default]
+    Random,
+    /// Exactly one packet per tick, real or dummy, plus budgeted media bursts.
+    ConstantRate,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct NetworkConfig {
+    /// Seconds without a new fragment before a partial incoming transfer is discarded.
+    pub transfer_timeout_secs: u64,
+    /// Seconds an outgoing upload stays available for resend requests.
+    pub outgoing_retention_secs: u64,
+    /// Interval between garbage-collection sweeps of transfer state.
+    pub sweep_interval_secs: u64,
+    /// Concurrent incoming reassemblies allowed from one sender.
+    pub max_transfers_per_sender: u32,
+    /// Concurrent incoming reassemblies allowed across all senders.
+    pub max_transfers_total: u32,
+    /// Disk one sender may reserve for in-progress reassemblies.
+    pub max_bytes_per_sender: u64,
+    /// Disk all senders together may reserve for in-progress reassemblies.
+    pub max_bytes_total: u64,
+    pub max_fragments_per_sec_per_sender: u32,
+    pub max_fragments_per_sec_total: u32,
+    /// How long a sender that broke a per-sender limit is ignored.
+    pub inbound_mute_secs: u64,
+    /// Seconds a queued outgoing message may wait for relay confirmation before it fails.
+    pub outbox_max_age_secs: u64,
+    /// Send attempts per queued message before it is marked failed.
+    pub outbox_max_attempts: u32,
+    /// Delay before the first resend of an unconfirmed message; doubles per attempt.
+    pub outbox_retry_base_secs: u64,
+    /// Cap on the resend delay.
+    pub outbox_retry_max_secs: u64,
+    /// Idle seconds before a stalled incoming signal transfer asks for its missing fragments.
+    pub signal_nack_delay_secs: u64,
+    /// Resend requests sent for one incoming signal transfer before giving up on it.
+    pub signal_max_nacks: u32,
+    /// Seconds after relay acceptance, and between attempts, before an unreceipted
+    /// text message is sent again in full.
+    pub signal_retransmit_secs: u64,
+    /// Full retransmissions of one unreceipted text message.
+    pub signal_max_retransmits: u32,
+    /// Seconds after relay acceptance a text message waits for the peer's delivery receipt.
+    pub delivery_ack_timeout_secs: u64,
+    /// Applies from the next connection.
+    pub cover_traffic: CoverTrafficMode,
+    /// Packets per second emitted in constant-rate mode.
+    pub constant_rate_pps: u32,
+    /// Upper bound on bytes per second in constant-rate mode, media bursts included.
+    pub bandwidth_budget_bytes_per_sec: u64,
+    /// Media packets that may be sent above the tick rate in one burst.
+    pub media_burst_packets: u32,
+    /// Rounds outgoing media fragment counts up to Padmé buckets so the fragment total
+    /// does not reveal the exact file size. Recipients on builds without padding
+    /// support cannot open padded transfers.
+    pub media_padding: bool,
+    /// Seals outgoing envelopes so the sender is named only inside a layer encrypted
+    /// to each recipient device. Recipients on builds without sealed-sender support
+    /// cannot open sealed messages.
+    pub sealed_sender: bool,
+    /// Seconds between websocket pings on an open connection.
+    pub heartbeat_interval_secs: u64,
+    /// Extra silence allowed after a missed pong before the connection is replaced.
+    pub heartbeat_timeout_secs: u64,
+    /// Interval between `network://stats` events.
+    pub stats_interval_secs: u64,
+    /// How long sending to a peer stays halted after the relay reports a delivery error.
+    pub halt_duration_secs: u64,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            transfer_timeout_secs: 300,
+            outgoing_retention_secs: 6 * 3600,
+            sweep_interval_secs: 30,
+            max_transfers_per_sender: 8,
+            max_transfers_total: 64,
+            max_bytes_per_sender: 384 * 1024 * 1024,
+            max_bytes_total: 1024 * 1024 * 1024,
+            max_fragments_per_sec_per_sender: 5000,
+            max_fragments_per_sec_total: 10000,
+            inbound_mute_secs: 300,
+            outbox_max_age_secs: 7 * 24 * 3600,
+            outbox_max_attempts: 12,
+            outbox_retry_base_secs: 15,
+            outbox_retry_max_secs: 1800,
+            signal_nack_delay_secs: 3,
+            signal_max_nacks: 5,
+            signal_retransmit_secs: 120,
+            signal_max_retransmits: 3,
+            delivery_ack_timeout_secs: 900,
+            cover_traffic: CoverTrafficMode::Random,
+            constant_rate_pps: 10,
+            bandwidth_budget_bytes_per_sec: 64 * 1024,
+            media_burst_packets: 200,
+            media_padding: false,
+            sealed_sender: false,
+            heartbeat_interval_secs: 15,
+            heartbeat_timeout_secs: 10,
+            stats_interval_secs: 5,
+            halt_duration_secs: 3600,
+        }
+    }
+}
+
+impl NetworkConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.transfer_timeout_secs < 30 {
+            return Err("Transfer timeout must be at least 30 seconds".into());
+        }
+        if self.outgoing_retention_secs < self.transfer_timeout_secs {
+            return Err("Outgoing retention cannot be shorter than the transfer timeout".into());
+        }
+        if !(5..=3600).contains(&self.sweep_interval_secs) {
+            return Err("Sweep interval must be between 5 and 3600 seconds".into());
+        }
+        if self.max_transfers_per_sender == 0
+            || self.max_bytes_per_sender == 0
+            || self.max_fragments_per_sec_per_sender == 0
+        {
+            return Err("Inbound limits must be greater than zero".into());
+        }
+        if self.max_transfers_per_sender > self.max_transfers_total
+            || self.max_bytes_per_sender > self.max_bytes_total
+            || self.max_fragments_per_sec_per_sender > self.max_fragments_per_sec_total
+        {
+            return Err("Per-sender inbound limits cannot exceed the global limits".into());
+        }
+        if self.outbox_max_attempts == 0 {
+            return Err("Outbox must allow at least one send attempt".into());
+        }
+        if self.outbox_retry_base_secs < 5
+            || self.outbox_retry_max_secs < self.outbox_retry_base_secs
+        {
+            return Err("Outbox retry delay must be at least 5 seconds and within its cap".into());
+        }
+        if self.outbox_max_age_secs < self.outbox_retry_max_secs {
+            return Err("Outbox max age cannot be shorter than the retry delay cap".into());
+        }
+        if !(1..=60).contains(&self.signal_nack_delay_secs) {
+            return Err("Resend request delay must be between 1 and 60 seconds".into());
+        }
+        if self.delivery_ack_timeout_secs < 30 {
+            return Err("Delivery timeout must be at least 30 seconds".into());
+        }
+        if self.signal_retransmit_secs < 10
+            || self.signal_retransmit_secs >= self.delivery_ack_timeout_secs
+        {
+            return Err(
+                "Retransmit delay must be at least 10 seconds and shorter than the delivery timeout"
+                    .into(),
+            );
+        }
+        if !(1..=200).contains(&self.constant_rate_pps) {
+            return Err("Constant rate must be between 1 and 200 packets per second".into());
+        }
+        if self.bandwidth_budget_bytes_per_sec < self.constant_rate_pps as u64 * PACKET_SIZE as u64
+        {
+            return Err("Bandwidth budget cannot be below the constant packet rate".into());
+        }
+        if !(5..=120).contains(&self.heartbeat_interval_secs) {
+            return Err("Heartbeat interval must be between 5 and 120 seconds".into());
+        }
+        if !(2..=60).contains(&self.heartbeat_timeout_secs) {
+            return Err("Heartbeat timeout must be between 2 and 60 seconds".into());
+        }
+        if !(1..=300).contains(&self.stats_interval_secs) {
+            return Err("Stats interval must be between 1 and 300 seconds".into());
+        }
+        if !(60..=7 * 24 * 3600).contains(&self.halt_duration_secs) {
+            return Err("Halt duration must be between one minute and seven days".into());
+        }
+        Ok(())
+    }
+}
+
+fn read_config(db_state: &DbState) -> Result<Option<NetworkConfig>, String> {
+    let conn = db_state.get_conn()?;
+    let raw: Option<String> = conn
+        .query_row(
+            "SELECT value FROM kv_store WHERE key = ?1",
+            [CONFIG_KEY],
+            |r| r.get(0),
+        )
+        .ok();
+    match raw {
+        Some(s) => serde_json::from_str(&s)
+            .map(Some)
+            .map_err(|e| e.to_string()),
+        None => Ok(None),
+    }
+}
+
+/// Loads the stored config into `NetworkState`; called once the vault is unlocked.
+pub(crate) fn load_network_config<R: Runtime>(app: &AppHandle<R>) {
+    let stored = read_config(&app.state::<DbState>())
+        .ok()
+        .flatten()
+        .filter(|c| c.validate().is_ok())
+        .unwrap_or_default();
+    if let Ok(mut config) = app.state::<NetworkState>().config.lock() {
+        *config = stored;
+    }
+}
+
+/// Snapshot of the active config for background tasks.
+pub(crate) fn current_config(state: &NetworkState) -> NetworkConfig {
+    state.config.lock().map(|c| c.clone()).unwrap_or_default()
+}
+
+#[tauri::command]
+pub fn network_get_config(state: State<'_, NetworkState>) -> Result<NetworkConfig, String> {
+    Ok(current_config(&state))
+}
+
+#[tauri::command]
+pub fn network_set_config(
+    db_state: State<'_, DbState>,
+    net_state: State<'_, NetworkState>,
+    config: NetworkConfig,
+) -> Result<(), String> {
+    config.validate()?;
+    let json = serde_json::to_string(&config).map_err(|e| e.to_string())?;
+    let conn = db_state.get_conn()?;
+    conn.execute(
+        "INSERT OR REPLACE INTO kv_store (key, value) VALUES (?1, ?2)",
+        [CONFIG_KEY, json.as_str()],
+    )
+    .map_err(|e| e.to_string())?;
+
+    let mut current = net_state
+        .config
+        .lock()
+        .map_err(|_| "Network state poisoned")?;
+    *current = config;
+    Ok(())
+}
@@ -0,0 +1,172 @@
+//! Halted Delivery Targets
+//!
+//! A `delivery_error` from the relay (full mailbox, exhausted sender quota) halts
+//! sending to that peer for `halt_duration_secs`, or until the user clears it. Halts
+//! are stored in the vault's `halted_targets` table and mirrored in
+//! `NetworkState.halted_targets`, keyed by the peer's normalized routing string.
+//! Packets for a halted peer are queued in the outbox, due when the halt expires,
+//! rather than sent to a relay that would refuse them.
+
+use serde::Serialize;
+use std::collections::HashMap;
+use tauri::{AppHandle, Manager, Runtime, State};
+
+use super::codec::{FrameCodec, ROUTING_LEN};
+use super::config::current_config;
+use super::retry::{flush_outbox, unix_now};
+use crate::app_state::{DbState, NetworkState};
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct HaltedTarget {
+    pub target: String,
+    /// The relay's `delivery_error` reason, e.g. `storage_full`.
+    pub reason: String,
+    pub halted_at: i64,
+    pub expires_at: i64,
+}
+
+/// Normalized form of a peer hash, as it appears in frame routing headers.
+pub(crate) fn halt_key(target: &str) -> String {
+    FrameCodec::routing_str(&FrameCodec::routing_header(target))
+}
+
+/// Expiry of the halt covering `routing`, if one is still in force at `now`.
+pub(crate) fn halted_until(
+    halts: &HashMap<String, HaltedTarget>,
+    routing: &[u8; ROUTING_LEN],
+    now: i64,
+) -> Option<i64> {
+    halts
+        .get(&FrameCodec::routing_str(routing))
+        .filter(|h| h.expires_at > now)
+        .map(|h| h.expires_at)
+}
+
+pub(crate) fn routing_halted_until(
+    state: &NetworkState,
+    routing: &[u8; ROUTING_LEN],
+) -> Option<i64> {
+    let halts = state.halted_targets.lock().ok()?;
+    halted_until(&halts, routing, unix_now())
+}
+
+/// Records a relay-reported delivery error against `target`.
+pub(crate) fn halt_target<R: Runtime>(app: &AppHandle<R>, target: &str, reason: &str) {
+    let key = halt_key(target);
+    if key.is_empty() {
+        return;
+    }
+    let net_state = app.state::<NetworkState>();
+    let now = unix_now();
+    let halt = HaltedTarget {
+        target: key.clone(),
+        reason: reason.to_string(),
+        halted_at: now,
+        expires_at: now + current_config(&net_state).halt_duration_secs as i64,
+    };
+    if let Ok(conn) = app.state::<DbState>().get_conn() {
+        let _ = conn.execute(
+            "INSERT OR REPLACE INTO halted_targets (target, reason, halted_at, expires_at) VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![halt.target, halt.reason, halt.halted_at, halt.expires_at],
+        );
+    }
+    if let Ok(mut halts) = net_state.halted_targets.lock() {
+        halts.retain(|_, h| h.expires_at > now);
+        halts.insert(key, halt);
+    }
+}
+
+/// Restores unexpired halts from the vault; called once the vault is unlocked.
+pub(crate) fn load_halted_targets<R: Runtime>(app: &AppHandle<R>) {
+    let now = unix_now();
+    let mut halts = HashMap::new();
+    if let Ok(conn) = app.state::<DbState>().get_conn() {
+        let _ = conn.execute("DELETE FROM halted_targets WHERE expires_at <= ?1", [now]);
+        if let Ok(mut stmt) =
+            conn.prepare("SELECT target, reason, halted_at, expires_at FROM halted_targets")
+            && let Ok(rows) = stmt.query_map([], |r| {
+                Ok(HaltedTarget {
+                    target: r.get(0)?,
+                    reason: r.get(1)?,
+                    halted_at: r.get(2)?,
+                    expires_at: r.get(3)?,
+                })
+            })
+        {
+            for halt in rows.flatten() {
+                halts.insert(halt.target.clone(), halt);
+            }
+        }
+    }
+    if let Ok(mut l) = app.state::<NetworkState>().halted_targets.lock() {
+        *l = halts;
+    }
+}
+
+#[tauri::command]
+pub fn network_list_halted_targets(
+    state: State<'_, NetworkState>,
+) -> Result<Vec<HaltedTarget>, String> {
+    let now = unix_now();
+    let mut halts: Vec<HaltedTarget> = state
+        .halted_targets
+        .lock()
+        .map_err(|_| "Network state poisoned")?
+        .values()
+        .filter(|h| h.expires_at > now)
+        .cloned()
+        .collect();
+    halts.sort_by_key(|h| h.halted_at);
+    Ok(halts)
+}
+
+/// Lifts the halt on `target`, or on every peer when `target` is omitted, and makes
+/// the messages queued for them due again.
+#[tauri::command]
+pub async fn network_clear_halted_targets<R: Runtime>(
+    app: AppHandle<R>,
+    db_state: State<'_, DbState>,
+    net_state: State<'_, NetworkState>,
+    target: Option<String>,
+) -> Result<(), String> {
+    let cleared: Vec<String> = {
+        let mut halts = net_state
+            .halted_targets
+            .lock()
+            .map_err(|_| "Network state poisoned")?;
+        match target {
+            Some(t) => halts
+                .remove(&halt_key(&t))
+                .map(|h| h.target)
+                .into_iter()
+                .collect(),
+            None => halts.drain().map(|(key, _)| key).collect(),
+        }
+    };
+    if cleared.is_empty() {
+        return Err("No such halted target".into());
+    }
+    {
+        let conn = db_state.get_conn()?;
+        for key in &cleared {
+            conn.execute("DELETE FROM halted_targets WHERE target = ?1", [key])
+                .map_err(|e| e.to_string())?;
+            let routing = FrameCodec::routing_header(key);
+            conn.execute(
+                "UPDATE pending_outbox SET next_attempt_at = 0 WHERE substr(content, 1, ?1) = ?2",
+                rusqlite::params![ROUTING_LEN as i64, routing.to_vec()],
+            )
+            .map_err(|e| e.to_string())?;
+        }
+    }
+    let authenticated = net_state
+        .is_authenticated
+        .lock()
+        .map(|l| *l)
+        .unwrap_or(false);
+    if authenticated {
+        flush_outbox(app.clone(), net_state).await?;
+    }
+    Ok(())
+}
@@ -1,7 +1,21 @@
+pub mod codec;
+pub mod config;
+pub use config::*;
+pub mod halts;
+pub use halts::*;
 pub mod pacing;
+pub use pacing::*;
 pub mod request;
+pub mod retry;
 pub(crate) use request::*;
+pub use retry::*;
+pub mod relay_protocol;
+pub mod relays;
+pub use relays::*;
 pub mod session;
 pub use session::*;
+pub mod stats;
+pub use stats::*;
+pub mod sweeper;
 pub mod transit;
 pub use transit::*;
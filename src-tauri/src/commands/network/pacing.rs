@@ -1,11 +1,38 @@
+//! Paced Writer Queues
+//!
+//! Everything bound for the relay passes through per-class queues drained by the
+//! connection's writer task in weighted fair order, so receipts and text are not stuck
+//! behind a long media upload. Packets are padded to `PACKET_SIZE` by the writer
+//! regardless of class.
+//!
+//! In the default random cover mode the writer sends packets as they arrive and fills
+//! idle gaps with dummies. In constant-rate mode it sends exactly one packet per tick,
+//! a dummy when nothing is queued, and lets queued media burst above that rate only
+//! as far as `BurstAllowance` and the bandwidth budget permit.
+
+use super::codec::{
+    FRAME_CONTROL, FRAME_MEDIA, FRAME_SIGNAL, FRAME_VOLATILE, FrameCodec, INLINE_JSON_LIMIT,
+    ROUTING_LEN,
+};
+use super::config::NetworkConfig;
 use crate::app_state::{NetworkState, PacedMessage};
-use tauri::Manager;
+use crate::noise::TrafficNormalizer;
+use serde::Serialize;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tauri::{Manager, Runtime, State};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::SendError;
 use tokio_tungstenite::tungstenite::Utf8Bytes;
 use tokio_tungstenite::tungstenite::protocol::Message;
 
-pub const PACKET_SIZE: usize = 1400;
+pub use super::codec::PACKET_SIZE;
 
-pub async fn send_paced_json(app: &tauri::AppHandle, val: serde_json::Value) -> Result<(), String> {
+pub async fn send_paced_json<R: Runtime>(
+    app: &tauri::AppHandle<R>,
+    val: serde_json::Value,
+) -> Result<(), String> {
     let json_str = serde_json::to_string(&val).map_err(|e| e.to_string())?;
     let raw_len = json_str.len();
 
@@ -18,26 +45,17 @@ pub async fn send_paced_json(app: &tauri::AppHandle, val: serde_json::Value) ->
         tx_lock.clone().ok_or("Network not connected")?
     };
 
-    if raw_len > 1200 {
-        let data_bytes = json_str.into_bytes();
-        let chunks = (data_bytes.len() as f32 / 1200.0).ceil() as usize;
-        let transfer_id: u32 = rand::random();
-        let zero_hash = vec![0u8; 64];
-
-        for i in 0..chunks {
-            let start = i * 1200;
-            let end = std::cmp::min(start + 1200, data_bytes.len());
-            let chunk_data = &data_bytes[start..end];
-            let mut envelope = Vec::with_capacity(PACKET_SIZE);
-            envelope.extend_from_slice(&zero_hash);
-            envelope.push(0x00);
-            envelope.extend_from_slice(&transfer_id.to_be_bytes());
-            envelope.extend_from_slice(&(i as u32).to_be_bytes());
-            envelope.extend_from_slice(&(chunks as u32).to_be_bytes());
-            envelope.extend_from_slice(&(chunk_data.len() as u32).to_be_bytes());
-            envelope.extend_from_slice(chunk_data);
+    if raw_len > INLINE_JSON_LIMIT {
+        let packets = FrameCodec::encode(
+            &[0u8; ROUTING_LEN],
+            FRAME_CONTROL,
+            rand::random(),
+            json_str.as_bytes(),
+        );
+        for packet in packets {
             tx.send(PacedMessage {
-                msg: Message::Binary(envelope.into()),
+                msg: Message::Binary(packet.into()),
+                msg_id: None,
             })
             .await
             .map_err(|e| e.to_string())?;
@@ -45,9 +63,306 @@ pub async fn send_paced_json(app: &tauri::AppHandle, val: serde_json::Value) ->
     } else {
         tx.send(PacedMessage {
             msg: Message::Text(Utf8Bytes::from(json_str)),
+            msg_id: None,
         })
         .await
         .map_err(|e| e.to_string())?;
     }
     Ok(())
 }
+
+/// Traffic classes of the paced writer, in descending priority.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrafficClass {
+    /// Relay requests, auth and other connection-scoped JSON.
+    Control = 0,
+    /// Peer-addressed signal transfers: text, receipts, reactions, metadata.
+    Text = 1,
+    /// Typing indicators and resend requests.
+    Volatile = 2,
+    /// Media fragments.
+    Media = 3,
+}
+
+pub const CLASS_COUNT: usize = 4;
+
+/// Packets each class may send per scheduling round.
+const CLASS_WEIGHTS: [u32; CLASS_COUNT] = [8, 4, 2, 1];
+/// Queue capacity per class; a full media queue only stalls media producers.
+const CLASS_CAPACITY: [usize; CLASS_COUNT] = [64, 256, 64, 100];
+
+impl TrafficClass {
+    const ALL: [TrafficClass; CLASS_COUNT] = [
+        TrafficClass::Control,
+        TrafficClass::Text,
+        TrafficClass::Volatile,
+        TrafficClass::Media,
+    ];
+
+    pub fn of(msg: &Message) -> TrafficClass {
+        let Message::Binary(packet) = msg else {
+            return TrafficClass::Control;
+        };
+        match FrameCodec::frame_type(packet) {
+            Some(FRAME_SIGNAL) => TrafficClass::Text,
+            Some(FRAME_VOLATILE) => TrafficClass::Volatile,
+            Some(FRAME_MEDIA) => TrafficClass::Media,
+            _ => TrafficClass::Control,
+        }
+    }
+}
+
+/// Deficit round robin over the traffic classes. Every packet has the same padded size,
+/// so deficits are counted in packets: a class with work may send up to its weight
+/// before the next class with work gets a turn, and idle classes bank nothing.
+pub struct DrrScheduler {
+    weights: [u32; CLASS_COUNT],
+    deficits: [u32; CLASS_COUNT],
+    cursor: usize,
+}
+
+impl DrrScheduler {
+    pub fn new(weights: [u32; CLASS_COUNT]) -> Self {
+        Self {
+            weights,
+            deficits: [0; CLASS_COUNT],
+            cursor: 0,
+        }
+    }
+
+    /// Picks the class to send from next among those `ready`, or `None` if none is.
+    pub fn pick(&mut self, ready: impl Fn(usize) -> bool) -> Option<usize> {
+        for _ in 0..=CLASS_COUNT {
+            let class = self.cursor;
+            if !ready(class) {
+                self.deficits[class] = 0;
+            } else {
+                if self.deficits[class] == 0 {
+                    self.deficits[class] = self.weights[class];
+                }
+                self.deficits[class] -= 1;
+                if self.deficits[class] == 0 {
+                    self.cursor = (class + 1) % CLASS_COUNT;
+                }
+                return Some(class);
+            }
+            self.cursor = (class + 1) % CLASS_COUNT;
+        }
+        None
+    }
+}
+
+/// Token bucket for media sent above the constant rate. It refills with whatever the
+/// bandwidth budget leaves over after one packet per tick, up to `media_burst_packets`.
+pub struct BurstAllowance {
+    tokens: f64,
+    capacity: f64,
+    refill_per_tick: f64,
+}
+
+impl BurstAllowance {
+    pub fn new(config: &NetworkConfig) -> Self {
+        let pps = config.constant_rate_pps.max(1) as f64;
+        let budget_pps = config.bandwidth_budget_bytes_per_sec as f64 / PACKET_SIZE as f64;
+        let capacity = config.media_burst_packets as f64;
+        Self {
+            tokens: capacity,
+            capacity,
+            refill_per_tick: (budget_pps - pps).max(0.0) / pps,
+        }
+    }
+
+    pub fn tick(&mut self) {
+        self.tokens = (self.tokens + self.refill_per_tick).min(self.capacity);
+    }
+
+    pub fn ready(&self) -> bool {
+        self.tokens >= 1.0
+    }
+
+    /// Spends one extra packet if the allowance has one.
+    pub fn take(&mut self) -> bool {
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Pads an outgoing packet to `PACKET_SIZE`. Control frames cannot be padded and
+/// are returned unchanged; the writer sends its pings outside the queue.
+pub(crate) fn pad_to_packet(msg: &Message) -> Message {
+    match msg {
+        Message::Text(text) => {
+            let mut final_json: String = text.to_string();
+            TrafficNormalizer::pad_json_str(&mut final_json, PACKET_SIZE);
+            Message::Text(Utf8Bytes::from(final_json))
+        }
+        Message::Binary(data) => {
+            let mut data_vec = data.to_vec();
+            TrafficNormalizer::pad_binary(&mut data_vec, PACKET_SIZE);
+            Message::Binary(data_vec.into())
+        }
+        other => other.clone(),
+    }
+}
+
+/// Interval between packets in constant-rate mode.
+pub fn tick_interval(config: &NetworkConfig) -> Duration {
+    Duration::from_secs_f64(1.0 / config.constant_rate_pps.max(1) as f64)
+}
+
+/// Current number of queued packets per class.
+#[derive(Debug, Serialize, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueDepths {
+    pub control: usize,
+    pub text: usize,
+    pub volatile: usize,
+    pub media: usize,
+}
+
+type Depths = Arc<[AtomicUsize; CLASS_COUNT]>;
+
+/// Producer side of the paced writer's class queues.
+#[derive(Clone)]
+pub struct PacedSender {
+    queues: [mpsc::Sender<PacedMessage>; CLASS_COUNT],
+    depths: Depths,
+}
+
+impl PacedSender {
+    /// Queues a packet on its traffic class, waiting while that class is full.
+    pub async fn send(&self, paced: PacedMessage) -> Result<(), SendError<PacedMessage>> {
+        let class = TrafficClass::of(&paced.msg) as usize;
+        match self.queues[class].reserve().await {
+            Ok(permit) => {
+                self.depths[class].fetch_add(1, Ordering::Relaxed);
+                permit.send(paced);
+                Ok(())
+            }
+            Err(_) => Err(SendError(paced)),
+        }
+    }
+
+    pub fn depths(&self) -> QueueDepths {
+        let d = |class: TrafficClass| self.depths[class as usize].load(Ordering::Relaxed);
+        QueueDepths {
+            control: d(TrafficClass::Control),
+            text: d(TrafficClass::Text),
+            volatile: d(TrafficClass::Volatile),
+            media: d(TrafficClass::Media),
+        }
+    }
+}
+
+/// Writer side: yields queued packets in weighted fair order.
+pub struct PacedReceiver {
+    queues: [mpsc::Receiver<PacedMessage>; CLASS_COUNT],
+    heads: [Option<PacedMessage>; CLASS_COUNT],
+    scheduler: DrrScheduler,
+    depths: Depths,
+}
+
+pub fn paced_channel() -> (PacedSender, PacedReceiver) {
+    let depths: Depths = Arc::new(Default::default());
+    let [(s0, r0), (s1, r1), (s2, r2), (s3, r3)] =
+        std::array::from_fn(|class| mpsc::channel::<PacedMessage>(CLASS_CAPACITY[class]));
+    (
+        PacedSender {
+            queues: [s0, s1, s2, s3],
+            depths: depths.clone(),
+        },
+        PacedReceiver {
+            queues: [r0, r1, r2, r3],
+            heads: Default::default(),
+            scheduler: DrrScheduler::new(CLASS_WEIGHTS),
+            depths,
+        },
+    )
+}
+
+impl PacedReceiver {
+    fn take(&mut self, class: usize) -> Option<PacedMessage> {
+        let paced = self.heads[class].take()?;
+        self.depths[class].fetch_sub(1, Ordering::Relaxed);
+        Some(paced)
+    }
+
+    fn fill_heads(&mut self) {
+        for (head, queue) in self.heads.iter_mut().zip(self.queues.iter_mut()) {
+            if head.is_none() {
+                *head = queue.try_recv().ok();
+            }
+        }
+    }
+
+    /// Next queued packet by class weight, without waiting.
+    pub fn try_recv(&mut self) -> Option<PacedMessage> {
+        self.fill_heads();
+        let heads = &self.heads;
+        let class = self.scheduler.pick(|c| heads[c].is_some())?;
+        self.take(class)
+    }
+
+    /// Next queued packet of one class, outside the weighted order.
+    pub fn try_recv_class(&mut self, class: TrafficClass) -> Option<PacedMessage> {
+        let c = class as usize;
+        if self.heads[c].is_none() {
+            self.heads[c] = self.queues[c].try_recv().ok();
+        }
+        self.take(c)
+    }
+
+    /// Next packet by class weight; `None` once every producer is gone and all queues
+    /// are empty.
+    pub async fn recv(&mut self) -> Option<PacedMessage> {
+        loop {
+            if let Some(paced) = self.try_recv() {
+                return Some(paced);
+            }
+            let [control, text, volatile, media] = &mut self.queues;
+            let (class, paced) = tokio::select! {
+                Some(p) = control.recv() => (TrafficClass::Control, p),
+                Some(p) = text.recv() => (TrafficClass::Text, p),
+                Some(p) = volatile.recv() => (TrafficClass::Volatile, p),
+                Some(p) = media.recv() => (TrafficClass::Media, p),
+                else => return None,
+            };
+            self.heads[class as usize] = Some(paced);
+        }
+    }
+
+    /// Stops accepting packets; those already queued can still be drained.
+    pub fn close(&mut self) {
+        for queue in &mut self.queues {
+            queue.close();
+        }
+    }
+
+    /// Removes every queued packet, highest priority class first.
+    pub fn drain(&mut self) -> Vec<PacedMessage> {
+        let mut drained = Vec::new();
+        for class in TrafficClass::ALL {
+            let c = class as usize;
+            drained.extend(self.take(c));
+            while let Ok(paced) = self.queues[c].try_recv() {
+                self.depths[c].fetch_sub(1, Ordering::Relaxed);
+                drained.push(paced);
+            }
+        }
+        drained
+    }
+}
+
+#[tauri::command]
+pub fn network_get_queue_depths(state: State<'_, NetworkState>) -> Result<QueueDepths, String> {
+    let sender = state
+        .sender
+        .lock()
+        .map_err(|_| "Network state poisoned")?
+        .clone();
+    Ok(sender.map(|s| s.depths()).unwrap_or_default())
+}
@@ -0,0 +1,425 @@
+//! Relay Wire Protocol
+//!
+//! Typed representation of the JSON control messages exchanged with the relay.
+//! `ClientMessage` covers every client→relay request and `RelayMessage` every
+//! unsolicited relay→client message; responses routed by `req_id` are decoded into
+//! the per-request response structs. Field names match the existing wire format
+//! exactly, so relays that predate this module interoperate unchanged.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Revision of the control protocol spoken by this client, announced in `auth`.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProtocolError {
+    /// Not JSON, no `type`, or a known message whose fields do not decode.
+    Malformed(String),
+    /// Well-formed JSON carrying a `type` this client does not understand.
+    UnknownType(String),
+    /// The relay speaks a protocol revision this client cannot talk to.
+    VersionMismatch { relay: u32, client: u32 },
+    /// The relay answered a request with an `error` message.
+    Relay {
+        kind: RelayErrorKind,
+        message: String,
+    },
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Malformed(e) => write!(f, "Malformed relay message: {}", e),
+            ProtocolError::UnknownType(t) => write!(f, "Unknown relay message type: {}", t),
+            ProtocolError::VersionMismatch { relay, client } => write!(
+                f,
+                "Relay protocol v{} is incompatible with client v{}",
+                relay, client
+            ),
+            ProtocolError::Relay { message, .. } => write!(f, "Relay error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<ProtocolError> for String {
+    fn from(e: ProtocolError) -> Self {
+        e.to_string()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayErrorKind {
+    Jailed,
+    AuthFailed,
+    VersionMismatch,
+    Other,
+}
+
+impl RelayErrorKind {
+    /// Classifies a relay error by its `code`, falling back to the message text
+    /// for relays that only report human-readable errors.
+    pub fn classify(code: Option<&str>, message: &str) -> Self {
+        match code {
+            Some("jailed") => return RelayErrorKind::Jailed,
+            Some("auth_failed") | Some("invalid_token") => return RelayErrorKind::AuthFailed,
+            Some("version_mismatch") | Some("unsupported_version") => {
+                return RelayErrorKind::VersionMismatch;
+            }
+            _ => {}
+        }
+        if message.contains("Jailed") {
+            RelayErrorKind::Jailed
+        } else if message.contains("Invalid Token")
+            || message.contains("Handshake failed")
+            || message.contains("Challenge")
+        {
+            RelayErrorKind::AuthFailed
+        } else {
+            RelayErrorKind::Other
+        }
+    }
+}
+
+/// A relay that does not report its revision predates versioning and speaks v1.
+pub fn check_version(relay_version: Option<u32>) -> Result<(), ProtocolError> {
+    match relay_version {
+        Some(v) if v != PROTOCOL_VERSION => Err(ProtocolError::VersionMismatch {
+            relay: v,
+            client: PROTOCOL_VERSION,
+        }),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum AuthCredentials {
+    Token {
+        session_token: String,
+    },
+    Proof {
+        seed: String,
+        nonce: String,
+        modulus: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        public_key: Option<String>,
+    },
+}
+
+/// A primary device's signed authorization of another device's key to act for its
+/// account. Relays accept a linked device's `auth` and `keys_upload` under the
+/// account hash on the strength of it, and peers check it before fanning out.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceLink {
+    /// Identity hash of the account, the hash of `primary_key`.
+    pub account: String,
+    pub primary_key: String,
+    pub device_id: u32,
+    pub device_key: String,
+    /// Milliseconds since the epoch.
+    pub issued_at: u64,
+    /// Milliseconds since the epoch; the primary renews links it still authorizes
+    /// before then.
+    pub expires_at: u64,
+    pub signature: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuthPayload {
+    pub identity_hash: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol_version: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<DeviceLink>,
+    #[serde(flatten)]
+    pub credentials: AuthCredentials,
+}
+
+impl AuthPayload {
+    pub fn new(identity_hash: String, credentials: AuthCredentials) -> Self {
+        Self {
+            identity_hash,
+            protocol_version: Some(PROTOCOL_VERSION),
+            device_id: None,
+            link: None,
+            credentials,
+        }
+    }
+
+    /// Authenticates as the linked device `link` names; a primary passes `None`.
+    pub fn for_device(mut self, link: Option<DeviceLink>) -> Self {
+        self.device_id = link.as_ref().map(|l| l.device_id);
+        self.link = link;
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SignedKeyUpload {
+    pub id: u32,
+    pub public_key: String,
+    pub signature: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PreKeyUpload {
+    pub id: u32,
+    pub public_key: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    PowChallenge {
+        identity_hash: String,
+    },
+    Auth {
+        payload: AuthPayload,
+    },
+    SessionRevoke,
+    KeysUpload {
+        identity_hash: String,
+        #[serde(rename = "registrationId")]
+        registration_id: u32,
+        #[serde(rename = "identityKey")]
+        identity_key: String,
+        #[serde(rename = "signedPreKey")]
+        signed_pre_key: SignedKeyUpload,
+        #[serde(rename = "preKeys")]
+        pre_keys: Vec<PreKeyUpload>,
+        #[serde(rename = "kyberPreKey")]
+        kyber_pre_key: SignedKeyUpload,
+        /// One-time Kyber keys, served ahead of the last-resort `kyberPreKey`.
+        #[serde(
+            rename = "kyberPreKeys",
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        kyber_pre_keys: Option<Vec<SignedKeyUpload>>,
+        signature: String,
+        /// Set by linked devices, whose bundles are published under `account.device_id`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        device_id: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        link: Option<DeviceLink>,
+        /// Set by a primary: the linked devices it still authorizes.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        devices: Option<Vec<u32>>,
+    },
+    FetchKey {
+        target_hash: String,
+        initiator_hash: String,
+    },
+    NicknameRegister {
+        identity_hash: String,
+        nickname: String,
+        public_key: String,
+        signature: String,
+    },
+    NicknameLookup {
+        name: String,
+        initiator_hash: String,
+        public_key: String,
+        signature: String,
+    },
+    IdentityResolve {
+        identity_hash: String,
+        initiator_hash: String,
+        public_key: String,
+        signature: String,
+    },
+    AccountBurn {
+        identity_hash: String,
+        public_key: String,
+        signature: String,
+    },
+}
+
+impl ClientMessage {
+    /// Serializes the message into its wire JSON, tagging it with `req_id` when the
+    /// caller expects a routed response.
+    pub fn encode(&self, req_id: Option<&str>) -> Result<serde_json::Value, ProtocolError> {
+        let mut val =
+            serde_json::to_value(self).map_err(|e| ProtocolError::Malformed(e.to_string()))?;
+        if let Some(id) = req_id {
+            val["req_id"] = serde_json::Value::String(id.to_string());
+        }
+        Ok(val)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RelayMessage {
+    PowChallengeRes {
+        seed: String,
+        difficulty: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        modulus: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        req_id: Option<String>,
+    },
+    AuthSuccess {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session_token: Option<String>,
+        #[serde(default)]
+        otk_count: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        protocol_version: Option<u32>,
+    },
+    #[serde(alias = "otk_low")]
+    KeysLow {
+        #[serde(default, alias = "otk_count")]
+        count: u64,
+    },
+    RelaySuccess {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        transfer_id: Option<u32>,
+    },
+    DeliveryStatus {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        transfer_id: Option<u32>,
+    },
+    DeliveryError {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        transfer_id: Option<u32>,
+        #[serde(default)]
+        reason: String,
+        #[serde(default)]
+        target: String,
+    },
+    Error {
+        #[serde(default)]
+        error: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry_after: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        req_id: Option<String>,
+    },
+}
+
+impl RelayMessage {
+    const KNOWN_TYPES: &'static [&'static str] = &[
+        "pow_challenge_res",
+        "auth_success",
+        "keys_low",
+        "otk_low",
+        "relay_success",
+        "delivery_status",
+        "delivery_error",
+        "error",
+    ];
+
+    pub fn from_value(val: &serde_json::Value) -> Result<Self, ProtocolError> {
+        let msg_type = val
+            .get("type")
+            .and_then(|t| t.as_str())
+            .ok_or_else(|| ProtocolError::Malformed("missing type".into()))?;
+        if !Self::KNOWN_TYPES.contains(&msg_type) {
+            return Err(ProtocolError::UnknownType(msg_type.to_string()));
+        }
+        serde_json::from_value(val.clone())
+            .map_err(|e| ProtocolError::Malformed(format!("{}: {}", msg_type, e)))
+    }
+
+    pub fn to_text(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Parses a text frame from the relay, tolerating the trailing space padding.
+pub fn parse_frame(text: &str) -> Result<serde_json::Value, ProtocolError> {
+    let val: serde_json::Value = serde_json::from_str(text.trim_end())
+        .map_err(|e| ProtocolError::Malformed(e.to_string()))?;
+    if !val.is_object() {
+        return Err(ProtocolError::Malformed("frame is not an object".into()));
+    }
+    Ok(val)
+}
+
+/// Converts a routed `error` response into a typed failure; anything else passes through.
+pub fn check_response(val: serde_json::Value) -> Result<serde_json::Value, ProtocolError> {
+    if val.get("type").and_then(|t| t.as_str()) == Some("error") {
+        let message = val["error"].as_str().unwrap_or("Unknown").to_string();
+        let kind = RelayErrorKind::classify(val["code"].as_str(), &message);
+        return Err(ProtocolError::Relay { kind, message });
+    }
+    Ok(val)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct KeysUploadResponse {
+    #[serde(default)]
+    pub status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct FetchKeyResponse {
+    #[serde(default)]
+    pub found: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bundle: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bundles: Option<HashMap<String, serde_json::Value>>,
+}
+
+impl FetchKeyResponse {
+    /// Multi-device relays answer with a `bundles` map keyed by address.
+    pub fn bundle_for(&self, remote_hash: &str) -> Option<serde_json::Value> {
+        let bundle = match &self.bundles {
+            Some(map) => map.get(remote_hash).cloned(),
+            None => self.bundle.clone(),
+        };
+        bundle.filter(|b| !b.is_null())
+    }
+
+    /// Every device bundle published for `account`, by device id. Relays without
+    /// multi-device support only know the primary.
+    pub fn device_bundles(&self, account: &str) -> Vec<(u32, serde_json::Value)> {
+        let mut devices: Vec<(u32, serde_json::Value)> = match &self.bundles {
+            Some(map) => map
+                .iter()
+                .filter(|(_, bundle)| !bundle.is_null())
+                .filter_map(|(address, bundle)| {
+                    let device = match address.split_once('.') {
+                        None if address.eq_ignore_ascii_case(account) => 1,
+                        Some((hash, device)) if hash.eq_ignore_ascii_case(account) => {
+                            device.parse().ok()?
+                        }
+                        _ => return None,
+                    };
+                    Some((device, bundle.clone()))
+                })
+                .collect(),
+            None => self
+                .bundle
+                .clone()
+                .filter(|b| !b.is_null())
+                .map(|b| vec![(1, b)])
+                .unwrap_or_default(),
+        };
+        devices.sort_by_key(|(device, _)| *device);
+        devices
+    }
+}
+
+pub fn decode_response<T: serde::de::DeserializeOwned>(
+    val: serde_json::Value,
+) -> Result<T, ProtocolError> {
+    serde_json::from_value(val).map_err(|e| ProtocolError::Malformed(e.to_string()))
+}
@@ -0,0 +1,176 @@
+//! Relay Endpoint Registry
+//!
+//! Maintains the vault-persisted list of relays the client may connect to.
+//! Endpoints are tried in ascending priority order; the connection loop rotates
+//! to the next endpoint after repeated failures on the active one.
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tauri::{AppHandle, Manager, Runtime, State};
+use url::Url;
+
+use crate::app_state::{DbState, NetworkState};
+
+pub const DEFAULT_RELAY_URL: &str = "wss://relay.entropymessenger.com/ws";
+
+/// Consecutive failed sessions tolerated on one relay before failing over.
+pub const FAILOVER_THRESHOLD: u32 = 3;
+
+/// Shortest authenticated session that does not count against its relay.
+pub const MIN_HEALTHY_SESSION: Duration = Duration::from_secs(60);
+
+/// Whether a session counts as a failure of its relay: it never authenticated,
+/// or it did and dropped again within [`MIN_HEALTHY_SESSION`].
+pub(crate) fn session_failed(authenticated: bool, lasted: Duration) -> bool {
+    !authenticated || lasted < MIN_HEALTHY_SESSION
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RelayEndpoint {
+    pub url: String,
+    #[serde(default)]
+    pub priority: i64,
+    /// Overrides the global routing proxy for this relay only.
+    pub proxy_url: Option<String>,
+    #[serde(default = "default_enabled")]
+    pub is_enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl RelayEndpoint {
+    pub fn default_relay() -> Self {
+        Self {
+            url: DEFAULT_RELAY_URL.to_string(),
+            priority: 0,
+            proxy_url: None,
+            is_enabled: true,
+        }
+    }
+}
+
+fn read_relays(db_state: &DbState) -> Result<Vec<RelayEndpoint>, String> {
+    let conn = db_state.get_conn()?;
+    let mut stmt = conn
+        .prepare(
+            "SELECT url, priority, proxy_url, is_enabled FROM relay_endpoints ORDER BY priority ASC, url ASC",
+        )
+        .map_err(|e| e.to_string())?;
+    let rows = stmt
+        .query_map([], |r| {
+            Ok(RelayEndpoint {
+                url: r.get(0)?,
+                priority: r.get(1)?,
+                proxy_url: r.get(2)?,
+                is_enabled: r.get::<_, i32>(3)? != 0,
+            })
+        })
+        .map_err(|e| e.to_string())?;
+    rows.collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())
+}
+
+/// Resolves the enabled relays in failover order, falling back to the public relay
+/// when the vault is locked or no endpoint has been configured.
+pub(crate) fn load_active_relays<R: Runtime>(app: &AppHandle<R>) -> Vec<RelayEndpoint> {
+    let relays: Vec<RelayEndpoint> = read_relays(&app.state::<DbState>())
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|r| r.is_enabled)
+        .collect();
+    if relays.is_empty() {
+        vec![RelayEndpoint::default_relay()]
+    } else {
+        relays
+    }
+}
+
+fn validate_relay(relay: &RelayEndpoint) -> Result<(), String> {
+    let url = Url::parse(&relay.url).map_err(|e| format!("Invalid relay URL: {}", e))?;
+    if url.scheme() != "wss" && url.scheme() != "ws" {
+        return Err(format!("Relay URL must use ws:// or wss://: {}", relay.url));
+    }
+    if url.host_str().is_none() {
+        return Err(format!("Relay URL has no host: {}", relay.url));
+    }
+    if let Some(p) = &relay.proxy_url {
+        let proxy = Url::parse(p).map_err(|e| format!("Invalid proxy URL: {}", e))?;
+        if proxy.scheme() != "socks5" && proxy.scheme() != "socks5h" {
+            return Err(format!("Relay proxy must be a socks5:// URL: {}", p));
+        }
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub fn network_get_relays(state: State<'_, DbState>) -> Result<Vec<RelayEndpoint>, String> {
+    let relays = read_relays(&state)?;
+    if relays.is_empty() {
+        return Ok(vec![RelayEndpoint::default_relay()]);
+    }
+    Ok(relays)
+}
+
+#[tauri::command]
+pub fn network_set_relays<R: Runtime>(
+    app: AppHandle<R>,
+    state: State<'_, DbState>,
+    relays: Vec<RelayEndpoint>,
+) -> Result<(), String> {
+    for relay in &relays {
+        validate_relay(relay)?;
+    }
+    if !relays.is_empty() && !relays.iter().any(|r| r.is_enabled) {
+        return Err("At least one relay must remain enabled".into());
+    }
+
+    let mut conn = state.get_conn()?;
+    let tx = conn.transaction().map_err(|e| e.to_string())?;
+    tx.execute("DELETE FROM relay_endpoints", [])
+        .map_err(|e| e.to_string())?;
+    for relay in &relays {
+        tx.execute(
+            "INSERT OR REPLACE INTO relay_endpoints (url, priority, proxy_url, is_enabled) VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![
+                relay.url,
+                relay.priority,
+                relay.proxy_url,
+                relay.is_enabled as i32
+            ],
+        )
+        .map_err(|e| e.to_string())?;
+    }
+    tx.commit().map_err(|e| e.to_string())?;
+
+    // The running loop picks the new order up on its next connection attempt
+    let net_state = app.state::<NetworkState>();
+    if let Ok(mut l) = net_state.relays.lock() {
+        *l = load_active_relays(&app);
+    }
+    if let Ok(mut l) = net_state.active_relay.lock() {
+        *l = 0;
+    }
+    Ok(())
+}
+
+pub(crate) fn current_relay(state: &NetworkState) -> Option<RelayEndpoint> {
+    let relays = state.relays.lock().ok()?;
+    if relays.is_empty() {
+        return None;
+    }
+    let idx = state.active_relay.lock().map(|l| *l).unwrap_or(0);
+    relays.get(idx % relays.len()).cloned()
+}
+
+/// Moves the connection loop on to the next relay in priority order, wrapping around.
+pub(crate) fn advance_relay(state: &NetworkState) {
+    let len = state.relays.lock().map(|l| l.len()).unwrap_or(0);
+    if len > 1
+        && let Ok(mut idx) = state.active_relay.lock()
+    {
+        *idx = (*idx + 1) % len;
+    }
+}
@@ -1,4 +1,5 @@
-use super::pacing::PACKET_SIZE;
+use super::codec::{FRAME_CONTROL, FrameCodec, INLINE_JSON_LIMIT, ROUTING_LEN};
+use super::relay_protocol::{ClientMessage, check_response};
 use crate::app_state::{NetworkState, PacedMessage};
 use std::time::Duration;
 use tokio_tungstenite::tungstenite::Utf8Bytes;
@@ -6,13 +7,10 @@ use tokio_tungstenite::tungstenite::protocol::Message;
 
 pub(crate) async fn internal_request(
     state: &NetworkState,
-    msg_type: &str,
-    payload: serde_json::Value,
+    message: ClientMessage,
 ) -> Result<serde_json::Value, String> {
     let req_id = uuid::Uuid::new_v4().to_string();
-    let mut full_payload = payload.clone();
-    full_payload["type"] = serde_json::Value::String(msg_type.to_string());
-    full_payload["req_id"] = serde_json::Value::String(req_id.clone());
+    let full_payload = message.encode(Some(&req_id))?;
 
     let (tx, rx) = tokio::sync::oneshot::channel();
     {
@@ -30,28 +28,18 @@ pub(crate) async fn internal_request(
 
     if let Some(ws_tx) = ws_tx {
         let text = full_payload.to_string();
-        if text.len() > 1200 {
-            let data_bytes = text.into_bytes();
-            let total_len = data_bytes.len();
-            let chunk_capacity = 1319;
-            let chunks = (total_len as f64 / chunk_capacity as f64).ceil() as usize;
-            let transfer_id: u32 = rand::random();
-            let zero_hash = vec![0u8; 64];
-            for i in 0..chunks {
-                let start = i * chunk_capacity;
-                let end = std::cmp::min(start + chunk_capacity, total_len);
-                let chunk_data = &data_bytes[start..end];
-                let mut env = Vec::with_capacity(PACKET_SIZE);
-                env.extend_from_slice(&zero_hash);
-                env.push(0x00);
-                env.extend_from_slice(&transfer_id.to_be_bytes());
-                env.extend_from_slice(&(i as u32).to_be_bytes());
-                env.extend_from_slice(&(chunks as u32).to_be_bytes());
-                env.extend_from_slice(&(chunk_data.len() as u32).to_be_bytes());
-                env.extend_from_slice(chunk_data);
+        if text.len() > INLINE_JSON_LIMIT {
+            let packets = FrameCodec::encode(
+                &[0u8; ROUTING_LEN],
+                FRAME_CONTROL,
+                rand::random(),
+                text.as_bytes(),
+            );
+            for packet in packets {
                 let _ = ws_tx
                     .send(PacedMessage {
-                        msg: Message::Binary(env.into()),
+                        msg: Message::Binary(packet.into()),
+                        msg_id: None,
                     })
                     .await;
             }
@@ -59,6 +47,7 @@ pub(crate) async fn internal_request(
             let _ = ws_tx
                 .send(PacedMessage {
                     msg: Message::Text(Utf8Bytes::from(text)),
+                    msg_id: None,
                 })
                 .await;
         }
@@ -72,7 +61,7 @@ pub(crate) async fn internal_request(
     }
 
     match tokio::time::timeout(Duration::from_secs(10), rx).await {
-        Ok(Ok(res)) => Ok(res),
+        Ok(Ok(res)) => Ok(check_response(res)?),
         _ => {
             let mut channels = state
                 .response_channels
@@ -0,0 +1,369 @@
+//! Outbox Retry Policy
+//!
+//! Rows in `pending_outbox` stay queued until the relay confirms their transfer with
+//! `relay_success`. Each flush sends only the rows that are due, counts the attempt and
+//! schedules the next one with exponential backoff. Messages that run out of attempts
+//! or outlive `outbox_max_age_secs` are dropped from the queue and marked `failed`.
+
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashSet;
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager, Runtime, State};
+use tokio_tungstenite::tungstenite::Utf8Bytes;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use super::codec::FrameCodec;
+use super::config::{NetworkConfig, current_config};
+use super::halts::halted_until;
+use super::transit::mark_pending;
+use crate::app_state::{DbState, NetworkState, PacedMessage};
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OutboxEntry {
+    pub msg_id: String,
+    pub fragments: u32,
+    pub attempts: u32,
+    pub queued_at: i64,
+    pub last_attempt_at: Option<i64>,
+    pub next_attempt_at: i64,
+    pub expires_at: i64,
+    /// `queued` before the first send, `retrying` while awaiting relay confirmation.
+    pub status: String,
+}
+
+struct OutboxRow {
+    id: i64,
+    msg_type: String,
+    content: Vec<u8>,
+    msg_id: Option<String>,
+    transfer_id: Option<u32>,
+    attempts: u32,
+    queued_at: i64,
+}
+
+pub(crate) fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+/// Seconds to wait after the `attempts`-th send before trying again.
+pub(crate) fn retry_delay_secs(attempts: u32, config: &NetworkConfig) -> u64 {
+    let doublings = attempts.saturating_sub(1).min(32);
+    config
+        .outbox_retry_base_secs
+        .saturating_mul(1u64 << doublings)
+        .min(config.outbox_retry_max_secs)
+}
+
+/// Whether a row queued at `queued_at` with `attempts` sends behind it should be given up.
+pub(crate) fn outbox_expired(
+    queued_at: i64,
+    attempts: u32,
+    now: i64,
+    config: &NetworkConfig,
+) -> bool {
+    attempts >= config.outbox_max_attempts
+        || now.saturating_sub(queued_at) >= config.outbox_max_age_secs as i64
+}
+
+pub fn start_outbox_retry<R: Runtime>(app: AppHandle<R>) {
+    tauri::async_runtime::spawn(async move {
+        loop {
+            let interval = current_config(&app.state::<NetworkState>()).outbox_retry_base_secs;
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+            let authenticated = app
+                .state::<NetworkState>()
+                .is_authenticated
+                .lock()
+                .map(|l| *l)
+                .unwrap_or(false);
+            if authenticated {
+                let _ = flush_outbox(app.clone(), app.state::<NetworkState>()).await;
+            }
+        }
+    });
+}
+
+/// Sends the outbox rows that are due. Rows without a transfer id can never be
+/// confirmed and are removed once the writer accepts them; the rest wait for
+/// `relay_success`.
+pub async fn flush_outbox<R: Runtime>(
+    app: AppHandle<R>,
+    state: State<'_, NetworkState>,
+) -> Result<(), String> {
+    {
+        let mut flushing = state
+            .is_flushing
+            .lock()
+            .map_err(|_| "Network state poisoned")?;
+        if *flushing {
+            return Ok(());
+        }
+        *flushing = true;
+    }
+    let result = flush_due(&app, &state).await;
+    if let Ok(mut flushing) = state.is_flushing.lock() {
+        *flushing = false;
+    }
+    result
+}
+
+async fn flush_due<R: Runtime>(app: &AppHandle<R>, state: &NetworkState) -> Result<(), String> {
+    let tx = {
+        let sender_lock = state.sender.lock().map_err(|_| "Network state poisoned")?;
+        sender_lock.clone()
+    };
+    let Some(tx) = tx else {
+        return Ok(());
+    };
+    let config = current_config(state);
+    let now = unix_now();
+    let due = load_due_rows(app, now)?;
+
+    let halts = state
+        .halted_targets
+        .lock()
+        .map_err(|_| "Network state poisoned")?
+        .clone();
+    let mut expired: HashSet<String> = HashSet::new();
+    let mut sent = Vec::new();
+    let mut deferred = Vec::new();
+    let mut retired = Vec::new();
+    for row in due {
+        if let Some(id) = &row.msg_id
+            && expired.contains(id)
+        {
+            continue;
+        }
+        if outbox_expired(row.queued_at, row.attempts, now, &config) {
+            match row.msg_id {
+                Some(id) => {
+                    expired.insert(id);
+                }
+                None => retired.push(row.id),
+            }
+            continue;
+        }
+        // Held back without spending an attempt until the peer's halt lifts
+        if let Ok(frame) = FrameCodec::decode(&row.content)
+            && let Some(until) = halted_until(&halts, &frame.routing, now)
+        {
+            deferred.push((row.id, until));
+            continue;
+        }
+
+        let msg = if row.msg_type == "text" {
+            Message::Text(Utf8Bytes::from(
+                String::from_utf8_lossy(&row.content).to_string(),
+            ))
+        } else {
+            Message::Binary(row.content.into())
+        };
+        // Rows the writer never accepted stay due for the next flush
+        if tx
+            .send(PacedMessage {
+                msg,
+                msg_id: row.msg_id.clone(),
+            })
+            .await
+            .is_err()
+        {
+            break;
+        }
+        match row.transfer_id {
+            Some(tid) => {
+                if let Some(id) = &row.msg_id
+                    && let Ok(mut pending) = state.pending_transfers.lock()
+                {
+                    pending.insert(tid, id.clone());
+                }
+                sent.push((row.id, row.attempts + 1));
+            }
+            None => retired.push(row.id),
+        }
+    }
+
+    let db_state = app.state::<DbState>();
+    let conn = db_state.get_conn()?;
+    for (id, attempts) in sent {
+        let next = now + retry_delay_secs(attempts, &config) as i64;
+        let _ = conn.execute(
+            "UPDATE pending_outbox SET attempts = ?1, last_attempt_at = ?2, next_attempt_at = ?3 WHERE id = ?4",
+            rusqlite::params![attempts, now, next, id],
+        );
+    }
+    for (id, until) in deferred {
+        let _ = conn.execute(
+            "UPDATE pending_outbox SET next_attempt_at = ?1 WHERE id = ?2",
+            rusqlite::params![until, id],
+        );
+    }
+    for id in retired {
+        let _ = conn.execute("DELETE FROM pending_outbox WHERE id = ?1", [id]);
+    }
+    drop(conn);
+    for msg_id in expired {
+        fail_queued_message(app, &msg_id);
+    }
+    Ok(())
+}
+
+fn load_due_rows<R: Runtime>(app: &AppHandle<R>, now: i64) -> Result<Vec<OutboxRow>, String> {
+    let db_state = app.state::<DbState>();
+    let conn = db_state.get_conn()?;
+    let mut stmt = conn
+        .prepare(
+            "SELECT id, msg_type, content, msg_id, transfer_id, attempts, timestamp
+             FROM pending_outbox WHERE next_attempt_at <= ?1 ORDER BY id ASC",
+        )
+        .map_err(|e| e.to_string())?;
+    let rows = stmt
+        .query_map([now], |row| {
+            Ok(OutboxRow {
+                id: row.get(0)?,
+                msg_type: row.get(1)?,
+                content: row.get(2)?,
+                msg_id: row.get(3)?,
+                transfer_id: row.get(4)?,
+                attempts: row.get(5)?,
+                queued_at: row.get(6)?,
+            })
+        })
+        .map_err(|e| e.to_string())?;
+    Ok(rows.flatten().collect())
+}
+
+/// Retires the outbox rows of a transfer the relay has accepted. Rows not yet sent are
+/// kept, since the relay may confirm fragment by fragment.
+pub(crate) fn confirm_outbox_transfer<R: Runtime>(app: &AppHandle<R>, transfer_id: Option<u32>) {
+    let Some(tid) = transfer_id else {
+        return;
+    };
+    if let Ok(conn) = app.state::<DbState>().get_conn() {
+        let _ = conn.execute(
+            "DELETE FROM pending_outbox WHERE transfer_id = ?1 AND attempts > 0",
+            [tid],
+        );
+    }
+}
+
+/// Drops every queued row of a message and marks it `failed`, unless it already
+/// reached the peer.
+fn fail_queued_message<R: Runtime>(app: &AppHandle<R>, msg_id: &str) {
+    let db_state = app.state::<DbState>();
+    let Ok(conn) = db_state.get_conn() else {
+        return;
+    };
+    let _ = conn.execute("DELETE FROM pending_outbox WHERE msg_id = ?1", [msg_id]);
+    let chat_address: Option<String> = conn
+        .query_row(
+            "SELECT chat_address FROM messages WHERE id = ?1",
+            [msg_id],
+            |r| r.get(0),
+        )
+        .ok();
+    let updated = conn
+        .execute(
+            "UPDATE messages SET status = 'failed' WHERE id = ?1 AND status NOT IN ('delivered', 'read')",
+            [msg_id],
+        )
+        .unwrap_or(0);
+    if updated > 0 {
+        let _ = app.emit(
+            "msg://status",
+            json!({ "id": msg_id, "status": "failed", "chat_address": chat_address }),
+        );
+    }
+}
+
+#[tauri::command]
+pub fn outbox_list(
+    db_state: State<'_, DbState>,
+    net_state: State<'_, NetworkState>,
+    chat_address: String,
+) -> Result<Vec<OutboxEntry>, String> {
+    let config = current_config(&net_state);
+    let conn = db_state.get_conn()?;
+    let mut stmt = conn
+        .prepare(
+            "SELECT o.msg_id, COUNT(*), MAX(o.attempts), MIN(o.timestamp), MAX(o.last_attempt_at), MIN(o.next_attempt_at)
+             FROM pending_outbox o JOIN messages m ON m.id = o.msg_id
+             WHERE LOWER(m.chat_address) = LOWER(?1)
+             GROUP BY o.msg_id ORDER BY MIN(o.id) ASC",
+        )
+        .map_err(|e| e.to_string())?;
+    let rows = stmt
+        .query_map([&chat_address], |row| {
+            let attempts: u32 = row.get(2)?;
+            let queued_at: i64 = row.get(3)?;
+            Ok(OutboxEntry {
+                msg_id: row.get(0)?,
+                fragments: row.get(1)?,
+                attempts,
+                queued_at,
+                last_attempt_at: row.get(4)?,
+                next_attempt_at: row.get(5)?,
+                expires_at: queued_at + config.outbox_max_age_secs as i64,
+                status: if attempts == 0 { "queued" } else { "retrying" }.to_string(),
+            })
+        })
+        .map_err(|e| e.to_string())?;
+    Ok(rows.flatten().collect())
+}
+
+/// Resets a queued message's attempts and age and sends it on the next flush.
+#[tauri::command]
+pub async fn outbox_retry<R: Runtime>(
+    app: AppHandle<R>,
+    db_state: State<'_, DbState>,
+    net_state: State<'_, NetworkState>,
+    msg_id: String,
+) -> Result<(), String> {
+    let updated = {
+        let conn = db_state.get_conn()?;
+        conn.execute(
+            "UPDATE pending_outbox SET attempts = 0, next_attempt_at = 0, timestamp = ?1 WHERE msg_id = ?2",
+            rusqlite::params![unix_now(), msg_id],
+        )
+        .map_err(|e| e.to_string())?
+    };
+    if updated == 0 {
+        return Err("Message is not queued".into());
+    }
+    mark_pending(&app, &msg_id, true);
+    let authenticated = net_state
+        .is_authenticated
+        .lock()
+        .map(|l| *l)
+        .unwrap_or(false);
+    if authenticated {
+        flush_outbox(app.clone(), net_state).await?;
+    }
+    Ok(())
+}
+
+/// Removes a queued message from the outbox; it is marked `failed`.
+#[tauri::command]
+pub fn outbox_cancel<R: Runtime>(
+    app: AppHandle<R>,
+    db_state: State<'_, DbState>,
+    msg_id: String,
+) -> Result<(), String> {
+    let queued: i64 = db_state
+        .get_conn()?
+        .query_row(
+            "SELECT COUNT(*) FROM pending_outbox WHERE msg_id = ?1",
+            [&msg_id],
+            |r| r.get(0),
+        )
+        .map_err(|e| e.to_string())?;
+    if queued == 0 {
+        return Err("Message is not queued".into());
+    }
+    fail_queued_message(&app, &msg_id);
+    Ok(())
+}
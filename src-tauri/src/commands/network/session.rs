@@ -5,27 +5,46 @@ use rand::rngs::StdRng;
 use serde_json::json;
 use sha2::{Digest, Sha256};
 use std::time::Duration;
-use tauri::{AppHandle, Emitter, Manager, State};
+use tauri::{AppHandle, Emitter, Manager, Runtime, State};
 use tokio::sync::mpsc;
+use tokio::time::MissedTickBehavior;
 use tokio_socks::tcp::Socks5Stream;
 use tokio_tungstenite::tungstenite::Utf8Bytes;
 use tokio_tungstenite::tungstenite::protocol::Message;
 use url::Url;
 
 use crate::app_state::{DbState, NetworkState, PacedMessage};
+use crate::commands::identity::devices::local_link;
+use crate::commands::messaging::delivery::mark_relay_accepted;
 use crate::commands::messaging::inbox::process_incoming_binary;
+use crate::commands::messaging::outbox::uploads::confirm_upload_fragment;
 use crate::commands::pow::internal_mine_pow;
 use crate::commands::signal::signal_sync_keys;
-use crate::noise::TrafficNormalizer;
 use crate::signal_store::SqliteSignalStore;
 
-use super::pacing::{PACKET_SIZE, send_paced_json};
-use super::transit::flush_outbox;
-const RELAY_URL: &str = "wss://relay.entropymessenger.com/ws";
+use super::codec::FrameCodec;
+use super::config::{CoverTrafficMode, current_config};
+use super::halts::halt_target;
+use super::pacing::{
+    BurstAllowance, TrafficClass, paced_channel, pad_to_packet, send_paced_json, tick_interval,
+};
+use super::relay_protocol::{
+    AuthCredentials, AuthPayload, ClientMessage, ProtocolError, RelayErrorKind, RelayMessage,
+    check_version, parse_frame,
+};
+use super::relays::{
+    FAILOVER_THRESHOLD, advance_relay, current_relay, load_active_relays, session_failed,
+};
+use super::retry::{confirm_outbox_transfer, flush_outbox};
+use super::stats::read_deadline;
+use super::transit::requeue_undelivered;
+
+/// Upper bound on how long a shutdown waits for the writer to persist its backlog.
+const WRITER_DRAIN_TIMEOUT: Duration = Duration::from_secs(3);
 
 #[tauri::command]
-pub async fn revoke_session_token(
-    app: AppHandle,
+pub async fn revoke_session_token<R: Runtime>(
+    app: AppHandle<R>,
     state: State<'_, NetworkState>,
 ) -> Result<(), String> {
     let id_hash = state
@@ -40,10 +59,11 @@ pub async fn revoke_session_token(
     };
 
     if let (Some(_id), Some(tx)) = (id_hash, tx) {
-        let revoke_req = json!({"type": "session_revoke", "req_id": "revoke_op"});
+        let revoke_req = ClientMessage::SessionRevoke.encode(Some("revoke_op"))?;
         let _ = tx
             .send(PacedMessage {
                 msg: Message::Text(Utf8Bytes::from(revoke_req.to_string())),
+                msg_id: None,
             })
             .await;
     }
@@ -66,35 +86,54 @@ pub async fn revoke_session_token(
 }
 
 #[tauri::command]
-pub async fn disconnect_network(state: State<'_, NetworkState>) -> Result<(), String> {
+pub async fn disconnect_network<R: Runtime>(app: AppHandle<R>) -> Result<(), String> {
+    shutdown_network(&app).await;
+    Ok(())
+}
+
+/// Stops the connection loop and waits briefly for the writer task to move the packets
+/// it never sent into `pending_outbox`, so they go out after the next `auth_success`.
+pub(crate) async fn shutdown_network<R: Runtime>(app: &AppHandle<R>) {
+    let state = app.state::<NetworkState>();
     if let Ok(mut l) = state.is_enabled.lock() {
         *l = false;
     }
     if let Ok(mut l) = state.sender.lock() {
         *l = None;
     }
+    let drained = state.writer_drained.lock().ok().and_then(|mut l| l.take());
     if let Ok(mut l) = state.cancel.lock()
         && let Some(token) = l.take()
     {
         token.cancel();
     }
-    if let Ok(mut l) = state.queue.lock() {
-        l.clear();
+    let queued: Vec<PacedMessage> = match state.queue.lock() {
+        Ok(mut l) => l.drain(..).collect(),
+        Err(_) => Vec::new(),
+    };
+    requeue_undelivered(app, queued);
+    if let Some(drained) = drained {
+        let _ = tokio::time::timeout(WRITER_DRAIN_TIMEOUT, drained).await;
     }
-    Ok(())
 }
 
-pub(crate) async fn internal_establish_network(
-    app: AppHandle,
+/// Runs one relay session until it drops. `authenticated` is set once the relay
+/// accepts the login, whether or not the session later ends in an error.
+pub(crate) async fn internal_establish_network<R: Runtime>(
+    app: AppHandle<R>,
     url_str: String,
     proxy_url: Option<String>,
     token: tokio_util::sync::CancellationToken,
+    authenticated: &mut bool,
 ) -> Result<(), String> {
     let url = Url::parse(&url_str).map_err(|e| e.to_string())?;
     let host = url.host_str().ok_or("Invalid host")?;
     let port = url.port_or_known_default().ok_or("Invalid port")?;
 
-    let _ = app.emit("network-status", "connecting");
+    let _ = app.emit(
+        "network-status",
+        json!({ "status": "connecting", "relay": url_str }),
+    );
 
     let mut config = tokio_tungstenite::tungstenite::protocol::WebSocketConfig::default();
     config.max_frame_size = Some(256 * 1024);
@@ -145,13 +184,52 @@ pub(crate) async fn internal_establish_network(
         )
     };
 
-    let (tx, rx) = mpsc::channel::<PacedMessage>(100);
+    // Everything that can fail runs before the session is published, so no early
+    // return leaves a sender behind
+    let id_hash = app
+        .state::<NetworkState>()
+        .identity_hash
+        .lock()
+        .map_err(|_| "Network state poisoned")?
+        .clone();
+    let session_token_lock = app
+        .state::<NetworkState>()
+        .session_token
+        .lock()
+        .map_err(|_| "Network state poisoned")?
+        .clone();
+    let opening_request = if let (Some(id), Some(token_val)) = (id_hash.clone(), session_token_lock)
+    {
+        Some(
+            ClientMessage::Auth {
+                payload: AuthPayload::new(
+                    id,
+                    AuthCredentials::Token {
+                        session_token: token_val,
+                    },
+                )
+                .for_device(local_link(&app)),
+            }
+            .encode(None)?,
+        )
+    } else if let Some(id) = id_hash {
+        Some(ClientMessage::PowChallenge { identity_hash: id }.encode(Some("auto_challenge"))?)
+    } else {
+        None
+    };
+
+    app.state::<NetworkState>().stats.reset_heartbeat();
+    let (tx, rx) = paced_channel();
     let (bin_tx, mut bin_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+    let (drained_tx, drained_rx) = tokio::sync::oneshot::channel::<()>();
 
     let net_state_setup = app.state::<NetworkState>();
     if let Ok(mut l) = net_state_setup.sender.lock() {
         *l = Some(tx);
     }
+    if let Ok(mut l) = net_state_setup.writer_drained.lock() {
+        *l = Some(drained_rx);
+    }
     if let Ok(mut l) = net_state_setup.binary_receiver.lock() {
         *l = Some(bin_tx);
     }
@@ -163,55 +241,110 @@ pub(crate) async fn internal_establish_network(
         }
     });
 
-    let write_token = token.clone();
+    let net_config = current_config(&app.state::<NetworkState>());
+    // The writer stops with its session, not only with the whole connection loop,
+    // so what it still holds is requeued whenever a session ends
+    let session = token.child_token();
+    let write_token = session.clone();
+    let app_writer = app.clone();
+    let cover = net_config.clone();
     tokio::task::spawn_local(async move {
+        let writer_state = app_writer.state::<NetworkState>();
+        let stats = &writer_state.stats;
         let mut rx = rx;
-        let mut next_dummy_sleep = Box::pin(tokio::time::sleep(Duration::from_millis(
-            rand::random::<u64>() % 9000 + 1000,
-        )));
-        loop {
-            tokio::select! {
-                _ = write_token.cancelled() => break,
-                Some(paced) = rx.recv() => {
-                    let mut msg_to_send = paced.msg;
-                    match &mut msg_to_send {
-                        Message::Text(text) => {
-                            let mut final_json: String = text.to_string();
-                            TrafficNormalizer::pad_json_str(&mut final_json, PACKET_SIZE);
-                            msg_to_send = Message::Text(Utf8Bytes::from(final_json));
-                        },
-                        Message::Binary(data) => {
-                            let mut data_vec = data.to_vec();
-                            TrafficNormalizer::pad_binary(&mut data_vec, PACKET_SIZE);
-                            msg_to_send = Message::Binary(data_vec.into());
-                        },
-                        _ => {}
+        let mut unsent = Vec::new();
+        // Pings cannot be padded to a packet (control frames carry at most 125 bytes),
+        // so they bypass the queue instead of taking a constant-rate tick
+        let heartbeat_every = Duration::from_secs(cover.heartbeat_interval_secs);
+        let mut heartbeat = tokio::time::interval_at(
+            tokio::time::Instant::now() + heartbeat_every,
+            heartbeat_every,
+        );
+        heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        match cover.cover_traffic {
+            CoverTrafficMode::Random => {
+                let mut next_dummy_sleep = Box::pin(tokio::time::sleep(Duration::from_millis(
+                    rand::random::<u64>() % 9000 + 1000,
+                )));
+                loop {
+                    tokio::select! {
+                        _ = write_token.cancelled() => break,
+                        _ = heartbeat.tick() => {
+                            let ping = Message::Ping(stats.begin_ping(std::time::Instant::now()).into());
+                            let len = ping.len();
+                            if write.send(ping).await.is_err() { break; }
+                            stats.record_control(len);
+                        }
+                        Some(paced) = rx.recv() => {
+                            let padded = pad_to_packet(&paced.msg);
+                            let len = padded.len();
+                            if write.send(padded).await.is_err() {
+                                unsent.push(paced);
+                                break;
+                            }
+                            stats.record_out(len, false);
+                        }
+                        _ = &mut next_dummy_sleep => {
+                            let dummy_vec = FrameCodec::dummy();
+                            let len = dummy_vec.len();
+                            if write.send(Message::Binary(dummy_vec.into())).await.is_err() { break; }
+                            stats.record_out(len, true);
+                            next_dummy_sleep = Box::pin(tokio::time::sleep(Duration::from_millis(rand::random::<u64>() % 9000 + 1000)));
+                        }
                     }
-                    if write.send(msg_to_send).await.is_err() { break; }
                 }
-                _ = &mut next_dummy_sleep => {
-                    let mut dummy_vec = vec![0u8; PACKET_SIZE];
-                    dummy_vec[0] = 0x03;
-                    if write.send(Message::Binary(dummy_vec.into())).await.is_err() { break; }
-                    next_dummy_sleep = Box::pin(tokio::time::sleep(Duration::from_millis(rand::random::<u64>() % 9000 + 1000)));
+            }
+            CoverTrafficMode::ConstantRate => {
+                let mut ticker = tokio::time::interval(tick_interval(&cover));
+                ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                let mut burst = BurstAllowance::new(&cover);
+                'ticks: loop {
+                    tokio::select! {
+                        _ = write_token.cancelled() => break,
+                        _ = heartbeat.tick() => {
+                            let ping = Message::Ping(stats.begin_ping(std::time::Instant::now()).into());
+                            let len = ping.len();
+                            if write.send(ping).await.is_err() { break; }
+                            stats.record_control(len);
+                        }
+                        _ = ticker.tick() => {
+                            burst.tick();
+                            let Some(paced) = rx.try_recv() else {
+                                let dummy_vec = FrameCodec::dummy();
+                                let len = dummy_vec.len();
+                                if write.send(Message::Binary(dummy_vec.into())).await.is_err() { break; }
+                                stats.record_out(len, true);
+                                continue;
+                            };
+                            // Queued media may use the spare budget on top of the tick
+                            let mut batch = vec![paced];
+                            while burst.ready() && let Some(media) = rx.try_recv_class(TrafficClass::Media) {
+                                burst.take();
+                                batch.push(media);
+                            }
+                            let mut batch = batch.into_iter();
+                            while let Some(paced) = batch.next() {
+                                let padded = pad_to_packet(&paced.msg);
+                                let len = padded.len();
+                                if write.send(padded).await.is_err() {
+                                    unsent.push(paced);
+                                    unsent.extend(batch);
+                                    break 'ticks;
+                                }
+                                stats.record_out(len, false);
+                            }
+                        }
+                    }
                 }
             }
         }
+        // Whatever is still buffered never reached the relay; hand it to the outbox
+        rx.close();
+        unsent.extend(rx.drain());
+        requeue_undelivered(&app_writer, unsent);
+        let _ = drained_tx.send(());
     });
 
-    let id_hash = app
-        .state::<NetworkState>()
-        .identity_hash
-        .lock()
-        .map_err(|_| "Network state poisoned")?
-        .clone();
-    let session_token_lock = app
-        .state::<NetworkState>()
-        .session_token
-        .lock()
-        .map_err(|_| "Network state poisoned")?
-        .clone();
-
     let tx_auth = {
         if let Ok(sender_lock) = app.state::<NetworkState>().sender.lock() {
             sender_lock.clone()
@@ -220,39 +353,30 @@ pub(crate) async fn internal_establish_network(
         }
     };
 
-    if let (Some(id), Some(token_val)) = (id_hash.clone(), session_token_lock) {
-        if let Some(tx) = tx_auth {
-            let payload = json!({ "identity_hash": id, "session_token": token_val });
-            let auth_req = json!({"type": "auth", "payload": payload});
-            let _ = tx
-                .send(PacedMessage {
-                    msg: Message::Text(Utf8Bytes::from(auth_req.to_string())),
-                })
-                .await;
-        }
-    } else if let Some(id) = id_hash
+    if let Some(request) = opening_request
         && let Some(tx) = tx_auth
     {
-        let challenge_req =
-            json!({"type": "pow_challenge", "identity_hash": id, "req_id": "auto_challenge"});
         let _ = tx
             .send(PacedMessage {
-                msg: Message::Text(Utf8Bytes::from(challenge_req.to_string())),
+                msg: Message::Text(Utf8Bytes::from(request.to_string())),
+                msg_id: None,
             })
             .await;
     }
 
-    loop {
+    // Every way out of the session goes through the cleanup below
+    let outcome: Result<(), String> = loop {
         tokio::select! {
-            _ = token.cancelled() => break,
-            res = tokio::time::timeout(Duration::from_secs(60), read.next()) => {
+            _ = token.cancelled() => break Ok(()),
+            res = tokio::time::timeout(read_deadline(&net_config), read.next()) => {
                 match res {
                     Ok(Some(Ok(msg))) => {
+                        app.state::<NetworkState>().stats.record_in(msg.len());
                         match msg {
                             Message::Text(text) => {
                                 let text_str = text.to_string();
                                 let mut handled = false;
-                                if let Ok(val) = serde_json::from_str::<serde_json::Value>(&text_str) {
+                                let parsed = parse_frame(&text_str).and_then(|val| {
                                     if let Some(req_id) = val.get("req_id").and_then(|r| r.as_str()) {
                                         let net_state = app.state::<NetworkState>();
                                         if let Ok(mut channels) = net_state.response_channels.lock()
@@ -262,154 +386,156 @@ pub(crate) async fn internal_establish_network(
                                             handled = true;
                                         }
                                     }
-                                    if let Some(msg_type) = val.get("type").and_then(|t| t.as_str()) {
-                                        match msg_type {
-                                            "auth_success" => {
-                                                let net_state = app.state::<NetworkState>();
-                                                if let Ok(mut l) = net_state.is_authenticated.lock() { *l = true; }
-                                                if let Some(token_val) = val.get("session_token").and_then(|t| t.as_str()) {
-                                                    if let Ok(mut l) = net_state.session_token.lock() { *l = Some(token_val.to_string()); }
-                                                    let app_token = app.clone();
-                                                    let token_str = token_val.to_string();
-                                                    tokio::task::spawn_local(async move {
-                                                        let _ = SqliteSignalStore::new(app_token).set_session_token(Some(token_str)).await;
-                                                    });
-                                                }
-                                                let count = val.get("otk_count").and_then(|c| c.as_u64()).unwrap_or(0);
-                                                if count < 50 && let Ok(mut refill_lock) = net_state.is_refilling.lock() && !*refill_lock {
-                                                    *refill_lock = true;
-                                                    let delta = 100_u32.saturating_sub(count as u32);
-                                                    if delta > 0 {
-                                                        let app_sync = app.clone();
-                                                        tokio::task::spawn_local(async move {
-                                                            let _ = signal_sync_keys(app_sync.clone(), Some(delta)).await;
-                                                            if let Ok(mut l) = app_sync.state::<NetworkState>().is_refilling.lock() { *l = false; }
-                                                        });
-                                                    } else { *refill_lock = false; }
-                                                }
-                                                let app_flush = app.clone();
+                                    RelayMessage::from_value(&val)
+                                });
+                                match parsed {
+                                    Ok(RelayMessage::AuthSuccess { session_token, otk_count, protocol_version }) => {
+                                        if let Err(e) = check_version(protocol_version) {
+                                            let _ = app.emit("network-status", json!({ "status": "version_mismatch", "relay": url_str, "error": e.to_string() }));
+                                            break Err(e.into());
+                                        }
+                                        let net_state = app.state::<NetworkState>();
+                                        if let Ok(mut l) = net_state.is_authenticated.lock() { *l = true; }
+                                        *authenticated = true;
+                                        if let Some(token_val) = session_token {
+                                            if let Ok(mut l) = net_state.session_token.lock() { *l = Some(token_val.clone()); }
+                                            let app_token = app.clone();
+                                            tokio::task::spawn_local(async move {
+                                                let _ = SqliteSignalStore::new(app_token).set_session_token(Some(token_val)).await;
+                                            });
+                                        }
+                                        if otk_count < 50 && let Ok(mut refill_lock) = net_state.is_refilling.lock() && !*refill_lock {
+                                            *refill_lock = true;
+                                            let delta = 100_u32.saturating_sub(otk_count as u32);
+                                            if delta > 0 {
+                                                let app_sync = app.clone();
                                                 tokio::task::spawn_local(async move {
-                                                    let _ = flush_outbox(app_flush.clone(), app_flush.state::<NetworkState>()).await;
+                                                    let _ = signal_sync_keys(app_sync.clone(), Some(delta)).await;
+                                                    if let Ok(mut l) = app_sync.state::<NetworkState>().is_refilling.lock() { *l = false; }
                                                 });
-                                                let _ = app.emit("network-status", "authenticated");
-                                                handled = true;
-                                            },
-                                            "keys_low" | "otk_low" => {
-                                                let count = val.get("count").or_else(|| val.get("otk_count")).and_then(|c| c.as_u64()).unwrap_or(0);
-                                                let net_state = app.state::<NetworkState>();
-                                                if let Ok(mut refill_lock) = net_state.is_refilling.lock() && !*refill_lock {
-                                                    *refill_lock = true;
-                                                    let delta = 100_u32.saturating_sub(count as u32);
-                                                    if delta > 0 {
-                                                        let app_sync = app.clone();
-                                                        tokio::task::spawn_local(async move {
-                                                            let _ = signal_sync_keys(app_sync.clone(), Some(delta)).await;
-                                                            if let Ok(mut l) = app_sync.state::<NetworkState>().is_refilling.lock() { *l = false; }
-                                                        });
-                                                    } else { *refill_lock = false; }
-                                                }
-                                                handled = true;
-                                            },
-                                            "relay_success" | "delivery_status" | "delivery_error" => {
-                                                let tid = val.get("transfer_id").and_then(|v| v.as_u64()).map(|v| v as u32);
-                                                let reason = val.get("reason").and_then(|r| r.as_str()).unwrap_or("");
-                                                let target_peer = val.get("target").and_then(|t| t.as_str()).unwrap_or("");
-                                                if let Ok(mut pending) = app.state::<NetworkState>().pending_transfers.lock() {
-                                                    let id_found = if let Some(transfer_id) = tid { pending.remove(&transfer_id) } else { None };
-                                                    if let Some(id) = id_found {
-                                                        let status = if msg_type == "delivery_error" {
-                                                            if reason == "media_offline" { "offline" } else { "failed" }
-                                                        } else { "sent" };
-                                                        let db_state = app.state::<DbState>();
-                                                        if let Ok(conn) = db_state.get_conn() {
-                                                            let chat_info: Option<(String, String)> = conn.query_row(
-                                                                "SELECT chat_address, status FROM messages WHERE LOWER(id) = LOWER(?1)",
-                                                                [&id],
-                                                                |r| Ok((r.get(0)?, r.get(1)?))
-                                                            ).ok();
-                                                            if let Some((addr, current_status)) = chat_info
-                                                                && (current_status == "pending" || current_status == "sending") {
-                                                                    let _ = conn.execute("UPDATE messages SET status = ?1 WHERE LOWER(id) = LOWER(?2)", [status, &id]);
-                                                                    let _ = conn.execute("UPDATE chats SET last_status = ?1 WHERE LOWER(address) = LOWER(?2)", [status, &addr]);
-                                                                    let _ = app.emit("msg://status", serde_json::json!({ "id": id, "status": status, "chat_address": addr }));
+                                            } else { *refill_lock = false; }
+                                        }
+                                        let app_flush = app.clone();
+                                        tokio::task::spawn_local(async move {
+                                            let _ = flush_outbox(app_flush.clone(), app_flush.state::<NetworkState>()).await;
+                                        });
+                                        let _ = app.emit("network-status", json!({ "status": "authenticated", "relay": url_str }));
+                                        handled = true;
+                                    },
+                                    Ok(RelayMessage::KeysLow { count }) => {
+                                        let net_state = app.state::<NetworkState>();
+                                        if let Ok(mut refill_lock) = net_state.is_refilling.lock() && !*refill_lock {
+                                            *refill_lock = true;
+                                            let delta = 100_u32.saturating_sub(count as u32);
+                                            if delta > 0 {
+                                                let app_sync = app.clone();
+                                                tokio::task::spawn_local(async move {
+                                                    let _ = signal_sync_keys(app_sync.clone(), Some(delta)).await;
+                                                    if let Ok(mut l) = app_sync.state::<NetworkState>().is_refilling.lock() { *l = false; }
+                                                });
+                                            } else { *refill_lock = false; }
+                                        }
+                                        handled = true;
+                                    },
+                                    Ok(RelayMessage::RelaySuccess { transfer_id } | RelayMessage::DeliveryStatus { transfer_id }) => {
+                                        record_delivery(&app, transfer_id, "sent");
+                                        confirm_outbox_transfer(&app, transfer_id);
+                                        confirm_upload_fragment(&app, transfer_id);
+                                        mark_relay_accepted(&app.state::<NetworkState>(), transfer_id);
+                                        handled = true;
+                                    },
+                                    Ok(RelayMessage::DeliveryError { transfer_id, reason, target }) => {
+                                        let status = if reason == "media_offline" { "offline" } else { "failed" };
+                                        record_delivery(&app, transfer_id, status);
+                                        if reason != "media_offline" { halt_target(&app, &target, &reason); }
+                                        let _ = app.emit("network-warning", json!({ "type": reason, "target": target }));
+                                        handled = true;
+                                    },
+                                    Ok(RelayMessage::PowChallengeRes { seed, difficulty, modulus, req_id }) if req_id.as_deref().is_none_or(|r| r == "auto_challenge") => {
+                                        let id = app.state::<NetworkState>().identity_hash.lock().map(|l| l.clone()).unwrap_or(None);
+                                        if let Some(i) = id {
+                                            let app_inner = app.clone();
+                                            let existing_token = app_inner.state::<NetworkState>().session_token.lock().map(|l| l.clone()).unwrap_or(None);
+                                            if let Some(token_val) = existing_token {
+                                                tokio::task::spawn_local(async move {
+                                                    let auth = ClientMessage::Auth { payload: AuthPayload::new(i, AuthCredentials::Token { session_token: token_val }).for_device(local_link(&app_inner)) };
+                                                    if let Ok(auth_val) = auth.encode(None) {
+                                                        let _ = send_paced_json(&app_inner, auth_val).await;
+                                                    }
+                                                });
+                                            } else {
+                                                let jailed = if let Ok(l) = app_inner.state::<NetworkState>().jailed_until.lock() {
+                                                    l.as_ref().map(|until| *until > tokio::time::Instant::now()).unwrap_or(false)
+                                                } else { false };
+
+                                                if jailed {
+                                                    let _ = app_inner.emit("network-status", "jailed");
+                                                } else {
+                                                    let _ = app_inner.emit("network-status", "mining");
+                                                    tokio::task::spawn_local(async move {
+                                                        let result = internal_mine_pow(seed.clone(), difficulty, i.clone(), modulus).await;
+                                                        let sig_res = SqliteSignalStore::new(app_inner.clone()).get_identity_key_pair().await.map_err(|e| e.to_string());
+                                                        let (mut signature, mut public_key) = (None, None);
+                                                        if let Ok(kp) = sig_res {
+                                                            let kp: IdentityKeyPair = kp;
+                                                            let mut rng = StdRng::from_os_rng();
+                                                            let seed_bytes = hex::decode(&seed).unwrap_or_else(|_| seed.as_bytes().to_vec());
+                                                            if let Ok(sig) = kp.private_key().calculate_signature(&seed_bytes, &mut rng) {
+                                                                signature = Some(hex::encode(sig));
+                                                                let mut pk = kp.identity_key().serialize().to_vec();
+                                                                if pk.len() == 33 && pk[0] == 0x05 { pk.remove(0); }
+                                                                public_key = Some(hex::encode(pk));
                                                             }
                                                         }
-                                                    }
-                                                }
-                                                if msg_type == "delivery_error" {
-                                                     if !target_peer.is_empty() && reason != "media_offline" && let Ok(mut l) = app.state::<NetworkState>().halted_targets.lock() { l.insert(target_peer.to_string()); }
-                                                     let _ = app.emit("network-warning", json!({ "type": reason, "target": target_peer }));
-                                                }
-                                                handled = true;
-                                            },
-                                            "pow_challenge_res" if val.get("req_id").is_none() || val.get("req_id").and_then(|r| r.as_str()) == Some("auto_challenge") => {
-                                                let seed = val.get("seed").and_then(|s| s.as_str()).map(|s| s.to_string());
-                                                let diff = val.get("difficulty").and_then(|d| d.as_u64()).map(|d| d as u32);
-                                                let id = app.state::<NetworkState>().identity_hash.lock().map(|l| l.clone()).unwrap_or(None);
-                                                let modulus = val.get("modulus").and_then(|m| m.as_str()).map(|s| s.to_string());
-                                                if let (Some(s), Some(d), Some(i)) = (seed, diff, id) {
-                                                    let app_inner = app.clone();
-                                                    let existing_token = app_inner.state::<NetworkState>().session_token.lock().map(|l| l.clone()).unwrap_or(None);
-                                                    if let Some(token_val) = existing_token {
-                                                        tokio::task::spawn_local(async move {
-                                                            let payload = json!({ "identity_hash": i, "session_token": token_val });
-                                                            let auth_val = json!({"type": "auth", "payload": payload});
+                                                        let auth = ClientMessage::Auth {
+                                                            payload: AuthPayload::new(i, AuthCredentials::Proof {
+                                                                seed: result["seed"].as_str().unwrap_or_default().to_string(),
+                                                                nonce: result["nonce"].as_str().unwrap_or_default().to_string(),
+                                                                modulus: result["modulus"].as_str().unwrap_or_default().to_string(),
+                                                                signature,
+                                                                public_key,
+                                                            }).for_device(local_link(&app_inner)),
+                                                        };
+                                                        if let Ok(auth_val) = auth.encode(None) {
                                                             let _ = send_paced_json(&app_inner, auth_val).await;
-                                                        });
-                                                    } else {
-                                                        let jailed = if let Ok(l) = app_inner.state::<NetworkState>().jailed_until.lock() {
-                                                            l.as_ref().map(|until| *until > tokio::time::Instant::now()).unwrap_or(false)
-                                                        } else { false };
-
-                                                        if jailed {
-                                                            let _ = app_inner.emit("network-status", "jailed");
-                                                        } else {
-                                                            let _ = app_inner.emit("network-status", "mining");
-                                                            tokio::task::spawn_local(async move {
-                                                                let result = internal_mine_pow(s.clone(), d, i.clone(), modulus).await;
-                                                                let sig_res = SqliteSignalStore::new(app_inner.clone()).get_identity_key_pair().await.map_err(|e| e.to_string());
-                                                                let mut auth_payload = json!({"identity_hash": i, "seed": result["seed"], "nonce": result["nonce"], "modulus": result["modulus"]});
-                                                                if let Ok(kp) = sig_res {
-                                                                    let kp: IdentityKeyPair = kp;
-                                                                    let mut rng = StdRng::from_os_rng();
-                                                                    let seed_bytes = hex::decode(&s).unwrap_or_else(|_| s.as_bytes().to_vec());
-                                                                    if let Ok(sig) = kp.private_key().calculate_signature(&seed_bytes, &mut rng) {
-                                                                        auth_payload["signature"] = json!(hex::encode(sig));
-                                                                        let mut pk = kp.identity_key().serialize().to_vec();
-                                                                        if pk.len() == 33 && pk[0] == 0x05 { pk.remove(0); }
-                                                                        auth_payload["public_key"] = json!(hex::encode(pk));
-                                                                    }
-                                                                }
-                                                                let auth_val = json!({"type": "auth", "payload": auth_payload});
-                                                                let _ = send_paced_json(&app_inner, auth_val).await;
-                                                            });
                                                         }
-                                                    }
+                                                    });
+                                                }
+                                            }
+                                        }
+                                        handled = true;
+                                    },
+                                    Ok(RelayMessage::Error { error, code, retry_after, .. }) => {
+                                        match RelayErrorKind::classify(code.as_deref(), &error) {
+                                            RelayErrorKind::Jailed => {
+                                                let retry_after = retry_after.unwrap_or(300);
+                                                if let Ok(mut l) = app.state::<NetworkState>().jailed_until.lock() {
+                                                    *l = Some(tokio::time::Instant::now() + Duration::from_secs(retry_after));
                                                 }
+                                                let _ = app.emit("network-status", "jailed");
                                                 handled = true;
                                             },
-                                            "error" => {
-                                                let error_msg = val.get("error").and_then(|e| e.as_str()).unwrap_or("");
-                                                let error_code = val.get("code").and_then(|c| c.as_str()).unwrap_or("");
-                                                if error_msg.contains("Jailed") || error_msg.contains("Identity Jailed") {
-                                                     let retry_after = val.get("retry_after").and_then(|t| t.as_u64()).unwrap_or(300);
-                                                     if let Ok(mut l) = app.state::<NetworkState>().jailed_until.lock() {
-                                                         *l = Some(tokio::time::Instant::now() + Duration::from_secs(retry_after));
-                                                     }
-                                                     let _ = app.emit("network-status", "jailed");
-                                                     handled = true;
-                                                } else if error_code == "auth_failed" || error_msg.contains("Invalid Token") || error_msg.contains("Handshake failed") || error_msg.contains("Challenge") {
-                                                    if let Ok(mut l) = app.state::<NetworkState>().session_token.lock() { *l = None; }
-                                                    if let Ok(mut l) = app.state::<NetworkState>().is_authenticated.lock() { *l = false; }
-                                                    let app_inner = app.clone();
-                                                    tokio::task::spawn_local(async move {
-                                                        let _ = SqliteSignalStore::new(app_inner).set_session_token(None).await;
-                                                    });
-                                                    let _ = app.emit("network-status", "auth_failed");
-                                                    return Err("Handshake/Auth failed - forcing reconnect".into());
-                                                }
+                                            RelayErrorKind::AuthFailed => {
+                                                if let Ok(mut l) = app.state::<NetworkState>().session_token.lock() { *l = None; }
+                                                if let Ok(mut l) = app.state::<NetworkState>().is_authenticated.lock() { *l = false; }
+                                                let app_inner = app.clone();
+                                                tokio::task::spawn_local(async move {
+                                                    let _ = SqliteSignalStore::new(app_inner).set_session_token(None).await;
+                                                });
+                                                let _ = app.emit("network-status", "auth_failed");
+                                                break Err("Handshake/Auth failed - forcing reconnect".into());
+                                            },
+                                            RelayErrorKind::VersionMismatch => {
+                                                let _ = app.emit("network-status", json!({ "status": "version_mismatch", "relay": url_str, "error": error }));
+                                                break Err(error);
                                             },
-                                            _ => {}
+                                            RelayErrorKind::Other => {}
+                                        }
+                                    },
+                                    Ok(_) | Err(ProtocolError::UnknownType(_)) => {},
+                                    Err(e) => {
+                                        if !handled {
+                                            let _ = app.emit("network-warning", json!({ "type": "protocol_error", "error": e.to_string() }));
                                         }
                                     }
                                 }
@@ -418,40 +544,86 @@ pub(crate) async fn internal_establish_network(
                             Message::Binary(bin) => {
                                 let _ = process_incoming_binary(app.clone(), bin.to_vec(), None).await;
                             },
+                            Message::Pong(payload) => {
+                                app.state::<NetworkState>().stats.complete_ping(&payload, std::time::Instant::now());
+                            }
                             _ => {}
                         }
                     },
-                    Ok(Some(Err(_))) => break,
-                    Ok(None) => break,
+                    Ok(Some(Err(_))) => break Ok(()),
+                    Ok(None) => break Ok(()),
                     Err(_) => {
-                        // Timeout reached - potential stale connection
-                        return Err("Network read timeout - potential stale connection".into());
+                        // Not even a pong within the heartbeat deadline
+                        break Err("Network read timeout - potential stale connection".into());
                     }
                 }
             }
         }
-    }
+    };
     if let Ok(mut l) = app.state::<NetworkState>().sender.lock() {
         *l = None;
     }
     if let Ok(mut l) = app.state::<NetworkState>().is_authenticated.lock() {
         *l = false;
     }
+    session.cancel();
+    app.state::<NetworkState>().stats.reset_heartbeat();
     let _ = app.emit("network-status", "disconnected");
-    Ok(())
+    outcome
+}
+
+/// Applies a relay delivery report to the message that owns `transfer_id`.
+fn record_delivery<R: Runtime>(app: &AppHandle<R>, transfer_id: Option<u32>, status: &str) {
+    let id_found = match (
+        transfer_id,
+        app.state::<NetworkState>().pending_transfers.lock(),
+    ) {
+        (Some(tid), Ok(mut pending)) => pending.remove(&tid),
+        _ => None,
+    };
+    let Some(id) = id_found else {
+        return;
+    };
+    let db_state = app.state::<DbState>();
+    if let Ok(conn) = db_state.get_conn() {
+        let chat_info: Option<(String, String)> = conn
+            .query_row(
+                "SELECT chat_address, status FROM messages WHERE LOWER(id) = LOWER(?1)",
+                [&id],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .ok();
+        if let Some((addr, current_status)) = chat_info
+            && (current_status == "pending" || current_status == "sending")
+        {
+            let _ = conn.execute(
+                "UPDATE messages SET status = ?1 WHERE LOWER(id) = LOWER(?2)",
+                [status, &id],
+            );
+            let _ = conn.execute(
+                "UPDATE chats SET last_status = ?1 WHERE LOWER(address) = LOWER(?2)",
+                [status, &addr],
+            );
+            let _ = app.emit(
+                "msg://status",
+                json!({ "id": id, "status": status, "chat_address": addr }),
+            );
+        }
+    }
 }
 
-async fn run_connection_loop(app: AppHandle) {
+async fn run_connection_loop<R: Runtime>(app: AppHandle<R>) {
     let mut retry_count = 0;
+    let mut relay_failures: u32 = 0;
     let backoff = [1, 2, 4, 8, 15, 30, 60];
     'outer_loop: loop {
-        let (enabled, url, proxy_url, token) = {
+        let (enabled, relay, proxy_url, token) = {
             let state = app.state::<NetworkState>();
             let enabled = state.is_enabled.lock().map(|l| *l).unwrap_or(false);
-            let url = state.url.lock().map(|l| l.clone()).unwrap_or(None);
+            let relay = current_relay(&state);
             let proxy_url = state.proxy_url.lock().map(|l| l.clone()).unwrap_or(None);
             let token = state.cancel.lock().map(|l| l.clone()).unwrap_or(None);
-            (enabled, url, proxy_url, token)
+            (enabled, relay, proxy_url, token)
         };
         if !enabled {
             break;
@@ -464,7 +636,7 @@ async fn run_connection_loop(app: AppHandle) {
             break;
         }
 
-        if let Some(target_url) = url {
+        if let Some(relay) = relay {
             // Check if identity is jailed before trying to connect
             let jailed_until = if let Ok(l) = app.state::<NetworkState>().jailed_until.lock() {
                 *l
@@ -486,14 +658,39 @@ async fn run_connection_loop(app: AppHandle) {
                 }
             }
 
-            let _ = app.emit("network-status", "connecting");
-            if internal_establish_network(app.clone(), target_url, proxy_url, token_val.clone())
-                .await
-                .is_err()
-            {
-                // Connection error handled by loop
+            if let Ok(mut l) = app.state::<NetworkState>().url.lock() {
+                *l = Some(relay.url.clone());
+            }
+            // A relay-specific proxy takes precedence over the global routing mode
+            let relay_proxy = relay.proxy_url.clone().or(proxy_url);
+            let started = tokio::time::Instant::now();
+            let mut authenticated = false;
+            let session = internal_establish_network(
+                app.clone(),
+                relay.url.clone(),
+                relay_proxy,
+                token_val.clone(),
+                &mut authenticated,
+            )
+            .await;
+            // Tell the user why a relay is being given up on or retried
+            if let Err(e) = session {
+                let _ = app.emit(
+                    "network-warning",
+                    json!({ "type": "relay_failed", "relay": relay.url, "error": e }),
+                );
+            }
+            // A relay that takes the socket but never logs us in, or keeps dropping
+            // right after, is as unusable as one that refuses the connection
+            if session_failed(authenticated, started.elapsed()) {
+                relay_failures += 1;
+                if relay_failures >= FAILOVER_THRESHOLD {
+                    relay_failures = 0;
+                    advance_relay(&app.state::<NetworkState>());
+                }
             } else {
                 retry_count = 0;
+                relay_failures = 0;
             }
         }
 
@@ -506,11 +703,12 @@ async fn run_connection_loop(app: AppHandle) {
         if !is_enabled || token_val.is_cancelled() {
             break;
         }
+        let next_relay = current_relay(&app.state::<NetworkState>()).map(|r| r.url);
         let delay_sec = backoff[retry_count.min(backoff.len() - 1)];
         for s in (1..=delay_sec).rev() {
             let _ = app.emit(
                 "network-status",
-                json!({ "status": "reconnecting", "seconds": s }),
+                json!({ "status": "reconnecting", "seconds": s, "relay": next_relay }),
             );
             tokio::select! {
                 _ = token_val.cancelled() => break 'outer_loop,
@@ -518,12 +716,13 @@ async fn run_connection_loop(app: AppHandle) {
             }
         }
         retry_count += 1;
+        app.state::<NetworkState>().stats.record_reconnect();
     }
 }
 
 #[tauri::command]
-pub async fn connect_network(
-    app: AppHandle,
+pub async fn connect_network<R: Runtime>(
+    app: AppHandle<R>,
     state: State<'_, NetworkState>,
     proxy_url: Option<String>,
     id_hash: Option<String>,
@@ -570,8 +769,15 @@ pub async fn connect_network(
         if let Ok(mut l) = state.is_enabled.lock() {
             *l = true;
         }
+        let relays = load_active_relays(&app);
         if let Ok(mut l) = state.url.lock() {
-            *l = Some(RELAY_URL.to_string());
+            *l = relays.first().map(|r| r.url.clone());
+        }
+        if let Ok(mut l) = state.relays.lock() {
+            *l = relays;
+        }
+        if let Ok(mut l) = state.active_relay.lock() {
+            *l = 0;
         }
         if let Ok(mut l) = state.proxy_url.lock() {
             *l = proxy_url.clone();
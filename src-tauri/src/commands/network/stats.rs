@@ -0,0 +1,175 @@
+//! Connection Quality
+//!
+//! The writer sends a websocket ping every `heartbeat_interval_secs`, outside the paced
+//! queue and its constant-rate ticks, and the matching pong yields the round-trip time. The reader gives up on a
+//! connection that stays silent for `heartbeat_interval_secs + heartbeat_timeout_secs`,
+//! so a half-dead socket is replaced well before the old fixed 60 s read timeout.
+//! Traffic counters live in `NetworkState.stats` for the app's lifetime; a snapshot
+//! is returned by `network_get_stats` and emitted on `network://stats`.
+
+use serde::Serialize;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter, Manager, Runtime, State};
+
+use super::config::{NetworkConfig, current_config};
+use super::pacing::QueueDepths;
+use crate::app_state::NetworkState;
+
+#[derive(Default)]
+pub struct NetworkStats {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    real_packets: AtomicU64,
+    dummy_packets: AtomicU64,
+    control_packets: AtomicU64,
+    reconnects: AtomicU64,
+    /// Last measured round trip in microseconds; zero until the first pong.
+    rtt_micros: AtomicU64,
+    next_ping: AtomicU64,
+    outstanding_ping: Mutex<Option<(u64, Instant)>>,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct NetworkStatsSnapshot {
+    pub rtt_ms: Option<f64>,
+    pub reconnects: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub real_packets: u64,
+    pub dummy_packets: u64,
+    /// Heartbeat pings, counted apart from the padded packets above.
+    pub control_packets: u64,
+    /// Share of sent packets that were cover traffic, between 0 and 1.
+    pub dummy_ratio: f64,
+    pub queue_depth: QueueDepths,
+    pub relay: Option<String>,
+    pub authenticated: bool,
+}
+
+impl NetworkStats {
+    pub fn record_in(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_out(&self, bytes: usize, dummy: bool) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+        let counter = if dummy {
+            &self.dummy_packets
+        } else {
+            &self.real_packets
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a websocket control frame, which is neither padded nor paced.
+    pub fn record_control(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.control_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Starts a heartbeat and returns the ping payload that identifies it. An earlier
+    /// ping still awaiting its pong is superseded.
+    pub fn begin_ping(&self, now: Instant) -> Vec<u8> {
+        let seq = self.next_ping.fetch_add(1, Ordering::Relaxed);
+        if let Ok(mut outstanding) = self.outstanding_ping.lock() {
+            *outstanding = Some((seq, now));
+        }
+        seq.to_be_bytes().to_vec()
+    }
+
+    /// Completes the heartbeat a pong answers and records its round trip. Pongs for
+    /// superseded or unknown pings are ignored.
+    pub fn complete_ping(&self, payload: &[u8], now: Instant) -> Option<Duration> {
+        let seq = u64::from_be_bytes(payload.try_into().ok()?);
+        let mut outstanding = self.outstanding_ping.lock().ok()?;
+        let (expected, sent_at) = (*outstanding)?;
+        if expected != seq {
+            return None;
+        }
+        *outstanding = None;
+        let rtt = now.saturating_duration_since(sent_at);
+        self.rtt_micros
+            .store((rtt.as_micros() as u64).max(1), Ordering::Relaxed);
+        Some(rtt)
+    }
+
+    /// Forgets the pending heartbeat and last RTT when a connection ends.
+    pub fn reset_heartbeat(&self) {
+        if let Ok(mut outstanding) = self.outstanding_ping.lock() {
+            *outstanding = None;
+        }
+        self.rtt_micros.store(0, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(
+        &self,
+        queue_depth: QueueDepths,
+        relay: Option<String>,
+        authenticated: bool,
+    ) -> NetworkStatsSnapshot {
+        let real_packets = self.real_packets.load(Ordering::Relaxed);
+        let dummy_packets = self.dummy_packets.load(Ordering::Relaxed);
+        let total = real_packets + dummy_packets;
+        let rtt_micros = self.rtt_micros.load(Ordering::Relaxed);
+        NetworkStatsSnapshot {
+            rtt_ms: (rtt_micros > 0).then(|| rtt_micros as f64 / 1000.0),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            real_packets,
+            dummy_packets,
+            control_packets: self.control_packets.load(Ordering::Relaxed),
+            dummy_ratio: if total == 0 {
+                0.0
+            } else {
+                dummy_packets as f64 / total as f64
+            },
+            queue_depth,
+            relay,
+            authenticated,
+        }
+    }
+}
+
+/// Longest silence the reader tolerates before treating the connection as dead.
+pub(crate) fn read_deadline(config: &NetworkConfig) -> Duration {
+    Duration::from_secs(config.heartbeat_interval_secs + config.heartbeat_timeout_secs)
+}
+
+fn current_stats(state: &NetworkState) -> NetworkStatsSnapshot {
+    let queue_depth = state
+        .sender
+        .lock()
+        .ok()
+        .and_then(|l| l.as_ref().map(|s| s.depths()))
+        .unwrap_or_default();
+    let relay = state.url.lock().ok().and_then(|l| l.clone());
+    let authenticated = state.is_authenticated.lock().map(|l| *l).unwrap_or(false);
+    state.stats.snapshot(queue_depth, relay, authenticated)
+}
+
+pub fn start_stats_emitter<R: Runtime>(app: AppHandle<R>) {
+    tauri::async_runtime::spawn(async move {
+        loop {
+            let interval = current_config(&app.state::<NetworkState>()).stats_interval_secs;
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+            let state = app.state::<NetworkState>();
+            let enabled = state.is_enabled.lock().map(|l| *l).unwrap_or(false);
+            if enabled {
+                let _ = app.emit("network://stats", current_stats(&state));
+            }
+        }
+    });
+}
+
+#[tauri::command]
+pub fn network_get_stats(state: State<'_, NetworkState>) -> Result<NetworkStatsSnapshot, String> {
+    Ok(current_stats(&state))
+}
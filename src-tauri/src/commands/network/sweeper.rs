@@ -0,0 +1,265 @@
+//! Transfer State Sweeper
+//!
+//! Periodically expires transfer bookkeeping that will never complete: idle incoming
+//! reassemblies (closing their file handles and deleting their temp files), media
+//! metadata whose fragments stopped arriving, routes of finished sealed transfers,
+//! and finished uploads past their resend retention window. Every expiry is reported on `transfer://expired`.
+
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter, Manager, Runtime};
+
+use super::codec::FRAME_MEDIA;
+use super::config::current_config;
+use crate::app_state::{DbState, NetworkState, TransferControl};
+use crate::commands::messaging::inbox::reassembler::{
+    assembler_key, is_temp_filename, parse_assembler_key, temp_filename,
+};
+use crate::commands::messaging::outbox::uploads::forget_upload;
+use crate::commands::messaging::transfers::prune_transfer_controls;
+use crate::commands::vault::get_media_dir;
+
+pub fn start_transfer_sweeper<R: Runtime>(app: AppHandle<R>) {
+    tauri::async_runtime::spawn(async move {
+        loop {
+            let interval = current_config(&app.state::<NetworkState>()).sweep_interval_secs;
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+            sweep_expired_transfers(&app);
+        }
+    });
+}
+
+/// Keys whose entries have been idle for at least `ttl` as of `now`.
+pub(crate) fn expired_keys<K: Clone, V>(
+    map: &HashMap<K, V>,
+    now: Instant,
+    ttl: Duration,
+    last_seen: impl Fn(&V) -> Instant,
+) -> Vec<K> {
+    map.iter()
+        .filter(|(_, v)| now.saturating_duration_since(last_seen(v)) >= ttl)
+        .map(|(k, _)| k.clone())
+        .collect()
+}
+
+pub(crate) fn sweep_expired_transfers<R: Runtime>(app: &AppHandle<R>) {
+    let net_state = app.state::<NetworkState>();
+    let config = current_config(&net_state);
+    let now = Instant::now();
+    let ttl = Duration::from_secs(config.transfer_timeout_secs);
+
+    // Pauses outlive neither their upload nor their reassembly
+    let mut transfers: HashSet<u32> = net_state
+        .active_outgoing_transfers
+        .lock()
+        .map(|active| {
+            active
+                .iter()
+                .filter(|(_, t)| t.finished_at.is_none())
+                .map(|(tid, _)| *tid)
+                .collect()
+        })
+        .unwrap_or_default();
+    if let Ok(assemblers) = net_state.media_assembler.lock() {
+        transfers.extend(
+            assemblers
+                .keys()
+                .filter_map(|k| parse_assembler_key(k))
+                .map(|(_, tid, _)| tid),
+        );
+    }
+
+    // Paused downloads are idle on purpose and never expire
+    let paused: HashSet<u32> = match net_state.transfer_controls.lock() {
+        Ok(mut controls) => {
+            prune_transfer_controls(&mut controls, &transfers, now, ttl);
+            controls
+                .iter()
+                .filter(|(_, c)| **c == TransferControl::Paused)
+                .map(|(tid, _)| *tid)
+                .collect()
+        }
+        Err(_) => HashSet::new(),
+    };
+
+    // Dropping the removed states closes their file handles before the files are deleted
+    let (stale, live): (Vec<String>, HashSet<String>) = {
+        let Ok(mut assemblers) = net_state.media_assembler.lock() else {
+            return;
+        };
+        let mut stale = expired_keys(&assemblers, now, ttl, |a| a.last_activity);
+        stale.retain(|k| !parse_assembler_key(k).is_some_and(|(_, tid, _)| paused.contains(&tid)));
+        for key in &stale {
+            assemblers.remove(key);
+        }
+        (stale, assemblers.keys().cloned().collect())
+    };
+
+    let mut expired_links = HashMap::new();
+    if let Ok(mut links) = net_state.pending_media_links.lock() {
+        let mut dead = expired_keys(&links, now, ttl, |m| m.registered_at);
+        dead.retain(|k| {
+            !k.split_once(':')
+                .is_some_and(|(_, tid)| tid.parse::<u32>().is_ok_and(|tid| paused.contains(&tid)))
+        });
+        for key in &stale {
+            if let Some((sender, tid, FRAME_MEDIA)) = parse_assembler_key(key) {
+                dead.push(format!("{}:{}", sender, tid));
+            }
+        }
+        for link_key in dead {
+            let still_receiving = link_key.split_once(':').is_some_and(|(sender, tid)| {
+                tid.parse()
+                    .is_ok_and(|tid| live.contains(&assembler_key(sender, tid, FRAME_MEDIA)))
+            });
+            if !still_receiving && let Some(meta) = links.remove(&link_key) {
+                expired_links.insert(link_key, meta.id);
+            }
+        }
+    }
+
+    release_downloads(app, &stale, expired_links);
+
+    // A sealed transfer's route is needed only while it is announced or reassembling
+    if let Ok(mut routes) = net_state.sealed_media_routes.lock()
+        && let Ok(links) = net_state.pending_media_links.lock()
+    {
+        routes.retain(|key, sender| {
+            key.rsplit_once(':')
+                .and_then(|(_, tid)| tid.parse::<u32>().ok())
+                .is_some_and(|tid| {
+                    paused.contains(&tid)
+                        || links.contains_key(&format!("{}:{}", sender, tid))
+                        || live.contains(&assembler_key(sender, tid, FRAME_MEDIA))
+                })
+        });
+    }
+
+    if let Ok(mut governor) = net_state.inbound_governor.lock() {
+        governor.prune(now);
+    }
+
+    let retention = Duration::from_secs(config.outgoing_retention_secs);
+    let expired_uploads = match net_state.active_outgoing_transfers.lock() {
+        Ok(mut active) => {
+            // Uploads still sending are never expired; retention runs from the last fragment
+            let dead = expired_keys(&active, now, retention, |t| t.finished_at.unwrap_or(now));
+            for tid in &dead {
+                active.remove(tid);
+            }
+            dead
+        }
+        Err(_) => Vec::new(),
+    };
+    for transfer_id in expired_uploads {
+        forget_upload(app, transfer_id);
+        let _ = app.emit(
+            "transfer://expired",
+            json!({ "transfer_id": transfer_id, "direction": "upload" }),
+        );
+    }
+}
+
+/// Deletes the temp files of already-removed reassemblies and reports them, together
+/// with any media metadata dropped alongside, on `transfer://expired`.
+fn release_downloads<R: Runtime>(
+    app: &AppHandle<R>,
+    stale: &[String],
+    mut expired_links: HashMap<String, String>,
+) {
+    let media_dir = get_media_dir(app, &app.state::<DbState>()).ok();
+    for key in stale {
+        let Some((sender, transfer_id, frame_type)) = parse_assembler_key(key) else {
+            continue;
+        };
+        if let Some(dir) = &media_dir {
+            let _ = std::fs::remove_file(dir.join(temp_filename(sender, transfer_id, frame_type)));
+        }
+        let msg_id = expired_links.remove(&format!("{}:{}", sender, transfer_id));
+        let _ = app.emit(
+            "transfer://expired",
+            json!({
+                "transfer_id": transfer_id,
+                "sender": sender,
+                "direction": "download",
+                "msgId": msg_id
+            }),
+        );
+    }
+
+    // Metadata that outlived its fragments leaves nothing on disk to remove
+    for (link_key, msg_id) in expired_links {
+        let Some((sender, tid)) = link_key.split_once(':') else {
+            continue;
+        };
+        let _ = app.emit(
+            "transfer://expired",
+            json!({
+                "transfer_id": tid.parse::<u32>().unwrap_or_default(),
+                "sender": sender,
+                "direction": "download",
+                "msgId": msg_id
+            }),
+        );
+    }
+}
+
+/// Aborts every incoming transfer from `sender`, releasing its disk reservations.
+pub(crate) fn discard_sender_transfers<R: Runtime>(app: &AppHandle<R>, sender: &str) {
+    let net_state = app.state::<NetworkState>();
+    let stale: Vec<String> = match net_state.media_assembler.lock() {
+        Ok(mut assemblers) => {
+            let keys: Vec<String> = assemblers
+                .keys()
+                .filter(|k| parse_assembler_key(k).is_some_and(|(s, _, _)| s == sender))
+                .cloned()
+                .collect();
+            for key in &keys {
+                assemblers.remove(key);
+            }
+            keys
+        }
+        Err(_) => return,
+    };
+    let prefix = format!("{}:", sender);
+    let expired_links = match net_state.pending_media_links.lock() {
+        Ok(mut links) => {
+            let keys: Vec<String> = links
+                .keys()
+                .filter(|k| k.starts_with(&prefix))
+                .cloned()
+                .collect();
+            keys.into_iter()
+                .filter_map(|k| links.remove(&k).map(|m| (k, m.id)))
+                .collect()
+        }
+        Err(_) => HashMap::new(),
+    };
+    release_downloads(app, &stale, expired_links);
+}
+
+/// Deletes reassembly temp files left behind by a previous run. Called once the vault
+/// is unlocked; files backing a reassembly that is already in progress are kept.
+pub(crate) fn purge_orphaned_temp_files<R: Runtime>(app: &AppHandle<R>) {
+    let Ok(media_dir) = get_media_dir(app, &app.state::<DbState>()) else {
+        return;
+    };
+    let live: HashSet<String> = match app.state::<NetworkState>().media_assembler.lock() {
+        Ok(assemblers) => assemblers
+            .keys()
+            .filter_map(|k| parse_assembler_key(k))
+            .map(|(sender, tid, frame_type)| temp_filename(sender, tid, frame_type))
+            .collect(),
+        Err(_) => return,
+    };
+    let Ok(entries) = std::fs::read_dir(&media_dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if is_temp_filename(&name) && !live.contains(&name) {
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+}
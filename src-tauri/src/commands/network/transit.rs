@@ -2,22 +2,23 @@
 //!
 //! The Transit Layer is responsible for the reliable delivery of binary payloads
 //! across the peer-to-peer network. Core responsibilities:
-//! - Fragmentation: Dividing large E2EE payloads into 1319-byte data chunks.
-//! - Network Framing: Encapsulating fragments with 64-byte padded recipient headers.
-//! - Padding: Enforcing a uniform 1400-byte packet size to neutralize side-channel analysis.
+//! - Fragmentation: Dividing large E2EE payloads into `CHUNK_CAPACITY`-byte data chunks.
+//! - Network Framing: Encapsulating fragments via `FrameCodec` with padded recipient headers.
+//! - Padding: Enforcing a uniform `PACKET_SIZE` packet size to neutralize side-channel analysis.
 //! - Offline Queuing: Persisting fragments in the local DB for delivery during network events.
 //! - Dummy Pacing: Intermittent injection of dummy traffic to mask usage patterns.
 
-use super::pacing::{PACKET_SIZE, send_paced_json};
+use super::codec::{CHUNK_CAPACITY, FRAME_MEDIA, FRAME_SIGNAL, FrameCodec, ROUTING_LEN};
+use super::halts::routing_halted_until;
+use super::pacing::send_paced_json;
 use crate::app_state::{DbState, NetworkState, PacedMessage};
 use serde_json::json;
-use tauri::{AppHandle, Emitter, Manager, State};
-use tokio_tungstenite::tungstenite::Utf8Bytes;
+use tauri::{AppHandle, Emitter, Manager, Runtime};
 use tokio_tungstenite::tungstenite::protocol::Message;
 
 #[allow(clippy::too_many_arguments)]
-pub async fn internal_send_to_network(
-    app: AppHandle,
+pub async fn internal_send_to_network<R: Runtime>(
+    app: AppHandle<R>,
     state: &NetworkState,
     target_hash: Option<String>,
     msg_id: Option<String>,
@@ -44,20 +45,15 @@ pub async fn internal_send_to_network(
         };
 
         if !bytes.is_empty() {
-            let (hash_bytes, data_bytes) = if let Some(h) = target_hash {
-                let mut h_padded = vec![0u8; 64];
-                let h_bytes = h.as_bytes();
-                let len = std::cmp::min(h_bytes.len(), 64);
-                h_padded[..len].copy_from_slice(&h_bytes[..len]);
-                (h_padded, bytes)
-            } else {
-                (vec![0u8; 64], bytes)
+            let hash_bytes = match target_hash {
+                Some(h) => FrameCodec::routing_header(&h),
+                None => [0u8; ROUTING_LEN],
             };
+            let data_bytes = bytes;
 
-            let total_len = data_bytes.len();
-            let chunk_capacity = 1319;
             let transfer_id: u32 = transfer_id_override.unwrap_or_else(rand::random);
-            let chunks = (total_len as f64 / chunk_capacity as f64).ceil() as usize;
+            let frame_type = FrameCodec::data_frame_type(is_media, is_volatile);
+            let chunks = FrameCodec::chunk_count(data_bytes.len()) as usize;
 
             if let Some(ref id) = msg_id {
                 state
@@ -68,38 +64,20 @@ pub async fn internal_send_to_network(
             }
 
             for i in 0..chunks {
-                let target_hash_str = hex::encode(&hash_bytes);
-                {
-                    let halted = state
-                        .halted_targets
-                        .lock()
-                        .map_err(|_| "Network state poisoned")?;
-                    if halted.contains(&target_hash_str) {
-                        break;
-                    }
-                }
-
-                let start = i * chunk_capacity;
-                let end = std::cmp::min(start + chunk_capacity, total_len);
-                let chunk_data = &data_bytes[start..end];
-
-                let mut envelope = Vec::with_capacity(PACKET_SIZE);
-                envelope.extend_from_slice(&hash_bytes);
-                if is_media {
-                    envelope.push(0x02);
-                } else if is_volatile {
-                    envelope.push(0x04);
-                } else {
-                    envelope.push(0x01);
-                }
-                envelope.extend_from_slice(&transfer_id.to_be_bytes());
-                envelope.extend_from_slice(&(i as u32).to_be_bytes());
-                envelope.extend_from_slice(&(chunks as u32).to_be_bytes());
-                envelope.extend_from_slice(&(chunk_data.len() as u32).to_be_bytes());
-                envelope.extend_from_slice(chunk_data);
+                let start = i * CHUNK_CAPACITY;
+                let end = std::cmp::min(start + CHUNK_CAPACITY, data_bytes.len());
+                let envelope = FrameCodec::encode_fragment(
+                    &hash_bytes,
+                    frame_type,
+                    transfer_id,
+                    i as u32,
+                    chunks as u32,
+                    &data_bytes[start..end],
+                )?;
 
                 paced_messages.push(PacedMessage {
                     msg: Message::Binary(envelope.into()),
+                    msg_id: msg_id.clone(),
                 });
 
                 let progress_step = (chunks / 20).max(1);
@@ -116,6 +94,16 @@ pub async fn internal_send_to_network(
                     );
                 }
             }
+
+            if let Some(until) = routing_halted_until(state, &hash_bytes) {
+                persist_to_outbox_at(&app, &paced_messages, until);
+                if let Some(id) = msg_id {
+                    mark_pending(&app, &id, false);
+                }
+                return Err(
+                    "Recipient is not accepting messages. Message queued in outbox.".into(),
+                );
+            }
         }
     }
 
@@ -132,8 +120,14 @@ pub async fn internal_send_to_network(
                 sender_lock.clone()
             };
             if let Some(tx) = tx {
-                for pm in paced_messages {
-                    tx.send(pm).await.map_err(|e| e.to_string())?;
+                let mut remaining = paced_messages.into_iter();
+                while let Some(pm) = remaining.next() {
+                    // The writer closed mid-send; keep what it never received
+                    if let Err(e) = tx.send(pm).await {
+                        let unsent = std::iter::once(e.0).chain(remaining).collect();
+                        requeue_undelivered(&app, unsent);
+                        return Err("Connection closed. Message queued in outbox.".into());
+                    }
                 }
             }
         } else {
@@ -145,46 +139,9 @@ pub async fn internal_send_to_network(
         Ok(())
     } else {
         if is_binary {
-            let db_state = app.state::<DbState>();
-            let timestamp = std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs();
-
-            if let Ok(conn) = db_state.get_conn() {
-                let _ = conn.execute("BEGIN TRANSACTION", []);
-                if let Ok(mut stmt) = conn.prepare("INSERT INTO pending_outbox (msg_id, msg_type, content, timestamp) VALUES (?1, ?2, ?3, ?4)") {
-                    for pm in paced_messages {
-                        let content = match &pm.msg {
-                            Message::Binary(b) => b.to_vec(),
-                            _ => vec![],
-                        };
-                        let _ = stmt.execute(rusqlite::params![msg_id.clone(), "binary", content, timestamp]);
-                    }
-                }
-                let _ = conn.execute("COMMIT", []);
-            }
+            persist_to_outbox(&app, &paced_messages);
             if let Some(id) = msg_id {
-                let db_state = app.state::<DbState>();
-                if let Ok(conn) = db_state.get_conn() {
-                    let chat_address: Option<String> = conn
-                        .query_row(
-                            "SELECT chat_address FROM messages WHERE id = ?",
-                            [&id],
-                            |r| r.get::<_, String>(0),
-                        )
-                        .ok();
-
-                    let _ = conn.execute(
-                        "UPDATE messages SET status = 'pending' WHERE id = ?",
-                        [id.clone()],
-                    );
-                    app.emit(
-                        "msg://status",
-                        json!({ "id": id, "status": "pending", "chat_address": chat_address }),
-                    )
-                    .map_err(|e| e.to_string())?;
-                }
+                mark_pending(&app, &id, false);
             }
         }
         Err("Network not connected. Message queued in outbox.".to_string())
@@ -192,10 +149,10 @@ pub async fn internal_send_to_network(
 }
 
 #[allow(clippy::too_many_arguments)]
-pub async fn internal_dispatch_fragment(
-    app: AppHandle,
+pub async fn internal_dispatch_fragment<R: Runtime>(
+    app: AppHandle<R>,
     state: &NetworkState,
-    target_hash_bytes: [u8; 64],
+    target_hash_bytes: [u8; ROUTING_LEN],
     msg_id: Option<String>,
     transfer_id: u32,
     index: u32,
@@ -205,20 +162,14 @@ pub async fn internal_dispatch_fragment(
     is_volatile: bool,
     silent: bool,
 ) -> Result<(), String> {
-    let mut envelope = Vec::with_capacity(PACKET_SIZE);
-    envelope.extend_from_slice(&target_hash_bytes);
-    if is_media {
-        envelope.push(0x02);
-    } else if is_volatile {
-        envelope.push(0x04);
-    } else {
-        envelope.push(0x01);
-    }
-    envelope.extend_from_slice(&transfer_id.to_be_bytes());
-    envelope.extend_from_slice(&index.to_be_bytes());
-    envelope.extend_from_slice(&total.to_be_bytes());
-    envelope.extend_from_slice(&(chunk_data.len() as u32).to_be_bytes());
-    envelope.extend_from_slice(chunk_data);
+    let envelope = FrameCodec::encode_fragment(
+        &target_hash_bytes,
+        FrameCodec::data_frame_type(is_media, is_volatile),
+        transfer_id,
+        index,
+        total,
+        chunk_data,
+    )?;
 
     if let Some(ref id) = msg_id {
         state
@@ -230,8 +181,14 @@ pub async fn internal_dispatch_fragment(
 
     let paced_msg = PacedMessage {
         msg: Message::Binary(envelope.into()),
+        msg_id: msg_id.clone(),
     };
 
+    if let Some(until) = routing_halted_until(state, &target_hash_bytes) {
+        persist_to_outbox_at(&app, std::slice::from_ref(&paced_msg), until);
+        return Ok(());
+    }
+
     let is_connected = state
         .sender
         .lock()
@@ -243,8 +200,11 @@ pub async fn internal_dispatch_fragment(
             let sender_lock = state.sender.lock().map_err(|_| "Network state poisoned")?;
             sender_lock.clone()
         };
-        if let Some(tx) = tx {
-            tx.send(paced_msg).await.map_err(|e| e.to_string())?;
+        if let Some(tx) = tx
+            && let Err(e) = tx.send(paced_msg).await
+        {
+            requeue_undelivered(&app, vec![e.0]);
+            return Ok(());
         }
 
         if !silent && (index.is_multiple_of(20) || index == total - 1) {
@@ -261,81 +221,126 @@ pub async fn internal_dispatch_fragment(
         }
         Ok(())
     } else {
-        let db_state = app.state::<DbState>();
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs();
-        if let Ok(conn) = db_state.get_conn() {
-            let content = match &paced_msg.msg {
-                Message::Binary(b) => b.to_vec(),
-                _ => vec![],
-            };
-            let _ = conn.execute(
-                "INSERT INTO pending_outbox (msg_id, msg_type, content, timestamp) VALUES (?1, ?2, ?3, ?4)",
-                rusqlite::params![msg_id, "binary", content, timestamp],
-            );
-        }
+        persist_to_outbox(&app, std::slice::from_ref(&paced_msg));
         Ok(())
     }
 }
 
-pub async fn flush_outbox(app: AppHandle, state: State<'_, NetworkState>) -> Result<(), String> {
-    let tx = {
-        let sender_lock = state.sender.lock().map_err(|_| "Network state poisoned")?;
-        sender_lock.clone()
-    };
-    if let Some(tx) = tx {
-        let mut msg_batch = Vec::new();
+/// Appends packets to `pending_outbox`, in order, for `flush_outbox` to send after the
+/// next successful authentication. Fragments keep their transfer id so the relay's
+/// confirmation can retire them.
+pub(crate) fn persist_to_outbox<R: Runtime>(app: &AppHandle<R>, packets: &[PacedMessage]) {
+    persist_to_outbox_at(app, packets, 0);
+}
 
-        {
-            // Scope to minimize DB lock duration
-            let db_state = app.state::<DbState>();
-            if let Ok(conn) = db_state.get_conn()
-                && let Ok(mut stmt) = conn.prepare(
-                    "SELECT id, msg_type, content, msg_id FROM pending_outbox ORDER BY rowid ASC",
-                )
-                && let Ok(rows) = stmt.query_map([], |row| {
-                    Ok((
-                        row.get::<_, i64>(0)?,
-                        row.get::<_, String>(1)?,
-                        row.get::<_, Vec<u8>>(2)?,
-                        row.get::<_, Option<String>>(3)?,
-                    ))
-                })
-            {
-                for row in rows.flatten() {
-                    msg_batch.push(row);
+/// Like `persist_to_outbox`, but the packets are not sent before `not_before`.
+pub(crate) fn persist_to_outbox_at<R: Runtime>(
+    app: &AppHandle<R>,
+    packets: &[PacedMessage],
+    not_before: i64,
+) {
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let db_state = app.state::<DbState>();
+    if let Ok(conn) = db_state.get_conn() {
+        let _ = conn.execute("BEGIN TRANSACTION", []);
+        if let Ok(mut stmt) = conn.prepare("INSERT INTO pending_outbox (msg_id, msg_type, content, timestamp, transfer_id, next_attempt_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)") {
+            for pm in packets {
+                let (msg_type, content, transfer_id) = match &pm.msg {
+                    Message::Text(t) => ("text", t.as_str().as_bytes().to_vec(), None),
+                    Message::Binary(b) => (
+                        "binary",
+                        b.to_vec(),
+                        FrameCodec::decode(b).ok().map(|f| f.transfer_id),
+                    ),
+                    _ => continue,
+                };
+                // A packet replayed from the outbox is still queued there; make it due again
+                if transfer_id.is_some()
+                    && conn
+                        .execute(
+                            "UPDATE pending_outbox SET next_attempt_at = ?3 WHERE transfer_id = ?1 AND content = ?2",
+                            rusqlite::params![transfer_id, content, not_before],
+                        )
+                        .unwrap_or(0)
+                        > 0
+                {
+                    continue;
                 }
+                let _ = stmt.execute(rusqlite::params![
+                    pm.msg_id,
+                    msg_type,
+                    content,
+                    timestamp,
+                    transfer_id,
+                    not_before
+                ]);
             }
         }
+        let _ = conn.execute("COMMIT", []);
+    }
+}
 
-        let mut ids_to_delete = Vec::new();
-        for (id, msg_type, content, _msg_id) in msg_batch {
-            let msg = if msg_type == "text" {
-                Message::Text(Utf8Bytes::from(
-                    String::from_utf8_lossy(&content).to_string(),
-                ))
-            } else {
-                Message::Binary(content.into())
-            };
-            let _ = tx.send(PacedMessage { msg }).await;
-            ids_to_delete.push(id);
-        }
-        if !ids_to_delete.is_empty() {
-            let db_state = app.state::<DbState>();
-            if let Ok(conn) = db_state.get_conn() {
-                let query = format!(
-                    "DELETE FROM pending_outbox WHERE id IN ({})",
-                    ids_to_delete
-                        .iter()
-                        .map(|_| "?")
-                        .collect::<Vec<_>>()
-                        .join(",")
-                );
-                let _ = conn.execute(&query, rusqlite::params_from_iter(ids_to_delete));
-            }
-        }
+/// Whether a packet the writer never sent is worth replaying on a later connection.
+/// Only peer-addressed signal and media fragments are: relay requests, auth frames,
+/// volatile signals and dummies belong to the connection that queued them.
+pub(crate) fn is_replayable(msg: &Message) -> bool {
+    match msg {
+        Message::Binary(b) => FrameCodec::decode(b).is_ok_and(|f| {
+            matches!(f.frame_type, FRAME_SIGNAL | FRAME_MEDIA) && f.routing != [0u8; ROUTING_LEN]
+        }),
+        _ => false,
+    }
+}
+
+/// Moves packets that were queued for a connection but never written to it into the
+/// outbox, and reverts their messages to `pending` so the UI does not claim delivery.
+pub(crate) fn requeue_undelivered<R: Runtime>(app: &AppHandle<R>, packets: Vec<PacedMessage>) {
+    let replayable: Vec<PacedMessage> = packets
+        .into_iter()
+        .filter(|pm| is_replayable(&pm.msg))
+        .collect();
+    if replayable.is_empty() {
+        return;
+    }
+    persist_to_outbox(app, &replayable);
+
+    let mut msg_ids: Vec<&String> = replayable
+        .iter()
+        .filter_map(|pm| pm.msg_id.as_ref())
+        .collect();
+    msg_ids.sort_unstable();
+    msg_ids.dedup();
+    for id in msg_ids {
+        mark_pending(app, id, true);
+    }
+}
+
+/// Sets a message back to `pending`. With `only_unconfirmed`, messages already marked
+/// delivered or read are left alone.
+pub(crate) fn mark_pending<R: Runtime>(app: &AppHandle<R>, id: &str, only_unconfirmed: bool) {
+    let db_state = app.state::<DbState>();
+    let Ok(conn) = db_state.get_conn() else {
+        return;
+    };
+    let chat_address: Option<String> = conn
+        .query_row(
+            "SELECT chat_address FROM messages WHERE id = ?",
+            [id],
+            |r| r.get::<_, String>(0),
+        )
+        .ok();
+    let query = if only_unconfirmed {
+        "UPDATE messages SET status = 'pending' WHERE id = ? AND status IN ('sending', 'sent')"
+    } else {
+        "UPDATE messages SET status = 'pending' WHERE id = ?"
+    };
+    if conn.execute(query, [id]).unwrap_or(0) > 0 {
+        let _ = app.emit(
+            "msg://status",
+            json!({ "id": id, "status": "pending", "chat_address": chat_address }),
+        );
     }
-    Ok(())
 }
@@ -10,22 +10,38 @@ use rand::SeedableRng;
 use rand::rngs::StdRng;
 use serde_json::json;
 use sha2::{Digest, Sha256};
-use tauri::{AppHandle, Manager};
+use tauri::{AppHandle, Manager, Runtime};
 
 use crate::app_state::{DbState, NetworkState};
+use crate::commands::identity::devices::{
+    PRIMARY_DEVICE, account_devices, device_address, device_envelope, link_binds, linked_devices,
+    local_link, protocol_address, split_address, verify_link,
+};
+use crate::commands::identity::prekeys::{
+    current_last_resort_key, current_signed_pre_key, mark_last_resort, now_ms,
+    retire_published_keys, rotation_due,
+};
+use crate::commands::identity::sealed::seal_entries;
+use crate::commands::identity::trust::blocked_until_reverified;
 use crate::commands::internal_request;
+use crate::commands::network::config::current_config;
+use crate::commands::network::relay_protocol::{
+    ClientMessage, DeviceLink, FetchKeyResponse, KeysUploadResponse, PreKeyUpload, SignedKeyUpload,
+    decode_response,
+};
+use crate::noise::TrafficNormalizer;
 use crate::signal_store::SqliteSignalStore;
 
-pub(crate) async fn internal_signal_encrypt(
-    app: AppHandle,
+/// Encrypts `message` for every device of `remote`, or for the one device a
+/// `hash.device` address names, and returns the envelope to send.
+pub(crate) async fn internal_signal_encrypt<R: Runtime>(
+    app: AppHandle<R>,
     net_state: &NetworkState,
-    remote_hash: &str,
+    remote: &str,
     message: String,
 ) -> Result<serde_json::Value, String> {
-    let address = ProtocolAddress::new(
-        remote_hash.to_string(),
-        DeviceId::try_from(1u32).expect("valid ID"),
-    );
+    let mut message = message;
+    TrafficNormalizer::pad_plaintext(&mut message);
 
     let own_hash = net_state
         .identity_hash
@@ -33,14 +49,75 @@ pub(crate) async fn internal_signal_encrypt(
         .map_err(|_| "Net lock poisoned")?
         .clone()
         .ok_or("Identity not established")?;
-    let own_address = ProtocolAddress::new(
-        own_hash.clone(),
-        DeviceId::try_from(1u32).expect("valid ID"),
-    );
+    let own_link = local_link(&app);
+    let own_device = own_link.as_ref().map_or(PRIMARY_DEVICE, |l| l.device_id);
+    let own_address = protocol_address(&own_hash, own_device)?;
+
+    let (remote_hash, device) = split_address(remote);
+    if !remote_hash.eq_ignore_ascii_case(&own_hash) {
+        let conn = app.state::<DbState>().get_conn()?;
+        if blocked_until_reverified(&conn, &format!("{}:{}", remote_hash, PRIMARY_DEVICE))? {
+            return Err(format!(
+                "Safety number with {} changed. Verify it before sending",
+                remote_hash
+            ));
+        }
+    }
+    let mut targets = match device {
+        Some(device) => vec![device],
+        None => account_devices(&app, net_state, remote_hash).await,
+    };
+    if remote_hash.eq_ignore_ascii_case(&own_hash) {
+        targets.retain(|d| *d != own_device);
+    }
+
+    let mut entries = Vec::with_capacity(targets.len());
+    let mut last_error = None;
+    for device in targets {
+        match encrypt_for_device(&app, net_state, &own_address, remote_hash, device, &message).await
+        {
+            Ok(envelope) => entries.push((device, envelope)),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    if entries.is_empty() {
+        return Err(last_error.unwrap_or_else(|| format!("No devices of {} to send to", remote)));
+    }
+    // Messages between this account's own devices gain nothing from sealing
+    if current_config(net_state).sealed_sender && !remote_hash.eq_ignore_ascii_case(&own_hash) {
+        return seal_entries(&app, &own_hash, own_link.as_ref(), remote_hash, entries).await;
+    }
+    Ok(device_envelope(entries, own_link.as_ref()))
+}
+
+/// Whether a session with `hash`'s `device` is open.
+pub(crate) async fn has_session<R: Runtime>(app: &AppHandle<R>, hash: &str, device: u32) -> bool {
+    let Ok(address) = protocol_address(hash, device) else {
+        return false;
+    };
+    let store = SqliteSignalStore::new(app.clone());
+    tauri::async_runtime::spawn_blocking(move || {
+        tauri::async_runtime::block_on(async {
+            matches!(store.load_session(&address).await, Ok(Some(_)))
+        })
+    })
+    .await
+    .unwrap_or(false)
+}
+
+async fn encrypt_for_device<R: Runtime>(
+    app: &AppHandle<R>,
+    net_state: &NetworkState,
+    own_address: &ProtocolAddress,
+    remote_hash: &str,
+    device: u32,
+    message: &str,
+) -> Result<serde_json::Value, String> {
+    let address = protocol_address(remote_hash, device)?;
 
     let (app_clone, address_clone, own_address_clone) =
         (app.clone(), address.clone(), own_address.clone());
-    let msg_clone = message.clone();
+    let msg_clone = message.to_string();
     let res = tauri::async_runtime::spawn_blocking(move || {
         let mut rng = StdRng::from_os_rng();
         let mut store = SqliteSignalStore::new(app_clone);
@@ -69,61 +146,43 @@ pub(crate) async fn internal_signal_encrypt(
     .map_err(|e| e.to_string())?;
 
     match res {
-        Ok(ciphertext) => {
-            let (type_val, body) = match ciphertext {
-                CiphertextMessage::SignalMessage(m) => {
-                    (CiphertextMessageType::Whisper, m.serialized().to_vec())
-                }
-                CiphertextMessage::PreKeySignalMessage(m) => {
-                    (CiphertextMessageType::PreKey, m.serialized().to_vec())
-                }
-                _ => return Err("Unsupported ciphertext type".into()),
-            };
-            Ok(json!({
-                "type": type_val as u8,
-                "body": base64::engine::general_purpose::STANDARD.encode(body),
-                "is_signal": true
-            }))
-        }
+        Ok(ciphertext) => ciphertext_envelope(ciphertext),
         Err(e)
             if e.to_string().to_lowercase().contains("session")
                 || e.to_string().to_lowercase().contains("not found") =>
         {
-            let response = internal_request(
-                net_state,
-                "fetch_key",
-                json!({
-                    "target_hash": remote_hash,
-                    "initiator_hash": own_hash
-                }),
-            )
-            .await?;
+            let response: FetchKeyResponse = decode_response(
+                internal_request(
+                    net_state,
+                    ClientMessage::FetchKey {
+                        target_hash: remote_hash.to_string(),
+                        initiator_hash: own_address.name().to_string(),
+                    },
+                )
+                .await?,
+            )?;
 
-            if !response["found"].as_bool().unwrap_or(false) {
+            if !response.found {
                 return Err(format!("Peer {} not found on server", remote_hash));
             }
 
-            let bundle = if let Some(bundles_val) = response.get("bundles") {
-                if let Some(bundles_obj) = bundles_val.as_object() {
-                    bundles_obj
-                        .get(remote_hash)
-                        .cloned()
-                        .unwrap_or(serde_json::Value::Null)
-                } else {
-                    serde_json::Value::Null
-                }
-            } else {
-                response["bundle"].clone()
-            };
-
-            if bundle.is_null() {
-                return Err(format!("Bundle for {} is null in response", remote_hash));
-            }
+            let bundle = response
+                .device_bundles(remote_hash)
+                .into_iter()
+                .find(|(d, _)| *d == device)
+                .map(|(_, bundle)| bundle)
+                .ok_or_else(|| {
+                    format!(
+                        "Bundle for {} is null in response",
+                        device_address(remote_hash, device)
+                    )
+                })?;
 
-            internal_establish_session_logic(app.clone(), remote_hash, bundle).await?;
+            internal_establish_session_logic(app.clone(), remote_hash, device, bundle).await?;
 
             let app_clone = app.clone();
             let (address_clone, own_address_clone) = (address.clone(), own_address.clone());
+            let message = message.to_string();
             let ciphertext = tauri::async_runtime::spawn_blocking(move || {
                 let mut store = SqliteSignalStore::new(app_clone);
                 let mut rng = StdRng::from_os_rng();
@@ -144,27 +203,34 @@ pub(crate) async fn internal_signal_encrypt(
             .map_err(|e| e.to_string())?
             .map_err(|e: SignalProtocolError| e.to_string())?;
 
-            let (type_val, body) = match ciphertext {
-                CiphertextMessage::SignalMessage(m) => {
-                    (CiphertextMessageType::Whisper, m.serialized().to_vec())
-                }
-                CiphertextMessage::PreKeySignalMessage(m) => {
-                    (CiphertextMessageType::PreKey, m.serialized().to_vec())
-                }
-                _ => return Err("Unsupported ciphertext type".into()),
-            };
-            Ok(json!({
-                "type": type_val as u8,
-                "body": base64::engine::general_purpose::STANDARD.encode(body),
-                "is_signal": true
-            }))
+            ciphertext_envelope(ciphertext)
         }
         Err(e) => Err::<serde_json::Value, String>(e.to_string()),
     }
 }
 
-pub async fn signal_get_bundle(
-    handle: tauri::AppHandle,
+/// Wraps a Signal ciphertext in the JSON envelope carried inside `0x01` frames.
+pub(crate) fn ciphertext_envelope(
+    ciphertext: CiphertextMessage,
+) -> Result<serde_json::Value, String> {
+    let (type_val, body) = match ciphertext {
+        CiphertextMessage::SignalMessage(m) => {
+            (CiphertextMessageType::Whisper, m.serialized().to_vec())
+        }
+        CiphertextMessage::PreKeySignalMessage(m) => {
+            (CiphertextMessageType::PreKey, m.serialized().to_vec())
+        }
+        _ => return Err("Unsupported ciphertext type".into()),
+    };
+    Ok(json!({
+        "type": type_val as u8,
+        "body": base64::engine::general_purpose::STANDARD.encode(body),
+        "is_signal": true
+    }))
+}
+
+pub async fn signal_get_bundle<R: Runtime>(
+    handle: tauri::AppHandle<R>,
     count: Option<u32>,
 ) -> Result<serde_json::Value, String> {
     tauri::async_runtime::spawn_blocking(move || {
@@ -188,15 +254,33 @@ pub async fn signal_get_bundle(
                 }));
             }
 
-            let signed_pre_key_id = SignedPreKeyId::from(rand::random::<u32>() & 0x7FFFFFFF);
-            let signed_pre_key_pair = KeyPair::generate(&mut rng);
-            let timestamp = Timestamp::from_epoch_millis(
-                std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap_or_default().as_millis() as u64
-            );
-            let signature = identity_key_pair.private_key().calculate_signature(&signed_pre_key_pair.public_key.serialize(), &mut rng)
-                .map_err(|e| e.to_string())?;
-            let signed_pre_key_record = SignedPreKeyRecord::new(signed_pre_key_id, timestamp, &signed_pre_key_pair, &signature);
-            store.save_signed_pre_key(signed_pre_key_id, &signed_pre_key_record).await.map_err(|e: SignalProtocolError| e.to_string())?;
+            // The signed prekey and the last-resort key are reused until they are due for rotation
+            let conn = handle.state::<DbState>().get_conn()?;
+            let now = now_ms();
+            let signed_pre_key_json = match current_signed_pre_key(&conn)? {
+                Some((id, created)) if !rotation_due(created, now) => {
+                    let record = store.get_signed_pre_key(SignedPreKeyId::from(id)).await.map_err(|e: SignalProtocolError| e.to_string())?;
+                    serde_json::json!({
+                        "id": id,
+                        "publicKey": hex::encode(record.public_key().map_err(|e: SignalProtocolError| e.to_string())?.serialize()),
+                        "signature": hex::encode(record.signature().map_err(|e: SignalProtocolError| e.to_string())?)
+                    })
+                }
+                _ => {
+                    let signed_pre_key_id = SignedPreKeyId::from(rand::random::<u32>() & 0x7FFFFFFF);
+                    let signed_pre_key_pair = KeyPair::generate(&mut rng);
+                    let timestamp = Timestamp::from_epoch_millis(now as u64);
+                    let signature = identity_key_pair.private_key().calculate_signature(&signed_pre_key_pair.public_key.serialize(), &mut rng)
+                        .map_err(|e| e.to_string())?;
+                    let signed_pre_key_record = SignedPreKeyRecord::new(signed_pre_key_id, timestamp, &signed_pre_key_pair, &signature);
+                    store.save_signed_pre_key(signed_pre_key_id, &signed_pre_key_record).await.map_err(|e: SignalProtocolError| e.to_string())?;
+                    serde_json::json!({
+                        "id": u32::from(signed_pre_key_id),
+                        "publicKey": hex::encode(signed_pre_key_pair.public_key.serialize()),
+                        "signature": hex::encode(signature)
+                    })
+                }
+            };
 
             let mut kyber_pre_keys_json = Vec::new();
             for _ in 0..key_count {
@@ -211,24 +295,29 @@ pub async fn signal_get_bundle(
                 }));
             }
 
-            // Also keep the single "last resort" kyberPreKey for compatibility with older establishments
-            let last_resort_id = KyberPreKeyId::from(rand::random::<u32>() & 0x7FFFFFFF);
-            let last_resort_record = KyberPreKeyRecord::generate(kem::KeyType::Kyber1024, last_resort_id, identity_key_pair.private_key())
-                .map_err(|e: SignalProtocolError| e.to_string())?;
-            store.save_kyber_pre_key(last_resort_id, &last_resort_record).await.map_err(|e: SignalProtocolError| e.to_string())?;
+            // The "last resort" kyberPreKey serves peers once the one-time keys run out
+            let last_resort_record = match current_last_resort_key(&conn)? {
+                Some((id, created)) if !rotation_due(created, now) => {
+                    store.get_kyber_pre_key(KyberPreKeyId::from(id)).await.map_err(|e: SignalProtocolError| e.to_string())?
+                }
+                _ => {
+                    let last_resort_id = KyberPreKeyId::from(rand::random::<u32>() & 0x7FFFFFFF);
+                    let record = KyberPreKeyRecord::generate(kem::KeyType::Kyber1024, last_resort_id, identity_key_pair.private_key())
+                        .map_err(|e: SignalProtocolError| e.to_string())?;
+                    store.save_kyber_pre_key(last_resort_id, &record).await.map_err(|e: SignalProtocolError| e.to_string())?;
+                    mark_last_resort(&conn, u32::from(last_resort_id))?;
+                    record
+                }
+            };
 
             Ok(serde_json::json!({
                 "registrationId": registration_id,
                 "identityKey": hex::encode(identity_key_pair.identity_key().serialize()),
                 "preKeys": pre_keys_json,
                 "kyberPreKeys": kyber_pre_keys_json,
-                "signedPreKey": {
-                    "id": u32::from(signed_pre_key_id),
-                    "publicKey": hex::encode(signed_pre_key_pair.public_key.serialize()),
-                    "signature": hex::encode(signature)
-                },
+                "signedPreKey": signed_pre_key_json,
                 "kyberPreKey": {
-                    "id": u32::from(last_resort_id),
+                    "id": u32::from(last_resort_record.id().map_err(|e: SignalProtocolError| e.to_string())?),
                     "publicKey": hex::encode(last_resort_record.public_key().map_err(|e: SignalProtocolError| e.to_string())?.serialize()),
                     "signature": hex::encode(last_resort_record.signature().map_err(|e: SignalProtocolError| e.to_string())?)
                 }
@@ -250,16 +339,22 @@ fn internal_decode_key(s: &str) -> Result<Vec<u8>, String> {
     hex::decode(s).map_err(|e| format!("Failed to decode key as Base64 or Hex: {} -> {}", s, e))
 }
 
-pub(crate) async fn internal_establish_session_logic(
-    app: AppHandle,
+/// Decodes a relay-served prekey bundle and checks that its identity key hashes to `remote_hash`.
+pub(crate) fn parse_prekey_bundle(
     remote_hash: &str,
-    bundle: serde_json::Value,
-) -> Result<(), String> {
-    let address = ProtocolAddress::new(
-        remote_hash.to_string(),
-        DeviceId::try_from(1u32).expect("valid ID"),
-    );
+    bundle: &serde_json::Value,
+) -> Result<PreKeyBundle, String> {
+    parse_device_bundle(remote_hash, PRIMARY_DEVICE, bundle)
+}
 
+/// Decodes the bundle of one of `remote_hash`'s devices. A primary's identity key
+/// has to hash to the account; a linked device's has to be the key its link
+/// authorizes.
+pub(crate) fn parse_device_bundle(
+    remote_hash: &str,
+    device_id: u32,
+    bundle: &serde_json::Value,
+) -> Result<PreKeyBundle, String> {
     let registration_id = bundle["registrationId"]
         .as_u64()
         .ok_or("Missing registrationId")? as u32;
@@ -268,41 +363,6 @@ pub(crate) async fn internal_establish_session_logic(
         .ok_or("Missing identityKey")?;
     let mut identity_key_bytes = internal_decode_key(identity_key_hex)?;
 
-    let mut existing_trust = 1;
-    {
-        let res: Result<(Vec<u8>, i32), _> = {
-            let db_state = app.state::<DbState>();
-            let conn = db_state.get_conn()?;
-            conn.query_row(
-                "SELECT public_key, trust_level FROM signal_identities_remote WHERE address = ?1",
-                rusqlite::params![address.to_string()],
-                |row| Ok((row.get(0)?, row.get(1)?)),
-            )
-            .map_err(|e| e.to_string())
-        };
-
-        if let Ok((old_key, old_trust)) = res {
-            let old_key_stripped = if old_key.len() == 33 && old_key[0] == 0x05 {
-                &old_key[1..]
-            } else {
-                &old_key
-            };
-            let new_key_stripped = &identity_key_bytes;
-
-            if old_key_stripped != new_key_stripped {
-                existing_trust = 0;
-                let db_state = app.state::<DbState>();
-                let conn = db_state.get_conn()?;
-                let _ = conn.execute(
-                    "UPDATE contacts SET trust_level = 0 WHERE hash = ?1",
-                    rusqlite::params![remote_hash],
-                );
-            } else {
-                existing_trust = old_trust;
-            }
-        }
-    }
-
     if identity_key_bytes.len() == 32 {
         let mut new_bytes = Vec::with_capacity(33);
         new_bytes.push(0x05);
@@ -382,7 +442,7 @@ pub(crate) async fn internal_establish_session_logic(
 
     let prekey_bundle = PreKeyBundle::new(
         registration_id,
-        DeviceId::try_from(1u32).expect("valid ID"),
+        DeviceId::try_from(device_id).map_err(|_| format!("Invalid device id {}", device_id))?,
         Some((pre_key_id, pre_key_pub)),
         signed_pre_key_id,
         signed_pre_key_pub,
@@ -394,6 +454,22 @@ pub(crate) async fn internal_establish_session_logic(
     )
     .map_err(|e| e.to_string())?;
 
+    if device_id != PRIMARY_DEVICE {
+        let link: DeviceLink = serde_json::from_value(bundle["link"].clone())
+            .map_err(|_| format!("Bundle for device {} has no link", device_id))?;
+        verify_link(&link)?;
+        if !link.account.eq_ignore_ascii_case(remote_hash)
+            || link.device_id != device_id
+            || !link_binds(&link, &identity_key.serialize())
+        {
+            return Err(format!(
+                "Identity Mismatch! Bundle for {} is not the linked device's key",
+                device_address(remote_hash, device_id)
+            ));
+        }
+        return Ok(prekey_bundle);
+    }
+
     let mut hasher = Sha256::new();
     let ser = identity_key.serialize();
     let to_hash = if ser.len() == 33 && ser[0] == 0x05 {
@@ -410,12 +486,29 @@ pub(crate) async fn internal_establish_session_logic(
         ));
     }
 
+    Ok(prekey_bundle)
+}
+
+pub(crate) async fn internal_establish_session_logic<R: Runtime>(
+    app: AppHandle<R>,
+    remote_hash: &str,
+    device_id: u32,
+    bundle: serde_json::Value,
+) -> Result<(), String> {
+    let address = protocol_address(remote_hash, device_id)?;
+
+    let prekey_bundle = parse_device_bundle(remote_hash, device_id, &bundle)?;
+
     let app_clone = app.clone();
     let address_sync = address.clone();
-    tauri::async_runtime::spawn_blocking(move || {
+    let processed = tauri::async_runtime::spawn_blocking(move || {
         let mut rng = StdRng::from_os_rng();
         let mut store = SqliteSignalStore::new(app_clone);
         tauri::async_runtime::block_on(async {
+            // The bundle key is already bound to the account, so a change is
+            // recorded and announced here even when the trust check then refuses it
+            let identity_key = prekey_bundle.identity_key()?;
+            store.save_identity(&address_sync, identity_key).await?;
             process_prekey_bundle(
                 &address_sync,
                 &mut store.clone(),
@@ -428,23 +521,23 @@ pub(crate) async fn internal_establish_session_logic(
         })
     })
     .await
-    .map_err(|e| e.to_string())?
-    .map_err(|e: SignalProtocolError| e.to_string())?;
+    .map_err(|e| e.to_string())?;
 
-    {
-        let db_state = app.state::<DbState>();
-        let conn = db_state.get_conn()?;
-        let _ = conn.execute(
-            "UPDATE signal_identities_remote SET trust_level = ?1 WHERE address = ?2",
-            rusqlite::params![existing_trust, address.to_string()],
-        );
+    if let Err(e) = processed {
+        let conn = app.state::<DbState>().get_conn()?;
+        if blocked_until_reverified(&conn, &format!("{}:{}", remote_hash, device_id))? {
+            return Err(format!(
+                "Safety number with {} changed. Verify it before sending",
+                remote_hash
+            ));
+        }
+        return Err(e.to_string());
     }
-
     Ok(())
 }
 
 #[tauri::command]
-pub async fn signal_init(handle: tauri::AppHandle) -> Result<String, String> {
+pub async fn signal_init<R: Runtime>(handle: tauri::AppHandle<R>) -> Result<String, String> {
     let store = SqliteSignalStore::new(handle.clone());
     let store_clone = store.clone();
     let kp_res = tauri::async_runtime::spawn_blocking(move || {
@@ -464,7 +557,10 @@ pub async fn signal_init(handle: tauri::AppHandle) -> Result<String, String> {
         let pub_bytes = hex::decode(&pub_key_hex).unwrap_or_default();
         let mut hasher = Sha256::new();
         hasher.update(&pub_bytes);
-        let id_hash = hex::encode(hasher.finalize());
+        // A linked device acts for its primary's account
+        let id_hash = local_link(&handle)
+            .map(|link| link.account)
+            .unwrap_or_else(|| hex::encode(hasher.finalize()));
         if let Ok(mut hash_lock) = state.identity_hash.lock() {
             *hash_lock = Some(id_hash);
         }
@@ -502,7 +598,10 @@ pub async fn signal_init(handle: tauri::AppHandle) -> Result<String, String> {
 }
 
 #[tauri::command]
-pub async fn signal_sync_keys(handle: AppHandle, count: Option<u32>) -> Result<(), String> {
+pub async fn signal_sync_keys<R: Runtime>(
+    handle: AppHandle<R>,
+    count: Option<u32>,
+) -> Result<(), String> {
     let state_handle = handle.clone();
     let state = state_handle.state::<NetworkState>();
     let raw_bundle = signal_get_bundle(handle.clone(), count).await?;
@@ -513,27 +612,26 @@ pub async fn signal_sync_keys(handle: AppHandle, count: Option<u32>) -> Result<(
             .map_err(|_| "Network state poisoned")?;
         lock.clone().ok_or("No identity hash in network state")?
     };
-    let mut ik_bytes =
-        hex::decode(raw_bundle["identityKey"].as_str().unwrap_or("")).unwrap_or_default();
-    if ik_bytes.len() == 33 && ik_bytes[0] == 0x05 {
-        ik_bytes.remove(0);
-    }
-    let bundle = json!({
-        "identity_hash": id_hash,
-        "registrationId": raw_bundle["registrationId"],
-        "identityKey": base64::engine::general_purpose::STANDARD.encode(&ik_bytes),
-        "signedPreKey": {
-            "id": raw_bundle["signedPreKey"]["id"],
-            "publicKey": base64::engine::general_purpose::STANDARD.encode(hex::decode(raw_bundle["signedPreKey"]["publicKey"].as_str().unwrap_or("")).unwrap_or_default()),
-            "signature": base64::engine::general_purpose::STANDARD.encode(hex::decode(raw_bundle["signedPreKey"]["signature"].as_str().unwrap_or("")).unwrap_or_default()),
-        },
-        "preKeys": raw_bundle["preKeys"],
-        "kyberPreKey": {
-            "id": raw_bundle["kyberPreKey"]["id"],
-            "publicKey": base64::engine::general_purpose::STANDARD.encode(hex::decode(raw_bundle["kyberPreKey"]["publicKey"].as_str().unwrap_or("")).unwrap_or_default()),
-            "signature": base64::engine::general_purpose::STANDARD.encode(hex::decode(raw_bundle["kyberPreKey"]["signature"].as_str().unwrap_or("")).unwrap_or_default())
-        }
-    });
+    let hex_to_b64 = |v: &serde_json::Value| {
+        base64::engine::general_purpose::STANDARD
+            .encode(hex::decode(v.as_str().unwrap_or("")).unwrap_or_default())
+    };
+    let signed_key_upload = |v: &serde_json::Value| SignedKeyUpload {
+        id: v["id"].as_u64().unwrap_or(0) as u32,
+        public_key: hex_to_b64(&v["publicKey"]),
+        signature: hex_to_b64(&v["signature"]),
+    };
+    let signed_pre_key = signed_key_upload(&raw_bundle["signedPreKey"]);
+    let kyber_pre_key = signed_key_upload(&raw_bundle["kyberPreKey"]);
+    let published = (signed_pre_key.id, kyber_pre_key.id);
+    let pre_keys: Vec<PreKeyUpload> =
+        serde_json::from_value(raw_bundle["preKeys"].clone()).map_err(|e| e.to_string())?;
+    let kyber_pre_keys: Vec<SignedKeyUpload> = raw_bundle["kyberPreKeys"]
+        .as_array()
+        .map(|keys| keys.iter().map(&signed_key_upload).collect())
+        .unwrap_or_default();
+    let registration_id = raw_bundle["registrationId"].as_u64().unwrap_or(0) as u32;
+
     let kp_res = tauri::async_runtime::spawn_blocking(move || {
         let store = SqliteSignalStore::new(handle);
         tauri::async_runtime::block_on(async { store.get_identity_key_pair().await })
@@ -547,27 +645,48 @@ pub async fn signal_sync_keys(handle: AppHandle, count: Option<u32>) -> Result<(
         .private_key()
         .calculate_signature(id_hash.as_bytes(), &mut rng)
         .map_err(|e| e.to_string())?;
-    let mut final_upload = bundle;
     let mut pk_bytes = kp.identity_key().serialize().to_vec();
     if pk_bytes.len() == 33 && pk_bytes[0] == 0x05 {
         pk_bytes.remove(0);
     }
-    final_upload["identityKey"] = json!(hex::encode(&pk_bytes));
-    final_upload["signature"] = json!(hex::encode(&sig));
-    let response = internal_request(&state, "keys_upload", final_upload).await?;
-    if response["status"].as_str() == Some("success") {
-        Ok(())
+    // A primary lists the devices it still authorizes; a linked device shows its link
+    let link = local_link(&state_handle);
+    let devices = match link {
+        Some(_) => None,
+        None => Some(linked_devices(
+            &state_handle.state::<DbState>().get_conn()?,
+            &id_hash,
+        )),
+    };
+    let upload = ClientMessage::KeysUpload {
+        identity_hash: id_hash,
+        registration_id,
+        identity_key: hex::encode(&pk_bytes),
+        signed_pre_key,
+        pre_keys,
+        kyber_pre_key,
+        kyber_pre_keys: (!kyber_pre_keys.is_empty()).then_some(kyber_pre_keys),
+        signature: hex::encode(&sig),
+        device_id: link.as_ref().map(|l| l.device_id),
+        link,
+        devices,
+    };
+    let response: KeysUploadResponse = decode_response(internal_request(&state, upload).await?)?;
+    if response.status == "success" {
+        // Keys the relay no longer serves start their grace period now
+        let conn = state_handle.state::<DbState>().get_conn()?;
+        retire_published_keys(&conn, published.0, published.1, now_ms())
     } else {
         Err(format!(
             "Key upload failed: {}",
-            response["error"].as_str().unwrap_or("Unknown")
+            response.error.as_deref().unwrap_or("Unknown")
         ))
     }
 }
 
 #[tauri::command]
-pub async fn signal_encrypt(
-    handle: tauri::AppHandle,
+pub async fn signal_encrypt<R: Runtime>(
+    handle: tauri::AppHandle<R>,
     remote_hash: String,
     message: String,
 ) -> Result<serde_json::Value, String> {
@@ -576,8 +695,8 @@ pub async fn signal_encrypt(
 }
 
 #[tauri::command]
-pub async fn signal_sign_message(
-    handle: tauri::AppHandle,
+pub async fn signal_sign_message<R: Runtime>(
+    handle: tauri::AppHandle<R>,
     message: String,
 ) -> Result<String, String> {
     let kp_res = tauri::async_runtime::spawn_blocking(move || {
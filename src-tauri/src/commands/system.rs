@@ -1,9 +1,9 @@
 use crate::app_state::DbState;
-use tauri::State;
+use tauri::{Runtime, State};
 
 #[tauri::command]
-pub fn open_file(
-    app: tauri::AppHandle,
+pub fn open_file<R: Runtime>(
+    app: tauri::AppHandle<R>,
     _state: State<'_, DbState>,
     path: String,
 ) -> Result<(), String> {
@@ -42,3 +42,19 @@ pub fn get_media_proxy_port(state: State<'_, DbState>) -> Result<u16, String> {
     let port = state.media_proxy_port.lock().map_err(|_| "Lock poisoned")?;
     port.ok_or_else(|| "Media proxy not initialized".to_string())
 }
+
+/// Signed, short-lived media proxy URL for vault media `id`.
+#[tauri::command]
+pub fn get_media_url(
+    state: State<'_, DbState>,
+    id: String,
+    mime_type: String,
+) -> Result<String, String> {
+    crate::media_proxy::media_url(&state, &id, &mime_type)
+}
+
+/// Signed media proxy URL for a file the user picked for sending.
+#[tauri::command]
+pub fn get_local_media_url(state: State<'_, DbState>, path: String) -> Result<String, String> {
+    crate::media_proxy::local_url(&state, &path)
+}
@@ -1,12 +1,12 @@
 use crate::app_state::DbState;
 use crate::commands::{get_db_filename, get_media_dirname};
-use tauri::{Manager, State};
+use tauri::{Manager, Runtime, State};
 use walkdir::WalkDir;
 use zip::write::FileOptions;
 
 #[tauri::command]
-pub async fn export_database(
-    app: tauri::AppHandle,
+pub async fn export_database<R: Runtime>(
+    app: tauri::AppHandle<R>,
     state: State<'_, DbState>,
     target_path: String,
     include_media: bool,
@@ -93,8 +93,8 @@ pub async fn export_database(
 }
 
 #[tauri::command]
-pub async fn import_database(
-    app: tauri::AppHandle,
+pub async fn import_database<R: Runtime>(
+    app: tauri::AppHandle<R>,
     state: State<'_, DbState>,
     src_path: String,
     include_media: bool,
@@ -106,6 +106,7 @@ pub async fn import_database(
             .map_err(|_| "Database connection lock poisoned")?;
         *pool_lock = None;
     }
+    crate::media_proxy::lock_media_proxy(&state);
 
     let backup_path = std::path::Path::new(&src_path);
     let extension = backup_path
@@ -5,7 +5,7 @@ use argon2::{
 };
 use r2d2::Pool;
 use rusqlite::OpenFlags;
-use tauri::{AppHandle, Manager, State};
+use tauri::{AppHandle, Manager, Runtime, State};
 
 const MIGRATIONS: &[&str] = &[
     // Version 1: Initial Schema
@@ -159,8 +159,120 @@ const MIGRATIONS: &[&str] = &[
     ",
     // Version 2: Reaction Emojis — adds reactions_json to existing databases
     "ALTER TABLE messages ADD COLUMN reactions_json TEXT;",
+    // Version 3: Relay Endpoints — user-configurable relays with failover priority
+    "
+    CREATE TABLE IF NOT EXISTS relay_endpoints (
+        url TEXT PRIMARY KEY,
+        priority INTEGER NOT NULL DEFAULT 0,
+        proxy_url TEXT,
+        is_enabled INTEGER DEFAULT 1
+    );
+    ",
+    // Version 4: Outbox Retry — attempt tracking, backoff and relay-confirmed removal
+    "
+    ALTER TABLE pending_outbox ADD COLUMN transfer_id INTEGER;
+    ALTER TABLE pending_outbox ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE pending_outbox ADD COLUMN last_attempt_at INTEGER;
+    ALTER TABLE pending_outbox ADD COLUMN next_attempt_at INTEGER NOT NULL DEFAULT 0;
+    CREATE INDEX IF NOT EXISTS idx_pending_outbox_msg ON pending_outbox(msg_id);
+    CREATE INDEX IF NOT EXISTS idx_pending_outbox_transfer ON pending_outbox(transfer_id);
+    ",
+    // Version 5: Halted Targets — relay delivery errors with reason and expiry
+    "
+    CREATE TABLE IF NOT EXISTS halted_targets (
+        target TEXT PRIMARY KEY,
+        reason TEXT NOT NULL,
+        halted_at INTEGER NOT NULL,
+        expires_at INTEGER NOT NULL
+    );
+    ",
+    // Version 6: Outgoing Transfers — upload progress and sealed transit keys for resume
+    "
+    CREATE TABLE IF NOT EXISTS outgoing_transfers (
+        transfer_id INTEGER PRIMARY KEY,
+        msg_id TEXT NOT NULL,
+        chat_address TEXT NOT NULL,
+        recipients TEXT NOT NULL,
+        file_path TEXT NOT NULL,
+        file_size INTEGER NOT NULL,
+        total_fragments INTEGER NOT NULL,
+        fragments_sent INTEGER NOT NULL DEFAULT 0,
+        fragments_confirmed INTEGER NOT NULL DEFAULT 0,
+        transit_key BLOB NOT NULL,
+        announcement TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        completed_at INTEGER
+    );
+    ",
+    // Version 7: Transfer Controls — uploads paused by the user stay paused across restarts
+    "ALTER TABLE outgoing_transfers ADD COLUMN paused INTEGER NOT NULL DEFAULT 0;",
+    // Version 8: Linked Devices — this device's link record and the devices of each account
+    "
+    ALTER TABLE signal_identity ADD COLUMN device_link TEXT;
+    CREATE TABLE IF NOT EXISTS account_devices (
+        account TEXT NOT NULL,
+        device_id INTEGER NOT NULL,
+        device_key TEXT NOT NULL,
+        link TEXT,
+        name TEXT,
+        updated_at INTEGER NOT NULL,
+        revoked_at INTEGER,
+        PRIMARY KEY (account, device_id)
+    );
+    ",
+    // Version 9: Prekey Rotation — creation and supersession times, and which Kyber key is
+    // the last-resort one. Only last-resort Kyber keys were ever published, so existing
+    // rows are all treated as such and superseded by the first upload after the upgrade.
+    "
+    ALTER TABLE signal_signed_pre_keys ADD COLUMN superseded_at INTEGER;
+    ALTER TABLE signal_kyber_pre_keys ADD COLUMN created_at INTEGER;
+    ALTER TABLE signal_kyber_pre_keys ADD COLUMN last_resort INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE signal_kyber_pre_keys ADD COLUMN superseded_at INTEGER;
+    UPDATE signal_kyber_pre_keys SET last_resort = 1;
+    ",
+    // Version 10: Identity Key History — every change of a peer's identity key, and whether
+    // the replacement still awaits verification.
+    "
+    ALTER TABLE signal_identities_remote ADD COLUMN awaiting_verification INTEGER NOT NULL DEFAULT 0;
+    CREATE TABLE IF NOT EXISTS identity_key_changes (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        address TEXT NOT NULL,
+        old_key BLOB NOT NULL,
+        new_key BLOB NOT NULL,
+        changed_at INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS idx_identity_key_changes_address ON identity_key_changes(address, changed_at);
+    ",
+    // Version 11: Identity Quarantine — messages decrypted from a key that awaits
+    // verification, held until the peer is verified again.
+    "
+    CREATE TABLE IF NOT EXISTS quarantined_messages (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        address TEXT NOT NULL,
+        routed_by TEXT NOT NULL,
+        plaintext TEXT NOT NULL,
+        received_at INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS idx_quarantined_messages_address ON quarantined_messages(address);
+    ",
 ];
 
+/// Brings the schema up to the latest version, tracked in `PRAGMA user_version`.
+pub(crate) fn apply_migrations(conn: &rusqlite::Connection) -> Result<(), String> {
+    let current_version: i32 = conn
+        .query_row("PRAGMA user_version", [], |r| r.get(0))
+        .map_err(|e| format!("Schema check failed: {}", e))?;
+    for (idx, sql) in MIGRATIONS.iter().enumerate() {
+        let ver = (idx + 1) as i32;
+        if ver > current_version {
+            conn.execute_batch(sql).map_err(|e| e.to_string())?;
+            conn.execute(&format!("PRAGMA user_version = {}", ver), [])
+                .map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(())
+}
+
 pub fn get_db_filename() -> String {
     if let Ok(profile) = std::env::var("ENTROPY_PROFILE")
         && !profile.is_empty()
@@ -180,7 +292,7 @@ pub fn get_media_dirname() -> String {
 }
 
 #[tauri::command]
-pub fn vault_exists(app: AppHandle) -> bool {
+pub fn vault_exists<R: Runtime>(app: AppHandle<R>) -> bool {
     if let Ok(app_data_dir) = app.path().app_data_dir() {
         return app_data_dir.join(get_db_filename()).exists();
     }
@@ -188,8 +300,8 @@ pub fn vault_exists(app: AppHandle) -> bool {
 }
 
 #[tauri::command]
-pub async fn init_vault(
-    app: tauri::AppHandle,
+pub async fn init_vault<R: Runtime>(
+    app: tauri::AppHandle<R>,
     state: State<'_, DbState>,
     passphrase: String,
 ) -> Result<(), String> {
@@ -361,21 +473,7 @@ pub async fn init_vault(
     let conn = state.get_conn()?;
     let _ = conn.execute("PRAGMA journal_mode=WAL;", []);
 
-    // Migrations
-    let current_version: i32 = conn
-        .query_row("PRAGMA user_version", [], |r| r.get(0))
-        .map_err(|e| format!("Schema check failed: {}", e))?;
-    let target_version = MIGRATIONS.len() as i32;
-    if current_version < target_version {
-        for (idx, sql) in MIGRATIONS.iter().enumerate() {
-            let ver = (idx + 1) as i32;
-            if ver > current_version {
-                conn.execute_batch(sql).map_err(|e| e.to_string())?;
-                conn.execute(&format!("PRAGMA user_version = {}", ver), [])
-                    .map_err(|e| e.to_string())?;
-            }
-        }
-    }
+    apply_migrations(&conn)?;
 
     let flag_path = app_data_dir.join(".restore_cleanup_pending");
     if flag_path.exists() {
@@ -419,6 +517,12 @@ pub async fn init_vault(
             .map_err(|_| "Media key lock poisoned")?;
         *state_key = Some(media_key);
     }
+    crate::media_proxy::unlock_media_proxy(&state);
+
+    crate::commands::network::load_network_config(&app);
+    crate::commands::network::halts::load_halted_targets(&app);
+    crate::commands::messaging::outbox::uploads::resume_uploads(&app);
+    crate::commands::network::sweeper::purge_orphaned_temp_files(&app);
 
     // Restore the secure session from the vault
     let (pub_key_opt, token_opt) = {
@@ -465,7 +569,10 @@ pub async fn init_vault(
 }
 
 #[tauri::command]
-pub fn set_panic_password(app: tauri::AppHandle, password: String) -> Result<(), String> {
+pub fn set_panic_password<R: Runtime>(
+    app: tauri::AppHandle<R>,
+    password: String,
+) -> Result<(), String> {
     let app_data_dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
 
     let argon2 = Argon2::new(
@@ -487,11 +594,15 @@ pub fn set_panic_password(app: tauri::AppHandle, password: String) -> Result<(),
 }
 
 #[tauri::command]
-pub fn reset_database(app: tauri::AppHandle, state: State<'_, DbState>) -> Result<(), String> {
+pub fn reset_database<R: Runtime>(
+    app: tauri::AppHandle<R>,
+    state: State<'_, DbState>,
+) -> Result<(), String> {
     {
         let mut pool_lock = state.pool.lock().map_err(|_| "Pool lock poisoned")?;
         *pool_lock = None;
     }
+    crate::media_proxy::lock_media_proxy(&state);
 
     let filename = get_db_filename();
     let app_dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
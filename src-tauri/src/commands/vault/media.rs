@@ -3,11 +3,11 @@ use chacha20poly1305::{
     Key, XChaCha20Poly1305, XNonce,
     aead::{Aead, KeyInit},
 };
-use tauri::{Manager, State};
+use tauri::{Manager, Runtime, State};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-pub fn get_media_dir(
-    app: &tauri::AppHandle,
+pub fn get_media_dir<R: Runtime>(
+    app: &tauri::AppHandle<R>,
     state: &State<'_, DbState>,
 ) -> Result<std::path::PathBuf, String> {
     let app_dir = app
@@ -25,7 +25,10 @@ pub fn get_media_dir(
 // vault_save_media handles encryption now.
 
 #[tauri::command]
-pub async fn vault_delete_media(app: tauri::AppHandle, id: String) -> Result<(), String> {
+pub async fn vault_delete_media<R: Runtime>(
+    app: tauri::AppHandle<R>,
+    id: String,
+) -> Result<(), String> {
     let state = app.state::<DbState>();
     let media_dir = get_media_dir(&app, &state)?;
     let safe_id = id
@@ -43,8 +46,8 @@ pub async fn vault_delete_media(app: tauri::AppHandle, id: String) -> Result<(),
     Ok(())
 }
 #[tauri::command]
-pub async fn vault_export_media(
-    app: tauri::AppHandle,
+pub async fn vault_export_media<R: Runtime>(
+    app: tauri::AppHandle<R>,
     state: tauri::State<'_, DbState>,
     id: String,
     target_path: String,
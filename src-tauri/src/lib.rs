@@ -16,7 +16,6 @@ mod signal_store;
 mod tests;
 
 use app_state::{DbState, NetworkState};
-use std::sync::Mutex;
 use tauri::Manager;
 use tauri::{
     menu::{Menu, MenuItem},
@@ -28,32 +27,8 @@ pub fn run() {
     println!("Starting Entropy (Profile: {})", profile);
 
     let mut builder = tauri::Builder::default()
-        .manage(DbState {
-            pool: Mutex::new(None),
-            media_key: Mutex::new(None),
-            profile: Mutex::new(profile),
-            media_proxy_port: Mutex::new(None),
-        })
-        .manage(NetworkState {
-            is_enabled: Mutex::new(false),
-            url: Mutex::new(None),
-            proxy_url: Mutex::new(None),
-            queue: Mutex::new(std::collections::VecDeque::new()),
-            sender: Mutex::new(None),
-            cancel: Mutex::new(None),
-            response_channels: Mutex::new(std::collections::HashMap::new()),
-            is_authenticated: Mutex::new(false),
-            identity_hash: Mutex::new(None),
-            session_token: Mutex::new(None),
-            halted_targets: Mutex::new(std::collections::HashSet::new()),
-            media_assembler: Mutex::new(std::collections::HashMap::new()),
-            pending_media_links: Mutex::new(std::collections::HashMap::new()),
-            binary_receiver: Mutex::new(None),
-            is_refilling: Mutex::new(false),
-            jailed_until: Mutex::new(None),
-            pending_transfers: Mutex::new(std::collections::HashMap::new()),
-            active_outgoing_transfers: Mutex::new(std::collections::HashMap::new()),
-        })
+        .manage(DbState::new(profile))
+        .manage(NetworkState::default())
         .plugin(tauri_plugin_shell::init())
         .plugin(tauri_plugin_notification::init())
         .plugin(tauri_plugin_fs::init())
@@ -84,11 +59,23 @@ pub fn run() {
             commands::signal_set_peer_trust,
             commands::signal_get_identity_hash,
             commands::signal_get_fingerprint,
+            commands::signal_verify_scanned_fingerprint,
+            commands::signal_get_identity_policy,
+            commands::signal_set_identity_policy,
+            commands::signal_get_key_history,
+            commands::device_link_begin,
+            commands::device_link_approve,
+            commands::device_link_complete,
+            commands::device_list,
+            commands::device_unlink,
+            commands::signal_prekey_inventory,
             commands::send_typing_status,
             commands::send_receipt,
             commands::send_profile_update,
             commands::open_file,
             commands::get_media_proxy_port,
+            commands::get_media_url,
+            commands::get_local_media_url,
             commands::db_get_messages,
             commands::db_search_messages,
             commands::db_update_messages,
@@ -118,7 +105,23 @@ pub fn run() {
             commands::process_outgoing_media,
             commands::process_outgoing_group_media,
             commands::process_outgoing_reaction,
-            commands::vault_retry_bridge
+            commands::vault_retry_bridge,
+            commands::network_get_relays,
+            commands::network_set_relays,
+            commands::network_get_config,
+            commands::network_set_config,
+            commands::outbox_list,
+            commands::outbox_retry,
+            commands::outbox_cancel,
+            commands::network_get_queue_depths,
+            commands::network_get_stats,
+            commands::network_list_halted_targets,
+            commands::network_clear_halted_targets,
+            commands::outbox_list_uploads,
+            commands::outbox_cancel_upload,
+            commands::transfer_pause,
+            commands::transfer_resume,
+            commands::transfer_cancel
         ])
         .setup(|app| {
             // Linux-specific fix: Allow microphone permission request for WebKitGTK
@@ -164,6 +167,7 @@ pub fn run() {
                     .menu(&menu)
                     .on_menu_event(|app, event| match event.id.as_ref() {
                         "quit" => {
+                            tauri::async_runtime::block_on(commands::shutdown_network(app));
                             app.exit(0);
                         }
                         "show" => {
@@ -197,6 +201,13 @@ pub fn run() {
             // Start the Zero-RAM Media Proxy
             media_proxy::start_media_server(app.handle().clone());
 
+            commands::network::sweeper::start_transfer_sweeper(app.handle().clone());
+            commands::network::retry::start_outbox_retry(app.handle().clone());
+            commands::messaging::delivery::start_delivery_monitor(app.handle().clone());
+            commands::network::stats::start_stats_emitter(app.handle().clone());
+            commands::identity::prekeys::start_prekey_rotation(app.handle().clone());
+            commands::identity::devices::start_link_renewal(app.handle().clone());
+
             Ok(())
         })
         .on_window_event(|window, event| {
@@ -1,37 +1,202 @@
-use crate::app_state::DbState;
+use crate::app_state::{DbState, MediaProxyAuth, NetworkState, PendingMediaMetadata};
+use crate::commands::messaging::inbox::handlers::media::find_partial_download;
+use crate::commands::messaging::inbox::reassembler::{
+    BlockState, media_block_state, temp_filename,
+};
+use crate::commands::network::codec::{
+    AEAD_OVERHEAD, CHUNK_CAPACITY, FRAME_MEDIA, MEDIA_BLOCK_SIZE, media_block_len,
+};
 use crate::commands::vault::media::get_media_dir;
+use base64::Engine;
 use chacha20poly1305::{
     Key, XChaCha20Poly1305, XNonce,
     aead::{Aead, KeyInit},
 };
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 use std::net::SocketAddr;
-use tauri::Manager;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tauri::{Manager, Runtime};
 use warp::Filter;
-use warp::http::{Response, StatusCode};
+use warp::http::{HeaderMap, Method, Response, StatusCode};
 use warp::hyper::Body;
 
-pub fn start_media_server(app: tauri::AppHandle) {
+mod http;
+
+pub(crate) use http::{ByteRange, parse_range, resolve_mime, sniff_mime};
+use http::{etag, if_range_holds, none_match_hits};
+
+/// How long a stream of a download in progress waits for a block that has not arrived.
+const PARTIAL_BLOCK_TIMEOUT: Duration = Duration::from_secs(30);
+const PARTIAL_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Lifetime of a signed proxy URL, long enough to finish playing a long video.
+const MEDIA_URL_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Issues a fresh URL-signing secret when the vault is unlocked. URLs signed before
+/// a lock stop working.
+pub(crate) fn unlock_media_proxy(state: &DbState) {
+    if let Ok(mut auth) = state.media_proxy_auth.lock() {
+        *auth = Some(MediaProxyAuth {
+            secret: rand::random(),
+            local_paths: Default::default(),
+        });
+    }
+}
+
+pub(crate) fn lock_media_proxy(state: &DbState) {
+    if let Ok(mut auth) = state.media_proxy_auth.lock() {
+        *auth = None;
+    }
+}
+
+/// HMAC over what a URL grants (`media:<id>` or `local:<path>`) and when it expires.
+pub(crate) fn sign_scope(secret: &[u8; 32], scope: &str, expires: u64) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes any key length");
+    mac.update(format!("{}\n{}", scope, expires).as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+pub(crate) fn verify_scope(
+    secret: &[u8; 32],
+    scope: &str,
+    expires: u64,
+    signature: &str,
+    now: u64,
+) -> bool {
+    if now > expires {
+        return false;
+    }
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes any key length");
+    mac.update(format!("{}\n{}", scope, expires).as_bytes());
+    mac.verify_slice(&signature).is_ok()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Signed proxy URL for `scope`, or an error while the vault is locked.
+fn signed_query(state: &DbState, scope: &str) -> Result<String, String> {
+    let auth = state.media_proxy_auth.lock().map_err(|_| "Lock poisoned")?;
+    let auth = auth.as_ref().ok_or("Vault is locked")?;
+    let expires = unix_now() + MEDIA_URL_TTL.as_secs();
+    Ok(format!(
+        "exp={}&sig={}",
+        expires,
+        sign_scope(&auth.secret, scope, expires)
+    ))
+}
+
+fn proxy_port(state: &DbState) -> Result<u16, String> {
+    let port = state.media_proxy_port.lock().map_err(|_| "Lock poisoned")?;
+    port.ok_or_else(|| "Media proxy not initialized".to_string())
+}
+
+/// URL serving vault media `id` as `mime_type` until it expires.
+pub(crate) fn media_url(state: &DbState, id: &str, mime_type: &str) -> Result<String, String> {
+    let query = signed_query(state, &format!("media:{}", id))?;
+    Ok(format!(
+        "http://127.0.0.1:{}/media/{}?type={}&{}",
+        proxy_port(state)?,
+        id,
+        url::form_urlencoded::byte_serialize(mime_type.as_bytes()).collect::<String>(),
+        query
+    ))
+}
+
+/// Allows `/local` to serve a file the user picked and returns its URL.
+pub(crate) fn local_url(state: &DbState, path: &str) -> Result<String, String> {
+    let canonical =
+        std::fs::canonicalize(path).map_err(|e| format!("Invalid or inaccessible path: {}", e))?;
+    if !canonical.is_file() {
+        return Err("Not a file".into());
+    }
+    let path_str = canonical.to_string_lossy().to_string();
+    state
+        .media_proxy_auth
+        .lock()
+        .map_err(|_| "Lock poisoned")?
+        .as_mut()
+        .ok_or("Vault is locked")?
+        .local_paths
+        .insert(canonical);
+    let query = signed_query(state, &format!("local:{}", path_str))?;
+    Ok(format!(
+        "http://127.0.0.1:{}/local?path={}&{}",
+        proxy_port(state)?,
+        url::form_urlencoded::byte_serialize(path_str.as_bytes()).collect::<String>(),
+        query
+    ))
+}
+
+/// Whether a request carries a valid signature for `scope` while the vault is open.
+/// `local_path` must also be one the user picked.
+fn authorize<R: Runtime>(
+    app: &tauri::AppHandle<R>,
+    scope: &str,
+    query: &std::collections::HashMap<String, String>,
+    local_path: Option<&std::path::Path>,
+) -> bool {
+    let state = app.state::<DbState>();
+    let unlocked = state.pool.lock().map(|p| p.is_some()).unwrap_or(false);
+    let Ok(auth) = state.media_proxy_auth.lock() else {
+        return false;
+    };
+    let Some(auth) = auth.as_ref().filter(|_| unlocked) else {
+        return false;
+    };
+    let expires = query
+        .get("exp")
+        .and_then(|e| e.parse::<u64>().ok())
+        .unwrap_or(0);
+    let signature = query.get("sig").map(String::as_str).unwrap_or_default();
+    verify_scope(&auth.secret, scope, expires, signature, unix_now())
+        && local_path.is_none_or(|p| auth.local_paths.contains(p))
+}
+
+pub fn start_media_server<R: Runtime>(app: tauri::AppHandle<R>) {
     let app_handle = app.clone();
     let app_handle2 = app.clone();
+    let app_handle3 = app.clone();
 
     tauri::async_runtime::spawn(async move {
         let media_route = warp::path!("media" / String)
-            .and(warp::header::optional::<String>("range"))
+            .and(warp::method())
+            .and(warp::header::headers_cloned())
             .and(warp::query::<std::collections::HashMap<String, String>>())
-            .and_then(move |id, range, query| {
+            .and_then(move |id, method, headers, query| {
                 let app = app_handle.clone();
-                async move { handle_media_request(app, id, range, query).await }
+                async move { handle_media_request(app, id, method, headers, query).await }
             });
 
         // Streams a raw local file (pre-encryption) for same-origin thumbnail capture
         let local_route = warp::path!("local")
+            .and(warp::method())
+            .and(warp::header::headers_cloned())
             .and(warp::query::<std::collections::HashMap<String, String>>())
-            .and(warp::header::optional::<String>("range"))
-            .and_then(|params: std::collections::HashMap<String, String>, range: Option<String>| async move {
-                let path = params.get("path").cloned().unwrap_or_default();
-                let path = percent_decode(&path);
-                handle_local_file_request(path, range).await
-            });
+            .and_then(
+                move |method: Method,
+                      headers: HeaderMap,
+                      params: std::collections::HashMap<String, String>| {
+                    let app = app_handle3.clone();
+                    async move {
+                        // The query is already decoded; the signature covers the canonical path
+                        let path = params.get("path").cloned().unwrap_or_default();
+                        let canonical = std::fs::canonicalize(&path).map_err(|_| warp::reject())?;
+                        if !authorize(&app, &format!("local:{}", path), &params, Some(&canonical)) {
+                            return Err(warp::reject());
+                        }
+                        handle_local_file_request(canonical, method, headers).await
+                    }
+                },
+            );
 
         let routes = media_route.or(local_route);
 
@@ -48,43 +213,128 @@ pub fn start_media_server(app: tauri::AppHandle) {
     });
 }
 
-async fn handle_local_file_request(
-    path: String,
-    range: Option<String>,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    let file_path = std::path::PathBuf::from(&path);
-    if !file_path.exists() {
-        return Err(warp::reject());
-    }
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
 
-    let total_size = std::fs::metadata(&file_path)
-        .map_err(|_| warp::reject())?
-        .len();
+/// Headers shared by every proxied representation.
+fn media_response(status: StatusCode, content_type: &str) -> warp::http::response::Builder {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", content_type)
+        .header("Accept-Ranges", "bytes")
+        .header("Access-Control-Allow-Origin", "*")
+        .header(
+            "Access-Control-Expose-Headers",
+            "Content-Range, Content-Length, Accept-Ranges, ETag",
+        )
+        .header("X-Content-Type-Options", "nosniff")
+}
 
-    let (start, end) = if let Some(r) = range {
-        if let Some(stripped) = r.strip_prefix("bytes=") {
-            let parts: Vec<&str> = stripped.split('-').collect();
-            let s = parts[0].parse::<u64>().unwrap_or(0);
-            let e = if parts.len() > 1 && !parts[1].is_empty() {
-                parts[1].parse::<u64>().unwrap_or(total_size - 1)
-            } else {
-                total_size - 1
-            };
-            (s, e)
-        } else {
-            (0, total_size - 1)
-        }
+fn status_response(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn not_modified(etag: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NOT_MODIFIED)
+        .header("ETag", etag)
+        .header("Cache-Control", "private, no-cache")
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn range_not_satisfiable(total: u64) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::RANGE_NOT_SATISFIABLE)
+        .header("Content-Range", format!("bytes */{}", total))
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Body::from("Range Not Satisfiable"))
+        .unwrap()
+}
+
+/// Start and length of the bytes to send from a representation of `total` bytes, and
+/// whether that is a partial response; `None` if the range cannot be satisfied.
+fn select_span(headers: &HeaderMap, total: u64, etag: Option<&str>) -> Option<(u64, u64, bool)> {
+    // A range only applies to the representation the client already holds part of
+    let if_range = header(headers, "if-range");
+    let range = header(headers, "range").filter(|_| match etag {
+        Some(etag) => if_range_holds(if_range, etag),
+        None => if_range.is_none(),
+    });
+    match parse_range(range, total) {
+        ByteRange::Full => Some((0, total, false)),
+        ByteRange::Partial(start, end) => Some((start, end - start + 1, true)),
+        ByteRange::Unsatisfiable => None,
+    }
+}
+
+/// Status, length and range headers for a span chosen by `select_span`.
+fn span_response(
+    content_type: &str,
+    (start, len, partial): (u64, u64, bool),
+    total: u64,
+) -> warp::http::response::Builder {
+    let status = if partial {
+        StatusCode::PARTIAL_CONTENT
     } else {
-        (0, total_size - 1)
+        StatusCode::OK
     };
+    let response = media_response(status, content_type).header("Content-Length", len);
+    if partial {
+        response.header(
+            "Content-Range",
+            format!("bytes {}-{}/{}", start, start + len - 1, total),
+        )
+    } else {
+        response
+    }
+}
+
+async fn handle_local_file_request(
+    file_path: std::path::PathBuf,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<Response<Body>, warp::Rejection> {
+    if method != Method::GET && method != Method::HEAD {
+        return Ok(status_response(StatusCode::METHOD_NOT_ALLOWED));
+    }
+    let metadata = std::fs::metadata(&file_path).map_err(|_| warp::reject())?;
+    let total_size = metadata.len();
+    let etag = etag(total_size, metadata.modified().ok());
+    if let Some(candidates) = header(&headers, "if-none-match")
+        && none_match_hits(candidates, &etag)
+    {
+        return Ok(not_modified(&etag));
+    }
 
-    let content_length = end - start + 1;
     let ext = file_path
         .extension()
         .and_then(|e| e.to_str())
         .unwrap_or("")
         .to_lowercase();
-    let content_type = mime_from_ext(&ext);
+    let mut head = Vec::with_capacity(64);
+    {
+        use std::io::Read;
+        let _ = std::fs::File::open(&file_path).and_then(|f| f.take(64).read_to_end(&mut head));
+    }
+    let content_type = resolve_mime(mime_from_ext(&ext), sniff_mime(&head));
+
+    let Some(span) = select_span(&headers, total_size, Some(&etag)) else {
+        return Ok(range_not_satisfiable(total_size));
+    };
+    let response = span_response(&content_type, span, total_size)
+        .header("ETag", &etag)
+        .header("Cache-Control", "private, no-cache");
+    let (start, content_length, _) = span;
+    if method == Method::HEAD || content_length == 0 {
+        return Ok(response.body(Body::empty()).unwrap());
+    }
 
     let (tx, rx) = tokio::sync::mpsc::channel::<Result<bytes::Bytes, std::io::Error>>(16);
 
@@ -115,44 +365,37 @@ async fn handle_local_file_request(
     });
 
     let stream = tokio_stream::wrappers::ReceiverStream::new(rx);
-    let status = if start > 0 || end < total_size - 1 {
-        StatusCode::PARTIAL_CONTENT
-    } else {
-        StatusCode::OK
-    };
-    let mut resp = Response::builder()
-        .status(status)
-        .header("Content-Type", content_type)
-        .header("Accept-Ranges", "bytes")
-        .header("Access-Control-Allow-Origin", "*")
-        .header("Content-Length", content_length);
-    if start > 0 || end < total_size - 1 {
-        resp = resp.header(
-            "Content-Range",
-            format!("bytes {}-{}/{}", start, end, total_size),
-        );
-    }
-    Ok(resp.body(Body::wrap_stream(stream)).unwrap())
+    Ok(response.body(Body::wrap_stream(stream)).unwrap())
 }
 
-async fn handle_media_request(
-    app: tauri::AppHandle,
+async fn handle_media_request<R: Runtime>(
+    app: tauri::AppHandle<R>,
     id: String,
-    range: Option<String>,
+    method: Method,
+    headers: HeaderMap,
     query: std::collections::HashMap<String, String>,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    let mime_type = query.get("type").cloned().unwrap_or_default();
-    let mime_type = percent_decode(&mime_type);
-    let mime_type = if mime_type.is_empty() {
-        mime_from_ext(
-            std::path::Path::new(&id)
-                .extension()
-                .and_then(|e| e.to_str())
-                .unwrap_or(""),
-        )
-    } else {
-        &mime_type
-    };
+) -> Result<Response<Body>, warp::Rejection> {
+    if !authorize(&app, &format!("media:{}", id), &query, None) {
+        return Err(warp::reject());
+    }
+    if method != Method::GET && method != Method::HEAD {
+        return Ok(status_response(StatusCode::METHOD_NOT_ALLOWED));
+    }
+
+    // The sender's declaration, checked against the content below
+    let declared = query
+        .get("type")
+        .map(|t| percent_decode(t))
+        .filter(|t| !t.is_empty())
+        .unwrap_or_else(|| {
+            mime_from_ext(
+                std::path::Path::new(&id)
+                    .extension()
+                    .and_then(|e| e.to_str())
+                    .unwrap_or(""),
+            )
+            .to_string()
+        });
 
     let state = app.state::<DbState>();
 
@@ -168,7 +411,27 @@ async fn handle_media_request(
     let file_path = media_dir.join(&id);
 
     if !file_path.exists() {
-        return Err(warp::reject());
+        // Not in the vault yet: stream from the reassembly if the download is under way
+        let partial = find_partial_download(&app.state::<NetworkState>(), &id);
+        return match partial {
+            Some((sender, transfer_id, meta)) => {
+                let temp_path = media_dir.join(temp_filename(&sender, transfer_id, FRAME_MEDIA));
+                handle_partial_media_request(
+                    app.clone(),
+                    PartialDownload {
+                        sender,
+                        transfer_id,
+                        meta,
+                        temp_path,
+                    },
+                    method,
+                    headers,
+                    declared,
+                )
+                .await
+            }
+            None => Err(warp::reject()),
+        };
     }
 
     let mut file = tokio::fs::File::open(&file_path)
@@ -177,52 +440,58 @@ async fn handle_media_request(
     let metadata = file.metadata().await.map_err(|_| warp::reject())?;
     let total_vault_size = metadata.len();
 
-    // Each block is 1319 bytes (24B Nonce + 1279B Data + 16B Tag)
-    let num_blocks = total_vault_size / 1319;
-    let last_block_rem = total_vault_size % 1319;
+    // Each block is a nonce, up to MEDIA_BLOCK_SIZE bytes of data and a tag
+    let block_size_enc = CHUNK_CAPACITY;
+    let block_size_plain = MEDIA_BLOCK_SIZE;
+    let num_blocks = total_vault_size / block_size_enc as u64;
+    let last_block_rem = total_vault_size % block_size_enc as u64;
 
     // Total plaintext size
-    let mut total_plain_size = num_blocks * 1279;
-    if last_block_rem > 40 {
-        total_plain_size += last_block_rem - 40;
+    let mut total_plain_size = num_blocks * block_size_plain as u64;
+    if last_block_rem > AEAD_OVERHEAD as u64 {
+        total_plain_size += last_block_rem - AEAD_OVERHEAD as u64;
     }
 
-    // 3. Handle Range Header
-    let (start, end) = if let Some(r) = range.as_ref() {
-        if let Some(stripped) = r.strip_prefix("bytes=") {
-            let parts: Vec<&str> = stripped.split('-').collect();
-            let start = parts[0].parse::<u64>().unwrap_or(0);
-            let end = if parts.len() > 1 && !parts[1].is_empty() {
-                parts[1].parse::<u64>().unwrap_or(total_plain_size - 1)
-            } else {
-                total_plain_size - 1
-            };
-            (start, end)
-        } else {
-            (0, total_plain_size - 1)
+    let cipher = XChaCha20Poly1305::new(key);
+
+    // 3. Validators and content type
+    let etag = etag(total_plain_size, metadata.modified().ok());
+    if let Some(candidates) = header(&headers, "if-none-match")
+        && none_match_hits(candidates, &etag)
+    {
+        return Ok(not_modified(&etag));
+    }
+    let head = {
+        use tokio::io::AsyncReadExt;
+        let mut first_block = vec![0u8; total_vault_size.min(block_size_enc as u64) as usize];
+        match file.read_exact(&mut first_block).await {
+            Ok(_) if first_block.len() > AEAD_OVERHEAD => cipher
+                .decrypt(XNonce::from_slice(&first_block[..24]), &first_block[24..])
+                .ok(),
+            _ => None,
         }
-    } else {
-        (0, total_plain_size - 1)
     };
+    let content_type = resolve_mime(&declared, head.as_deref().and_then(sniff_mime));
 
-    if start >= total_plain_size {
-        return Ok(Response::builder()
-            .status(StatusCode::RANGE_NOT_SATISFIABLE)
-            .body(Body::from("Range Not Satisfiable"))
-            .unwrap());
+    // 4. Handle Range Header
+    let Some(span) = select_span(&headers, total_plain_size, Some(&etag)) else {
+        return Ok(range_not_satisfiable(total_plain_size));
+    };
+    let response = span_response(&content_type, span, total_plain_size)
+        .header("ETag", &etag)
+        .header("Cache-Control", "private, no-cache");
+    let (start, content_length, _) = span;
+    if method == Method::HEAD || content_length == 0 {
+        return Ok(response.body(Body::empty()).unwrap());
     }
+    let end = start + content_length - 1;
 
-    let content_length = end - start + 1;
-    let cipher = XChaCha20Poly1305::new(key);
-
-    // 4. Create the streaming body
+    // 5. Create the streaming body
     let (tx, rx) = tokio::sync::mpsc::channel(128);
 
     tauri::async_runtime::spawn(async move {
         use tokio::io::{AsyncReadExt, AsyncSeekExt};
         let mut current_offset = start;
-        let block_size_enc = 1319;
-        let block_size_plain = 1279;
 
         let mut first_run = true;
 
@@ -258,7 +527,7 @@ async fn handle_media_request(
                 break;
             }
 
-            if block_data.len() < 40 {
+            if block_data.len() < AEAD_OVERHEAD {
                 break;
             }
 
@@ -294,34 +563,171 @@ async fn handle_media_request(
     });
 
     let stream = tokio_stream::wrappers::ReceiverStream::new(rx);
+    Ok(response.body(Body::wrap_stream(stream)).unwrap())
+}
 
-    let is_range = range.is_some();
-    let mut response = Response::builder()
-        .status(if is_range {
-            StatusCode::PARTIAL_CONTENT
-        } else {
-            StatusCode::OK
-        })
-        .header("Content-Type", mime_type.to_string())
-        .header("Accept-Ranges", "bytes")
-        .header("Access-Control-Allow-Origin", "*")
-        .header(
-            "Access-Control-Expose-Headers",
-            "Content-Range, Content-Length, Accept-Ranges",
-        );
+/// A download of the requested media that is still being reassembled.
+struct PartialDownload {
+    sender: String,
+    transfer_id: u32,
+    meta: PendingMediaMetadata,
+    temp_path: std::path::PathBuf,
+}
 
-    if is_range {
-        response = response.header(
-            "Content-Range",
-            format!("bytes {}-{}/{}", start, end, total_plain_size),
-        );
+/// Serves a download that is still being reassembled, opening transit-sealed blocks
+/// from its temp file as they arrive. A missing block is waited for up to
+/// `PARTIAL_BLOCK_TIMEOUT`, after which the stream ends early and the player asks for
+/// the rest again. The content is not final yet, so it carries no validator.
+async fn handle_partial_media_request<R: Runtime>(
+    app: tauri::AppHandle<R>,
+    download: PartialDownload,
+    method: Method,
+    headers: HeaderMap,
+    declared: String,
+) -> Result<Response<Body>, warp::Rejection> {
+    let PartialDownload {
+        sender,
+        transfer_id,
+        meta,
+        temp_path,
+    } = download;
+    let total_plain_size = meta.size;
+    let transit_key = base64::engine::general_purpose::STANDARD
+        .decode(&meta.key)
+        .map_err(|_| warp::reject())?;
+    if transit_key.len() != 32 {
+        return Err(warp::reject());
     }
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(&transit_key));
+
+    // Sniff from the first block if it is already here; do not hold the response for it
+    let head = match media_block_state(&app, &sender, transfer_id, 0) {
+        BlockState::Pending => None,
+        _ => std::fs::File::open(&temp_path).ok().and_then(|mut f| {
+            read_sealed_block(&mut f, 0, media_block_len(total_plain_size, 0), &cipher)
+        }),
+    };
+    let content_type = resolve_mime(&declared, head.as_deref().and_then(sniff_mime));
+
+    let Some(span) = select_span(&headers, total_plain_size, None) else {
+        return Ok(range_not_satisfiable(total_plain_size));
+    };
+    let response =
+        span_response(&content_type, span, total_plain_size).header("Cache-Control", "no-store");
+    let (start, content_length, _) = span;
+    if method == Method::HEAD || content_length == 0 {
+        return Ok(response.body(Body::empty()).unwrap());
+    }
+    let end = start + content_length - 1;
+
+    let (tx, rx) = tokio::sync::mpsc::channel(128);
+
+    tauri::async_runtime::spawn(async move {
+        // Opened once the first block is there; the handle outlives the bridge deleting it
+        let mut file: Option<std::fs::File> = None;
+        let mut current_offset = start;
+
+        while current_offset <= end {
+            let index = current_offset / MEDIA_BLOCK_SIZE as u64;
+            let plain_len = media_block_len(total_plain_size, index);
+            let Some(ptext) = wait_for_block(
+                &app,
+                &sender,
+                transfer_id,
+                index,
+                plain_len,
+                &temp_path,
+                &mut file,
+                &cipher,
+            )
+            .await
+            else {
+                break;
+            };
 
-    response = response.header("Content-Length", content_length);
+            let offset_in_block = (current_offset % MEDIA_BLOCK_SIZE as u64) as usize;
+            if offset_in_block >= ptext.len() {
+                break;
+            }
+            let remaining_in_range = (end - current_offset + 1) as usize;
+            let actual_send = (ptext.len() - offset_in_block).min(remaining_in_range);
+            if tx
+                .send(Ok::<_, std::io::Error>(bytes::Bytes::copy_from_slice(
+                    &ptext[offset_in_block..offset_in_block + actual_send],
+                )))
+                .await
+                .is_err()
+            {
+                return;
+            }
+            current_offset += actual_send as u64;
+        }
+    });
 
+    let stream = tokio_stream::wrappers::ReceiverStream::new(rx);
     Ok(response.body(Body::wrap_stream(stream)).unwrap())
 }
 
+/// Waits for block `index` of a download to arrive and opens it. Returns `None` once
+/// the wait times out or the transfer is gone.
+#[allow(clippy::too_many_arguments)]
+async fn wait_for_block<R: Runtime>(
+    app: &tauri::AppHandle<R>,
+    sender: &str,
+    transfer_id: u32,
+    index: u64,
+    plain_len: usize,
+    temp_path: &std::path::Path,
+    file: &mut Option<std::fs::File>,
+    cipher: &XChaCha20Poly1305,
+) -> Option<Vec<u8>> {
+    let deadline = Instant::now() + PARTIAL_BLOCK_TIMEOUT;
+    loop {
+        let state = media_block_state(app, sender, transfer_id, index as u32);
+        if state == BlockState::Gone && file.is_none() {
+            return None;
+        }
+        if state != BlockState::Pending {
+            if file.is_none() {
+                *file = std::fs::File::open(temp_path).ok();
+            }
+            // A block that fails to open may yet be replaced by an integrity resend
+            if let Some(f) = file.as_mut()
+                && let Some(ptext) = read_sealed_block(f, index, plain_len, cipher)
+            {
+                return Some(ptext);
+            }
+            if state == BlockState::Gone {
+                return None;
+            }
+        }
+        if Instant::now() >= deadline {
+            return None;
+        }
+        tokio::time::sleep(PARTIAL_POLL_INTERVAL).await;
+    }
+}
+
+/// Opens block `index` of a reassembly file, which sits at its fragment's offset.
+fn read_sealed_block(
+    file: &mut std::fs::File,
+    index: u64,
+    plain_len: usize,
+    cipher: &XChaCha20Poly1305,
+) -> Option<Vec<u8>> {
+    use std::io::{Read, Seek, SeekFrom};
+    if plain_len == 0 {
+        return None;
+    }
+    let mut sealed = vec![0u8; plain_len + AEAD_OVERHEAD];
+    file.seek(SeekFrom::Start(index * CHUNK_CAPACITY as u64))
+        .ok()?;
+    file.read_exact(&mut sealed).ok()?;
+    cipher
+        .decrypt(XNonce::from_slice(&sealed[..24]), &sealed[24..])
+        .ok()
+}
+
 fn mime_from_ext(ext: &str) -> &'static str {
     match ext.to_lowercase().as_str() {
         "mp4" => "video/mp4",
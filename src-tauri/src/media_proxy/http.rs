@@ -0,0 +1,187 @@
+//! HTTP Semantics of the Media Proxy
+//!
+//! Byte ranges, validators and content types for proxied media (RFC 9110). Received
+//! files are stored under bare message ids and their declared type comes from the
+//! sender, so the type served is sniffed from the first decrypted bytes wherever
+//! they are available.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What a `Range` header asks of a representation of `total` bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ByteRange {
+    /// No range, or one to be ignored: the whole representation.
+    Full,
+    /// First and last byte, inclusive.
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Parses a single `bytes=` range, including open-ended and suffix ranges. Malformed
+/// headers and multiple ranges are answered with the whole representation, which
+/// RFC 9110 allows.
+pub(crate) fn parse_range(header: Option<&str>, total: u64) -> ByteRange {
+    let Some(spec) = header.and_then(|h| h.trim().strip_prefix("bytes=")) else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((first, last)) = spec.split_once('-') else {
+        return ByteRange::Full;
+    };
+    let (first, last) = (first.trim(), last.trim());
+
+    if first.is_empty() {
+        // Suffix range: the final `last` bytes
+        let Ok(suffix) = last.parse::<u64>() else {
+            return ByteRange::Full;
+        };
+        if suffix == 0 || total == 0 {
+            return ByteRange::Unsatisfiable;
+        }
+        return ByteRange::Partial(total - suffix.min(total), total - 1);
+    }
+
+    let Ok(start) = first.parse::<u64>() else {
+        return ByteRange::Full;
+    };
+    let end = if last.is_empty() {
+        u64::MAX
+    } else {
+        match last.parse::<u64>() {
+            Ok(end) if end >= start => end,
+            _ => return ByteRange::Full,
+        }
+    };
+    if start >= total {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(start, end.min(total - 1))
+}
+
+/// Strong validator for a stored file, from its plaintext size and last write.
+pub(crate) fn etag(size: u64, modified: Option<SystemTime>) -> String {
+    let modified = modified
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("\"{:x}-{:x}\"", size, modified)
+}
+
+/// `If-None-Match` against `etag`, using the weak comparison the header calls for.
+pub(crate) fn none_match_hits(header: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    header
+        .split(',')
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+/// Whether a range may be honoured under `If-Range`. Only a strong match of the
+/// current entity tag counts; dates are not tracked, so they never match.
+pub(crate) fn if_range_holds(header: Option<&str>, etag: &str) -> bool {
+    header.is_none_or(|h| h.trim() == etag)
+}
+
+/// Content type from magic bytes at the start of a file.
+pub(crate) fn sniff_mime(head: &[u8]) -> Option<&'static str> {
+    let at = |offset: usize, magic: &[u8]| head.get(offset..offset + magic.len()) == Some(magic);
+    let contains = |needle: &[u8]| head.windows(needle.len()).any(|w| w == needle);
+
+    if at(0, &[0xFF, 0xD8, 0xFF]) {
+        return Some("image/jpeg");
+    }
+    if at(0, b"\x89PNG\r\n\x1a\n") {
+        return Some("image/png");
+    }
+    if at(0, b"GIF87a") || at(0, b"GIF89a") {
+        return Some("image/gif");
+    }
+    if at(0, b"RIFF") {
+        return match head.get(8..12) {
+            Some(b"WEBP") => Some("image/webp"),
+            Some(b"WAVE") => Some("audio/wav"),
+            Some(b"AVI ") => Some("video/x-msvideo"),
+            _ => None,
+        };
+    }
+    if at(0, b"%PDF-") {
+        return Some("application/pdf");
+    }
+    if at(0, b"II*\0") || at(0, b"MM\0*") {
+        return Some("image/tiff");
+    }
+    if at(4, b"ftyp") {
+        return Some(match head.get(8..12) {
+            Some(b"qt  ") => "video/quicktime",
+            Some(b"M4A ") | Some(b"M4B ") => "audio/mp4",
+            Some(b"avif") | Some(b"avis") => "image/avif",
+            Some(b"heic") | Some(b"heix") | Some(b"mif1") => "image/heic",
+            _ => "video/mp4",
+        });
+    }
+    if at(0, &[0x1A, 0x45, 0xDF, 0xA3]) {
+        return Some(if contains(b"webm") {
+            "video/webm"
+        } else {
+            "video/x-matroska"
+        });
+    }
+    if at(0, b"OggS") {
+        return Some(if contains(b"theora") {
+            "video/ogg"
+        } else {
+            "audio/ogg"
+        });
+    }
+    if at(0, b"fLaC") {
+        return Some("audio/flac");
+    }
+    if at(0, b"ID3") {
+        return Some("audio/mpeg");
+    }
+    match head {
+        [0xFF, b1, ..] if b1 & 0xF6 == 0xF0 => Some("audio/aac"),
+        [0xFF, b1, ..] if b1 & 0xE0 == 0xE0 => Some("audio/mpeg"),
+        _ => None,
+    }
+}
+
+/// Types that name the same kind of container, so a declaration is not overruled
+/// for choosing, say, audio over video for an MP4.
+fn container_family(mime: &str) -> &str {
+    match mime {
+        "image/jpg" | "image/pjpeg" => "image/jpeg",
+        "audio/x-wav" | "audio/wave" | "audio/vnd.wave" => "audio/wav",
+        "audio/mp4" | "audio/m4a" | "audio/x-m4a" | "video/quicktime" | "video/x-m4v" => {
+            "video/mp4"
+        }
+        "audio/ogg" | "application/ogg" => "video/ogg",
+        "audio/webm" | "video/x-matroska" | "audio/x-matroska" => "video/webm",
+        "audio/mp3" => "audio/mpeg",
+        other => other,
+    }
+}
+
+/// The type to serve: the sender's declaration if the content bears it out, the
+/// sniffed type if it does not, and never a declared type that could run script.
+pub(crate) fn resolve_mime(declared: &str, sniffed: Option<&'static str>) -> String {
+    let declared = declared
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    match sniffed {
+        Some(sniffed) if container_family(&declared) == container_family(sniffed) => declared,
+        Some(sniffed) => sniffed.to_string(),
+        None if (declared.starts_with("image/") && !declared.contains("svg"))
+            || declared.starts_with("audio/")
+            || declared.starts_with("video/") =>
+        {
+            declared
+        }
+        None => "application/octet-stream".to_string(),
+    }
+}
@@ -1,3 +1,6 @@
+/// Smallest padded plaintext, so short messages are indistinguishable from each other.
+pub const MIN_PADDED_PLAINTEXT: usize = 256;
+
 pub struct TrafficNormalizer;
 
 impl TrafficNormalizer {
@@ -14,4 +17,35 @@ impl TrafficNormalizer {
     pub fn pad_binary(data: &mut Vec<u8>, target_size: usize) {
         data.resize(target_size, 0);
     }
+
+    /// Padmé length for `len`: rounds up with at most ~12% overhead while leaving only
+    /// O(log log n) bits of the original size.
+    pub fn padme_len(len: usize) -> usize {
+        if len < 2 {
+            return len;
+        }
+        let exponent = usize::BITS - 1 - len.leading_zeros();
+        let significant = u32::BITS - exponent.leading_zeros();
+        let mask = (1usize << (exponent - significant)) - 1;
+        (len + mask) & !mask
+    }
+
+    /// Pads a JSON plaintext with trailing spaces to its Padmé length before Signal
+    /// encryption. JSON ignores trailing whitespace, so peers that do not unpad still
+    /// parse it. Anything that is not a JSON object is left untouched.
+    pub fn pad_plaintext(plaintext: &mut String) {
+        if !plaintext.starts_with('{') {
+            return;
+        }
+        let target = Self::padme_len(plaintext.len().max(MIN_PADDED_PLAINTEXT));
+        plaintext.push_str(&" ".repeat(target - plaintext.len()));
+    }
+
+    /// Strips the padding added by `pad_plaintext`.
+    pub fn unpad_plaintext(plaintext: &mut String) {
+        if plaintext.starts_with('{') {
+            let len = plaintext.trim_end_matches(' ').len();
+            plaintext.truncate(len);
+        }
+    }
 }
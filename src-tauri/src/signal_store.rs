@@ -1,6 +1,11 @@
 //! Persistent SQLite backing for Signal protocol state and identity keys.
 
 use crate::app_state::DbState;
+use crate::commands::identity::prekeys::now_ms;
+use crate::commands::identity::trust::{
+    IdentityChangePolicy, blocked_until_reverified, identity_change_policy,
+    post_identity_change_notices, record_identity_change,
+};
 use async_trait::async_trait;
 use libsignal_protocol::{
     Direction, GenericSignedPreKey, IdentityChange, IdentityKey, IdentityKeyPair, IdentityKeyStore,
@@ -9,15 +14,22 @@ use libsignal_protocol::{
     SignedPreKeyRecord, SignedPreKeyStore,
 };
 use rusqlite::params;
-use tauri::{AppHandle, Manager};
+use tauri::{AppHandle, Manager, Runtime};
+
+pub struct SqliteSignalStore<R: Runtime> {
+    app: AppHandle<R>,
+}
 
-#[derive(Clone)]
-pub struct SqliteSignalStore {
-    app: AppHandle,
+impl<R: Runtime> Clone for SqliteSignalStore<R> {
+    fn clone(&self) -> Self {
+        Self {
+            app: self.app.clone(),
+        }
+    }
 }
 
-impl SqliteSignalStore {
-    pub fn new(app: AppHandle) -> Self {
+impl<R: Runtime> SqliteSignalStore<R> {
+    pub fn new(app: AppHandle<R>) -> Self {
         Self { app }
     }
 
@@ -46,10 +58,53 @@ impl SqliteSignalStore {
 
         Ok(())
     }
+
+    /// The key and trust level stored for `address_str`.
+    fn stored_identity(
+        &self,
+        address_str: &str,
+    ) -> std::result::Result<Option<(Vec<u8>, i32)>, SignalProtocolError> {
+        let db_state = self.app.state::<DbState>();
+        let conn = db_state
+            .get_conn()
+            .map_err(|e| SignalProtocolError::InvalidArgument(format!("Pool error: {}", e)))?;
+        match conn.query_row(
+            "SELECT public_key, trust_level FROM signal_identities_remote WHERE address = ?1",
+            params![address_str],
+            |row| Ok((row.get(0)?, row.get::<_, Option<i32>>(1)?.unwrap_or(1))),
+        ) {
+            Ok(stored) => Ok(Some(stored)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(SignalProtocolError::InvalidArgument(e.to_string())),
+        }
+    }
+
+    /// Replaces the key of `address_str` under the identity-change policy and
+    /// tells every chat shared with the peer.
+    async fn replace_identity(
+        &self,
+        address_str: &str,
+        old_key: &[u8],
+        new_key: &[u8],
+    ) -> std::result::Result<(), SignalProtocolError> {
+        let (policy, chats) = {
+            let db_state = self.app.state::<DbState>();
+            let conn = db_state
+                .get_conn()
+                .map_err(|e| SignalProtocolError::InvalidArgument(format!("Pool error: {}", e)))?;
+            let chats = record_identity_change(&conn, address_str, old_key, new_key, now_ms())
+                .map_err(SignalProtocolError::InvalidArgument)?;
+            (identity_change_policy(&conn), chats)
+        };
+        let hash = address_str.split(':').next().unwrap_or(address_str);
+        // The change is recorded either way; a failed notice must not fail the session
+        let _ = post_identity_change_notices(&self.app, hash, chats, policy).await;
+        Ok(())
+    }
 }
 
 #[async_trait(?Send)]
-impl IdentityKeyStore for SqliteSignalStore {
+impl<R: Runtime> IdentityKeyStore for SqliteSignalStore<R> {
     async fn get_identity_key_pair(
         &self,
     ) -> std::result::Result<IdentityKeyPair, SignalProtocolError> {
@@ -116,47 +171,30 @@ impl IdentityKeyStore for SqliteSignalStore {
         }
     }
 
+    /// Called only once the message or bundle carrying `identity` has authenticated,
+    /// so this is where a changed key is recorded and announced.
     async fn save_identity(
         &mut self,
         address: &ProtocolAddress,
         identity: &IdentityKey,
     ) -> std::result::Result<IdentityChange, SignalProtocolError> {
-        let db_state = self.app.state::<DbState>();
-        let conn = db_state
-            .get_conn()
-            .map_err(|e| SignalProtocolError::InvalidArgument(format!("Pool error: {}", e)))?;
-
         let address_str = format!("{}:{}", address.name(), address.device_id());
         let pub_bytes = identity.serialize();
 
-        let mut stmt = conn
-            .prepare(
-                "SELECT public_key, trust_level FROM signal_identities_remote WHERE address = ?1",
-            )
-            .map_err(|e: rusqlite::Error| SignalProtocolError::InvalidArgument(e.to_string()))?;
-        let mut rows = stmt
-            .query(params![address_str])
-            .map_err(|e: rusqlite::Error| SignalProtocolError::InvalidArgument(e.to_string()))?;
-
-        let (change, target_trust) = if let Some(row) = rows
-            .next()
-            .map_err(|e: rusqlite::Error| SignalProtocolError::InvalidArgument(e.to_string()))?
-        {
-            let existing_pub: Vec<u8> =
-                row.get::<_, Vec<u8>>(0).map_err(|e: rusqlite::Error| {
-                    SignalProtocolError::InvalidArgument(e.to_string())
-                })?;
-            let old_trust: i32 = row.get(1).unwrap_or(1);
-
-            if existing_pub == pub_bytes.as_ref() {
-                (IdentityChange::NewOrUnchanged, old_trust)
-            } else {
-                (IdentityChange::ReplacedExisting, 0)
+        let target_trust = match self.stored_identity(&address_str)? {
+            Some((existing_pub, _)) if existing_pub != pub_bytes.as_ref() => {
+                self.replace_identity(&address_str, &existing_pub, &pub_bytes)
+                    .await?;
+                return Ok(IdentityChange::ReplacedExisting);
             }
-        } else {
-            (IdentityChange::NewOrUnchanged, 1)
+            Some((_, old_trust)) => old_trust,
+            None => 1,
         };
 
+        let db_state = self.app.state::<DbState>();
+        let conn = db_state
+            .get_conn()
+            .map_err(|e| SignalProtocolError::InvalidArgument(format!("Pool error: {}", e)))?;
         conn.execute(
             "INSERT INTO signal_identities_remote (address, public_key, trust_level) VALUES (?1, ?2, ?3)
              ON CONFLICT(address) DO UPDATE SET public_key = excluded.public_key, trust_level = excluded.trust_level",
@@ -169,39 +207,35 @@ impl IdentityKeyStore for SqliteSignalStore {
             params![target_trust, hash],
         );
 
-        Ok(change)
+        Ok(IdentityChange::NewOrUnchanged)
     }
 
     async fn is_trusted_identity(
         &self,
         address: &ProtocolAddress,
         identity: &IdentityKey,
-        _direction: Direction,
+        direction: Direction,
     ) -> std::result::Result<bool, SignalProtocolError> {
+        // Trust is checked before the message is authenticated, so nothing is recorded
+        // here. Received messages always pass: a changed key reaches `save_identity`,
+        // and the inbox quarantines what a blocked key sends.
+        if matches!(direction, Direction::Receiving) {
+            return Ok(true);
+        }
+        let address_str = format!("{}:{}", address.name(), address.device_id());
+        let Some((stored_pub, _)) = self.stored_identity(&address_str)? else {
+            return Ok(true);
+        };
         let db_state = self.app.state::<DbState>();
         let conn = db_state
             .get_conn()
             .map_err(|e| SignalProtocolError::InvalidArgument(format!("Pool error: {}", e)))?;
-
-        let address_str = format!("{}:{}", address.name(), address.device_id());
-        let mut stmt = conn
-            .prepare("SELECT public_key FROM signal_identities_remote WHERE address = ?1")
-            .map_err(|e: rusqlite::Error| SignalProtocolError::InvalidArgument(e.to_string()))?;
-        let mut rows = stmt
-            .query(params![address_str])
-            .map_err(|e: rusqlite::Error| SignalProtocolError::InvalidArgument(e.to_string()))?;
-
-        if let Some(row) = rows
-            .next()
-            .map_err(|e: rusqlite::Error| SignalProtocolError::InvalidArgument(e.to_string()))?
-        {
-            let stored_pub: Vec<u8> = row.get::<_, Vec<u8>>(0).map_err(|e: rusqlite::Error| {
-                SignalProtocolError::InvalidArgument(e.to_string())
-            })?;
-            Ok(stored_pub == identity.serialize().as_ref())
-        } else {
-            Ok(true)
+        if stored_pub != identity.serialize().as_ref() {
+            return Ok(identity_change_policy(&conn) == IdentityChangePolicy::Warn);
         }
+        blocked_until_reverified(&conn, &address_str)
+            .map(|blocked| !blocked)
+            .map_err(SignalProtocolError::InvalidArgument)
     }
 
     async fn get_identity(
@@ -236,7 +270,7 @@ impl IdentityKeyStore for SqliteSignalStore {
 }
 
 #[async_trait(?Send)]
-impl SessionStore for SqliteSignalStore {
+impl<R: Runtime> SessionStore for SqliteSignalStore<R> {
     async fn load_session(
         &self,
         address: &ProtocolAddress,
@@ -292,7 +326,7 @@ impl SessionStore for SqliteSignalStore {
 }
 
 #[async_trait(?Send)]
-impl PreKeyStore for SqliteSignalStore {
+impl<R: Runtime> PreKeyStore for SqliteSignalStore<R> {
     async fn get_pre_key(
         &self,
         pre_key_id: PreKeyId,
@@ -357,6 +391,8 @@ impl PreKeyStore for SqliteSignalStore {
 
         let id_u32: u32 = pre_key_id.into();
 
+        conn.execute_batch("PRAGMA secure_delete = ON;")
+            .map_err(|e: rusqlite::Error| SignalProtocolError::InvalidArgument(e.to_string()))?;
         conn.execute(
             "DELETE FROM signal_pre_keys WHERE key_id = ?1",
             params![id_u32],
@@ -368,7 +404,7 @@ impl PreKeyStore for SqliteSignalStore {
 }
 
 #[async_trait(?Send)]
-impl SignedPreKeyStore for SqliteSignalStore {
+impl<R: Runtime> SignedPreKeyStore for SqliteSignalStore<R> {
     async fn get_signed_pre_key(
         &self,
         signed_pre_key_id: SignedPreKeyId,
@@ -412,9 +448,11 @@ impl SignedPreKeyStore for SqliteSignalStore {
         let id_u32: u32 = signed_pre_key_id.into();
         let data = record.serialize()?;
 
+        // Upsert so a key keeps its creation and supersession times
         conn.execute(
-            "INSERT OR REPLACE INTO signal_signed_pre_keys (key_id, key_data) VALUES (?1, ?2)",
-            params![id_u32, data],
+            "INSERT INTO signal_signed_pre_keys (key_id, key_data, timestamp) VALUES (?1, ?2, ?3)
+             ON CONFLICT(key_id) DO UPDATE SET key_data = excluded.key_data",
+            params![id_u32, data, now_ms()],
         )
         .map_err(|e: rusqlite::Error| SignalProtocolError::InvalidArgument(e.to_string()))?;
 
@@ -423,7 +461,7 @@ impl SignedPreKeyStore for SqliteSignalStore {
 }
 
 #[async_trait(?Send)]
-impl KyberPreKeyStore for SqliteSignalStore {
+impl<R: Runtime> KyberPreKeyStore for SqliteSignalStore<R> {
     async fn get_kyber_pre_key(
         &self,
         kyber_prekey_id: KyberPreKeyId,
@@ -469,8 +507,9 @@ impl KyberPreKeyStore for SqliteSignalStore {
         let data = record.serialize()?;
 
         conn.execute(
-            "INSERT OR REPLACE INTO signal_kyber_pre_keys (key_id, key_data) VALUES (?1, ?2)",
-            params![id_u32, data],
+            "INSERT INTO signal_kyber_pre_keys (key_id, key_data, created_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT(key_id) DO UPDATE SET key_data = excluded.key_data",
+            params![id_u32, data, now_ms()],
         )
         .map_err(|e: rusqlite::Error| SignalProtocolError::InvalidArgument(e.to_string()))?;
 
@@ -503,6 +542,24 @@ impl KyberPreKeyStore for SqliteSignalStore {
             }
         })?;
 
+        // One-time keys are spent, and a replay can no longer load them; the
+        // last-resort key stays until it is rotated out
+        conn.execute_batch("PRAGMA secure_delete = ON;")
+            .map_err(|e: rusqlite::Error| SignalProtocolError::InvalidArgument(e.to_string()))?;
+        let spent = conn
+            .execute(
+                "DELETE FROM signal_kyber_pre_keys WHERE key_id = ?1 AND last_resort = 0",
+                params![kyber_id],
+            )
+            .map_err(|e: rusqlite::Error| SignalProtocolError::InvalidArgument(e.to_string()))?;
+        if spent > 0 {
+            conn.execute(
+                "DELETE FROM signal_kyber_base_keys_seen WHERE kyber_prekey_id = ?1",
+                params![kyber_id],
+            )
+            .map_err(|e: rusqlite::Error| SignalProtocolError::InvalidArgument(e.to_string()))?;
+        }
+
         Ok(())
     }
 }
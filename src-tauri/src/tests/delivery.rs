@@ -0,0 +1,170 @@
+//! Selective-repeat decisions for signal transfers: when a receiver asks for missing
+//! fragments and how long a sender keeps ciphertext around.
+
+use std::time::{Duration, Instant};
+
+use crate::app_state::{MediaTransferState, OutgoingSignal};
+use crate::commands::messaging::delivery::{
+    RetentionVerdict, nack_due, retention_verdict, retransmit_due,
+};
+use crate::commands::network::config::NetworkConfig;
+
+fn config() -> NetworkConfig {
+    NetworkConfig {
+        signal_nack_delay_secs: 3,
+        signal_max_nacks: 2,
+        signal_retransmit_secs: 20,
+        signal_max_retransmits: 2,
+        delivery_ack_timeout_secs: 60,
+        outbox_max_age_secs: 3600,
+        ..Default::default()
+    }
+}
+
+fn reassembly(received: &[bool], last_activity: Instant) -> MediaTransferState {
+    MediaTransferState {
+        total: received.len() as u32,
+        received_chunks: received.to_vec(),
+        last_activity,
+        file_handle: None,
+        received_count: received.iter().filter(|r| **r).count() as u32,
+        nacks_sent: 0,
+        last_nack: None,
+    }
+}
+
+fn retained(queued_at: Instant, accepted_at: Option<Instant>) -> OutgoingSignal {
+    OutgoingSignal {
+        msg_id: "m1".into(),
+        recipient: "peer".into(),
+        payload: vec![0u8; 10],
+        queued_at,
+        accepted_at,
+        awaits_receipt: true,
+        retransmits: 0,
+        last_retransmit: None,
+    }
+}
+
+#[test]
+fn stalled_reassembly_requests_only_missing_indices() {
+    let config = config();
+    let start = Instant::now();
+    let state = reassembly(&[true, false, true, false], start);
+
+    assert_eq!(
+        nack_due(&state, start + Duration::from_secs(2), &config),
+        None
+    );
+    assert_eq!(
+        nack_due(&state, start + Duration::from_secs(3), &config),
+        Some(vec![1, 3])
+    );
+}
+
+#[test]
+fn resend_requests_are_spaced_and_capped() {
+    let config = config();
+    let start = Instant::now();
+    let mut state = reassembly(&[false, true], start);
+
+    state.nacks_sent = 1;
+    state.last_nack = Some(start + Duration::from_secs(3));
+    assert_eq!(
+        nack_due(&state, start + Duration::from_secs(5), &config),
+        None
+    );
+    assert_eq!(
+        nack_due(&state, start + Duration::from_secs(6), &config),
+        Some(vec![0])
+    );
+
+    state.nacks_sent = config.signal_max_nacks;
+    assert_eq!(
+        nack_due(&state, start + Duration::from_secs(60), &config),
+        None
+    );
+}
+
+#[test]
+fn receipt_timeout_starts_at_relay_acceptance() {
+    let config = config();
+    let start = Instant::now();
+    let accepted = start + Duration::from_secs(100);
+
+    let waiting = retained(start, None);
+    assert_eq!(
+        retention_verdict(&waiting, start + Duration::from_secs(120), &config),
+        RetentionVerdict::Keep
+    );
+    assert_eq!(
+        retention_verdict(&waiting, start + Duration::from_secs(3600), &config),
+        RetentionVerdict::Abandon
+    );
+
+    let sent = retained(start, Some(accepted));
+    assert_eq!(
+        retention_verdict(&sent, accepted + Duration::from_secs(59), &config),
+        RetentionVerdict::Keep
+    );
+    assert_eq!(
+        retention_verdict(&sent, accepted + Duration::from_secs(60), &config),
+        RetentionVerdict::TimedOut
+    );
+}
+
+#[test]
+fn unreceipted_transfers_go_out_again_on_a_capped_schedule() {
+    let config = config();
+    let start = Instant::now();
+    let accepted = start + Duration::from_secs(5);
+
+    // Nothing is resent before the relay took the first copy
+    let waiting = retained(start, None);
+    assert!(!retransmit_due(
+        &waiting,
+        start + Duration::from_secs(600),
+        &config
+    ));
+
+    let mut sent = retained(start, Some(accepted));
+    assert!(!retransmit_due(
+        &sent,
+        accepted + Duration::from_secs(19),
+        &config
+    ));
+    assert!(retransmit_due(
+        &sent,
+        accepted + Duration::from_secs(20),
+        &config
+    ));
+
+    sent.retransmits = 1;
+    sent.last_retransmit = Some(accepted + Duration::from_secs(20));
+    assert!(!retransmit_due(
+        &sent,
+        accepted + Duration::from_secs(39),
+        &config
+    ));
+    assert!(retransmit_due(
+        &sent,
+        accepted + Duration::from_secs(40),
+        &config
+    ));
+
+    sent.retransmits = config.signal_max_retransmits;
+    assert!(!retransmit_due(
+        &sent,
+        accepted + Duration::from_secs(600),
+        &config
+    ));
+
+    // Group copies are never receipted, so there is nothing to wait for
+    let mut group = retained(start, Some(accepted));
+    group.awaits_receipt = false;
+    assert!(!retransmit_due(
+        &group,
+        accepted + Duration::from_secs(600),
+        &config
+    ));
+}
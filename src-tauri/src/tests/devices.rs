@@ -0,0 +1,251 @@
+//! Device links, per-device bundles and the envelopes senders fan out to them.
+
+use crate::commands::identity::devices::{
+    LINK_LIFETIME, PRIMARY_DEVICE, check_sender_device, device_address, device_envelope,
+    envelope_for_device, link_binds, sign_link, split_address, verify_link,
+};
+use crate::commands::identity::prekeys::now_ms;
+use crate::commands::network::relay_protocol::{DeviceLink, FetchKeyResponse};
+use crate::commands::signal::parse_device_bundle;
+use base64::Engine;
+use libsignal_protocol::{IdentityKeyPair, KeyPair, KyberPreKeyId, KyberPreKeyRecord, kem};
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+fn stripped(identity: &IdentityKeyPair) -> Vec<u8> {
+    identity.identity_key().serialize()[1..].to_vec()
+}
+
+fn account_of(identity: &IdentityKeyPair) -> String {
+    hex::encode(Sha256::digest(stripped(identity)))
+}
+
+fn link(primary: &IdentityKeyPair, device: &IdentityKeyPair, device_id: u32) -> DeviceLink {
+    sign_link(primary, device_id, &stripped(device), now_ms() as u64).unwrap()
+}
+
+/// A bundle as the relay serves it for a device with `identity`.
+fn bundle(identity: &IdentityKeyPair, link: Option<&DeviceLink>) -> serde_json::Value {
+    let b64 = base64::engine::general_purpose::STANDARD;
+    let mut rng = StdRng::from_os_rng();
+    let pre_key = KeyPair::generate(&mut rng);
+    let signed = KeyPair::generate(&mut rng);
+    let signed_sig = identity
+        .private_key()
+        .calculate_signature(&signed.public_key.serialize(), &mut rng)
+        .unwrap();
+    let kyber = KyberPreKeyRecord::generate(
+        kem::KeyType::Kyber1024,
+        KyberPreKeyId::from(3),
+        identity.private_key(),
+    )
+    .unwrap();
+    let mut bundle = json!({
+        "registrationId": 7,
+        "identityKey": hex::encode(stripped(identity)),
+        "preKey": { "id": 1, "publicKey": hex::encode(pre_key.public_key.serialize()) },
+        "signedPreKey": {
+            "id": 2,
+            "publicKey": hex::encode(signed.public_key.serialize()),
+            "signature": b64.encode(signed_sig)
+        },
+        "kyberPreKey": {
+            "id": 3,
+            "publicKey": b64.encode(kyber.public_key().unwrap().serialize()),
+            "signature": b64.encode(kyber.signature().unwrap())
+        }
+    });
+    if let Some(link) = link {
+        bundle["link"] = json!(link);
+    }
+    bundle
+}
+
+#[test]
+fn primary_signed_links_verify_and_bind_the_device_key() {
+    let mut rng = StdRng::from_os_rng();
+    let primary = IdentityKeyPair::generate(&mut rng);
+    let device = IdentityKeyPair::generate(&mut rng);
+    let link = link(&primary, &device, 2);
+
+    assert_eq!(link.account, account_of(&primary));
+    assert_eq!(verify_link(&link), Ok(()));
+    assert!(link_binds(&link, &device.identity_key().serialize()));
+    assert!(!link_binds(&link, &primary.identity_key().serialize()));
+}
+
+#[test]
+fn altered_links_fail_verification() {
+    let mut rng = StdRng::from_os_rng();
+    let primary = IdentityKeyPair::generate(&mut rng);
+    let device = IdentityKeyPair::generate(&mut rng);
+    let other = IdentityKeyPair::generate(&mut rng);
+    let genuine = link(&primary, &device, 2);
+
+    let mut renumbered = genuine.clone();
+    renumbered.device_id = 3;
+    assert!(verify_link(&renumbered).is_err());
+
+    let mut rekeyed = genuine.clone();
+    rekeyed.device_key = hex::encode(stripped(&other));
+    assert!(verify_link(&rekeyed).is_err());
+
+    // Another identity cannot vouch for an account that is not its own
+    let mut foreign = link(&other, &device, 2);
+    foreign.account = genuine.account.clone();
+    assert!(verify_link(&foreign).is_err());
+
+    let mut reissued = genuine.clone();
+    reissued.issued_at += 1;
+    assert!(verify_link(&reissued).is_err());
+
+    let mut extended = genuine.clone();
+    extended.expires_at += 1;
+    assert!(verify_link(&extended).is_err());
+}
+
+#[test]
+fn links_never_name_the_primary() {
+    let mut rng = StdRng::from_os_rng();
+    let primary = IdentityKeyPair::generate(&mut rng);
+    let device = IdentityKeyPair::generate(&mut rng);
+    assert!(verify_link(&link(&primary, &device, PRIMARY_DEVICE)).is_err());
+}
+
+#[test]
+fn device_addresses_keep_the_bare_hash_for_primaries() {
+    assert_eq!(device_address("abc", 1), "abc");
+    assert_eq!(device_address("abc", 4), "abc.4");
+    assert_eq!(split_address("abc"), ("abc", None));
+    assert_eq!(split_address("abc.4"), ("abc", Some(4)));
+    assert_eq!(split_address("abc.x"), ("abc", None));
+}
+
+#[test]
+fn fetch_responses_list_bundles_per_device() {
+    let multi = FetchKeyResponse {
+        found: true,
+        bundle: None,
+        bundles: Some(
+            [
+                ("acct.3".to_string(), json!({ "registrationId": 3 })),
+                ("acct".to_string(), json!({ "registrationId": 1 })),
+                ("acct.2".to_string(), serde_json::Value::Null),
+                ("acct.x".to_string(), json!({ "registrationId": 9 })),
+                ("other.2".to_string(), json!({ "registrationId": 9 })),
+            ]
+            .into_iter()
+            .collect(),
+        ),
+    };
+    let devices: Vec<u32> = multi
+        .device_bundles("acct")
+        .into_iter()
+        .map(|(d, _)| d)
+        .collect();
+    assert_eq!(devices, vec![1, 3]);
+
+    let legacy = FetchKeyResponse {
+        found: true,
+        bundle: Some(json!({ "registrationId": 1 })),
+        bundles: None,
+    };
+    assert_eq!(legacy.device_bundles("acct").len(), 1);
+    assert_eq!(legacy.device_bundles("acct")[0].0, PRIMARY_DEVICE);
+}
+
+#[test]
+fn envelopes_stay_single_for_primary_to_primary_messages() {
+    let ciphertext = json!({ "type": 2, "body": "AA==", "is_signal": true });
+    let envelope = device_envelope(vec![(1, ciphertext.clone())], None);
+    assert_eq!(envelope, ciphertext);
+    assert_eq!(envelope_for_device(&envelope, 1), Some(ciphertext));
+    // A linked device finds nothing for it in a single-device envelope
+    assert_eq!(envelope_for_device(&envelope, 2), None);
+}
+
+#[test]
+fn fanned_out_envelopes_carry_one_ciphertext_per_device() {
+    let mut rng = StdRng::from_os_rng();
+    let primary = IdentityKeyPair::generate(&mut rng);
+    let device = IdentityKeyPair::generate(&mut rng);
+    let sender_link = link(&primary, &device, 2);
+
+    let envelope = device_envelope(
+        vec![
+            (1, json!({ "type": 3, "body": "b25l", "is_signal": true })),
+            (3, json!({ "type": 2, "body": "dHdv", "is_signal": true })),
+        ],
+        Some(&sender_link),
+    );
+    assert_eq!(envelope["device"], 2);
+    assert_eq!(envelope["is_signal"], true);
+    assert_eq!(
+        envelope_for_device(&envelope, 3),
+        Some(json!({ "type": 2, "body": "dHdv" }))
+    );
+    assert_eq!(envelope_for_device(&envelope, 1).unwrap()["body"], "b25l");
+    assert_eq!(envelope_for_device(&envelope, 2), None);
+}
+
+#[test]
+fn linked_senders_must_present_their_accounts_link() {
+    let mut rng = StdRng::from_os_rng();
+    let primary = IdentityKeyPair::generate(&mut rng);
+    let device = IdentityKeyPair::generate(&mut rng);
+    let account = account_of(&primary);
+    let sender_link = link(&primary, &device, 2);
+    let envelope = json!({ "device": 2, "link": sender_link });
+
+    assert_eq!(
+        check_sender_device(&account, 1, &json!({}), 2, b"").unwrap(),
+        None
+    );
+    assert!(check_sender_device(&account, 2, &json!({ "device": 2 }), 2, b"").is_err());
+    assert_eq!(
+        check_sender_device(&account, 2, &envelope, 2, b"").unwrap(),
+        Some(sender_link.clone())
+    );
+    // The link names another account, or another device of this one
+    assert!(check_sender_device(&"0".repeat(64), 2, &envelope, 2, b"").is_err());
+    assert!(check_sender_device(&account, 3, &envelope, 2, b"").is_err());
+}
+
+#[test]
+fn linked_device_bundles_need_a_link_to_their_own_key() {
+    let mut rng = StdRng::from_os_rng();
+    let primary = IdentityKeyPair::generate(&mut rng);
+    let device = IdentityKeyPair::generate(&mut rng);
+    let stranger = IdentityKeyPair::generate(&mut rng);
+    let account = account_of(&primary);
+    let device_link = link(&primary, &device, 2);
+
+    assert!(parse_device_bundle(&account, 1, &bundle(&primary, None)).is_ok());
+    assert!(parse_device_bundle(&account, 2, &bundle(&device, Some(&device_link))).is_ok());
+
+    assert!(parse_device_bundle(&account, 2, &bundle(&device, None)).is_err());
+    assert!(parse_device_bundle(&account, 3, &bundle(&device, Some(&device_link))).is_err());
+    assert!(parse_device_bundle(&account, 2, &bundle(&stranger, Some(&device_link))).is_err());
+    // A linked device's key does not hash to the account, so it cannot pose as the primary
+    assert!(parse_device_bundle(&account, 1, &bundle(&device, None)).is_err());
+}
+
+#[test]
+fn links_lapse_once_their_lifetime_is_over() {
+    let mut rng = StdRng::from_os_rng();
+    let primary = IdentityKeyPair::generate(&mut rng);
+    let device = IdentityKeyPair::generate(&mut rng);
+    let account = account_of(&primary);
+    let issued_at = now_ms() as u64 - LINK_LIFETIME.as_millis() as u64;
+    let expired = sign_link(&primary, 2, &stripped(&device), issued_at).unwrap();
+
+    assert!(verify_link(&expired).is_err());
+    let envelope = json!({ "device": 2, "link": expired });
+    assert!(check_sender_device(&account, 2, &envelope, 2, b"").is_err());
+    assert!(parse_device_bundle(&account, 2, &bundle(&device, Some(&expired))).is_err());
+
+    let renewed = sign_link(&primary, 2, &stripped(&device), issued_at + 1000).unwrap();
+    assert_eq!(verify_link(&renewed), Ok(()));
+}
@@ -1,9 +1,12 @@
 //! Production-grade validation suite for the PQXDH encryption pipeline.
 
+use crate::commands::identity::devices::{PRIMARY_DEVICE, check_sender_device};
 use async_trait::async_trait;
 use libsignal_protocol::*;
 use rand::SeedableRng;
 use rand::rngs::StdRng;
+use serde_json::json;
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 
@@ -665,6 +668,63 @@ async fn identity_rotation_detection() -> Result<(), Box<dyn std::error::Error>>
     Ok(())
 }
 
+#[tokio::test]
+async fn prekey_messages_must_carry_the_senders_account_key()
+-> Result<(), Box<dyn std::error::Error>> {
+    let mut rng = StdRng::from_os_rng();
+
+    let (mut alice_store, alice_addr) = setup_test_user("alice", 1111, &mut rng).await?;
+    let (mut bob_store, bob_addr) = setup_test_user("bob", 2222, &mut rng).await?;
+
+    let bob_bundle = generate_bundle(&mut bob_store, 2222, &mut rng).await?;
+    process_prekey_bundle(
+        &bob_addr,
+        &mut alice_store.clone(),
+        &mut alice_store,
+        &bob_bundle,
+        std::time::SystemTime::now(),
+        &mut rng,
+    )
+    .await?;
+    let ciphertext = message_encrypt(
+        b"Who am I?",
+        &bob_addr,
+        &alice_addr,
+        &mut alice_store.clone(),
+        &mut alice_store,
+        std::time::SystemTime::now(),
+        &mut rng,
+    )
+    .await?;
+    assert_eq!(ciphertext.message_type(), CiphertextMessageType::PreKey);
+
+    let alice_key = alice_store.get_identity_key_pair().await?;
+    let alice_account = hex::encode(Sha256::digest(&alice_key.identity_key().serialize()[1..]));
+    let message_type = ciphertext.message_type() as u8;
+    assert!(
+        check_sender_device(
+            &alice_account,
+            PRIMARY_DEVICE,
+            &json!({}),
+            message_type,
+            ciphertext.serialize()
+        )
+        .is_ok()
+    );
+    // Another account cannot claim a session opened with Alice's key
+    assert!(
+        check_sender_device(
+            &"0".repeat(64),
+            PRIMARY_DEVICE,
+            &json!({}),
+            message_type,
+            ciphertext.serialize()
+        )
+        .is_err()
+    );
+    Ok(())
+}
+
 // --- Helper Functions for Tests ---
 
 async fn setup_test_user(
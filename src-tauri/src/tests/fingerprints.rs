@@ -0,0 +1,69 @@
+//! Safety numbers and the scannable payloads that verify them.
+
+use crate::commands::identity::trust::{fingerprint_qr_svg, format_safety_number, safety_number};
+use libsignal_protocol::IdentityKeyPair;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use sha2::{Digest, Sha256};
+
+struct Party {
+    hash: String,
+    key: Vec<u8>,
+}
+
+fn party() -> Party {
+    let identity = IdentityKeyPair::generate(&mut StdRng::from_os_rng());
+    let key = identity.identity_key().serialize().to_vec();
+    Party {
+        hash: hex::encode(Sha256::digest(&key[1..])),
+        key,
+    }
+}
+
+#[test]
+fn both_sides_compute_the_same_safety_number() {
+    let (alice, bob) = (party(), party());
+    let seen_by_alice = safety_number(&alice.hash, &alice.key, &bob.hash, &bob.key).unwrap();
+    let seen_by_bob = safety_number(&bob.hash, &bob.key, &alice.hash, &alice.key).unwrap();
+
+    let digits = seen_by_alice.display_string().unwrap();
+    assert_eq!(digits.len(), 60);
+    assert!(digits.chars().all(|c| c.is_ascii_digit()));
+    assert_eq!(digits, seen_by_bob.display_string().unwrap());
+
+    let carol = party();
+    let other = safety_number(&alice.hash, &alice.key, &carol.hash, &carol.key).unwrap();
+    assert_ne!(digits, other.display_string().unwrap());
+}
+
+#[test]
+fn scanned_payloads_match_only_the_same_pair_of_keys() {
+    let (alice, bob) = (party(), party());
+    let seen_by_alice = safety_number(&alice.hash, &alice.key, &bob.hash, &bob.key).unwrap();
+    let seen_by_bob = safety_number(&bob.hash, &bob.key, &alice.hash, &alice.key).unwrap();
+    let shown_by_bob = seen_by_bob.scannable.serialize().unwrap();
+    assert!(seen_by_alice.scannable.compare(&shown_by_bob).unwrap());
+
+    // Bob's key as someone in the middle would present it
+    let mallory = party();
+    let seen_via_mallory = safety_number(&alice.hash, &alice.key, &bob.hash, &mallory.key).unwrap();
+    assert!(!seen_via_mallory.scannable.compare(&shown_by_bob).unwrap());
+}
+
+#[test]
+fn safety_numbers_display_in_two_lines_of_six_groups() {
+    let digits: String = (0..60).map(|i| char::from(b'0' + (i % 10) as u8)).collect();
+    let formatted = format_safety_number(&digits);
+    let lines: Vec<&str> = formatted.split('\n').collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0], "01234 56789 01234 56789 01234 56789");
+    assert_eq!(lines[1].split(' ').count(), 6);
+}
+
+#[test]
+fn scannable_payloads_render_as_svg_qr_codes() {
+    let (alice, bob) = (party(), party());
+    let fingerprint = safety_number(&alice.hash, &alice.key, &bob.hash, &bob.key).unwrap();
+    let svg = fingerprint_qr_svg(&fingerprint.scannable.serialize().unwrap()).unwrap();
+    assert!(svg.contains("<svg"));
+}
@@ -0,0 +1,147 @@
+//! Inbound quota decisions: transfer admission, fragment rates and muting.
+
+use std::time::{Duration, Instant};
+
+use crate::commands::messaging::inbox::governor::*;
+use crate::commands::messaging::inbox::reassembler::assembler_key;
+use crate::commands::network::codec::{FRAME_MEDIA, FRAME_SIGNAL};
+use crate::commands::network::config::NetworkConfig;
+
+fn limits() -> NetworkConfig {
+    NetworkConfig {
+        max_transfers_per_sender: 2,
+        max_transfers_total: 3,
+        max_bytes_per_sender: reserved_bytes(100),
+        max_bytes_total: reserved_bytes(150),
+        max_fragments_per_sec_per_sender: 5,
+        max_fragments_per_sec_total: 8,
+        inbound_mute_secs: 60,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn transfer_admission_counts_per_sender_and_globally() {
+    let config = limits();
+    let a1 = assembler_key("alice", 1, FRAME_SIGNAL);
+    let a2 = assembler_key("alice", 2, FRAME_MEDIA);
+    let b1 = assembler_key("bob", 1, FRAME_MEDIA);
+
+    assert_eq!(
+        admit_transfer(std::iter::empty(), "alice", 1, &config),
+        Ok(())
+    );
+
+    let two_from_alice = [(a1.as_str(), 1), (a2.as_str(), 1)];
+    assert_eq!(
+        admit_transfer(two_from_alice.into_iter(), "alice", 1, &config),
+        Err(QuotaLimit::SenderTransfers)
+    );
+    assert_eq!(
+        admit_transfer(two_from_alice.into_iter(), "bob", 1, &config),
+        Ok(())
+    );
+
+    let full = [(a1.as_str(), 1), (a2.as_str(), 1), (b1.as_str(), 1)];
+    assert_eq!(
+        admit_transfer(full.into_iter(), "carol", 1, &config),
+        Err(QuotaLimit::GlobalTransfers)
+    );
+}
+
+#[test]
+fn transfer_admission_bounds_reserved_disk() {
+    let config = limits();
+    let a1 = assembler_key("alice", 1, FRAME_MEDIA);
+    let b1 = assembler_key("bob", 1, FRAME_MEDIA);
+
+    assert_eq!(
+        admit_transfer(std::iter::empty(), "alice", 101, &config),
+        Err(QuotaLimit::SenderBytes)
+    );
+    assert_eq!(
+        admit_transfer([(a1.as_str(), 60)].into_iter(), "alice", 40, &config),
+        Ok(())
+    );
+    assert_eq!(
+        admit_transfer([(a1.as_str(), 60)].into_iter(), "alice", 41, &config),
+        Err(QuotaLimit::SenderBytes)
+    );
+    assert_eq!(
+        admit_transfer(
+            [(a1.as_str(), 90), (b1.as_str(), 50)].into_iter(),
+            "carol",
+            20,
+            &config
+        ),
+        Err(QuotaLimit::GlobalBytes)
+    );
+}
+
+#[test]
+fn fragment_rate_resets_each_second() {
+    let config = limits();
+    let mut governor = InboundGovernor::default();
+    let now = Instant::now();
+
+    for _ in 0..5 {
+        assert_eq!(
+            governor.admit_fragment("alice", now, &config),
+            Admission::Accept
+        );
+    }
+    assert_eq!(
+        governor.admit_fragment("alice", now, &config),
+        Admission::Reject(QuotaLimit::SenderRate)
+    );
+
+    let later = now + Duration::from_secs(1);
+    assert_eq!(
+        governor.admit_fragment("alice", later, &config),
+        Admission::Accept
+    );
+}
+
+#[test]
+fn global_rate_sheds_without_blaming_one_sender() {
+    let config = limits();
+    let mut governor = InboundGovernor::default();
+    let now = Instant::now();
+
+    let mut verdicts = Vec::new();
+    for sender in ["a", "b", "c", "d", "e"] {
+        for _ in 0..2 {
+            verdicts.push(governor.admit_fragment(sender, now, &config));
+        }
+    }
+    assert_eq!(
+        verdicts.iter().filter(|v| **v == Admission::Accept).count(),
+        8
+    );
+    assert!(verdicts.contains(&Admission::Reject(QuotaLimit::GlobalRate)));
+    assert!(!QuotaLimit::GlobalRate.mutes_sender());
+}
+
+#[test]
+fn muted_senders_are_dropped_until_the_mute_lapses() {
+    let config = limits();
+    let mut governor = InboundGovernor::default();
+    let now = Instant::now();
+
+    assert!(QuotaLimit::SenderRate.mutes_sender());
+    governor.mute("mallory", now, &config);
+    assert_eq!(
+        governor.admit_fragment("mallory", now, &config),
+        Admission::Muted
+    );
+    assert_eq!(
+        governor.admit_fragment("alice", now, &config),
+        Admission::Accept
+    );
+
+    let after = now + Duration::from_secs(config.inbound_mute_secs + 1);
+    assert_eq!(
+        governor.admit_fragment("mallory", after, &config),
+        Admission::Accept
+    );
+}
@@ -0,0 +1,58 @@
+//! Expiry and matching of halted delivery targets.
+
+use std::collections::HashMap;
+
+use crate::commands::network::codec::FrameCodec;
+use crate::commands::network::config::NetworkConfig;
+use crate::commands::network::halts::*;
+
+fn halts(target: &str, expires_at: i64) -> HashMap<String, HaltedTarget> {
+    let key = halt_key(target);
+    HashMap::from([(
+        key.clone(),
+        HaltedTarget {
+            target: key,
+            reason: "storage_full".into(),
+            halted_at: 0,
+            expires_at,
+        },
+    )])
+}
+
+#[test]
+fn halts_match_the_routing_header_of_outgoing_frames() {
+    let peer = "ab12".repeat(16);
+    let map = halts(&peer.to_uppercase(), 1_000);
+
+    let frame =
+        FrameCodec::encode_fragment(&FrameCodec::routing_header(&peer), 1, 7, 0, 1, b"x").unwrap();
+    let routing = FrameCodec::decode(&frame).unwrap().routing;
+    assert_eq!(halted_until(&map, &routing, 999), Some(1_000));
+
+    let other = FrameCodec::routing_header(&"cd34".repeat(16));
+    assert_eq!(halted_until(&map, &other, 999), None);
+}
+
+#[test]
+fn expired_halts_no_longer_apply() {
+    let peer = "ab12".repeat(16);
+    let map = halts(&peer, 1_000);
+    let routing = FrameCodec::routing_header(&peer);
+    assert_eq!(halted_until(&map, &routing, 1_000), None);
+}
+
+#[test]
+fn blank_targets_have_no_key() {
+    assert_eq!(halt_key(""), "");
+    assert_eq!(halt_key(" AbC "), "abc");
+}
+
+#[test]
+fn halt_duration_is_validated() {
+    let short = NetworkConfig {
+        halt_duration_secs: 10,
+        ..Default::default()
+    };
+    assert!(short.validate().is_err());
+    assert!(NetworkConfig::default().validate().is_ok());
+}
@@ -0,0 +1,191 @@
+//! Identity key changes: the key history, trust resets and the notices they post.
+
+use super::support::{NOW, vault};
+use crate::commands::identity::trust::{
+    IdentityChangePolicy, VERIFIED_TRUST, awaiting_verification, blocked_until_reverified,
+    identity_change_notice, identity_change_policy, key_history, mark_reverified,
+    record_identity_change, set_peer_trust,
+};
+use crate::commands::messaging::inbox::quarantine::{hold, take_held};
+use rusqlite::{Connection, params};
+
+const ALICE: &str = "aa11";
+const BOB: &str = "bb22";
+
+fn add_identity(conn: &Connection, address: &str, key: &[u8], trust_level: i32) {
+    conn.execute(
+        "INSERT INTO signal_identities_remote (address, public_key, trust_level) VALUES (?1, ?2, ?3)",
+        params![address, key, trust_level],
+    )
+    .unwrap();
+}
+
+fn add_group(conn: &Connection, address: &str, members: &[&str]) {
+    conn.execute(
+        "INSERT INTO chats (address, is_group) VALUES (?1, 1)",
+        params![address],
+    )
+    .unwrap();
+    for member in members {
+        conn.execute(
+            "INSERT INTO chat_members (chat_address, member_hash) VALUES (?1, ?2)",
+            params![address, member],
+        )
+        .unwrap();
+    }
+}
+
+#[test]
+fn changed_keys_reset_trust_and_reach_every_shared_chat() {
+    let conn = vault();
+    add_identity(&conn, "aa11:1", &[1], 2);
+    conn.execute(
+        "INSERT INTO contacts (hash, trust_level) VALUES (?1, 2)",
+        params![ALICE],
+    )
+    .unwrap();
+    conn.execute("INSERT INTO chats (address) VALUES (?1)", params![ALICE])
+        .unwrap();
+    add_group(&conn, "group-shared", &[ALICE, BOB]);
+    add_group(&conn, "group-other", &[BOB]);
+
+    let mut chats = record_identity_change(&conn, "aa11:1", &[1], &[2], NOW).unwrap();
+    chats.sort();
+    assert_eq!(
+        chats,
+        vec![
+            (ALICE.to_string(), false),
+            ("group-shared".to_string(), true)
+        ]
+    );
+
+    let (key, trust): (Vec<u8>, i32) = conn
+        .query_row(
+            "SELECT public_key, trust_level FROM signal_identities_remote WHERE address = 'aa11:1'",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )
+        .unwrap();
+    assert_eq!((key, trust), (vec![2], 0));
+    let contact_trust: i32 = conn
+        .query_row("SELECT trust_level FROM contacts", [], |r| r.get(0))
+        .unwrap();
+    assert_eq!(contact_trust, 0);
+    assert!(awaiting_verification(&conn, "aa11:1").unwrap());
+    assert!(!awaiting_verification(&conn, "bb22:1").unwrap());
+}
+
+#[test]
+fn verification_clears_every_device_of_the_account() {
+    let conn = vault();
+    add_identity(&conn, "aa11:1", &[1], 1);
+    add_identity(&conn, "aa11:2", &[3], 1);
+    add_identity(&conn, "bb22:1", &[5], 1);
+    record_identity_change(&conn, "aa11:1", &[1], &[2], NOW).unwrap();
+    record_identity_change(&conn, "aa11:2", &[3], &[4], NOW).unwrap();
+    record_identity_change(&conn, "bb22:1", &[5], &[6], NOW).unwrap();
+
+    mark_reverified(&conn, ALICE).unwrap();
+    assert!(!awaiting_verification(&conn, "aa11:1").unwrap());
+    assert!(!awaiting_verification(&conn, "aa11:2").unwrap());
+    assert!(awaiting_verification(&conn, "bb22:1").unwrap());
+}
+
+#[test]
+fn key_history_lists_an_accounts_changes_newest_first() {
+    let conn = vault();
+    add_identity(&conn, "aa11:1", &[1], 1);
+    add_identity(&conn, "aa111:1", &[7], 1);
+    record_identity_change(&conn, "aa11:1", &[1], &[2], NOW).unwrap();
+    record_identity_change(&conn, "aa11:1", &[2], &[3], NOW + 1000).unwrap();
+    record_identity_change(&conn, "aa111:1", &[7], &[8], NOW).unwrap();
+
+    let history = key_history(&conn, ALICE).unwrap();
+    assert_eq!(history.len(), 2);
+    assert_eq!(history[0].changed_at, NOW + 1000);
+    assert_eq!(
+        (history[0].old_key.as_str(), history[0].new_key.as_str()),
+        ("02", "03")
+    );
+    assert_eq!(history[1].address, "aa11:1");
+    assert!(key_history(&conn, "cc33").unwrap().is_empty());
+}
+
+#[test]
+fn policy_defaults_to_warning() {
+    let conn = vault();
+    assert_eq!(identity_change_policy(&conn), IdentityChangePolicy::Warn);
+
+    conn.execute(
+        "INSERT INTO kv_store VALUES ('_identity_change_policy', '\"blockUntilReverified\"')",
+        [],
+    )
+    .unwrap();
+    assert_eq!(
+        identity_change_policy(&conn),
+        IdentityChangePolicy::BlockUntilReverified
+    );
+
+    let warning = identity_change_notice("0123456789ab", false, IdentityChangePolicy::Warn);
+    assert!(warning.starts_with("Safety number with 01234567 changed"));
+    assert!(!warning.contains("blocked"));
+    let blocking = identity_change_notice(
+        "0123456789ab",
+        true,
+        IdentityChangePolicy::BlockUntilReverified,
+    );
+    assert!(blocking.contains("blocked until they are verified again"));
+}
+
+#[test]
+fn only_the_blocking_policy_refuses_an_unverified_key() {
+    let conn = vault();
+    add_identity(&conn, "aa11:1", &[1], 1);
+    record_identity_change(&conn, "aa11:1", &[1], &[2], NOW).unwrap();
+    assert!(!blocked_until_reverified(&conn, "aa11:1").unwrap());
+
+    conn.execute(
+        "INSERT INTO kv_store VALUES ('_identity_change_policy', '\"blockUntilReverified\"')",
+        [],
+    )
+    .unwrap();
+    assert!(blocked_until_reverified(&conn, "aa11:1").unwrap());
+    assert!(!blocked_until_reverified(&conn, "bb22:1").unwrap());
+
+    mark_reverified(&conn, ALICE).unwrap();
+    assert!(!blocked_until_reverified(&conn, "aa11:1").unwrap());
+}
+
+#[test]
+fn resetting_verification_keeps_a_changed_key_blocked() {
+    let conn = vault();
+    conn.execute(
+        "INSERT INTO kv_store VALUES ('_identity_change_policy', '\"blockUntilReverified\"')",
+        [],
+    )
+    .unwrap();
+    add_identity(&conn, "aa11:1", &[1], 2);
+    record_identity_change(&conn, "aa11:1", &[1], &[2], NOW).unwrap();
+
+    set_peer_trust(&conn, ALICE, 1).unwrap();
+    assert!(blocked_until_reverified(&conn, "aa11:1").unwrap());
+
+    set_peer_trust(&conn, ALICE, VERIFIED_TRUST).unwrap();
+    assert!(!blocked_until_reverified(&conn, "aa11:1").unwrap());
+}
+
+#[test]
+fn held_messages_come_back_once_per_account_in_arrival_order() {
+    let conn = vault();
+    hold(&conn, "aa11:1", "aa11", "first").unwrap();
+    hold(&conn, "bb22:1", "bb22", "other").unwrap();
+    hold(&conn, "aa11:2", "cc33", "second").unwrap();
+    hold(&conn, "aa111:1", "aa111", "lookalike").unwrap();
+
+    let held = take_held(&conn, ALICE).unwrap();
+    let plaintexts: Vec<&str> = held.iter().map(|m| m.plaintext.as_str()).collect();
+    assert_eq!(plaintexts, vec!["first", "second"]);
+    assert_eq!(held[1].routed_by, "cc33");
+    assert!(take_held(&conn, ALICE).unwrap().is_empty());
+    assert_eq!(take_held(&conn, BOB).unwrap().len(), 1);
+}
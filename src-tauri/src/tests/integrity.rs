@@ -0,0 +1,108 @@
+//! Whole-file digests of media transfers and the resends a mismatch asks for.
+
+use crate::commands::messaging::inbox::handlers::media::{BridgeReport, MediaIntegrity};
+use crate::commands::messaging::outbox::handlers::media::plaintext_digest;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+fn report(blocks: u32, sha256: [u8; 32], bad_blocks: Vec<u32>) -> BridgeReport {
+    BridgeReport {
+        blocks,
+        sha256,
+        bad_blocks,
+    }
+}
+
+#[test]
+fn digest_matches_a_one_shot_hash_across_read_boundaries() {
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    let expected: [u8; 32] = Sha256::digest(&data).into();
+    assert_eq!(plaintext_digest(data.as_slice()).unwrap(), expected);
+    let empty: [u8; 32] = Sha256::digest(b"").into();
+    assert_eq!(plaintext_digest(b"".as_slice()).unwrap(), empty);
+}
+
+#[test]
+fn announcements_are_parsed_from_the_bundle() {
+    let digest = [9u8; 32];
+    let integrity = MediaIntegrity::from_bundle(&json!({
+        "key": "k",
+        "sha256": hex::encode(digest),
+        "blocks": 12
+    }));
+    assert_eq!(integrity.sha256, Some(digest));
+    assert_eq!(integrity.blocks, Some(12));
+
+    // Older senders announce neither; malformed digests are ignored
+    assert_eq!(
+        MediaIntegrity::from_bundle(&json!({ "key": "k" })),
+        MediaIntegrity::default()
+    );
+    assert_eq!(
+        MediaIntegrity::from_bundle(&json!({ "sha256": "abcd" })).sha256,
+        None
+    );
+}
+
+#[test]
+fn a_matching_file_needs_no_resend() {
+    let integrity = MediaIntegrity {
+        sha256: Some([1u8; 32]),
+        blocks: Some(4),
+    };
+    assert!(
+        integrity
+            .blocks_to_resend(&report(4, [1u8; 32], vec![]))
+            .is_empty()
+    );
+    assert!(
+        MediaIntegrity::default()
+            .blocks_to_resend(&report(4, [5u8; 32], vec![]))
+            .is_empty()
+    );
+}
+
+#[test]
+fn damaged_blocks_are_asked_for_alone() {
+    let integrity = MediaIntegrity {
+        sha256: Some([1u8; 32]),
+        blocks: Some(6),
+    };
+    assert_eq!(
+        integrity.blocks_to_resend(&report(6, [2u8; 32], vec![1, 4])),
+        vec![1, 4]
+    );
+    // Without an announcement, tag failures still count
+    assert_eq!(
+        MediaIntegrity::default().blocks_to_resend(&report(6, [2u8; 32], vec![3])),
+        vec![3]
+    );
+}
+
+#[test]
+fn a_short_file_is_topped_up() {
+    let integrity = MediaIntegrity {
+        sha256: Some([1u8; 32]),
+        blocks: Some(5),
+    };
+    assert_eq!(
+        integrity.blocks_to_resend(&report(3, [2u8; 32], vec![])),
+        vec![3, 4]
+    );
+}
+
+#[test]
+fn a_digest_or_count_mismatch_resends_the_whole_file() {
+    let integrity = MediaIntegrity {
+        sha256: Some([1u8; 32]),
+        blocks: Some(3),
+    };
+    assert_eq!(
+        integrity.blocks_to_resend(&report(3, [2u8; 32], vec![])),
+        vec![0, 1, 2]
+    );
+    assert_eq!(
+        integrity.blocks_to_resend(&report(4, [1u8; 32], vec![])),
+        vec![0, 1, 2]
+    );
+}
@@ -0,0 +1,96 @@
+//! Ranges, validators and content types served by the media proxy.
+
+use crate::media_proxy::{ByteRange, parse_range, resolve_mime, sniff_mime};
+
+#[test]
+fn single_ranges_are_clamped_to_the_file() {
+    assert_eq!(parse_range(None, 100), ByteRange::Full);
+    assert_eq!(
+        parse_range(Some("bytes=10-"), 100),
+        ByteRange::Partial(10, 99)
+    );
+    assert_eq!(
+        parse_range(Some("bytes=10-19"), 100),
+        ByteRange::Partial(10, 19)
+    );
+    assert_eq!(
+        parse_range(Some("bytes=90-500"), 100),
+        ByteRange::Partial(90, 99)
+    );
+    assert_eq!(parse_range(Some("bytes=0-0"), 1), ByteRange::Partial(0, 0));
+}
+
+#[test]
+fn suffix_ranges_count_from_the_end() {
+    assert_eq!(
+        parse_range(Some("bytes=-10"), 100),
+        ByteRange::Partial(90, 99)
+    );
+    assert_eq!(
+        parse_range(Some("bytes=-500"), 100),
+        ByteRange::Partial(0, 99)
+    );
+    assert_eq!(parse_range(Some("bytes=-0"), 100), ByteRange::Unsatisfiable);
+    assert_eq!(parse_range(Some("bytes=-5"), 0), ByteRange::Unsatisfiable);
+}
+
+#[test]
+fn ranges_past_the_end_are_unsatisfiable() {
+    assert_eq!(
+        parse_range(Some("bytes=100-"), 100),
+        ByteRange::Unsatisfiable
+    );
+    assert_eq!(parse_range(Some("bytes=0-"), 0), ByteRange::Unsatisfiable);
+}
+
+#[test]
+fn unusable_ranges_fall_back_to_the_whole_file() {
+    assert_eq!(parse_range(Some("items=1-2"), 100), ByteRange::Full);
+    assert_eq!(parse_range(Some("bytes=20-10"), 100), ByteRange::Full);
+    assert_eq!(parse_range(Some("bytes=a-b"), 100), ByteRange::Full);
+    assert_eq!(parse_range(Some("bytes=0-1,5-6"), 100), ByteRange::Full);
+}
+
+#[test]
+fn common_formats_are_sniffed() {
+    assert_eq!(sniff_mime(&[0xFF, 0xD8, 0xFF, 0xE0]), Some("image/jpeg"));
+    assert_eq!(sniff_mime(b"\x89PNG\r\n\x1a\n...."), Some("image/png"));
+    assert_eq!(sniff_mime(b"GIF89a"), Some("image/gif"));
+    assert_eq!(sniff_mime(b"RIFF\0\0\0\0WEBPVP8 "), Some("image/webp"));
+    assert_eq!(sniff_mime(b"RIFF\0\0\0\0WAVEfmt "), Some("audio/wav"));
+    assert_eq!(sniff_mime(b"\0\0\0\x18ftypisom"), Some("video/mp4"));
+    assert_eq!(sniff_mime(b"\0\0\0\x14ftypqt  "), Some("video/quicktime"));
+    assert_eq!(
+        sniff_mime(b"\x1a\x45\xdf\xa3\x9f\x42\x82\x84webm"),
+        Some("video/webm")
+    );
+    assert_eq!(sniff_mime(b"OggS\0\x02....OpusHead"), Some("audio/ogg"));
+    assert_eq!(sniff_mime(b"%PDF-1.7"), Some("application/pdf"));
+    assert_eq!(sniff_mime(b"ID3\x04"), Some("audio/mpeg"));
+    assert_eq!(sniff_mime(b"<html>"), None);
+    assert_eq!(sniff_mime(b""), None);
+}
+
+#[test]
+fn declared_types_stand_only_when_the_content_agrees() {
+    assert_eq!(resolve_mime("image/jpeg", Some("image/jpeg")), "image/jpeg");
+    assert_eq!(resolve_mime("image/jpg", Some("image/jpeg")), "image/jpg");
+    assert_eq!(resolve_mime("audio/mp4", Some("video/mp4")), "audio/mp4");
+    assert_eq!(
+        resolve_mime("Video/MP4; codecs=avc1", Some("video/mp4")),
+        "video/mp4"
+    );
+    assert_eq!(resolve_mime("image/png", Some("video/mp4")), "video/mp4");
+    assert_eq!(resolve_mime("text/html", Some("image/gif")), "image/gif");
+}
+
+#[test]
+fn undetected_content_is_never_served_as_script() {
+    assert_eq!(resolve_mime("video/mp4", None), "video/mp4");
+    assert_eq!(resolve_mime("text/html", None), "application/octet-stream");
+    assert_eq!(
+        resolve_mime("image/svg+xml", None),
+        "application/octet-stream"
+    );
+    assert_eq!(resolve_mime("", None), "application/octet-stream");
+}
@@ -0,0 +1,510 @@
+//! In-process relay for end-to-end tests.
+//!
+//! Binds to an ephemeral localhost port and speaks the relay protocol the client
+//! uses today: PoW-gated `auth` with session-token re-authentication, `keys_upload`
+//! and `fetch_key`, fragmented `0x00` control requests, and routing of binary frames
+//! by their 64-byte header. Frames addressed to offline peers are held in a mailbox,
+//! except media, which is refused with `delivery_error` like the production relay.
+
+use futures_util::{SinkExt, StreamExt};
+use libsignal_protocol::PublicKey;
+use num_bigint::BigUint;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio_util::sync::CancellationToken;
+
+use crate::commands::network::codec::{FRAME_CONTROL, FRAME_MEDIA, FrameCodec, ROUTING_LEN};
+use crate::commands::network::relay_protocol::{
+    AuthCredentials, AuthPayload, ClientMessage, FetchKeyResponse, KeysUploadResponse,
+    PROTOCOL_VERSION, PreKeyUpload, RelayMessage, SignedKeyUpload,
+};
+
+/// Mersenne prime 2^127 - 1; keeps the VDF cheap while exercising the real solver.
+const TEST_MODULUS: &str = "170141183460469231731687303715884105727";
+const TEST_DIFFICULTY: u32 = 256;
+
+pub fn solve_challenge(seed: &str, difficulty: u32, modulus: &str) -> String {
+    let n = BigUint::parse_bytes(modulus.as_bytes(), 10).expect("valid modulus");
+    let mut x = BigUint::from_bytes_be(&hex::decode(seed).unwrap_or_default()) % &n;
+    for _ in 0..difficulty {
+        x = (&x * &x) % &n;
+    }
+    hex::encode(x.to_bytes_be())
+}
+
+struct StoredKeys {
+    registration_id: u32,
+    identity_key: String,
+    signed_pre_key: SignedKeyUpload,
+    pre_keys: Vec<PreKeyUpload>,
+    kyber_pre_key: SignedKeyUpload,
+    kyber_pre_keys: Vec<SignedKeyUpload>,
+}
+
+#[derive(Default)]
+struct RelayState {
+    online: HashMap<String, mpsc::UnboundedSender<Message>>,
+    sessions: HashMap<String, String>,
+    bundles: HashMap<String, StoredKeys>,
+    mailbox: HashMap<String, Vec<Vec<u8>>>,
+    /// Protocol revision the relay speaks and reports in `auth_success`.
+    version: u32,
+    /// Forwards frames with the routing header left as the recipient, like a relay
+    /// that does not attribute sealed traffic.
+    unattributed: bool,
+}
+
+pub struct MockRelay {
+    addr: SocketAddr,
+    state: Arc<Mutex<RelayState>>,
+    shutdown: CancellationToken,
+}
+
+impl MockRelay {
+    pub async fn start() -> Self {
+        Self::start_at_version(PROTOCOL_VERSION).await
+    }
+
+    /// A relay speaking protocol revision `version`, to test clients against an upgrade.
+    pub async fn start_at_version(version: u32) -> Self {
+        Self::launch(RelayState {
+            version,
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// A relay that never tells recipients who sent a frame.
+    pub async fn start_unattributed() -> Self {
+        Self::launch(RelayState {
+            version: PROTOCOL_VERSION,
+            unattributed: true,
+            ..Default::default()
+        })
+        .await
+    }
+
+    async fn launch(state: RelayState) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind mock relay");
+        let addr = listener.local_addr().expect("mock relay address");
+        let state = Arc::new(Mutex::new(state));
+        let shutdown = CancellationToken::new();
+
+        let (accept_state, accept_shutdown) = (state.clone(), shutdown.clone());
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = accept_shutdown.cancelled() => break,
+                    Ok((stream, _)) = listener.accept() => {
+                        tokio::spawn(serve_connection(stream, accept_state.clone(), accept_shutdown.clone()));
+                    }
+                }
+            }
+        });
+
+        Self {
+            addr,
+            state,
+            shutdown,
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("ws://{}/ws", self.addr)
+    }
+
+    pub fn is_online(&self, identity_hash: &str) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .online
+            .contains_key(identity_hash)
+    }
+
+    /// Whether `identity_hash` has published a prekey bundle.
+    pub fn has_keys(&self, identity_hash: &str) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .bundles
+            .contains_key(identity_hash)
+    }
+
+    pub fn mailbox_len(&self, identity_hash: &str) -> usize {
+        self.state
+            .lock()
+            .unwrap()
+            .mailbox
+            .get(identity_hash)
+            .map(|m| m.len())
+            .unwrap_or(0)
+    }
+}
+
+impl Drop for MockRelay {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+struct ControlAssembly {
+    chunks: Vec<Option<Vec<u8>>>,
+    received: usize,
+}
+
+struct Connection {
+    identity: Option<String>,
+    pending_seed: Option<String>,
+    control: HashMap<u32, ControlAssembly>,
+    acked: HashSet<u32>,
+    out: mpsc::UnboundedSender<Message>,
+}
+
+impl Connection {
+    fn send(&self, msg: &RelayMessage) {
+        let _ = self.out.send(Message::Text(msg.to_text().into()));
+    }
+
+    fn reply_error(&self, error: &str, code: &str, req_id: Option<String>) {
+        self.send(&RelayMessage::Error {
+            error: error.to_string(),
+            code: Some(code.to_string()),
+            retry_after: None,
+            req_id,
+        });
+    }
+
+    /// Request responses carry their own `type` and are routed by `req_id`.
+    fn reply_response<T: serde::Serialize>(
+        &self,
+        body: &T,
+        msg_type: &str,
+        req_id: Option<String>,
+    ) {
+        let mut val = serde_json::to_value(body).unwrap_or_default();
+        val["type"] = json!(msg_type);
+        val["req_id"] = json!(req_id);
+        let _ = self.out.send(Message::Text(val.to_string().into()));
+    }
+}
+
+async fn serve_connection(
+    stream: TcpStream,
+    state: Arc<Mutex<RelayState>>,
+    shutdown: CancellationToken,
+) {
+    let Ok(ws) = tokio_tungstenite::accept_async(stream).await else {
+        return;
+    };
+    let (mut write, mut read) = ws.split();
+    let (out_tx, mut out_rx) = mpsc::unbounded_channel::<Message>();
+    let writer = tokio::spawn(async move {
+        while let Some(msg) = out_rx.recv().await {
+            if write.send(msg).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut conn = Connection {
+        identity: None,
+        pending_seed: None,
+        control: HashMap::new(),
+        acked: HashSet::new(),
+        out: out_tx,
+    };
+
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            msg = read.next() => match msg {
+                Some(Ok(Message::Text(text))) => {
+                    if let Ok(val) = serde_json::from_str::<Value>(text.trim_end()) {
+                        handle_request(&state, &mut conn, val);
+                    }
+                }
+                Some(Ok(Message::Binary(bin))) => handle_binary(&state, &mut conn, &bin),
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                _ => {}
+            }
+        }
+    }
+
+    if let Some(id) = conn.identity {
+        state.lock().unwrap().online.remove(&id);
+    }
+    writer.abort();
+}
+
+fn handle_request(state: &Arc<Mutex<RelayState>>, conn: &mut Connection, val: Value) {
+    let req_id = val["req_id"].as_str().map(|s| s.to_string());
+    let message = match serde_json::from_value::<ClientMessage>(val) {
+        Ok(m) => m,
+        Err(e) => {
+            conn.reply_error(&format!("Unknown request: {}", e), "unknown_type", req_id);
+            return;
+        }
+    };
+
+    match message {
+        ClientMessage::PowChallenge { .. } => {
+            let seed = hex::encode(rand::random::<[u8; 16]>());
+            conn.pending_seed = Some(seed.clone());
+            conn.send(&RelayMessage::PowChallengeRes {
+                seed,
+                difficulty: TEST_DIFFICULTY,
+                modulus: Some(TEST_MODULUS.to_string()),
+                req_id,
+            });
+        }
+        ClientMessage::Auth { payload } => handle_auth(state, conn, payload),
+        _ if conn.identity.is_none() => {
+            conn.reply_error("Not authenticated", "auth_failed", req_id);
+        }
+        ClientMessage::KeysUpload {
+            registration_id,
+            identity_key,
+            signed_pre_key,
+            pre_keys,
+            kyber_pre_key,
+            kyber_pre_keys,
+            signature,
+            ..
+        } => {
+            let id_hash = conn.identity.clone().unwrap_or_default();
+            let valid =
+                verify_identity_signature(&identity_key, &signature, id_hash.as_bytes(), &id_hash);
+            if valid {
+                let mut lock = state.lock().unwrap();
+                // Uploads add to the one-time keys still held, as refills expect
+                let (mut held, mut held_kyber) = lock
+                    .bundles
+                    .remove(&id_hash)
+                    .map(|stored| (stored.pre_keys, stored.kyber_pre_keys))
+                    .unwrap_or_default();
+                held.extend(pre_keys);
+                held_kyber.extend(kyber_pre_keys.unwrap_or_default());
+                lock.bundles.insert(
+                    id_hash,
+                    StoredKeys {
+                        registration_id,
+                        identity_key,
+                        signed_pre_key,
+                        pre_keys: held,
+                        kyber_pre_key,
+                        kyber_pre_keys: held_kyber,
+                    },
+                );
+            }
+            conn.reply_response(
+                &KeysUploadResponse {
+                    status: if valid { "success" } else { "error" }.to_string(),
+                    error: (!valid).then(|| "Invalid key signature".to_string()),
+                },
+                "keys_upload_res",
+                req_id,
+            );
+        }
+        ClientMessage::FetchKey { target_hash, .. } => {
+            let target = target_hash.to_lowercase();
+            let bundle = {
+                let mut lock = state.lock().unwrap();
+                lock.bundles.get_mut(&target).map(|stored| {
+                    // One-time prekeys are handed out once, like the production relay
+                    let pre_key = (!stored.pre_keys.is_empty()).then(|| stored.pre_keys.remove(0));
+                    let kyber_pre_key = if stored.kyber_pre_keys.is_empty() {
+                        stored.kyber_pre_key.clone()
+                    } else {
+                        stored.kyber_pre_keys.remove(0)
+                    };
+                    json!({
+                        "registrationId": stored.registration_id,
+                        "identityKey": stored.identity_key,
+                        "signedPreKey": stored.signed_pre_key,
+                        "kyberPreKey": kyber_pre_key,
+                        "preKey": pre_key
+                    })
+                })
+            };
+            conn.reply_response(
+                &FetchKeyResponse {
+                    found: bundle.is_some(),
+                    bundle,
+                    bundles: None,
+                },
+                "fetch_key_res",
+                req_id,
+            );
+        }
+        other => {
+            let name = serde_json::to_value(&other)
+                .ok()
+                .and_then(|v| v["type"].as_str().map(|s| s.to_string()))
+                .unwrap_or_default();
+            conn.reply_error(
+                &format!("Unsupported request type: {}", name),
+                "unknown_type",
+                req_id,
+            );
+        }
+    }
+}
+
+fn handle_auth(state: &Arc<Mutex<RelayState>>, conn: &mut Connection, payload: AuthPayload) {
+    let version = state.lock().unwrap().version;
+    if payload.protocol_version.is_some_and(|v| v > version) {
+        conn.reply_error("Unsupported protocol version", "version_mismatch", None);
+        return;
+    }
+    let id_hash = payload.identity_hash.to_lowercase();
+
+    let authorized = match &payload.credentials {
+        AuthCredentials::Token { session_token } => {
+            state.lock().unwrap().sessions.get(session_token) == Some(&id_hash)
+        }
+        AuthCredentials::Proof {
+            seed,
+            nonce,
+            modulus,
+            signature,
+            public_key,
+        } => {
+            let expected = conn.pending_seed.take();
+            match (expected, signature, public_key) {
+                (Some(expected), Some(sig_hex), Some(pk_hex))
+                    if expected == *seed
+                        && *nonce == solve_challenge(seed, TEST_DIFFICULTY, modulus) =>
+                {
+                    let seed_bytes = hex::decode(seed).unwrap_or_default();
+                    verify_identity_signature(pk_hex, sig_hex, &seed_bytes, &id_hash)
+                }
+                _ => false,
+            }
+        }
+    };
+
+    if !authorized {
+        conn.reply_error("Invalid Token", "auth_failed", None);
+        return;
+    }
+
+    let token = uuid::Uuid::new_v4().to_string();
+    let (otk_count, queued) = {
+        let mut lock = state.lock().unwrap();
+        lock.sessions.insert(token.clone(), id_hash.clone());
+        lock.online.insert(id_hash.clone(), conn.out.clone());
+        let count = lock
+            .bundles
+            .get(&id_hash)
+            .map(|b| b.pre_keys.len())
+            .unwrap_or(0);
+        (count, lock.mailbox.remove(&id_hash).unwrap_or_default())
+    };
+    conn.identity = Some(id_hash);
+    conn.send(&RelayMessage::AuthSuccess {
+        session_token: Some(token),
+        otk_count: otk_count as u64,
+        protocol_version: Some(version),
+    });
+    for frame in queued {
+        let _ = conn.out.send(Message::Binary(frame.into()));
+    }
+}
+
+fn verify_identity_signature(pk_hex: &str, sig_hex: &str, message: &[u8], id_hash: &str) -> bool {
+    let (Ok(pk_raw), Ok(sig)) = (hex::decode(pk_hex), hex::decode(sig_hex)) else {
+        return false;
+    };
+    if hex::encode(Sha256::digest(&pk_raw)) != id_hash {
+        return false;
+    }
+    let mut prefixed = vec![0x05];
+    prefixed.extend_from_slice(&pk_raw);
+    PublicKey::deserialize(&prefixed)
+        .map(|pk| pk.verify_signature(message, &sig))
+        .unwrap_or(false)
+}
+
+fn handle_binary(state: &Arc<Mutex<RelayState>>, conn: &mut Connection, packet: &[u8]) {
+    // Cover traffic is discarded unread
+    if FrameCodec::is_dummy(packet) {
+        return;
+    }
+    let Ok(frag) = FrameCodec::decode(packet) else {
+        return;
+    };
+
+    if frag.frame_type == FRAME_CONTROL {
+        let assembly = conn
+            .control
+            .entry(frag.transfer_id)
+            .or_insert_with(|| ControlAssembly {
+                chunks: vec![None; frag.total as usize],
+                received: 0,
+            });
+        if let Some(slot) = assembly.chunks.get_mut(frag.index as usize)
+            && slot.is_none()
+        {
+            *slot = Some(frag.payload);
+            assembly.received += 1;
+        }
+        if assembly.received == assembly.chunks.len()
+            && let Some(done) = conn.control.remove(&frag.transfer_id)
+        {
+            let bytes: Vec<u8> = done.chunks.into_iter().flatten().flatten().collect();
+            if let Ok(val) = serde_json::from_slice::<Value>(&bytes) {
+                handle_request(state, conn, val);
+            }
+        }
+        return;
+    }
+
+    let Some(sender) = conn.identity.clone() else {
+        return;
+    };
+
+    let target = frag.routing_str();
+    let first_report = conn.acked.insert(frag.transfer_id);
+    let delivered = {
+        let mut lock = state.lock().unwrap();
+        // Re-address the frame so the recipient sees who sent it
+        let mut forwarded = packet.to_vec();
+        if !lock.unattributed {
+            forwarded[..ROUTING_LEN].copy_from_slice(&FrameCodec::routing_header(&sender));
+        }
+        if let Some(peer) = lock.online.get(&target) {
+            peer.send(Message::Binary(forwarded.into())).is_ok()
+        } else if frag.frame_type == FRAME_MEDIA {
+            false
+        } else {
+            lock.mailbox
+                .entry(target.clone())
+                .or_default()
+                .push(forwarded);
+            true
+        }
+    };
+
+    if !first_report {
+        return;
+    }
+    if delivered {
+        conn.send(&RelayMessage::RelaySuccess {
+            transfer_id: Some(frag.transfer_id),
+        });
+    } else {
+        conn.send(&RelayMessage::DeliveryError {
+            transfer_id: Some(frag.transfer_id),
+            reason: "media_offline".to_string(),
+            target,
+        });
+    }
+}
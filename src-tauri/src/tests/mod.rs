@@ -1,2 +1,50 @@
 #[cfg(test)]
+mod codec;
+#[cfg(test)]
+mod delivery;
+#[cfg(test)]
+mod devices;
+#[cfg(test)]
 mod encryption;
+#[cfg(test)]
+mod fingerprints;
+#[cfg(test)]
+mod governor;
+#[cfg(test)]
+mod halts;
+#[cfg(test)]
+mod identity_changes;
+#[cfg(test)]
+mod integrity;
+#[cfg(test)]
+mod media_http;
+#[cfg(test)]
+mod mock_relay;
+#[cfg(test)]
+mod outbox;
+#[cfg(test)]
+mod pacing;
+#[cfg(test)]
+mod padding;
+#[cfg(test)]
+mod prekeys;
+#[cfg(test)]
+mod proxy_auth;
+#[cfg(test)]
+mod relay_e2e;
+#[cfg(test)]
+mod relay_protocol;
+#[cfg(test)]
+mod sealed;
+#[cfg(test)]
+mod stats;
+#[cfg(test)]
+mod streaming;
+#[cfg(test)]
+mod support;
+#[cfg(test)]
+mod sweeper;
+#[cfg(test)]
+mod transfers;
+#[cfg(test)]
+mod uploads;
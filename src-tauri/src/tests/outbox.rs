@@ -0,0 +1,80 @@
+//! Which undelivered packets survive a disconnect into the persistent outbox, and how
+//! long queued messages are retried.
+
+use tokio_tungstenite::tungstenite::Utf8Bytes;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use crate::commands::network::codec::*;
+use crate::commands::network::config::NetworkConfig;
+use crate::commands::network::retry::{outbox_expired, retry_delay_secs};
+use crate::commands::network::transit::is_replayable;
+
+fn fragment(routing: &[u8; ROUTING_LEN], frame_type: u8) -> Message {
+    let packet = FrameCodec::encode_fragment(routing, frame_type, 7, 0, 1, b"payload").unwrap();
+    Message::Binary(packet.into())
+}
+
+#[test]
+fn peer_addressed_data_frames_are_replayed() {
+    let peer = FrameCodec::routing_header("peer");
+    assert!(is_replayable(&fragment(&peer, FRAME_SIGNAL)));
+    assert!(is_replayable(&fragment(&peer, FRAME_MEDIA)));
+}
+
+#[test]
+fn connection_scoped_traffic_is_dropped() {
+    let peer = FrameCodec::routing_header("peer");
+    assert!(!is_replayable(&fragment(&peer, FRAME_VOLATILE)));
+    assert!(!is_replayable(&fragment(
+        &[0u8; ROUTING_LEN],
+        FRAME_CONTROL
+    )));
+    assert!(!is_replayable(&fragment(&[0u8; ROUTING_LEN], FRAME_SIGNAL)));
+    assert!(!is_replayable(&Message::Binary(FrameCodec::dummy().into())));
+    assert!(!is_replayable(&Message::Text(Utf8Bytes::from(
+        r#"{"type":"auth"}"#
+    ))));
+}
+
+fn retry_policy() -> NetworkConfig {
+    NetworkConfig {
+        outbox_max_age_secs: 3600,
+        outbox_max_attempts: 5,
+        outbox_retry_base_secs: 10,
+        outbox_retry_max_secs: 60,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn retry_delay_doubles_up_to_the_cap() {
+    let config = retry_policy();
+    let delays: Vec<u64> = (1..=5).map(|n| retry_delay_secs(n, &config)).collect();
+    assert_eq!(delays, vec![10, 20, 40, 60, 60]);
+    assert_eq!(retry_delay_secs(u32::MAX, &config), 60);
+}
+
+#[test]
+fn queued_messages_expire_by_age_or_attempts() {
+    let config = retry_policy();
+    let queued_at = 1_000_000;
+
+    assert!(!outbox_expired(queued_at, 4, queued_at + 3599, &config));
+    assert!(outbox_expired(queued_at, 0, queued_at + 3600, &config));
+    assert!(outbox_expired(queued_at, 5, queued_at + 1, &config));
+}
+
+#[test]
+fn retry_policy_is_validated() {
+    assert!(retry_policy().validate().is_ok());
+    let no_attempts = NetworkConfig {
+        outbox_max_attempts: 0,
+        ..retry_policy()
+    };
+    assert!(no_attempts.validate().is_err());
+    let inverted = NetworkConfig {
+        outbox_retry_max_secs: 5,
+        ..retry_policy()
+    };
+    assert!(inverted.validate().is_err());
+}
@@ -0,0 +1,173 @@
+//! Weighted fair ordering of the paced writer's traffic classes and constant-rate cover
+//! traffic.
+
+use std::time::Duration;
+
+use tokio_tungstenite::tungstenite::Utf8Bytes;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use crate::app_state::PacedMessage;
+use crate::commands::network::codec::*;
+use crate::commands::network::config::{CoverTrafficMode, NetworkConfig};
+use crate::commands::network::pacing::*;
+
+fn packet(frame_type: u8, index: u32) -> PacedMessage {
+    let routing = FrameCodec::routing_header("peer");
+    let frame = FrameCodec::encode_fragment(&routing, frame_type, 1, index, 1000, b"x").unwrap();
+    PacedMessage {
+        msg: Message::Binary(frame.into()),
+        msg_id: None,
+    }
+}
+
+#[test]
+fn packets_are_classified_by_frame_type() {
+    let control = Message::Text(Utf8Bytes::from("{}"));
+    assert_eq!(TrafficClass::of(&control), TrafficClass::Control);
+    assert_eq!(
+        TrafficClass::of(&packet(FRAME_SIGNAL, 0).msg),
+        TrafficClass::Text
+    );
+    assert_eq!(
+        TrafficClass::of(&packet(FRAME_VOLATILE, 0).msg),
+        TrafficClass::Volatile
+    );
+    assert_eq!(
+        TrafficClass::of(&packet(FRAME_MEDIA, 0).msg),
+        TrafficClass::Media
+    );
+    assert_eq!(
+        TrafficClass::of(&packet(FRAME_CONTROL, 0).msg),
+        TrafficClass::Control
+    );
+}
+
+#[test]
+fn scheduler_shares_by_weight_among_busy_classes() {
+    let mut drr = DrrScheduler::new([3, 1, 1, 1]);
+    let busy = [true, false, false, true];
+    let picks: Vec<usize> = (0..8).filter_map(|_| drr.pick(|c| busy[c])).collect();
+    assert_eq!(picks, vec![0, 0, 0, 3, 0, 0, 0, 3]);
+    assert_eq!(drr.pick(|_| false), None);
+}
+
+#[test]
+fn idle_class_does_not_bank_its_turn() {
+    let mut drr = DrrScheduler::new([2, 2, 1, 1]);
+    assert_eq!(drr.pick(|c| c == 3), Some(3));
+    assert_eq!(drr.pick(|c| c == 0 || c == 3), Some(0));
+    assert_eq!(drr.pick(|c| c == 3), Some(3));
+    assert_eq!(drr.pick(|c| c == 0 || c == 3), Some(0));
+    assert_eq!(drr.pick(|c| c == 0 || c == 3), Some(0));
+}
+
+#[tokio::test]
+async fn text_overtakes_a_media_backlog() {
+    let (tx, mut rx) = paced_channel();
+    for i in 0..50 {
+        tx.send(packet(FRAME_MEDIA, i)).await.unwrap();
+    }
+    tx.send(packet(FRAME_SIGNAL, 0)).await.unwrap();
+    assert_eq!(
+        tx.depths(),
+        QueueDepths {
+            media: 50,
+            text: 1,
+            ..Default::default()
+        }
+    );
+
+    let first = rx.recv().await.unwrap();
+    assert_eq!(TrafficClass::of(&first.msg), TrafficClass::Text);
+    assert_eq!(tx.depths().text, 0);
+
+    rx.close();
+    assert_eq!(rx.drain().len(), 50);
+    assert_eq!(tx.depths(), QueueDepths::default());
+    assert!(tx.send(packet(FRAME_MEDIA, 0)).await.is_err());
+}
+
+fn constant_rate(pps: u32, budget_packets: u64, burst: u32) -> NetworkConfig {
+    NetworkConfig {
+        cover_traffic: CoverTrafficMode::ConstantRate,
+        constant_rate_pps: pps,
+        bandwidth_budget_bytes_per_sec: budget_packets * PACKET_SIZE as u64,
+        media_burst_packets: burst,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn random_cover_stays_the_default() {
+    let config = NetworkConfig::default();
+    assert_eq!(config.cover_traffic, CoverTrafficMode::Random);
+    assert!(config.validate().is_ok());
+
+    let legacy: NetworkConfig = serde_json::from_str(r#"{"transferTimeoutSecs":300}"#).unwrap();
+    assert_eq!(legacy.cover_traffic, CoverTrafficMode::Random);
+    let constant: NetworkConfig =
+        serde_json::from_str(r#"{"coverTraffic":"constantRate"}"#).unwrap();
+    assert_eq!(constant.cover_traffic, CoverTrafficMode::ConstantRate);
+}
+
+#[test]
+fn constant_rate_must_fit_the_budget() {
+    assert_eq!(
+        tick_interval(&constant_rate(20, 20, 0)),
+        Duration::from_millis(50)
+    );
+    assert!(constant_rate(20, 20, 0).validate().is_ok());
+    assert!(constant_rate(20, 19, 0).validate().is_err());
+    assert!(constant_rate(0, 20, 0).validate().is_err());
+}
+
+#[test]
+fn burst_allowance_refills_from_spare_budget() {
+    // 10 packets per tick budgeted, 1 used by the tick itself
+    let mut burst = BurstAllowance::new(&constant_rate(1, 10, 12));
+    let mut spent = 0;
+    while burst.take() {
+        spent += 1;
+    }
+    assert_eq!(spent, 12);
+
+    burst.tick();
+    let mut refilled = 0;
+    while burst.take() {
+        refilled += 1;
+    }
+    assert_eq!(refilled, 9);
+
+    for _ in 0..10 {
+        burst.tick();
+    }
+    assert!(burst.ready());
+    let mut capped = 0;
+    while burst.take() {
+        capped += 1;
+    }
+    assert_eq!(capped, 12);
+}
+
+#[test]
+fn budget_at_the_tick_rate_allows_no_bursts() {
+    let mut burst = BurstAllowance::new(&constant_rate(10, 10, 0));
+    burst.tick();
+    assert!(!burst.ready());
+    assert!(!burst.take());
+}
+
+#[tokio::test]
+async fn media_can_be_pulled_outside_weighted_order() {
+    let (tx, mut rx) = paced_channel();
+    assert!(rx.try_recv().is_none());
+    tx.send(packet(FRAME_SIGNAL, 0)).await.unwrap();
+    tx.send(packet(FRAME_MEDIA, 0)).await.unwrap();
+
+    let media = rx.try_recv_class(TrafficClass::Media).unwrap();
+    assert_eq!(TrafficClass::of(&media.msg), TrafficClass::Media);
+    assert!(rx.try_recv_class(TrafficClass::Media).is_none());
+    let text = rx.try_recv().unwrap();
+    assert_eq!(TrafficClass::of(&text.msg), TrafficClass::Text);
+    assert_eq!(tx.depths(), QueueDepths::default());
+}
@@ -0,0 +1,68 @@
+//! Length-hiding padding of Signal plaintexts and media fragment counts.
+
+use crate::commands::network::codec::{
+    AEAD_OVERHEAD, CHUNK_CAPACITY, MEDIA_BLOCK_SIZE, sealed_media_len,
+};
+use crate::noise::{MIN_PADDED_PLAINTEXT, TrafficNormalizer};
+
+#[test]
+fn padme_rounds_up_with_bounded_overhead() {
+    assert_eq!(TrafficNormalizer::padme_len(0), 0);
+    assert_eq!(TrafficNormalizer::padme_len(1), 1);
+    assert_eq!(TrafficNormalizer::padme_len(256), 256);
+    assert_eq!(TrafficNormalizer::padme_len(257), 272);
+    assert_eq!(TrafficNormalizer::padme_len(1000), 1024);
+    assert_eq!(TrafficNormalizer::padme_len(9000), 9216);
+
+    for len in 2..50_000 {
+        let padded = TrafficNormalizer::padme_len(len);
+        assert!(padded >= len);
+        assert!((padded - len) * 100 <= len * 12, "{len} -> {padded}");
+    }
+}
+
+#[test]
+fn nearby_lengths_share_a_bucket() {
+    let buckets: std::collections::HashSet<usize> =
+        (1985..=2048).map(TrafficNormalizer::padme_len).collect();
+    assert_eq!(buckets.len(), 1);
+}
+
+#[test]
+fn json_plaintext_round_trips_through_padding() {
+    let original = r#"{"type":"text_msg","content":"hi  "}"#.to_string();
+    let mut padded = original.clone();
+    TrafficNormalizer::pad_plaintext(&mut padded);
+    assert_eq!(padded.len(), MIN_PADDED_PLAINTEXT);
+
+    // Peers that do not unpad still parse the padded form
+    let parsed: serde_json::Value = serde_json::from_str(&padded).unwrap();
+    assert_eq!(parsed["content"], "hi  ");
+
+    TrafficNormalizer::unpad_plaintext(&mut padded);
+    assert_eq!(padded, original);
+}
+
+#[test]
+fn non_json_plaintext_is_left_alone() {
+    let mut plain = "hello ".to_string();
+    TrafficNormalizer::pad_plaintext(&mut plain);
+    assert_eq!(plain, "hello ");
+    TrafficNormalizer::unpad_plaintext(&mut plain);
+    assert_eq!(plain, "hello ");
+}
+
+#[test]
+fn sealed_media_length_stops_before_padding_blocks() {
+    assert_eq!(CHUNK_CAPACITY, MEDIA_BLOCK_SIZE + AEAD_OVERHEAD);
+    assert_eq!(sealed_media_len(0), 0);
+    assert_eq!(sealed_media_len(1), 1 + AEAD_OVERHEAD as u64);
+    assert_eq!(
+        sealed_media_len(MEDIA_BLOCK_SIZE as u64),
+        CHUNK_CAPACITY as u64
+    );
+    assert_eq!(
+        sealed_media_len(MEDIA_BLOCK_SIZE as u64 * 3 + 10),
+        CHUNK_CAPACITY as u64 * 3 + 10 + AEAD_OVERHEAD as u64
+    );
+}
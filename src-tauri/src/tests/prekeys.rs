@@ -0,0 +1,122 @@
+//! Rotation and pruning of signed and last-resort prekeys.
+
+use super::support::{NOW, vault};
+use crate::commands::identity::prekeys::{
+    PREKEY_GRACE_MS, PREKEY_ROTATION_MS, current_last_resort_key, current_signed_pre_key,
+    mark_last_resort, prune_superseded_keys, retire_published_keys, rotation_due, rotation_pending,
+};
+use rusqlite::{Connection, params};
+
+fn add_signed(conn: &Connection, id: u32, created_at: Option<i64>) {
+    conn.execute(
+        "INSERT INTO signal_signed_pre_keys (key_id, key_data, timestamp) VALUES (?1, x'00', ?2)",
+        params![id, created_at],
+    )
+    .unwrap();
+}
+
+fn add_kyber(conn: &Connection, id: u32, created_at: Option<i64>, last_resort: bool) {
+    conn.execute(
+        "INSERT INTO signal_kyber_pre_keys (key_id, key_data, created_at) VALUES (?1, x'00', ?2)",
+        params![id, created_at],
+    )
+    .unwrap();
+    if last_resort {
+        mark_last_resort(conn, id).unwrap();
+    }
+}
+
+fn ids(conn: &Connection, table: &str) -> Vec<u32> {
+    let mut stmt = conn
+        .prepare(&format!("SELECT key_id FROM {} ORDER BY key_id", table))
+        .unwrap();
+    stmt.query_map([], |r| r.get(0))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap()
+}
+
+#[test]
+fn keys_rotate_weekly_and_keys_without_a_creation_time_at_once() {
+    assert!(!rotation_due(Some(NOW), NOW));
+    assert!(!rotation_due(Some(NOW - PREKEY_ROTATION_MS + 1), NOW));
+    assert!(rotation_due(Some(NOW - PREKEY_ROTATION_MS), NOW));
+    assert!(rotation_due(None, NOW));
+}
+
+#[test]
+fn fresh_published_keys_need_no_rotation() {
+    let conn = vault();
+    assert!(rotation_pending(&conn, NOW).unwrap());
+
+    add_signed(&conn, 1, Some(NOW - 1000));
+    add_kyber(&conn, 2, Some(NOW - 1000), true);
+    add_kyber(&conn, 3, Some(NOW - PREKEY_ROTATION_MS * 2), false);
+    assert_eq!(
+        current_signed_pre_key(&conn).unwrap(),
+        Some((1, Some(NOW - 1000)))
+    );
+    // One-time Kyber keys never count as the last-resort key
+    assert_eq!(
+        current_last_resort_key(&conn).unwrap(),
+        Some((2, Some(NOW - 1000)))
+    );
+    assert!(!rotation_pending(&conn, NOW).unwrap());
+    assert!(rotation_pending(&conn, NOW + PREKEY_ROTATION_MS).unwrap());
+}
+
+#[test]
+fn replacements_stay_pending_until_the_relay_accepts_them() {
+    let conn = vault();
+    add_signed(&conn, 1, None);
+    add_kyber(&conn, 2, None, true);
+    add_signed(&conn, 10, Some(NOW));
+    add_kyber(&conn, 20, Some(NOW), true);
+
+    // The newest keys are served, but the upload that publishes them is still owed
+    assert_eq!(current_signed_pre_key(&conn).unwrap().unwrap().0, 10);
+    assert_eq!(current_last_resort_key(&conn).unwrap().unwrap().0, 20);
+    assert!(rotation_pending(&conn, NOW).unwrap());
+
+    retire_published_keys(&conn, 10, 20, NOW).unwrap();
+    assert!(!rotation_pending(&conn, NOW).unwrap());
+    assert_eq!(current_signed_pre_key(&conn).unwrap().unwrap().0, 10);
+}
+
+#[test]
+fn superseded_keys_are_deleted_only_after_their_grace_period() {
+    let conn = vault();
+    add_signed(&conn, 1, Some(NOW - PREKEY_ROTATION_MS));
+    add_kyber(&conn, 2, Some(NOW - PREKEY_ROTATION_MS), true);
+    add_kyber(&conn, 3, Some(NOW - PREKEY_ROTATION_MS), false);
+    add_signed(&conn, 10, Some(NOW));
+    add_kyber(&conn, 20, Some(NOW), true);
+    conn.execute(
+        "INSERT INTO signal_kyber_base_keys_seen VALUES (2, 1, x'01'), (20, 10, x'02')",
+        [],
+    )
+    .unwrap();
+    retire_published_keys(&conn, 10, 20, NOW).unwrap();
+
+    assert_eq!(
+        prune_superseded_keys(&conn, NOW + PREKEY_GRACE_MS - 1).unwrap(),
+        0
+    );
+    assert_eq!(ids(&conn, "signal_signed_pre_keys"), vec![1, 10]);
+
+    assert_eq!(
+        prune_superseded_keys(&conn, NOW + PREKEY_GRACE_MS).unwrap(),
+        2
+    );
+    assert_eq!(ids(&conn, "signal_signed_pre_keys"), vec![10]);
+    // Unconsumed one-time keys are never superseded by a rotation
+    assert_eq!(ids(&conn, "signal_kyber_pre_keys"), vec![3, 20]);
+    let seen: Vec<u32> = conn
+        .prepare("SELECT kyber_prekey_id FROM signal_kyber_base_keys_seen")
+        .unwrap()
+        .query_map([], |r| r.get(0))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(seen, vec![20]);
+}
@@ -0,0 +1,32 @@
+//! Signed media proxy URLs.
+
+use crate::media_proxy::{sign_scope, verify_scope};
+
+const SECRET: [u8; 32] = [3u8; 32];
+
+#[test]
+fn signatures_hold_until_expiry() {
+    let sig = sign_scope(&SECRET, "media:abc", 1_000);
+    assert!(verify_scope(&SECRET, "media:abc", 1_000, &sig, 999));
+    assert!(verify_scope(&SECRET, "media:abc", 1_000, &sig, 1_000));
+    assert!(!verify_scope(&SECRET, "media:abc", 1_000, &sig, 1_001));
+}
+
+#[test]
+fn signatures_bind_scope_expiry_and_secret() {
+    let sig = sign_scope(&SECRET, "media:abc", 1_000);
+    assert!(!verify_scope(&SECRET, "media:abd", 1_000, &sig, 0));
+    assert!(!verify_scope(&SECRET, "local:abc", 1_000, &sig, 0));
+    // Extending the expiry invalidates the signature
+    assert!(!verify_scope(&SECRET, "media:abc", 2_000, &sig, 0));
+    // A new unlock draws a new secret
+    assert!(!verify_scope(&[4u8; 32], "media:abc", 1_000, &sig, 0));
+}
+
+#[test]
+fn malformed_signatures_are_refused() {
+    let sig = sign_scope(&SECRET, "media:abc", 1_000);
+    assert!(!verify_scope(&SECRET, "media:abc", 1_000, "", 0));
+    assert!(!verify_scope(&SECRET, "media:abc", 1_000, "zz", 0));
+    assert!(!verify_scope(&SECRET, "media:abc", 1_000, &sig[..32], 0));
+}
@@ -0,0 +1,479 @@
+//! End-to-end runs of the desktop client against the in-process mock relay.
+//!
+//! Each `AppClient` is a full app instance on Tauri's mock runtime with its own
+//! vault, identity and connection thread. Tests drive it through the same commands
+//! the UI invokes, so login, key upload, the pacer, fragmentation, the inbox and the
+//! media bridge all run the production code, and check the outcome in the vault.
+
+use serde_json::Value;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tauri::test::MockRuntime;
+use tauri::{App, AppHandle, Listener, Manager};
+
+use super::mock_relay::MockRelay;
+use crate::app_state::{DbState, NetworkState};
+use crate::commands::network::codec::MEDIA_BLOCK_SIZE;
+use crate::commands::network::config::NetworkConfig;
+use crate::commands::network::relay_protocol::PROTOCOL_VERSION;
+use crate::commands::{
+    DbMessage, OutgoingMedia, OutgoingText, RelayEndpoint, connect_network, create_group,
+    db_get_chats, db_get_messages, disconnect_network, init_vault, network_get_config,
+    network_set_config, network_set_relays, process_outgoing_group_text, process_outgoing_media,
+    process_outgoing_text, send_receipt, signal_get_identity_hash, signal_init, vault_export_media,
+};
+
+const WAIT_TIMEOUT: Duration = Duration::from_secs(30);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Events the tests wait on, recorded as `(event, payload)` in arrival order.
+const RECORDED_EVENTS: [&str; 3] = ["network-status", "network-warning", "network-bin-complete"];
+
+/// Polls `probe` until it yields a value, failing the test after `WAIT_TIMEOUT`.
+async fn wait_for<T>(what: &str, mut probe: impl AsyncFnMut() -> Option<T>) -> T {
+    let deadline = tokio::time::Instant::now() + WAIT_TIMEOUT;
+    loop {
+        if let Some(found) = probe().await {
+            return found;
+        }
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "timed out waiting for {}",
+            what
+        );
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// `network-status` carries either a bare status or an object with one.
+fn status_of(payload: &Value) -> &str {
+    payload["status"]
+        .as_str()
+        .or(payload.as_str())
+        .unwrap_or_default()
+}
+
+struct AppClient {
+    app: App<MockRuntime>,
+    hash: String,
+    data_dir: PathBuf,
+    events: Arc<Mutex<Vec<(String, Value)>>>,
+}
+
+impl AppClient {
+    /// A fresh profile pointed at `relay`, with its vault open and identity created,
+    /// but not connected.
+    async fn offline(relay: &MockRelay) -> Self {
+        let mut ctx = tauri::test::mock_context(tauri::test::noop_assets());
+        ctx.config_mut().identifier = format!("com.entropy.e2e.{}", uuid::Uuid::new_v4());
+        let app = tauri::test::mock_builder()
+            .manage(DbState::new("default".to_string()))
+            .manage(NetworkState::default())
+            .build(ctx)
+            .expect("build mock app");
+        let handle = app.handle().clone();
+        let data_dir = handle.path().app_data_dir().expect("app data dir");
+
+        init_vault(handle.clone(), handle.state(), String::new())
+            .await
+            .expect("open vault");
+        network_set_relays(
+            handle.clone(),
+            handle.state(),
+            vec![RelayEndpoint {
+                url: relay.url(),
+                priority: 0,
+                proxy_url: None,
+                is_enabled: true,
+            }],
+        )
+        .expect("point the client at the mock relay");
+        signal_init(handle.clone()).await.expect("create identity");
+        let hash = signal_get_identity_hash(handle.state(), handle.state())
+            .await
+            .expect("identity hash");
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        for name in RECORDED_EVENTS {
+            let events = events.clone();
+            handle.listen_any(name, move |event| {
+                let payload = serde_json::from_str(event.payload()).unwrap_or(Value::Null);
+                events.lock().unwrap().push((name.to_string(), payload));
+            });
+        }
+
+        Self {
+            app,
+            hash,
+            data_dir,
+            events,
+        }
+    }
+
+    /// A fresh profile that has logged in to `relay` and published its keys.
+    async fn online(relay: &MockRelay) -> Self {
+        let client = Self::offline(relay).await;
+        client.connect(None).await;
+        client.wait_online(relay).await;
+        client
+    }
+
+    fn handle(&self) -> AppHandle<MockRuntime> {
+        self.app.handle().clone()
+    }
+
+    fn enable_sealed_sender(&self) {
+        let handle = self.handle();
+        let config = NetworkConfig {
+            sealed_sender: true,
+            ..network_get_config(handle.state()).expect("read network config")
+        };
+        network_set_config(handle.state(), handle.state(), config).expect("enable sealed sender");
+    }
+
+    /// Starts the connection loop like the UI does after unlocking; the identity and
+    /// any saved session token are read from the vault.
+    async fn connect(&self, session_token: Option<String>) {
+        let handle = self.handle();
+        connect_network(handle.clone(), handle.state(), None, None, session_token)
+            .await
+            .expect("start connection loop");
+    }
+
+    async fn disconnect(&self, relay: &MockRelay) {
+        disconnect_network(self.handle())
+            .await
+            .expect("stop connection loop");
+        wait_for("the relay to drop the session", async || {
+            (!relay.is_online(&self.hash)).then_some(())
+        })
+        .await;
+    }
+
+    fn is_authenticated(&self) -> bool {
+        *self
+            .handle()
+            .state::<NetworkState>()
+            .is_authenticated
+            .lock()
+            .unwrap()
+    }
+
+    fn session_token(&self) -> Option<String> {
+        self.handle()
+            .state::<NetworkState>()
+            .session_token
+            .lock()
+            .unwrap()
+            .clone()
+    }
+
+    async fn wait_online(&self, relay: &MockRelay) {
+        wait_for("login and key upload", async || {
+            (self.is_authenticated() && relay.is_online(&self.hash) && relay.has_keys(&self.hash))
+                .then_some(())
+        })
+        .await;
+    }
+
+    async fn wait_event(&self, name: &str, matches: impl Fn(&Value) -> bool) -> Value {
+        wait_for(name, async || {
+            self.events
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|(event, payload)| event == name && matches(payload))
+                .map(|(_, payload)| payload.clone())
+        })
+        .await
+    }
+
+    async fn messages(&self, chat: &str) -> Vec<DbMessage> {
+        let handle = self.handle();
+        db_get_messages(handle.state(), chat.to_string(), 100, 0, true)
+            .await
+            .unwrap_or_default()
+    }
+
+    /// The first message in `chat` matching `matches`, once it has been stored.
+    async fn wait_message(&self, chat: &str, matches: impl Fn(&DbMessage) -> bool) -> DbMessage {
+        wait_for(&format!("a message in {}", chat), async || {
+            self.messages(chat).await.into_iter().find(|m| matches(m))
+        })
+        .await
+    }
+
+    async fn send_text(&self, peer: &str, content: &str) -> String {
+        let handle = self.handle();
+        let sent = process_outgoing_text(
+            handle.clone(),
+            handle.state(),
+            handle.state(),
+            OutgoingText {
+                recipient: peer.to_string(),
+                content: content.to_string(),
+                reply_to: None,
+                group_name: None,
+                is_group: false,
+                group_members: None,
+            },
+        )
+        .await
+        .expect("send text");
+        sent["id"].as_str().expect("message id").to_string()
+    }
+
+    async fn send_file(&self, peer: &str, data: Vec<u8>) -> String {
+        let handle = self.handle();
+        let payload = OutgoingMedia {
+            recipient: peer.to_string(),
+            file_path: None,
+            file_data: Some(data),
+            file_name: Some("blob.bin".to_string()),
+            file_type: Some("application/octet-stream".to_string()),
+            msg_type: Some("file".to_string()),
+            group_name: None,
+            duration: None,
+            thumbnail: None,
+            is_group: false,
+            group_members: None,
+            reply_to: None,
+        };
+        // A synchronous command, run off the async runtime like Tauri does
+        let sent = tokio::task::spawn_blocking(move || process_outgoing_media(handle, payload))
+            .await
+            .expect("media command panicked")
+            .expect("send file");
+        sent["id"].as_str().expect("message id").to_string()
+    }
+}
+
+impl Drop for AppClient {
+    fn drop(&mut self) {
+        let state = self.app.state::<NetworkState>();
+        if let Ok(mut l) = state.is_enabled.lock() {
+            *l = false;
+        }
+        if let Ok(mut l) = state.cancel.lock()
+            && let Some(token) = l.take()
+        {
+            token.cancel();
+        }
+        let _ = std::fs::remove_dir_all(&self.data_dir);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn text_message_is_stored_and_receipted() {
+    let relay = MockRelay::start().await;
+    let alice = AppClient::online(&relay).await;
+    let bob = AppClient::online(&relay).await;
+
+    let msg_id = alice.send_text(&bob.hash, "hello bob").await;
+
+    let received = bob.wait_message(&alice.hash, |m| m.id == msg_id).await;
+    assert_eq!(received.content, "hello bob");
+    assert_eq!(received.sender_hash, alice.hash);
+
+    // The inbox answers with a delivery receipt on its own
+    alice
+        .wait_message(&bob.hash, |m| m.id == msg_id && m.status == "delivered")
+        .await;
+
+    let handle = bob.handle();
+    send_receipt(
+        handle.clone(),
+        handle.state(),
+        handle.state(),
+        alice.hash.clone(),
+        vec![msg_id.clone()],
+        "read".to_string(),
+    )
+    .await
+    .expect("send read receipt");
+    alice
+        .wait_message(&bob.hash, |m| m.id == msg_id && m.status == "read")
+        .await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn media_transfer_lands_in_the_recipients_vault() {
+    let relay = MockRelay::start().await;
+    let alice = AppClient::online(&relay).await;
+    let bob = AppClient::online(&relay).await;
+
+    let file: Vec<u8> = (0..MEDIA_BLOCK_SIZE * 3 + 421)
+        .map(|_| rand::random::<u8>())
+        .collect();
+    alice.send_file(&bob.hash, file.clone()).await;
+
+    let done = bob
+        .wait_event("network-bin-complete", |p| {
+            p["sender"] == alice.hash.as_str()
+        })
+        .await;
+    let msg_id = done["msg_id"].as_str().expect("completed download id");
+    let received = bob.wait_message(&alice.hash, |m| m.id == msg_id).await;
+    assert_eq!(received.r#type, "file");
+
+    let exported = bob.data_dir.join("exported.bin");
+    let handle = bob.handle();
+    vault_export_media(
+        handle.clone(),
+        handle.state(),
+        msg_id.to_string(),
+        exported.to_string_lossy().to_string(),
+    )
+    .await
+    .expect("export received file");
+    assert_eq!(std::fs::read(&exported).expect("read export"), file);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sealed_media_is_attributed_to_its_sender_without_routing_headers() {
+    let relay = MockRelay::start_unattributed().await;
+    let alice = AppClient::online(&relay).await;
+    let bob = AppClient::online(&relay).await;
+    for client in [&alice, &bob] {
+        client.enable_sealed_sender();
+    }
+
+    let file: Vec<u8> = (0..MEDIA_BLOCK_SIZE * 2 + 77)
+        .map(|_| rand::random::<u8>())
+        .collect();
+    alice.send_file(&bob.hash, file.clone()).await;
+
+    let done = bob
+        .wait_event("network-bin-complete", |p| {
+            p["sender"] == alice.hash.as_str()
+        })
+        .await;
+    let msg_id = done["msg_id"].as_str().expect("completed download id");
+    bob.wait_message(&alice.hash, |m| m.id == msg_id).await;
+
+    let exported = bob.data_dir.join("exported.bin");
+    let handle = bob.handle();
+    vault_export_media(
+        handle.clone(),
+        handle.state(),
+        msg_id.to_string(),
+        exported.to_string_lossy().to_string(),
+    )
+    .await
+    .expect("export received file");
+    assert_eq!(std::fs::read(&exported).expect("read export"), file);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn group_invite_reaches_every_member() {
+    let relay = MockRelay::start().await;
+    let alice = AppClient::online(&relay).await;
+    let bob = AppClient::online(&relay).await;
+    let carol = AppClient::online(&relay).await;
+
+    let handle = alice.handle();
+    let group_id = create_group(
+        handle.clone(),
+        handle.state(),
+        handle.state(),
+        "Test Group".to_string(),
+        vec![bob.hash.clone(), carol.hash.clone()],
+    )
+    .await
+    .expect("create group");
+
+    for member in [&bob, &carol] {
+        let chat = wait_for("the group invite", async || {
+            let handle = member.handle();
+            db_get_chats(handle.state())
+                .await
+                .unwrap_or_default()
+                .into_iter()
+                .find(|c| c.address == group_id)
+        })
+        .await;
+        assert!(chat.is_group);
+        assert_eq!(chat.alias.as_deref(), Some("Test Group"));
+        assert_eq!(chat.members.map(|m| m.len()), Some(3));
+    }
+
+    let members = vec![alice.hash.clone(), bob.hash.clone(), carol.hash.clone()];
+    process_outgoing_group_text(
+        carol.handle(),
+        OutgoingText {
+            recipient: group_id.clone(),
+            content: "hi all".to_string(),
+            reply_to: None,
+            group_name: Some("Test Group".to_string()),
+            is_group: true,
+            group_members: Some(members),
+        },
+    )
+    .await
+    .expect("send group text");
+
+    for member in [&alice, &bob] {
+        let received = member
+            .wait_message(&group_id, |m| m.content == "hi all")
+            .await;
+        assert_eq!(received.sender_hash, carol.hash);
+        assert!(received.is_group);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn offline_peer_gets_mailbox_and_media_is_refused() {
+    let relay = MockRelay::start().await;
+    let alice = AppClient::online(&relay).await;
+    let bob = AppClient::online(&relay).await;
+    bob.disconnect(&relay).await;
+
+    let msg_id = alice.send_text(&bob.hash, "while you were out").await;
+    alice
+        .wait_message(&bob.hash, |m| m.id == msg_id && m.status == "sent")
+        .await;
+    assert!(relay.mailbox_len(&bob.hash) > 0);
+
+    alice.send_file(&bob.hash, b"offline blob".to_vec()).await;
+    alice
+        .wait_event("network-warning", |p| {
+            p["type"] == "media_offline" && p["target"] == bob.hash.as_str()
+        })
+        .await;
+
+    // Reconnecting re-authenticates with the token saved in the vault
+    bob.connect(None).await;
+    bob.wait_online(&relay).await;
+    let received = bob.wait_message(&alice.hash, |m| m.id == msg_id).await;
+    assert_eq!(received.content, "while you were out");
+    assert_eq!(relay.mailbox_len(&bob.hash), 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn forged_session_token_falls_back_to_proof_of_work() {
+    let relay = MockRelay::start().await;
+    let mallory = AppClient::offline(&relay).await;
+    let forged = "00000000-0000-0000-0000-000000000000".to_string();
+
+    mallory.connect(Some(forged.clone())).await;
+    mallory
+        .wait_event("network-status", |p| status_of(p) == "auth_failed")
+        .await;
+
+    // The rejected token is dropped and the next attempt mines a fresh login
+    mallory.wait_online(&relay).await;
+    assert!(mallory.session_token().is_some_and(|t| t != forged));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn newer_relay_protocol_is_refused_at_auth() {
+    let relay = MockRelay::start_at_version(PROTOCOL_VERSION + 1).await;
+    let client = AppClient::offline(&relay).await;
+
+    client.connect(None).await;
+    client
+        .wait_event("network-status", |p| status_of(p) == "version_mismatch")
+        .await;
+    assert!(!client.is_authenticated());
+}
@@ -0,0 +1,207 @@
+//! Wire-compatibility checks for the typed relay protocol.
+
+use serde_json::json;
+
+use crate::commands::network::relay_protocol::*;
+
+#[test]
+fn client_messages_keep_legacy_wire_shape() {
+    let challenge = ClientMessage::PowChallenge {
+        identity_hash: "abc".into(),
+    }
+    .encode(Some("auto_challenge"))
+    .unwrap();
+    assert_eq!(
+        challenge,
+        json!({ "type": "pow_challenge", "identity_hash": "abc", "req_id": "auto_challenge" })
+    );
+
+    let auth = ClientMessage::Auth {
+        payload: AuthPayload::new(
+            "abc".into(),
+            AuthCredentials::Token {
+                session_token: "tok".into(),
+            },
+        ),
+    }
+    .encode(None)
+    .unwrap();
+    assert_eq!(
+        auth,
+        json!({
+            "type": "auth",
+            "payload": { "identity_hash": "abc", "session_token": "tok", "protocol_version": PROTOCOL_VERSION }
+        })
+    );
+
+    let upload = ClientMessage::KeysUpload {
+        identity_hash: "abc".into(),
+        registration_id: 7,
+        identity_key: "ik".into(),
+        signed_pre_key: SignedKeyUpload {
+            id: 1,
+            public_key: "spk".into(),
+            signature: "sig".into(),
+        },
+        pre_keys: vec![PreKeyUpload {
+            id: 2,
+            public_key: "pk".into(),
+        }],
+        kyber_pre_key: SignedKeyUpload {
+            id: 3,
+            public_key: "kpk".into(),
+            signature: "ksig".into(),
+        },
+        kyber_pre_keys: None,
+        signature: "owner".into(),
+        device_id: None,
+        link: None,
+        devices: None,
+    }
+    .encode(Some("r1"))
+    .unwrap();
+    assert_eq!(upload["registrationId"], 7);
+    assert_eq!(upload["signedPreKey"]["publicKey"], "spk");
+    assert_eq!(upload["preKeys"][0]["publicKey"], "pk");
+    assert_eq!(upload["kyberPreKey"]["id"], 3);
+    assert!(upload.get("kyberPreKeys").is_none());
+
+    let revoke = ClientMessage::SessionRevoke.encode(None).unwrap();
+    assert_eq!(revoke, json!({ "type": "session_revoke" }));
+}
+
+#[test]
+fn auth_payload_roundtrips_both_credential_forms() {
+    let proof = json!({
+        "identity_hash": "abc",
+        "seed": "00ff",
+        "nonce": "11",
+        "modulus": "23",
+        "signature": "aa",
+        "public_key": "bb"
+    });
+    let parsed: AuthPayload = serde_json::from_value(proof).unwrap();
+    assert_eq!(parsed.protocol_version, None);
+    assert!(matches!(
+        parsed.credentials,
+        AuthCredentials::Proof { ref seed, .. } if seed == "00ff"
+    ));
+
+    let token: AuthPayload =
+        serde_json::from_value(json!({ "identity_hash": "abc", "session_token": "t" })).unwrap();
+    assert!(matches!(token.credentials, AuthCredentials::Token { .. }));
+}
+
+#[test]
+fn relay_messages_decode_with_legacy_aliases() {
+    let low = RelayMessage::from_value(&json!({ "type": "otk_low", "otk_count": 12 })).unwrap();
+    assert_eq!(low, RelayMessage::KeysLow { count: 12 });
+
+    let success = RelayMessage::from_value(&json!({
+        "type": "auth_success",
+        "session_token": "t"
+    }))
+    .unwrap();
+    assert_eq!(
+        success,
+        RelayMessage::AuthSuccess {
+            session_token: Some("t".into()),
+            otk_count: 0,
+            protocol_version: None
+        }
+    );
+
+    let err = RelayMessage::from_value(&json!({
+        "type": "delivery_error",
+        "transfer_id": 9,
+        "reason": "media_offline",
+        "target": "peer"
+    }))
+    .unwrap();
+    assert!(matches!(
+        err,
+        RelayMessage::DeliveryError { transfer_id: Some(9), ref reason, .. } if reason == "media_offline"
+    ));
+}
+
+#[test]
+fn unknown_and_malformed_frames_are_distinguished() {
+    assert_eq!(
+        RelayMessage::from_value(&json!({ "type": "server_notice" })),
+        Err(ProtocolError::UnknownType("server_notice".into()))
+    );
+    assert!(matches!(
+        RelayMessage::from_value(&json!({ "type": "pow_challenge_res", "seed": 5 })),
+        Err(ProtocolError::Malformed(_))
+    ));
+    assert!(matches!(
+        RelayMessage::from_value(&json!({ "seed": "x" })),
+        Err(ProtocolError::Malformed(_))
+    ));
+    assert!(matches!(
+        parse_frame("not json"),
+        Err(ProtocolError::Malformed(_))
+    ));
+    assert!(matches!(
+        parse_frame("[1, 2]"),
+        Err(ProtocolError::Malformed(_))
+    ));
+    assert!(parse_frame("{\"type\":\"error\"}      ").is_ok());
+}
+
+#[test]
+fn error_classification_prefers_code_over_message() {
+    assert_eq!(
+        RelayErrorKind::classify(Some("jailed"), ""),
+        RelayErrorKind::Jailed
+    );
+    assert_eq!(
+        RelayErrorKind::classify(None, "Identity Jailed for 300s"),
+        RelayErrorKind::Jailed
+    );
+    assert_eq!(
+        RelayErrorKind::classify(None, "Invalid Token"),
+        RelayErrorKind::AuthFailed
+    );
+    assert_eq!(
+        RelayErrorKind::classify(Some("version_mismatch"), "Jailed"),
+        RelayErrorKind::VersionMismatch
+    );
+    assert_eq!(
+        RelayErrorKind::classify(Some("rate_limited"), "slow down"),
+        RelayErrorKind::Other
+    );
+}
+
+#[test]
+fn version_check_accepts_legacy_relays() {
+    assert!(check_version(None).is_ok());
+    assert!(check_version(Some(PROTOCOL_VERSION)).is_ok());
+    assert_eq!(
+        check_version(Some(PROTOCOL_VERSION + 1)),
+        Err(ProtocolError::VersionMismatch {
+            relay: PROTOCOL_VERSION + 1,
+            client: PROTOCOL_VERSION
+        })
+    );
+}
+
+#[test]
+fn routed_error_responses_become_typed_failures() {
+    let res = check_response(json!({ "type": "error", "error": "Invalid Token", "req_id": "x" }));
+    assert_eq!(
+        res,
+        Err(ProtocolError::Relay {
+            kind: RelayErrorKind::AuthFailed,
+            message: "Invalid Token".into()
+        })
+    );
+
+    let fetch: FetchKeyResponse = decode_response(
+        check_response(json!({ "found": true, "bundles": { "peer": { "registrationId": 1 } } }))
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(fetch.bundle_for("peer").unwrap()["registrationId"], 1);
+    assert!(fetch.bundle_for("other").is_none());
+}
@@ -0,0 +1,103 @@
+//! Sealed-sender layers and the sender certificates inside them.
+
+use crate::commands::identity::devices::sign_link;
+use crate::commands::identity::prekeys::now_ms;
+use crate::commands::identity::sealed::{is_sealed, seal, sealed_for_device, unseal};
+use base64::Engine;
+use libsignal_protocol::IdentityKeyPair;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+fn identity() -> IdentityKeyPair {
+    IdentityKeyPair::generate(&mut StdRng::from_os_rng())
+}
+
+fn account_of(identity: &IdentityKeyPair) -> String {
+    hex::encode(Sha256::digest(&identity.identity_key().serialize()[1..]))
+}
+
+fn ciphertext() -> serde_json::Value {
+    json!({ "type": 2, "body": "AA==", "is_signal": true })
+}
+
+#[test]
+fn recipients_open_the_layer_and_learn_the_sender() {
+    let sender = identity();
+    let recipient = identity();
+    let account = account_of(&sender);
+
+    let layer = seal(&sender, &account, recipient.identity_key(), &ciphertext()).unwrap();
+    let opened = unseal(&recipient, &layer).unwrap();
+    assert_eq!(opened.sender, account);
+    assert_eq!(opened.envelope, ciphertext());
+}
+
+#[test]
+fn layers_open_only_for_the_device_they_were_sealed_to() {
+    let sender = identity();
+    let account = account_of(&sender);
+    let layer = seal(&sender, &account, identity().identity_key(), &ciphertext()).unwrap();
+    assert!(unseal(&identity(), &layer).is_err());
+
+    let mut tampered = base64::engine::general_purpose::STANDARD
+        .decode(seal(&sender, &account, sender.identity_key(), &ciphertext()).unwrap())
+        .unwrap();
+    *tampered.last_mut().unwrap() ^= 1;
+    let tampered = base64::engine::general_purpose::STANDARD.encode(tampered);
+    assert!(unseal(&sender, &tampered).is_err());
+}
+
+#[test]
+fn certificates_must_belong_to_the_named_sender() {
+    let impostor = identity();
+    let victim = identity();
+    let recipient = identity();
+
+    // Signed correctly, but by a key that does not hash to the claimed account
+    let layer = seal(
+        &impostor,
+        &account_of(&victim),
+        recipient.identity_key(),
+        &ciphertext(),
+    )
+    .unwrap();
+    assert!(unseal(&recipient, &layer).is_err());
+}
+
+#[test]
+fn linked_devices_seal_under_their_accounts_link() {
+    let primary = identity();
+    let device = identity();
+    let recipient = identity();
+    let account = account_of(&primary);
+    let link = sign_link(
+        &primary,
+        2,
+        &device.identity_key().serialize()[1..],
+        now_ms() as u64,
+    )
+    .unwrap();
+
+    let mut envelope = ciphertext();
+    envelope["device"] = json!(2);
+    envelope["link"] = json!(link);
+    let layer = seal(&device, &account, recipient.identity_key(), &envelope).unwrap();
+    let opened = unseal(&recipient, &layer).unwrap();
+    assert_eq!(opened.sender, account);
+    assert_eq!(opened.envelope["device"], 2);
+
+    // Another key cannot borrow the link
+    let layer = seal(&identity(), &account, recipient.identity_key(), &envelope).unwrap();
+    assert!(unseal(&recipient, &layer).is_err());
+}
+
+#[test]
+fn sealed_envelopes_hold_one_layer_per_device() {
+    let envelope = json!({ "sealed": { "1": "b25l", "3": "dGhyZWU=" }, "is_signal": true });
+    assert!(is_sealed(&envelope));
+    assert!(!is_sealed(&ciphertext()));
+    assert_eq!(sealed_for_device(&envelope, 3), Some("dGhyZWU="));
+    assert_eq!(sealed_for_device(&envelope, 2), None);
+}
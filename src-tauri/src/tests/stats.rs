@@ -0,0 +1,99 @@
+//! Heartbeat round trips and connection statistics.
+
+use std::time::{Duration, Instant};
+
+use crate::commands::network::config::NetworkConfig;
+use crate::commands::network::pacing::QueueDepths;
+use crate::commands::network::stats::*;
+
+#[test]
+fn pong_for_the_outstanding_ping_sets_the_rtt() {
+    let stats = NetworkStats::default();
+    let sent = Instant::now();
+    let ping = stats.begin_ping(sent);
+
+    assert_eq!(stats.complete_ping(b"nonsense", sent), None);
+    let rtt = stats.complete_ping(&ping, sent + Duration::from_millis(42));
+    assert_eq!(rtt, Some(Duration::from_millis(42)));
+    assert_eq!(
+        stats.snapshot(QueueDepths::default(), None, true).rtt_ms,
+        Some(42.0)
+    );
+
+    // A duplicate pong does not count twice
+    assert_eq!(
+        stats.complete_ping(&ping, sent + Duration::from_secs(5)),
+        None
+    );
+}
+
+#[test]
+fn superseded_pings_are_ignored() {
+    let stats = NetworkStats::default();
+    let now = Instant::now();
+    let first = stats.begin_ping(now);
+    let second = stats.begin_ping(now + Duration::from_secs(15));
+
+    assert_eq!(
+        stats.complete_ping(&first, now + Duration::from_secs(16)),
+        None
+    );
+    assert_eq!(
+        stats.complete_ping(&second, now + Duration::from_secs(16)),
+        Some(Duration::from_secs(1))
+    );
+
+    stats.reset_heartbeat();
+    assert_eq!(
+        stats.snapshot(QueueDepths::default(), None, false).rtt_ms,
+        None
+    );
+}
+
+#[test]
+fn snapshot_reports_traffic_and_cover_ratio() {
+    let stats = NetworkStats::default();
+    let empty = stats.snapshot(QueueDepths::default(), None, false);
+    assert_eq!(empty.dummy_ratio, 0.0);
+
+    for _ in 0..3 {
+        stats.record_out(1400, true);
+    }
+    stats.record_out(1400, false);
+    stats.record_control(8);
+    stats.record_in(700);
+    stats.record_reconnect();
+
+    let depths = QueueDepths {
+        text: 2,
+        ..Default::default()
+    };
+    let snap = stats.snapshot(depths.clone(), Some("wss://relay".into()), true);
+    assert_eq!(snap.bytes_out, 5608);
+    assert_eq!(snap.bytes_in, 700);
+    assert_eq!((snap.real_packets, snap.dummy_packets), (1, 3));
+    // Heartbeats stay out of the cover ratio
+    assert_eq!(snap.control_packets, 1);
+    assert_eq!(snap.dummy_ratio, 0.75);
+    assert_eq!(snap.reconnects, 1);
+    assert_eq!(snap.queue_depth, depths);
+    assert_eq!(snap.relay.as_deref(), Some("wss://relay"));
+}
+
+#[test]
+fn heartbeat_settings_bound_the_read_deadline() {
+    let config = NetworkConfig::default();
+    assert!(config.validate().is_ok());
+    assert_eq!(read_deadline(&config), Duration::from_secs(25));
+
+    let too_fast = NetworkConfig {
+        heartbeat_interval_secs: 1,
+        ..Default::default()
+    };
+    assert!(too_fast.validate().is_err());
+    let no_stats = NetworkConfig {
+        stats_interval_secs: 0,
+        ..Default::default()
+    };
+    assert!(no_stats.validate().is_err());
+}
@@ -0,0 +1,35 @@
+//! Serving downloads that are still being reassembled.
+
+use crate::commands::messaging::inbox::reassembler::{BlockState, block_state};
+use crate::commands::network::codec::{MEDIA_BLOCK_SIZE, media_block_len};
+
+const BLOCK: u64 = MEDIA_BLOCK_SIZE as u64;
+
+#[test]
+fn live_reassemblies_report_their_slots() {
+    assert_eq!(block_state(Some(true), true, true), BlockState::Received);
+    assert_eq!(block_state(Some(false), true, true), BlockState::Pending);
+    // A reopened reassembly still has its temp file but waits for the resend
+    assert_eq!(block_state(Some(false), false, true), BlockState::Pending);
+}
+
+#[test]
+fn finished_and_abandoned_downloads_are_told_apart() {
+    // Complete and waiting for the bridge
+    assert_eq!(block_state(None, false, true), BlockState::Received);
+    assert_eq!(block_state(None, true, true), BlockState::Received);
+    // Announced, no fragment yet
+    assert_eq!(block_state(None, true, false), BlockState::Pending);
+    // Bridged or cancelled
+    assert_eq!(block_state(None, false, false), BlockState::Gone);
+}
+
+#[test]
+fn block_lengths_follow_the_announced_size() {
+    let size = 2 * BLOCK + 7;
+    assert_eq!(media_block_len(size, 0), MEDIA_BLOCK_SIZE);
+    assert_eq!(media_block_len(size, 1), MEDIA_BLOCK_SIZE);
+    assert_eq!(media_block_len(size, 2), 7);
+    assert_eq!(media_block_len(size, 3), 0);
+    assert_eq!(media_block_len(0, 0), 0);
+}
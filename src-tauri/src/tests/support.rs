@@ -0,0 +1,14 @@
+//! Shared fixtures for unit tests.
+
+use crate::commands::vault::apply_migrations;
+use rusqlite::Connection;
+
+/// A fixed wall-clock time, in milliseconds, for tests that age rows.
+pub(crate) const NOW: i64 = 1_700_000_000_000;
+
+/// An in-memory vault with the full, current schema.
+pub(crate) fn vault() -> Connection {
+    let conn = Connection::open_in_memory().unwrap();
+    apply_migrations(&conn).unwrap();
+    conn
+}
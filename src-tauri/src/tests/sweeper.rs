@@ -0,0 +1,80 @@
+//! Expiry and naming helpers behind the transfer sweeper.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::commands::messaging::inbox::reassembler::{
+    assembler_key, is_temp_filename, parse_assembler_key, temp_filename,
+};
+use crate::commands::network::codec::{FRAME_MEDIA, FRAME_SIGNAL, FRAME_VOLATILE};
+use crate::commands::network::config::NetworkConfig;
+use crate::commands::network::sweeper::expired_keys;
+
+#[test]
+fn only_idle_entries_expire() {
+    let ttl = Duration::from_secs(60);
+    let start = Instant::now();
+    let now = start + ttl * 3;
+    let mut map = HashMap::new();
+    map.insert("fresh", now);
+    map.insert("edge", start + ttl * 2);
+    map.insert("stale", start);
+
+    let mut expired = expired_keys(&map, now, ttl, |t| *t);
+    expired.sort();
+    assert_eq!(expired, vec!["edge", "stale"]);
+
+    // Activity recorded after the sweep started must never count as idle
+    map.insert("future", now + ttl);
+    assert!(!expired_keys(&map, now, ttl, |t| *t).contains(&"future"));
+}
+
+#[test]
+fn assembler_keys_roundtrip() {
+    let sender = "a".repeat(64);
+    for frame_type in [FRAME_SIGNAL, FRAME_MEDIA, FRAME_VOLATILE] {
+        let key = assembler_key(&sender, 4_000_000_000, frame_type);
+        assert_eq!(
+            parse_assembler_key(&key),
+            Some((sender.as_str(), 4_000_000_000, frame_type))
+        );
+    }
+    assert_eq!(parse_assembler_key("peer:12"), None);
+    assert_eq!(parse_assembler_key("peer:x:2"), None);
+}
+
+#[test]
+fn temp_files_are_recognised_by_name() {
+    let media = temp_filename("peer", 7, FRAME_MEDIA);
+    let sig = temp_filename("peer", 7, FRAME_VOLATILE);
+    assert_eq!(media, "transfer_peer_7_media.bin");
+    assert_eq!(sig, "transfer_peer_7_sig.bin");
+    assert!(is_temp_filename(&media) && is_temp_filename(&sig));
+    assert!(!is_temp_filename("msg-123"));
+    assert!(!is_temp_filename("msg-123_thumb"));
+}
+
+#[test]
+fn config_fills_missing_fields_and_validates() {
+    let partial: NetworkConfig = serde_json::from_str(r#"{ "transferTimeoutSecs": 120 }"#).unwrap();
+    assert_eq!(partial.transfer_timeout_secs, 120);
+    assert_eq!(
+        partial.sweep_interval_secs,
+        NetworkConfig::default().sweep_interval_secs
+    );
+    assert!(partial.validate().is_ok());
+    assert!(NetworkConfig::default().validate().is_ok());
+
+    let too_short = NetworkConfig {
+        transfer_timeout_secs: 5,
+        ..Default::default()
+    };
+    assert!(too_short.validate().is_err());
+
+    let inverted = NetworkConfig {
+        transfer_timeout_secs: 600,
+        outgoing_retention_secs: 300,
+        ..Default::default()
+    };
+    assert!(inverted.validate().is_err());
+}
@@ -0,0 +1,48 @@
+//! Expiry of user transfer controls.
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::app_state::TransferControl;
+use crate::commands::messaging::transfers::prune_transfer_controls;
+
+#[test]
+fn cancellations_expire_but_pauses_are_kept() {
+    let start = Instant::now();
+    let ttl = Duration::from_secs(60);
+    let live = HashSet::from([1]);
+    let mut controls = HashMap::from([
+        (1, TransferControl::Paused),
+        (2, TransferControl::Cancelled(start)),
+        (
+            3,
+            TransferControl::Cancelled(start + Duration::from_secs(30)),
+        ),
+    ]);
+
+    prune_transfer_controls(&mut controls, &live, start + Duration::from_secs(59), ttl);
+    assert_eq!(controls.len(), 3);
+
+    prune_transfer_controls(&mut controls, &live, start + Duration::from_secs(60), ttl);
+    assert_eq!(controls.get(&1), Some(&TransferControl::Paused));
+    assert!(!controls.contains_key(&2));
+    assert!(controls.contains_key(&3));
+
+    prune_transfer_controls(&mut controls, &live, start + Duration::from_secs(3600), ttl);
+    assert_eq!(controls.len(), 1);
+    assert!(controls.contains_key(&1));
+}
+
+#[test]
+fn pauses_of_finished_transfers_are_forgotten() {
+    let start = Instant::now();
+    let ttl = Duration::from_secs(60);
+    let mut controls = HashMap::from([(1, TransferControl::Paused), (2, TransferControl::Paused)]);
+
+    prune_transfer_controls(&mut controls, &HashSet::from([2]), start, ttl);
+    assert!(!controls.contains_key(&1));
+    assert_eq!(controls.get(&2), Some(&TransferControl::Paused));
+
+    prune_transfer_controls(&mut controls, &HashSet::new(), start, ttl);
+    assert!(controls.is_empty());
+}
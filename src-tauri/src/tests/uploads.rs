@@ -0,0 +1,58 @@
+//! Transit key sealing and resume points of recorded uploads.
+
+use crate::commands::messaging::outbox::uploads::*;
+use crate::commands::network::codec::{CHUNK_CAPACITY, MEDIA_BLOCK_SIZE};
+
+const BLOCK: u64 = MEDIA_BLOCK_SIZE as u64;
+
+#[test]
+fn transit_keys_open_only_under_the_sealing_vault_key() {
+    let vault_key = [7u8; 32];
+    let transit_key = [42u8; 32];
+    let sealed = seal_transit_key(&vault_key, &transit_key).unwrap();
+
+    assert!(!sealed.windows(32).any(|w| w == transit_key));
+    assert_eq!(open_transit_key(&vault_key, &sealed).unwrap(), transit_key);
+    assert!(open_transit_key(&[8u8; 32], &sealed).is_err());
+    assert!(open_transit_key(&vault_key, &sealed[..20]).is_err());
+}
+
+#[test]
+fn sealing_twice_uses_fresh_nonces() {
+    let vault_key = [1u8; 32];
+    let transit_key = [2u8; 32];
+    assert_ne!(
+        seal_transit_key(&vault_key, &transit_key).unwrap(),
+        seal_transit_key(&vault_key, &transit_key).unwrap()
+    );
+}
+
+#[test]
+fn vault_length_tracks_sealed_blocks() {
+    let file_size = 3 * BLOCK + 10;
+    assert_eq!(vault_len_at(file_size, 0), 0);
+    assert_eq!(vault_len_at(file_size, 2), 2 * CHUNK_CAPACITY as u64);
+    // The short final block carries the same per-block overhead
+    assert_eq!(
+        vault_len_at(file_size, 4),
+        3 * CHUNK_CAPACITY as u64 + 10 + (CHUNK_CAPACITY - MEDIA_BLOCK_SIZE) as u64
+    );
+    assert_eq!(vault_len_at(file_size, 9), vault_len_at(file_size, 4));
+}
+
+#[test]
+fn resume_continues_from_a_checkpoint_backed_by_the_vault() {
+    let file_size = 100 * BLOCK;
+    let vault_len = vault_len_at(file_size, 40);
+    assert_eq!(resume_index(file_size, 32, vault_len), 32);
+    assert_eq!(resume_index(file_size, 40, vault_len), 40);
+}
+
+#[test]
+fn resume_falls_back_to_whole_blocks_when_the_vault_lags() {
+    let file_size = 100 * BLOCK;
+    // A crash cut the vault copy short, partway into block 20
+    let vault_len = 20 * CHUNK_CAPACITY as u64 + 100;
+    assert_eq!(resume_index(file_size, 32, vault_len), 20);
+    assert_eq!(resume_index(file_size, 32, 0), 0);
+}
@@ -8,18 +8,13 @@ import type { Message, Chat } from '../types';
  * Cache and attachment orchestration:
  * Memory cache for media assets and indexing logic.
  */
-let proxyPort: number | null = null;
-
 export const getMediaUrl = async (id: string, type: string): Promise<string> => {
-    if (!proxyPort) {
-        try {
-            proxyPort = await invoke<number>('get_media_proxy_port');
-        } catch (e) {
-
-            return "";
-        }
+    // URLs are signed per unlock and expire, so they are not cached
+    try {
+        return await invoke<string>('get_media_url', { id, mimeType: type });
+    } catch (e) {
+        return "";
     }
-    return `http://127.0.0.1:${proxyPort}/media/${id}?type=${encodeURIComponent(type)}`;
 };
 
 export const setReplyingTo = (msg: Message | null) => userStore.update(s => ({ ...s, replyingTo: msg }));
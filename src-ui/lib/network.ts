@@ -31,11 +31,15 @@ export class NetworkLayer {
                 this.onAuthenticated();
             } else if (status === 'auth_failed') {
                 this.onAuthFailed();
+            } else if (status === 'version_mismatch') {
+                import('./stores/ui').then(({ addToast }) => {
+                    addToast("This relay speaks an incompatible protocol version. Update Entropy or switch relays.", 'error');
+                });
             }
         });
 
         listen('network-warning', async (event) => {
-            const { type } = event.payload as any;
+            const { type, limit, relay, error } = event.payload as any;
 
             const now = Date.now();
             const last = this.lastWarningTime.get(type) || 0;
@@ -46,9 +50,17 @@ export class NetworkLayer {
             if (type === 'media_offline') {
                 addToast("Recipient is offline. Media cannot be sent.", 'warning');
             } else if (type === 'storage_full' || type === 'Mailbox full') {
-                addToast("Recipient's offline storage is full (500 limit).", 'error');
+                addToast("Recipient's offline storage is full (500 limit). New messages will wait in the outbox.", 'error');
             } else if (type === 'sender_quota_exceeded' || type === 'Sender quota exceeded') {
-                addToast("You've hit your limit for this user's mailbox (15/15).", 'error');
+                addToast("You've hit your limit for this user's mailbox (15/15). New messages will wait in the outbox.", 'error');
+            } else if (type === 'inbound_quota') {
+                if (String(limit).startsWith('global')) {
+                    addToast("Too many incoming transfers. Some data was dropped.", 'warning');
+                } else {
+                    addToast("A contact is sending too much data and was muted for a few minutes.", 'warning');
+                }
+            } else if (type === 'relay_failed') {
+                addToast(`Relay ${relay} failed: ${error}`, 'warning');
             } else if (type) {
                 addToast(`Relay Error: ${type}`, 'error');
             }
@@ -192,6 +204,29 @@ export class NetworkLayer {
             });
         });
 
+        listen('transfer://expired', (event) => {
+            const { transfer_id, msgId } = event.payload as any;
+            import('./stores/transfers').then(m => m.removeTransfer(transfer_id));
+            if (!msgId) return;
+
+            import('./actions/chat').then(m => {
+                m.markMessageAsError(msgId, "Transfer timed out");
+            });
+        });
+
+        listen('transfer://state', (event) => {
+            const { transfer_id, state, msgId } = event.payload as any;
+            import('./stores/transfers').then(m => {
+                if (state === 'cancelled') m.removeTransfer(transfer_id);
+                else m.setTransferPaused(transfer_id, state === 'paused');
+            });
+            if (state !== 'cancelled' || !msgId) return;
+
+            import('./actions/chat').then(m => {
+                m.markMessageAsError(msgId, "Transfer cancelled");
+            });
+        });
+
         listen('network-bin-complete', (event) => {
             const { msg_id } = event.payload as any;
             if (!msg_id) return;
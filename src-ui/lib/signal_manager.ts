@@ -50,20 +50,73 @@ export class SignalManager {
         return await invoke<string>('signal_sign_message', { message });
     }
 
-    async getFingerprint(recipientHash: string): Promise<{ digits: string, trustLevel: number }> {
+    async getFingerprint(recipientHash: string): Promise<{ digits: string, trustLevel: number, scannable: string, qrSvg: string }> {
         try {
             const result = await invoke<any>('signal_get_fingerprint', {
                 remoteHash: recipientHash
             });
             return {
                 digits: result.digits,
-                trustLevel: result.trustLevel
+                trustLevel: result.trustLevel,
+                scannable: result.scannable,
+                qrSvg: result.qrSvg
             };
         } catch (e: any) {
             throw e;
         }
     }
 
+    async verifyScannedFingerprint(recipientHash: string, scanned: string): Promise<boolean> {
+        return await invoke<boolean>('signal_verify_scanned_fingerprint', {
+            remoteHash: recipientHash,
+            scanned
+        });
+    }
+
+    async getIdentityChangePolicy(): Promise<'warn' | 'blockUntilReverified'> {
+        return await invoke<'warn' | 'blockUntilReverified'>('signal_get_identity_policy');
+    }
+
+    async setIdentityChangePolicy(policy: 'warn' | 'blockUntilReverified'): Promise<void> {
+        await invoke('signal_set_identity_policy', { policy });
+    }
+
+    async getKeyHistory(recipientHash: string): Promise<{ address: string, oldKey: string, newKey: string, changedAt: number }[]> {
+        return await invoke<any[]>('signal_get_key_history', { remoteHash: recipientHash });
+    }
+
+    async beginDeviceLink(): Promise<string> {
+        return await invoke<string>('device_link_begin');
+    }
+
+    async approveDevice(deviceKey: string, name?: string): Promise<string> {
+        return await invoke<string>('device_link_approve', { deviceKey, name });
+    }
+
+    async completeDeviceLink(linkCode: string): Promise<string> {
+        const account = await invoke<string>('device_link_complete', { linkCode });
+        this.userIdentity = account;
+        return account;
+    }
+
+    async listDevices(): Promise<{ deviceId: number, name: string | null, linkedAt: number | null, isCurrent: boolean }[]> {
+        return await invoke<any[]>('device_list');
+    }
+
+    async unlinkDevice(deviceId: number): Promise<void> {
+        await invoke('device_unlink', { deviceId });
+    }
+
+    async getPreKeyInventory(): Promise<{
+        oneTimePreKeys: number, oneTimeKyberPreKeys: number,
+        signedPreKeyId: number | null, signedPreKeyCreatedAt: number | null,
+        lastResortKyberKeyId: number | null, lastResortKyberCreatedAt: number | null,
+        nextRotationAt: number | null,
+        supersededSignedPreKeys: number, supersededKyberPreKeys: number
+    }> {
+        return await invoke<any>('signal_prekey_inventory');
+    }
+
     async verifySession(peerHash: string, trustLevel: number): Promise<void> {
         try {
             await invoke('signal_set_peer_trust', {
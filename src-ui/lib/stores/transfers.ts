@@ -7,13 +7,14 @@ export interface Transfer {
     total: number;
     direction: 'upload' | 'download';
     msgId?: string;
+    paused?: boolean;
 }
 
 export const transfers = writable<Record<number, Transfer>>({});
 
 export const updateTransferProgress = (id: number, current: number, total: number, direction: 'upload' | 'download', sender?: string, msgId?: string) => {
     transfers.update(t => {
-        t[id] = { id, current, total, direction, sender, msgId };
+        t[id] = { id, current, total, direction, sender, msgId, paused: t[id]?.paused };
         return { ...t };
     });
 };
@@ -24,3 +25,10 @@ export const removeTransfer = (id: number) => {
         return { ...t };
     });
 };
+
+export const setTransferPaused = (id: number, paused: boolean) => {
+    transfers.update(t => {
+        if (t[id]) t[id] = { ...t[id], paused };
+        return { ...t };
+    });
+};